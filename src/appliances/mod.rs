@@ -0,0 +1,86 @@
+use serde_json::{Value, json};
+use std::sync::{Condvar, Mutex};
+
+#[cfg(test)]
+mod tests;
+
+static APPLIANCES: Appliances = Appliances::new();
+
+// Every guestfs launch starts a qemu appliance, so a burst of peers with NBD configs could start dozens
+// at once. Launches beyond the limit wait for a slot instead. Shared by all the disk worker threads.
+pub(super) fn appliances() -> &'static Appliances {
+    &APPLIANCES
+}
+
+struct Counters {
+    limit: Option<usize>,
+    queued: usize,
+    launching: usize,
+    running: usize,
+}
+
+pub(super) struct Appliances {
+    counters: Mutex<Counters>,
+    slot_released: Condvar,
+}
+
+impl Appliances {
+    const fn new() -> Self {
+        Self {
+            counters: Mutex::new(Counters {
+                limit: None,
+                queued: 0,
+                launching: 0,
+                running: 0,
+            }),
+            slot_released: Condvar::new(),
+        }
+    }
+
+    pub(super) fn limit_launches(&self, limit: usize) {
+        self.counters.lock().unwrap().limit = Some(limit);
+        self.slot_released.notify_all();
+    }
+
+    // Blocks the calling thread until a launch slot is free. A successfully launched appliance is counted
+    // as running until `closed` is called.
+    pub(super) fn launch<T, E>(&self, launch: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        {
+            let mut counters = self.counters.lock().unwrap();
+            counters.queued += 1;
+            let mut counters = self
+                .slot_released
+                .wait_while(counters, |counters| {
+                    counters
+                        .limit
+                        .is_some_and(|limit| counters.launching >= limit)
+                })
+                .unwrap();
+            counters.queued -= 1;
+            counters.launching += 1;
+        }
+        let result = launch();
+        let mut counters = self.counters.lock().unwrap();
+        counters.launching -= 1;
+        if result.is_ok() {
+            counters.running += 1;
+        }
+        self.slot_released.notify_one();
+        result
+    }
+
+    pub(super) fn closed(&self) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.running = counters.running.saturating_sub(1);
+    }
+
+    pub(super) fn to_json(&self) -> Value {
+        let counters = self.counters.lock().unwrap();
+        json!({
+            "running": counters.running,
+            "launching": counters.launching,
+            "queued": counters.queued,
+            "launch_limit": counters.limit,
+        })
+    }
+}
@@ -0,0 +1,44 @@
+use super::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn launches_serialize_within_limit() {
+    let appliances = Appliances::new();
+    appliances.limit_launches(2);
+    let concurrent = AtomicUsize::new(0);
+    let max_concurrent = AtomicUsize::new(0);
+    let started = Instant::now();
+    thread::scope(|scope| {
+        for _ in 0..6 {
+            scope.spawn(|| {
+                appliances.launch(|| {
+                    let now = concurrent.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_concurrent.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(50));
+                    concurrent.fetch_sub(1, Ordering::SeqCst);
+                    Ok::<(), ()>(())
+                })
+            });
+        }
+    });
+    assert_eq!(max_concurrent.load(Ordering::SeqCst), 2);
+    assert!(started.elapsed() >= Duration::from_millis(150));
+    assert_eq!(appliances.to_json()["running"], 6);
+    for _ in 0..6 {
+        appliances.closed();
+    }
+    assert_eq!(appliances.to_json()["running"], 0);
+}
+
+#[test]
+fn failed_launch_not_running() {
+    let appliances = Appliances::new();
+    let result = appliances.launch(|| Err::<(), &str>("qemu failed"));
+    assert!(result.is_err());
+    let snapshot = appliances.to_json();
+    assert_eq!(snapshot["running"], 0);
+    assert_eq!(snapshot["launching"], 0);
+    assert_eq!(snapshot["launch_limit"], Value::Null);
+}
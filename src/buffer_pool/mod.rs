@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[cfg(test)]
+mod tests;
+
+const MAX_RETAINED_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Default)]
+struct FreeList {
+    buffers: HashMap<usize, Vec<Vec<u8>>>,
+    retained_bytes: usize,
+}
+
+#[derive(Default)]
+struct Shared {
+    free_list: Mutex<FreeList>,
+    allocated: AtomicUsize,
+    reused: AtomicUsize,
+}
+
+// Buffers are shared between all peer handler threads and are kept by their size.
+#[derive(Clone, Default)]
+pub(super) struct BufferPool {
+    shared: Arc<Shared>,
+}
+
+impl BufferPool {
+    // Leased buffers are not zeroed: a buffer may hold data left by the previous session.
+    pub(super) fn lease(&self, size: usize) -> PooledBuffer {
+        let reused = {
+            let mut free_list = self.shared.free_list.lock().unwrap();
+            let buffer = free_list.buffers.get_mut(&size).and_then(Vec::pop);
+            if buffer.is_some() {
+                free_list.retained_bytes -= size;
+            }
+            buffer
+        };
+        let mut buffer = match reused {
+            Some(buffer) => {
+                self.shared.reused.fetch_add(1, Ordering::Relaxed);
+                buffer
+            }
+            None => {
+                self.shared.allocated.fetch_add(1, Ordering::Relaxed);
+                vec![0; size]
+            }
+        };
+        buffer.resize(size, 0);
+        PooledBuffer {
+            buffer,
+            pool: self.clone(),
+        }
+    }
+
+    fn give_back(&self, buffer: Vec<u8>) {
+        let size = buffer.capacity();
+        let mut free_list = self.shared.free_list.lock().unwrap();
+        if free_list.retained_bytes + size <= MAX_RETAINED_BYTES {
+            free_list.retained_bytes += size;
+            free_list.buffers.entry(size).or_default().push(buffer);
+        }
+    }
+
+    pub(super) fn allocated(&self) -> usize {
+        self.shared.allocated.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn reused(&self) -> usize {
+        self.shared.reused.load(Ordering::Relaxed)
+    }
+}
+
+impl Debug for BufferPool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl Display for BufferPool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "<BufferPool: allocated {}, reused {}>",
+            self.allocated(),
+            self.reused()
+        )
+    }
+}
+
+// Returns the buffer to the pool it was leased from on drop.
+pub(super) struct PooledBuffer {
+    buffer: Vec<u8>,
+    pool: BufferPool,
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buffer
+    }
+}
+
+impl AsMut<[u8]> for PooledBuffer {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.give_back(mem::take(&mut self.buffer));
+    }
+}
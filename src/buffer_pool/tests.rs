@@ -0,0 +1,69 @@
+use super::*;
+use std::thread;
+
+#[test]
+fn reuse_released_buffer() {
+    let pool = BufferPool::default();
+    let buffer = pool.lease(1024);
+    let first_address = buffer.as_ptr();
+    drop(buffer);
+    let buffer = pool.lease(1024);
+    assert_eq!(buffer.as_ptr(), first_address);
+    assert_eq!(buffer.len(), 1024);
+    assert_eq!(pool.allocated(), 1);
+    assert_eq!(pool.reused(), 1);
+}
+
+#[test]
+fn sizes_are_kept_apart() {
+    let pool = BufferPool::default();
+    drop(pool.lease(512));
+    let buffer = pool.lease(1468);
+    assert_eq!(buffer.len(), 1468);
+    assert_eq!(pool.allocated(), 2);
+    assert_eq!(pool.reused(), 0);
+}
+
+#[test]
+fn truncated_buffer_restored() {
+    let pool = BufferPool::default();
+    let mut buffer = pool.lease(516);
+    buffer.truncate(10);
+    drop(buffer);
+    assert_eq!(pool.lease(516).len(), 516);
+}
+
+#[test]
+fn retained_bytes_capped() {
+    let pool = BufferPool::default();
+    let size = MAX_RETAINED_BYTES / 2 + 1;
+    let first = pool.lease(size);
+    let second = pool.lease(size);
+    drop(first);
+    drop(second);
+    let _first = pool.lease(size);
+    let _second = pool.lease(size);
+    assert_eq!(pool.allocated(), 3);
+    assert_eq!(pool.reused(), 1);
+}
+
+#[test]
+fn concurrent_lease() {
+    let pool = BufferPool::default();
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    let mut buffer = pool.lease(u16::MAX as usize);
+                    buffer[0] = 1;
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert!(pool.allocated() <= 8);
+    assert_eq!(pool.allocated() + pool.reused(), 8000);
+}
@@ -0,0 +1,131 @@
+use crate::fs::OpenedFile;
+use sha2::{Digest, Sha256};
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::str::FromStr;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Algorithm {
+    Crc32,
+    Sha256,
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "crc32" => Ok(Self::Crc32),
+            "sha256" => Ok(Self::Sha256),
+            _ => Err(format!("Unsupported hash algorithm: {value}")),
+        }
+    }
+}
+
+impl Display for Algorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Crc32 => write!(f, "crc32"),
+            Self::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
+
+enum Hasher {
+    Crc32(crc32fast::Hasher),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Crc32 => Self::Crc32(crc32fast::Hasher::new()),
+            Algorithm::Sha256 => Self::Sha256(Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Crc32(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            Self::Crc32(hasher) => format!("{:08x}", hasher.finalize()),
+            Self::Sha256(hasher) => to_hex(&hasher.finalize()),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// Passes reads through to the wrapped file, feeding every byte read into the hasher.
+pub(super) struct HashingFile<'a> {
+    inner: &'a mut dyn OpenedFile,
+    algorithm: Algorithm,
+    hasher: Hasher,
+}
+
+impl<'a> HashingFile<'a> {
+    pub(super) fn new(inner: &'a mut dyn OpenedFile, algorithm: Algorithm) -> Self {
+        Self {
+            inner,
+            algorithm,
+            hasher: Hasher::new(algorithm),
+        }
+    }
+
+    pub(super) fn finish(self) -> String {
+        self.hasher.finish()
+    }
+}
+
+impl Debug for HashingFile<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HashingFile {} of {:?}", self.algorithm, self.inner)
+    }
+}
+
+impl Display for HashingFile<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<{} of {}>", self.algorithm, self.inner)
+    }
+}
+
+impl OpenedFile for HashingFile<'_> {
+    fn read_to(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read_to(buffer)?;
+        self.hasher.update(&buffer[..read]);
+        Ok(read)
+    }
+
+    fn get_size(&mut self) -> io::Result<usize> {
+        self.inner.get_size()
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.inner.rewind()?;
+        self.hasher = Hasher::new(self.algorithm);
+        Ok(())
+    }
+}
+
+// Reads the whole file through the given buffer and rewinds it, so it can be sent afterwards.
+pub(super) fn digest_of(
+    opened_file: &mut dyn OpenedFile,
+    algorithm: Algorithm,
+    buffer: &mut [u8],
+) -> io::Result<String> {
+    let mut hashing_file = HashingFile::new(opened_file, algorithm);
+    while hashing_file.read_to(buffer)? > 0 {}
+    let digest = hashing_file.finish();
+    opened_file.rewind()?;
+    Ok(digest)
+}
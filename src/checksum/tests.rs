@@ -0,0 +1,42 @@
+use super::*;
+use crate::fs::Root;
+use crate::local_fs::LocalRoot;
+use crate::tests_common::{make_payload, mk_tmp};
+use std::fs;
+
+#[test]
+fn parse_algorithm() {
+    assert_eq!("crc32".parse::<Algorithm>().unwrap(), Algorithm::Crc32);
+    assert_eq!("SHA256".parse::<Algorithm>().unwrap(), Algorithm::Sha256);
+    assert!("md5".parse::<Algorithm>().is_err());
+}
+
+#[test]
+fn known_digests() {
+    let mut crc32 = Hasher::new(Algorithm::Crc32);
+    crc32.update(b"123456789");
+    assert_eq!(crc32.finish(), "cbf43926");
+    let mut sha256 = Hasher::new(Algorithm::Sha256);
+    sha256.update(b"abc");
+    assert_eq!(
+        sha256.finish(),
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+}
+
+#[test]
+fn digest_of_local_file() {
+    let tftp_root = mk_tmp(digest_of_local_file);
+    let payload = make_payload(100_000);
+    fs::write(tftp_root.join("payload.bin"), &payload).unwrap();
+    let local_root = LocalRoot::new(tftp_root);
+    let mut opened = local_root.open("payload.bin").unwrap();
+    let mut buffer = [0u8; 1468];
+    let digest = digest_of(&mut opened, Algorithm::Sha256, &mut buffer).unwrap();
+    assert_eq!(digest, to_hex(&Sha256::digest(&payload)));
+    let digest = digest_of(&mut opened, Algorithm::Crc32, &mut buffer).unwrap();
+    assert_eq!(digest, format!("{:08x}", crc32fast::hash(&payload)));
+    let mut read_back = vec![0u8; payload.len()];
+    assert_eq!(opened.read_to(&mut read_back).unwrap(), payload.len());
+    assert_eq!(read_back, payload);
+}
@@ -63,3 +63,19 @@ fn extract_non_terminated_string() {
         io::ErrorKind::InvalidData
     ));
 }
+
+#[test]
+fn put_string_into_dirty_buffer() {
+    let mut buffer = [0xFFu8; 8];
+    let mut cursor = WriteCursor::new(&mut buffer);
+    assert_eq!(cursor.put_string("hash").unwrap(), 5);
+    assert_eq!(&buffer[..6], b"hash\x00\xFF");
+}
+
+#[test]
+fn put_string_exact_fit() {
+    let mut buffer = [0xFFu8; 5];
+    let mut cursor = WriteCursor::new(&mut buffer);
+    assert_eq!(cursor.put_string("hash").unwrap(), 5);
+    assert_eq!(&buffer, b"hash\x00");
+}
@@ -1,18 +1,215 @@
-use std::fmt;
+use crate::stats;
+use std::cell::Cell;
 use std::fmt::{Debug, Display, Formatter};
 use std::io::ErrorKind;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::time::{Duration, Instant};
+use std::{fmt, io, mem};
 use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc::error::TryRecvError;
+
+pub(super) const MAX_DSCP: u8 = 63;
+// Stray datagrams from other addresses are ignored, but a flood of them within the window means someone
+// off-path is trying to inject into the session. It is only reported: the client is still served, aborting
+// would hand the one flooding a way to cut the transfers of others short.
+pub(super) const MAX_ALIEN_DATAGRAMS: usize = 64;
+const ALIEN_WINDOW: Duration = Duration::from_secs(1);
+
+/// Marks the outgoing datagrams of the socket with the DSCP, the ECN bits are left clear.
+pub(super) fn set_dscp(socket: &UdpSocket, dscp: u8) -> io::Result<()> {
+    let (level, name) = match socket.local_addr()? {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_TOS),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+    };
+    let traffic_class = libc::c_int::from(dscp) << 2;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &traffic_class as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Linux doubles the requested size for its own bookkeeping, so a larger one can't be asked for.
+pub(super) const MIN_SOCKET_BUFFER: u32 = 4096;
+pub(super) const MAX_SOCKET_BUFFER: u32 = libc::c_int::MAX as u32 / 2;
+
+#[derive(Clone, Copy, Debug)]
+pub(super) enum SocketBuffer {
+    Receive,
+    Send,
+}
+
+impl SocketBuffer {
+    fn option(self) -> libc::c_int {
+        match self {
+            Self::Receive => libc::SO_RCVBUF,
+            Self::Send => libc::SO_SNDBUF,
+        }
+    }
+}
+
+impl Display for SocketBuffer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Receive => write!(f, "receive"),
+            Self::Send => write!(f, "send"),
+        }
+    }
+}
+
+/// Asks for the buffer size and returns the size the kernel granted, which is capped by
+/// `net.core.rmem_max` or `net.core.wmem_max`. Linux reports the size doubled for its bookkeeping
+/// overhead, the size returned is the one usable for the datagrams, as asked for.
+pub(super) fn set_buffer_size(
+    socket: &UdpSocket,
+    buffer: SocketBuffer,
+    size: u32,
+) -> io::Result<usize> {
+    let requested = size as libc::c_int;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            buffer.option(),
+            &requested as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut granted: libc::c_int = 0;
+    let mut length = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            buffer.option(),
+            &mut granted as *mut libc::c_int as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(granted as usize / 2)
+}
+
+// The buffer sizes asked for the sockets the sessions reply from, the system defaults where unset.
+#[derive(Clone, Copy, Debug, Default)]
+pub(super) struct SocketBuffers {
+    receive: Option<u32>,
+    send: Option<u32>,
+}
+
+impl SocketBuffers {
+    pub(super) fn new(receive: Option<u32>, send: Option<u32>) -> Self {
+        Self { receive, send }
+    }
+
+    // A buffer smaller than asked for still works, so the sizes are only reported. The granted sizes are
+    // logged for the long-lived sockets, or only when short of the requested ones.
+    pub(super) fn apply(&self, socket: &UdpSocket, owner: impl Display, verbose: bool) {
+        for (buffer, size) in [
+            (SocketBuffer::Receive, self.receive),
+            (SocketBuffer::Send, self.send),
+        ] {
+            let Some(size) = size else {
+                continue;
+            };
+            match set_buffer_size(socket, buffer, size) {
+                Ok(granted) if granted < size as usize => eprintln!(
+                    "{owner}: The {buffer} buffer is {granted} bytes instead of {size}, capped by the kernel"
+                ),
+                Ok(granted) if verbose => {
+                    eprintln!("{owner}: The {buffer} buffer is {granted} bytes")
+                }
+                Ok(_) => {}
+                Err(error) => {
+                    eprintln!("{owner}: Can't set the {buffer} buffer to {size} bytes: {error}")
+                }
+            }
+        }
+    }
+}
+
+/// Binds an IPv6 wildcard socket accepting IPv4 peers as well, by their IPv4-mapped addresses.
+pub(super) fn bind_dual_stack(port: u16) -> io::Result<std::net::UdpSocket> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_INET6,
+            libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Owning the fd right away closes it on the errors below.
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+    let v6_only: libc::c_int = 0;
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            &v6_only as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut address: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    address.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    address.sin6_port = port.to_be();
+    address.sin6_addr.s6_addr = Ipv6Addr::UNSPECIFIED.octets();
+    let result = unsafe {
+        libc::bind(
+            fd,
+            &address as *const libc::sockaddr_in6 as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
 
 pub(super) struct DatagramStream {
     local_socket: UdpSocket,
     peer_address: SocketAddr,
+    // Datagrams of the peer forwarded by the reader of a socket shared with other sessions.
+    inbox: Option<Mutex<UnboundedReceiver<Vec<u8>>>>,
+    // The start of the current window and the alien datagrams received within it.
+    aliens: Cell<(Instant, usize)>,
+    // Tells the log lines of one transfer from those of others running at the same time.
+    request_id: Option<u64>,
     display: String,
 }
 
 impl DatagramStream {
     pub(super) fn new(local_socket: UdpSocket, peer_address: SocketAddr) -> Self {
         let local_address = local_socket.local_addr().unwrap();
+        // An IPv6 socket reaches IPv4 peers by their IPv4-mapped addresses only.
+        let peer_address = match (local_address, peer_address) {
+            (SocketAddr::V6(_), SocketAddr::V4(peer_v4)) => {
+                SocketAddr::new(IpAddr::V6(peer_v4.ip().to_ipv6_mapped()), peer_v4.port())
+            }
+            _ => peer_address,
+        };
         let local_ip = local_address.ip().to_string();
         let local_port = local_address.port().to_string();
         let remote_ip = peer_address.ip().to_string();
@@ -21,10 +218,44 @@ impl DatagramStream {
         Self {
             local_socket,
             peer_address,
+            inbox: None,
+            aliens: Cell::new((Instant::now(), 0)),
+            request_id: None,
             display,
         }
     }
 
+    // Replies from a socket read by someone else, which passes the datagrams of the peer to the inbox.
+    pub(super) fn shared(
+        local_socket: UdpSocket,
+        peer_address: SocketAddr,
+        inbox: UnboundedReceiver<Vec<u8>>,
+    ) -> Self {
+        Self {
+            inbox: Some(Mutex::new(inbox)),
+            ..Self::new(local_socket, peer_address)
+        }
+    }
+
+    // Only a connected socket is told about the ICMP port unreachable of a gone client, which is reported
+    // as ConnectionRefused by the next send or recv.
+    pub(super) async fn connect(
+        local_socket: UdpSocket,
+        peer_address: SocketAddr,
+    ) -> std::io::Result<Self> {
+        local_socket.connect(peer_address).await?;
+        Ok(Self::new(local_socket, peer_address))
+    }
+
+    pub(super) fn with_request_id(mut self, request_id: u64) -> Self {
+        self.request_id = Some(request_id);
+        self
+    }
+
+    pub(super) fn peer_address(&self) -> SocketAddr {
+        self.peer_address
+    }
+
     pub(super) async fn send(&self, buffer: &[u8]) -> std::io::Result<()> {
         match self.local_socket.send_to(buffer, self.peer_address).await {
             Ok(sent) => {
@@ -39,13 +270,14 @@ impl DatagramStream {
     }
 
     pub(super) async fn recv(&self, buffer: &mut [u8], min_size: usize) -> std::io::Result<usize> {
+        if let Some(inbox) = &self.inbox {
+            return self.recv_forwarded(inbox, buffer, min_size).await;
+        }
         loop {
             match self.local_socket.recv_from(buffer).await {
                 Ok((recv_size, remote_address)) => {
                     if remote_address != self.peer_address {
-                        eprintln!(
-                            "{self}: Ignore datagram {recv_size} long from alien {remote_address}"
-                        );
+                        self.ignore_alien(recv_size, remote_address);
                     } else if recv_size < min_size {
                         eprintln!("{self}: Ignore runt datagram {recv_size} long");
                     } else {
@@ -56,16 +288,94 @@ impl DatagramStream {
             }
         }
     }
+
+    // Doesn't wait, the absence of a received datagram is reported as WouldBlock.
+    pub(super) fn try_recv(&self, buffer: &mut [u8], min_size: usize) -> std::io::Result<usize> {
+        if let Some(inbox) = &self.inbox {
+            let Ok(mut inbox) = inbox.try_lock() else {
+                return Err(ErrorKind::WouldBlock.into());
+            };
+            loop {
+                let datagram = match inbox.try_recv() {
+                    Ok(datagram) => datagram,
+                    Err(TryRecvError::Empty) => return Err(ErrorKind::WouldBlock.into()),
+                    Err(TryRecvError::Disconnected) => {
+                        return Err(ErrorKind::ConnectionAborted.into());
+                    }
+                };
+                if datagram.len() < min_size {
+                    eprintln!("{self}: Ignore runt datagram {} long", datagram.len());
+                    continue;
+                }
+                let recv_size = datagram.len().min(buffer.len());
+                buffer[..recv_size].copy_from_slice(&datagram[..recv_size]);
+                return Ok(recv_size);
+            }
+        }
+        loop {
+            let (recv_size, remote_address) = self.local_socket.try_recv_from(buffer)?;
+            if remote_address != self.peer_address {
+                self.ignore_alien(recv_size, remote_address);
+            } else if recv_size < min_size {
+                eprintln!("{self}: Ignore runt datagram {recv_size} long");
+            } else {
+                return Ok(recv_size);
+            }
+        }
+    }
+
+    // Only the first alien datagram of a window is logged and the flood reported once, the rest are just
+    // counted.
+    fn ignore_alien(&self, recv_size: usize, remote_address: SocketAddr) {
+        stats::count_alien_datagram();
+        let now = Instant::now();
+        let (since, count) = match self.aliens.get() {
+            (since, count) if now.duration_since(since) < ALIEN_WINDOW => (since, count + 1),
+            _ => (now, 1),
+        };
+        self.aliens.set((since, count));
+        if count == 1 {
+            eprintln!("{self}: Ignore datagram {recv_size} long from alien {remote_address}");
+        } else if count == MAX_ALIEN_DATAGRAMS + 1 {
+            eprintln!(
+                "{self}: SECURITY: {count} datagrams from aliens within {ALIEN_WINDOW:?}, the last from {remote_address}"
+            );
+        }
+    }
+
+    async fn recv_forwarded(
+        &self,
+        inbox: &Mutex<UnboundedReceiver<Vec<u8>>>,
+        buffer: &mut [u8],
+        min_size: usize,
+    ) -> std::io::Result<usize> {
+        let mut inbox = inbox.lock().await;
+        loop {
+            let Some(datagram) = inbox.recv().await else {
+                return Err(ErrorKind::ConnectionAborted.into());
+            };
+            if datagram.len() < min_size {
+                eprintln!("{self}: Ignore runt datagram {} long", datagram.len());
+                continue;
+            }
+            let recv_size = datagram.len().min(buffer.len());
+            buffer[..recv_size].copy_from_slice(&datagram[..recv_size]);
+            return Ok(recv_size);
+        }
+    }
 }
 
 impl Debug for DatagramStream {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "<{}>", self.display)
+        write!(f, "{self}")
     }
 }
 
 impl Display for DatagramStream {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "<{}>", self.display)
+        match self.request_id {
+            Some(request_id) => write!(f, "<#{request_id} {}>", self.display),
+            None => write!(f, "<{}>", self.display),
+        }
     }
 }
@@ -0,0 +1,115 @@
+use crate::fs::OpenedFile;
+use crate::local_fs::read_full;
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::io::Read;
+use std::task::{Context, Poll};
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum Codec {
+    Gzip,
+}
+
+// The compressed file as the source of the decoder.
+struct Compressed<O: OpenedFile>(O);
+
+impl<O: OpenedFile> Read for Compressed<O> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.0.read_to(buffer)
+    }
+}
+
+/// Serves the decompressed content of a compressed file, streamed through the decoder local files are
+/// served with by `--transparent-gzip`.
+pub(super) struct Decompressed<O: OpenedFile> {
+    // Only taken apart to be rebuilt on a rewind.
+    decoder: Option<GzDecoder<Compressed<O>>>,
+    codec: Codec,
+    decompressed_size: Option<usize>,
+}
+
+impl<O: OpenedFile> Decompressed<O> {
+    pub(super) fn new(inner: O, codec: Codec) -> Self {
+        Self {
+            decoder: Some(new_decoder(codec, Compressed(inner))),
+            codec,
+            decompressed_size: None,
+        }
+    }
+
+    fn decoder(&mut self) -> &mut GzDecoder<Compressed<O>> {
+        self.decoder.as_mut().unwrap()
+    }
+
+    fn inner(&self) -> &O {
+        &self.decoder.as_ref().unwrap().get_ref().0
+    }
+
+    // Nothing tells the decompressed size but decompressing the whole file, which is rewound afterwards.
+    fn count_decompressed(&mut self) -> io::Result<usize> {
+        self.rewind()?;
+        let size = io::copy(self.decoder(), &mut io::sink())?;
+        self.rewind()?;
+        Ok(size as usize)
+    }
+}
+
+fn new_decoder<O: OpenedFile>(codec: Codec, source: Compressed<O>) -> GzDecoder<Compressed<O>> {
+    match codec {
+        Codec::Gzip => GzDecoder::new(source),
+    }
+}
+
+impl<O: OpenedFile> OpenedFile for Decompressed<O> {
+    fn read_to(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        read_full(self.decoder(), buffer)
+    }
+
+    fn get_size(&mut self) -> io::Result<usize> {
+        if let Some(size) = self.decompressed_size {
+            return Ok(size);
+        }
+        let size = self.count_decompressed()?;
+        self.decompressed_size = Some(size);
+        Ok(size)
+    }
+
+    // The decoder keeps no state worth reusing, a fresh one starts over on the rewound file.
+    fn rewind(&mut self) -> io::Result<()> {
+        let mut source = self.decoder.take().unwrap().into_inner();
+        let result = source.0.rewind();
+        self.decoder = Some(new_decoder(self.codec, source));
+        result
+    }
+
+    fn mtime(&mut self) -> io::Result<u64> {
+        self.decoder().get_mut().0.mtime()
+    }
+
+    fn poll_open(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.decoder().get_mut().0.poll_open(cx)
+    }
+
+    // The compressed data is read in pieces as large as the reads of the decompressed one.
+    fn poll_fill(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<io::Result<()>> {
+        self.decoder().get_mut().0.poll_fill(cx, wanted)
+    }
+}
+
+impl<O: OpenedFile> Display for Decompressed<O> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<Decompressed {}>", self.inner())
+    }
+}
+
+impl<O: OpenedFile> Debug for Decompressed<O> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<Decompressed {:?}>", self.inner())
+    }
+}
@@ -0,0 +1,72 @@
+use super::*;
+use crate::fs::Root;
+use crate::local_fs::{LocalOpenedFile, LocalRoot};
+use crate::options::TSize;
+use crate::tests_common::{make_payload, mk_tmp, read_file};
+use flate2::Compression;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+fn gzip(content: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(content).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn open_decompressed(directory: PathBuf, compressed: &[u8]) -> Decompressed<LocalOpenedFile> {
+    fs::write(directory.join("compressed.file"), compressed).unwrap();
+    let opened = LocalRoot::new(directory).open("compressed.file").unwrap();
+    Decompressed::new(opened, Codec::Gzip)
+}
+
+#[test]
+fn decompress_gzip() {
+    let content = make_payload(100_000);
+    let mut opened = open_decompressed(mk_tmp(decompress_gzip), &gzip(&content));
+    assert_eq!(read_file(&mut opened), content);
+}
+
+#[test]
+fn tsize_counts_decompressed() {
+    let content = make_payload(1_000_000);
+    let compressed = gzip(&content);
+    assert!(compressed.len() < content.len());
+    let mut opened = open_decompressed(mk_tmp(tsize_counts_decompressed), &compressed);
+    let tsize = TSize::obtain(&mut opened).unwrap();
+    assert_eq!(
+        tsize.as_key_pair(),
+        (String::from("tsize"), content.len().to_string())
+    );
+    // The size is counted by decompressing a pass of its own, the reads start from the top regardless.
+    assert_eq!(read_file(&mut opened), content);
+}
+
+#[test]
+fn rewind_restarts_decompression() {
+    let content = make_payload(10_000);
+    let mut opened = open_decompressed(mk_tmp(rewind_restarts_decompression), &gzip(&content));
+    let mut head = [0u8; 1000];
+    assert_eq!(opened.read_to(&mut head).unwrap(), head.len());
+    opened.rewind().unwrap();
+    assert_eq!(read_file(&mut opened), content);
+}
+
+#[test]
+fn truncated_gzip_fails() {
+    let compressed = gzip(&make_payload(10_000));
+    let mut opened = open_decompressed(
+        mk_tmp(truncated_gzip_fails),
+        &compressed[..compressed.len() / 2],
+    );
+    assert!(opened.get_size().is_err());
+}
+
+#[test]
+fn parse_codec() {
+    assert_eq!(
+        serde_json::from_value::<Codec>(serde_json::json!("gzip")).unwrap(),
+        Codec::Gzip
+    );
+    assert!(serde_json::from_value::<Codec>(serde_json::json!("zstd")).is_err());
+}
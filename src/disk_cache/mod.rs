@@ -0,0 +1,139 @@
+use crate::remote_fs::RemoteRoot;
+use std::fmt::{Debug, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use std::{fs, thread};
+
+#[cfg(test)]
+mod tests;
+
+// A config edited since its disk was connected no longer matches and gets connected anew.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct ConfigKey {
+    path: PathBuf,
+    mtime: SystemTime,
+}
+
+impl ConfigKey {
+    pub(super) fn of(path: &Path) -> Option<Self> {
+        let mtime = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()?;
+        Some(Self {
+            path: path.to_path_buf(),
+            mtime,
+        })
+    }
+
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // The config is still there and unchanged.
+    pub(super) fn is_current(&self) -> bool {
+        Self::of(&self.path).as_ref() == Some(self)
+    }
+}
+
+struct CachedDisk<D> {
+    key: ConfigKey,
+    disk: D,
+    released: Instant,
+}
+
+// Keeps the disks of finished peer handlers connected for a while, so the next handler serving the same
+// config skips the appliance launch. The disks are Send: their guestfs handles stay on the worker threads.
+pub(super) struct DiskCache<D = RemoteRoot> {
+    ttl: Option<Duration>,
+    entries: Arc<Mutex<Vec<CachedDisk<D>>>>,
+}
+
+// Clones share the entries, the disks themselves are never cloned.
+impl<D> Clone for DiskCache<D> {
+    fn clone(&self) -> Self {
+        Self {
+            ttl: self.ttl,
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+impl<D> Default for DiskCache<D> {
+    fn default() -> Self {
+        Self {
+            ttl: None,
+            entries: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl<D: Send + 'static> DiskCache<D> {
+    pub(super) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl: Some(ttl),
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn is_enabled(&self) -> bool {
+        self.ttl.is_some()
+    }
+
+    pub(super) fn take(&self, key: &ConfigKey) -> Option<D> {
+        self.evict_expired();
+        let mut entries = self.entries.lock().unwrap();
+        let index = entries.iter().position(|entry| entry.key == *key)?;
+        Some(entries.swap_remove(index).disk)
+    }
+
+    pub(super) fn put(&self, key: ConfigKey, disk: D) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.evict_expired();
+        self.entries.lock().unwrap().push(CachedDisk {
+            key,
+            disk,
+            released: Instant::now(),
+        });
+    }
+
+    pub(super) fn evict_expired(&self) {
+        let Some(ttl) = self.ttl else {
+            return;
+        };
+        let expired: Vec<D> = {
+            let mut entries = self.entries.lock().unwrap();
+            let (expired, kept) = entries
+                .drain(..)
+                .partition(|entry| entry.released.elapsed() >= ttl);
+            *entries = kept;
+            expired.into_iter().map(|entry| entry.disk).collect()
+        };
+        if !expired.is_empty() {
+            eprintln!("{self}: Releasing {} expired disks", expired.len());
+            // Closing a disk waits for its appliance to shut down, which must not stall the caller.
+            thread::spawn(move || drop(expired));
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+impl<D: Send + 'static> Display for DiskCache<D> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.ttl {
+            Some(ttl) => write!(f, "<DiskCache: {} disks, TTL {ttl:?}>", self.len()),
+            None => write!(f, "<DiskCache: disabled>"),
+        }
+    }
+}
+
+impl<D: Send + 'static> Debug for DiskCache<D> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
@@ -0,0 +1,54 @@
+use super::*;
+use crate::tests_common::mk_tmp;
+
+fn config_key(path: &Path) -> ConfigKey {
+    fs::write(path, "{}").unwrap();
+    ConfigKey::of(path).unwrap()
+}
+
+#[test]
+fn reclaim_released_disk() {
+    let tftp_root = mk_tmp(reclaim_released_disk);
+    let key = config_key(&tftp_root.join("127.0.0.11.nbd"));
+    let other_key = config_key(&tftp_root.join("127.0.0.12.nbd"));
+    let cache = DiskCache::new(Duration::from_secs(60));
+    cache.put(key.clone(), "disk");
+    assert_eq!(cache.take(&other_key), None);
+    assert_eq!(cache.take(&key), Some("disk"));
+    assert_eq!(cache.take(&key), None);
+}
+
+#[test]
+fn edited_config_misses() {
+    let tftp_root = mk_tmp(edited_config_misses);
+    let config_path = tftp_root.join("127.0.0.11.nbd");
+    let key = config_key(&config_path);
+    let cache = DiskCache::new(Duration::from_secs(60));
+    cache.put(key.clone(), "disk");
+    let edited = ConfigKey {
+        mtime: key.mtime + Duration::from_secs(1),
+        ..key
+    };
+    assert_eq!(cache.take(&edited), None);
+}
+
+#[test]
+fn expired_disk_released() {
+    let tftp_root = mk_tmp(expired_disk_released);
+    let key = config_key(&tftp_root.join("127.0.0.11.nbd"));
+    let cache = DiskCache::new(Duration::from_millis(50));
+    cache.put(key.clone(), "disk");
+    thread::sleep(Duration::from_millis(100));
+    cache.evict_expired();
+    assert_eq!(cache.len(), 0);
+    assert_eq!(cache.take(&key), None);
+}
+
+#[test]
+fn disabled_cache_keeps_nothing() {
+    let tftp_root = mk_tmp(disabled_cache_keeps_nothing);
+    let key = config_key(&tftp_root.join("127.0.0.11.nbd"));
+    let cache = DiskCache::default();
+    cache.put(key.clone(), "disk");
+    assert_eq!(cache.take(&key), None);
+}
@@ -0,0 +1,112 @@
+use crate::guestfs::{GuestFS, GuestFSError};
+use std::fmt::{Debug, Display, Formatter};
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::task::{Context, Poll};
+use std::thread::{Builder, JoinHandle};
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce(&GuestFS) + Send>;
+
+fn worker_gone() -> GuestFSError {
+    GuestFSError::Generic("Disk worker is gone".to_string())
+}
+
+// Owns a GuestFS handle on a dedicated thread, so slow guestfs calls never block a runtime thread.
+pub(super) struct DiskWorker {
+    jobs: Option<mpsc::Sender<Job>>,
+    thread: Option<JoinHandle<()>>,
+    name: String,
+}
+
+impl DiskWorker {
+    pub(super) fn spawn(name: String) -> Result<Self, GuestFSError> {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let thread = Builder::new()
+            .name(name.clone())
+            .spawn(move || {
+                let handle = GuestFS::new();
+                for job in receiver {
+                    job(&handle);
+                }
+            })
+            .map_err(|error| GuestFSError::Generic(format!("Can't spawn {name}: {error}")))?;
+        Ok(Self {
+            jobs: Some(jobs),
+            thread: Some(thread),
+            name,
+        })
+    }
+
+    fn enqueue(&self, job: Job) {
+        if let Some(jobs) = &self.jobs {
+            // A dead worker drops the job along with its reply sender, which the caller sees as an error.
+            _ = jobs.send(job);
+        }
+    }
+
+    // Blocks the calling thread until the job is done.
+    pub(super) fn call<T, F>(&self, job: F) -> Result<T, GuestFSError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&GuestFS) -> Result<T, GuestFSError> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        self.enqueue(Box::new(move |handle| _ = sender.send(job(handle))));
+        receiver.recv().map_err(|_| worker_gone())?
+    }
+
+    // Queues the job, the result can be awaited without blocking the runtime thread.
+    pub(super) fn submit<T, F>(&self, job: F) -> PendingResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&GuestFS) -> Result<T, GuestFSError> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        self.enqueue(Box::new(move |handle| _ = sender.send(job(handle))));
+        PendingResult { receiver }
+    }
+}
+
+impl Drop for DiskWorker {
+    fn drop(&mut self) {
+        drop(self.jobs.take());
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            eprintln!("{self}: Worker thread panicked");
+        }
+    }
+}
+
+impl Display for DiskWorker {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<DiskWorker {}>", self.name)
+    }
+}
+
+impl Debug for DiskWorker {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<DiskWorker {}>", self.name)
+    }
+}
+
+pub(super) struct PendingResult<T> {
+    receiver: oneshot::Receiver<Result<T, GuestFSError>>,
+}
+
+impl<T> Future for PendingResult<T> {
+    type Output = Result<T, GuestFSError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.receiver)
+            .poll(cx)
+            .map(|result| result.unwrap_or_else(|_| Err(worker_gone())))
+    }
+}
+
+impl<T> Debug for PendingResult<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<PendingResult>")
+    }
+}
@@ -4,18 +4,28 @@ use std::fmt::Display;
 
 pub(super) const ERROR: u16 = 0x05;
 const UNDEFINED_ERROR: u16 = 0x00;
-
 const FILE_NOT_FOUND: u16 = 0x01;
-
 const ACCESS_VIOLATION: u16 = 0x02;
+const DISK_FULL: u16 = 0x03;
 const ILLEGAL_OPERATION: u16 = 0x04;
+const UNKNOWN_TRANSFER_ID: u16 = 0x05;
+const FILE_ALREADY_EXISTS: u16 = 0x06;
+const NO_SUCH_USER: u16 = 0x07;
+pub(super) const OPTION_NEGOTIATION: u16 = 0x08;
 
 #[derive(Debug)]
 pub(super) enum TFTPError {
     UndefinedError(String),
     FileNotFound(String),
     AccessViolation(String),
+    DiskFull(String),
     IllegalOperation(String),
+    UnknownTransferId(String),
+    #[allow(dead_code)]
+    FileAlreadyExists(String),
+    #[allow(dead_code)]
+    NoSuchUser(String),
+    OptionNegotiation(String),
 }
 
 impl TFTPError {
@@ -27,14 +37,46 @@ impl TFTPError {
         Self::FileNotFound("File not found".to_string())
     }
 
+    pub(super) fn is_a_directory() -> Self {
+        Self::FileNotFound("Requested path is a directory".to_string())
+    }
+
+    pub(super) fn file_too_large() -> Self {
+        Self::UndefinedError("File exceeds the maximum served size".to_string())
+    }
+
+    pub(super) fn transfer_timed_out() -> Self {
+        Self::UndefinedError("Transfer exceeded the connect timeout".to_string())
+    }
+
+    pub(super) fn timed_out() -> Self {
+        Self::UndefinedError("Timed out accessing the file".to_string())
+    }
+
     pub(super) fn access_violation() -> Self {
         Self::AccessViolation("Access violation".to_string())
     }
 
+    pub(super) fn disk_full() -> Self {
+        Self::DiskFull("Disk full or allocation exceeded".to_string())
+    }
+
     pub(super) fn illegal_operation<M: Into<String>>(message: M) -> Self {
         Self::IllegalOperation(message.into())
     }
 
+    pub(super) fn unknown_transfer_id() -> Self {
+        Self::UnknownTransferId("Unknown transfer ID".to_string())
+    }
+
+    pub(super) fn option_negotiation<M: Into<String>>(message: M) -> Self {
+        Self::OptionNegotiation(message.into())
+    }
+
+    pub(super) fn code(&self) -> u16 {
+        self.parse().0
+    }
+
     pub(super) fn serialize(&self, buffer: &mut [u8]) -> Result<usize, BufferError> {
         let mut cursor = WriteCursor::new(buffer);
         let (code, message) = self.parse();
@@ -48,7 +90,12 @@ impl TFTPError {
             TFTPError::UndefinedError(string) => (UNDEFINED_ERROR, string),
             TFTPError::FileNotFound(string) => (FILE_NOT_FOUND, string),
             TFTPError::AccessViolation(string) => (ACCESS_VIOLATION, string),
+            TFTPError::DiskFull(string) => (DISK_FULL, string),
             TFTPError::IllegalOperation(string) => (ILLEGAL_OPERATION, string),
+            TFTPError::UnknownTransferId(string) => (UNKNOWN_TRANSFER_ID, string),
+            TFTPError::FileAlreadyExists(string) => (FILE_ALREADY_EXISTS, string),
+            TFTPError::NoSuchUser(string) => (NO_SUCH_USER, string),
+            TFTPError::OptionNegotiation(string) => (OPTION_NEGOTIATION, string),
         }
     }
 }
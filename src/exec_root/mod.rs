@@ -0,0 +1,261 @@
+use crate::file_filter::matches;
+use crate::fs::{OpenedFile, Root};
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+
+#[cfg(test)]
+mod tests;
+
+pub(super) const DEFAULT_EXEC_TIMEOUT_MS: u64 = 5000;
+pub(super) const DEFAULT_EXEC_MAX_OUTPUT: usize = 16 * 1024 * 1024;
+
+// Commands generating the contents of the files matching their patterns.
+#[derive(Clone)]
+pub(super) struct ExecHooks {
+    entries: Vec<(String, PathBuf)>,
+    timeout: Duration,
+    max_output: usize,
+}
+
+impl Default for ExecHooks {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            timeout: Duration::from_millis(DEFAULT_EXEC_TIMEOUT_MS),
+            max_output: DEFAULT_EXEC_MAX_OUTPUT,
+        }
+    }
+}
+
+impl ExecHooks {
+    // Every mapping is `PATTERN=COMMAND`, the pattern is matched like the --allow ones.
+    pub(super) fn parse(mappings: &[String]) -> Result<Self, String> {
+        let mut entries = Vec::with_capacity(mappings.len());
+        for mapping in mappings {
+            let (pattern, command) = mapping
+                .split_once('=')
+                .ok_or_else(|| format!("{mapping:?} is not in the PATTERN=COMMAND form"))?;
+            if pattern.is_empty() || command.is_empty() {
+                return Err(format!("Empty pattern or command in {mapping:?}"));
+            }
+            entries.push((pattern.to_string(), PathBuf::from(command)));
+        }
+        Ok(Self {
+            entries,
+            ..Self::default()
+        })
+    }
+
+    pub(super) fn with_limits(mut self, timeout: Duration, max_output: usize) -> Self {
+        self.timeout = timeout;
+        self.max_output = max_output;
+        self
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // The first matching pattern wins.
+    fn lookup(&self, path: &str) -> Option<&PathBuf> {
+        self.entries
+            .iter()
+            .find(|(pattern, _command)| matches(pattern, path))
+            .map(|(_pattern, command)| command)
+    }
+}
+
+impl Debug for ExecHooks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl Display for ExecHooks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let entries: Vec<String> = self
+            .entries
+            .iter()
+            .map(|(pattern, command)| format!("{pattern} => {command:?}"))
+            .collect();
+        write!(f, "<ExecHooks {}>", entries.join(", "))
+    }
+}
+
+// Runs the command to completion. A command over the timeout or the output limit is killed along with
+// the future, and so is one left running by a transfer gone.
+async fn run(
+    command: PathBuf,
+    args: [String; 2],
+    timeout: Duration,
+    max_output: usize,
+) -> io::Result<Vec<u8>> {
+    let mut child = Command::new(&command)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let finished = async {
+        let mut output = Vec::new();
+        (&mut stdout)
+            .take(max_output as u64 + 1)
+            .read_to_end(&mut output)
+            .await?;
+        if output.len() > max_output {
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                format!("{command:?} output exceeds {max_output} bytes"),
+            ));
+        }
+        Ok((output, child.wait().await?))
+    };
+    let (output, status) = match tokio::time::timeout(timeout, finished).await {
+        Ok(finished) => finished?,
+        Err(_elapsed) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{command:?} didn't finish in {timeout:?}"),
+            ));
+        }
+    };
+    if !status.success() {
+        return Err(io::Error::other(format!("{command:?} failed: {status}")));
+    }
+    Ok(output)
+}
+
+// The path is passed to the command as an argument, so it must neither climb out of the tree it names
+// nor read as an option.
+fn check_path(path: &str) -> io::Result<()> {
+    if path.starts_with('-') || path.split('/').any(|component| component == "..") {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{path:?} is not passed to a command"),
+        ));
+    }
+    Ok(())
+}
+
+// Files generated for a peer by the commands of the hooks matching the requested paths.
+pub(super) struct ExecRoot {
+    hooks: ExecHooks,
+    peer: IpAddr,
+}
+
+impl ExecRoot {
+    pub(super) fn new(hooks: ExecHooks, peer: IpAddr) -> Self {
+        Self { hooks, peer }
+    }
+}
+
+impl Debug for ExecRoot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl Display for ExecRoot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<Exec for {}>", self.peer)
+    }
+}
+
+impl Root for ExecRoot {
+    type OpenedFile = GeneratedFile;
+
+    // The command is run by `poll_open`, the file is ready once it finishes.
+    fn open(&self, path: &str) -> io::Result<Self::OpenedFile> {
+        let Some(command) = self.hooks.lookup(path) else {
+            return Err(io::ErrorKind::NotFound.into());
+        };
+        check_path(path)?;
+        let peer = self.peer.to_string();
+        let display = format!("{command:?} {peer} {path}");
+        let generation = run(
+            command.clone(),
+            [peer, path.to_string()],
+            self.hooks.timeout,
+            self.hooks.max_output,
+        );
+        Ok(GeneratedFile {
+            generation: Some(Box::pin(generation)),
+            content: Vec::new(),
+            position: 0,
+            display,
+        })
+    }
+}
+
+type Generation = Pin<Box<dyn Future<Output = io::Result<Vec<u8>>> + Send>>;
+
+pub(super) struct GeneratedFile {
+    // The command still running.
+    generation: Option<Generation>,
+    content: Vec<u8>,
+    position: usize,
+    display: String,
+}
+
+impl GeneratedFile {
+    fn content(&self) -> io::Result<&[u8]> {
+        match self.generation {
+            Some(_) => Err(io::Error::other(format!("{self} is not generated yet"))),
+            None => Ok(&self.content),
+        }
+    }
+}
+
+impl Debug for GeneratedFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "GeneratedFile: {} ({} bytes)",
+            self.display,
+            self.content.len()
+        )
+    }
+}
+
+impl Display for GeneratedFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<GeneratedFile {}>", self.display)
+    }
+}
+
+impl OpenedFile for GeneratedFile {
+    fn read_to(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let remaining = &self.content()?[self.position..];
+        let read_bytes = remaining.len().min(buffer.len());
+        buffer[..read_bytes].copy_from_slice(&remaining[..read_bytes]);
+        self.position += read_bytes;
+        Ok(read_bytes)
+    }
+
+    fn get_size(&mut self) -> io::Result<usize> {
+        Ok(self.content()?.len())
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.position = 0;
+        Ok(())
+    }
+
+    fn poll_open(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(generation) = self.generation.as_mut() {
+            let generated = ready!(generation.as_mut().poll(cx));
+            self.generation = None;
+            self.content = generated?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
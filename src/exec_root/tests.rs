@@ -0,0 +1,128 @@
+use super::*;
+use crate::tests_common::mk_tmp;
+use std::fs;
+use std::future::poll_fn;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Instant;
+
+fn hooks(specs: &[&str]) -> ExecHooks {
+    let specs: Vec<String> = specs.iter().map(|spec| spec.to_string()).collect();
+    ExecHooks::parse(&specs).unwrap()
+}
+
+fn write_script(path: &Path, body: &str) -> String {
+    fs::write(path, format!("#!/bin/sh\n{body}\n")).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+fn peer() -> IpAddr {
+    "127.0.0.11".parse().unwrap()
+}
+
+async fn generate(root: &ExecRoot, path: &str) -> io::Result<GeneratedFile> {
+    let mut file = root.open(path)?;
+    poll_fn(|cx| file.poll_open(cx)).await?;
+    Ok(file)
+}
+
+fn read_all(file: &mut GeneratedFile) -> Vec<u8> {
+    let mut content = Vec::new();
+    let mut buffer = [0u8; 4];
+    loop {
+        match file.read_to(&mut buffer).unwrap() {
+            0 => return content,
+            read_bytes => content.extend_from_slice(&buffer[..read_bytes]),
+        }
+    }
+}
+
+#[test]
+fn parse_hooks() {
+    let parsed = hooks(&["*.ign=/bin/echo", "hosts/*=/usr/bin/env"]);
+    assert_eq!(parsed.lookup("web.ign"), Some(&PathBuf::from("/bin/echo")));
+    assert_eq!(
+        parsed.lookup("hosts/web"),
+        Some(&PathBuf::from("/usr/bin/env"))
+    );
+    assert_eq!(parsed.lookup("pxelinux.0"), None);
+    for invalid in ["*.ign", "=/bin/echo", "*.ign="] {
+        assert!(
+            ExecHooks::parse(&[invalid.to_string()]).is_err(),
+            "{invalid}"
+        );
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn serve_command_output() {
+    let root = ExecRoot::new(hooks(&["*.ign=/bin/echo"]), peer());
+    let mut file = generate(&root, "hosts/web.ign").await.unwrap();
+    let expected = b"127.0.0.11 hosts/web.ign\n";
+    assert_eq!(file.get_size().unwrap(), expected.len());
+    assert_eq!(read_all(&mut file), expected);
+    file.rewind().unwrap();
+    assert_eq!(read_all(&mut file), expected);
+}
+
+#[test]
+fn unmatched_path_not_found() {
+    let root = ExecRoot::new(hooks(&["*.ign=/bin/echo"]), peer());
+    let error = root.open("pxelinux.0").unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn slow_command_killed() {
+    let temp_dir = mk_tmp(slow_command_killed);
+    let script = write_script(&temp_dir.join("slow.sh"), "exec sleep 10");
+    let hooks = hooks(&[&format!("*={script}")]).with_limits(Duration::from_millis(200), 1024);
+    let started = Instant::now();
+    let error = generate(&ExecRoot::new(hooks, peer()), "file")
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn oversized_output_refused() {
+    let temp_dir = mk_tmp(oversized_output_refused);
+    let script = write_script(&temp_dir.join("large.sh"), "head -c 2048 /dev/zero");
+    let hooks = hooks(&[&format!("*={script}")]).with_limits(Duration::from_secs(5), 1024);
+    let error = generate(&ExecRoot::new(hooks, peer()), "file")
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::FileTooLarge);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn failed_command_refused() {
+    let temp_dir = mk_tmp(failed_command_refused);
+    let script = write_script(&temp_dir.join("failing.sh"), "echo partial; exit 3");
+    let root = ExecRoot::new(hooks(&[&format!("*={script}")]), peer());
+    let error = generate(&root, "file").await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::Other);
+}
+
+#[test]
+fn unsafe_paths_refused() {
+    let temp_dir = mk_tmp(unsafe_paths_refused);
+    let marker = temp_dir.join("ran");
+    let script = write_script(&temp_dir.join("touch.sh"), &format!("touch {marker:?}"));
+    let root = ExecRoot::new(hooks(&[&format!("*={script}")]), peer());
+    for path in ["-rf", "hosts/../../etc/passwd", ".."] {
+        let error = root.open(path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied, "{path}");
+    }
+    assert!(!marker.exists());
+}
+
+#[test]
+fn ungenerated_file_unreadable() {
+    let root = ExecRoot::new(hooks(&["*.ign=/bin/echo"]), peer());
+    let mut file = root.open("web.ign").unwrap();
+    assert!(file.get_size().is_err());
+    assert!(file.read_to(&mut [0u8; 4]).is_err());
+}
@@ -0,0 +1,59 @@
+use std::fmt::{Debug, Display, Formatter};
+
+#[cfg(test)]
+mod tests;
+
+const PLACEHOLDERS: [&str; 2] = ["{dir}", "{name}"];
+
+// Files served in place of a requested one missing in every root, tried in order.
+#[derive(Clone, Default)]
+pub(super) struct FallbackFiles {
+    templates: Vec<String>,
+}
+
+impl FallbackFiles {
+    // `{dir}` expands to the directory of the requested file and `{name}` to its name.
+    pub(super) fn parse(templates: &[String]) -> Result<Self, String> {
+        for template in templates {
+            let mut remainder = template.clone();
+            for placeholder in PLACEHOLDERS {
+                remainder = remainder.replace(placeholder, "");
+            }
+            if remainder.contains(['{', '}']) {
+                return Err(format!("Unknown placeholder in {template:?}"));
+            }
+            if remainder.trim_matches('/').is_empty() && !template.contains("{name}") {
+                return Err(format!("{template:?} doesn't name a file"));
+            }
+        }
+        Ok(Self {
+            templates: templates.to_vec(),
+        })
+    }
+
+    pub(super) fn candidates(&self, filename: &str) -> Vec<String> {
+        let filename = filename.trim_start_matches('/');
+        let (dir, name) = filename.rsplit_once('/').unwrap_or(("", filename));
+        let mut candidates: Vec<String> = Vec::with_capacity(self.templates.len());
+        for template in &self.templates {
+            let candidate = template.replace("{dir}", dir).replace("{name}", name);
+            let candidate = candidate.trim_start_matches('/').to_string();
+            if candidate != filename && !candidates.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+        candidates
+    }
+}
+
+impl Debug for FallbackFiles {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl Display for FallbackFiles {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<FallbackFiles {}>", self.templates.join(", "))
+    }
+}
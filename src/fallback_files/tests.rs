@@ -0,0 +1,44 @@
+use super::*;
+
+fn fallbacks(templates: &[&str]) -> FallbackFiles {
+    let templates: Vec<String> = templates.iter().map(|spec| spec.to_string()).collect();
+    FallbackFiles::parse(&templates).unwrap()
+}
+
+#[test]
+fn expand_directory() {
+    let fallback_files = fallbacks(&["{dir}/default", "pxelinux.0"]);
+    assert_eq!(
+        fallback_files.candidates("/pxelinux.cfg/01-aa-bb-cc-dd-ee-ff"),
+        vec!["pxelinux.cfg/default", "pxelinux.0"]
+    );
+    assert_eq!(
+        fallback_files.candidates("menu"),
+        vec!["default", "pxelinux.0"]
+    );
+}
+
+#[test]
+fn expand_name() {
+    let fallback_files = fallbacks(&["generic/{name}"]);
+    assert_eq!(
+        fallback_files.candidates("hosts/grub.cfg"),
+        vec!["generic/grub.cfg"]
+    );
+}
+
+#[test]
+fn skip_requested_file() {
+    let fallback_files = fallbacks(&["{dir}/default", "pxelinux.cfg/default"]);
+    assert!(fallback_files.candidates("pxelinux.cfg/default").is_empty());
+}
+
+#[test]
+fn invalid_templates() {
+    for template in ["{mac}/default", "{dir}/", "{dir"] {
+        assert!(
+            FallbackFiles::parse(&[template.to_string()]).is_err(),
+            "{template} is accepted"
+        );
+    }
+}
@@ -0,0 +1,366 @@
+use crate::fs::OpenedFile;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::{io, mem};
+
+#[cfg(test)]
+mod tests;
+
+// Identical files are those of the same disk source and path which didn't change size or mtime since.
+#[derive(Clone, Debug, PartialEq)]
+pub(super) struct FileKey {
+    source: String,
+    path: String,
+    size: usize,
+    mtime: u64,
+}
+
+impl FileKey {
+    pub(super) fn new(source: &str, path: &str, size: usize, mtime: u64) -> Self {
+        Self {
+            source: source.to_string(),
+            path: path.to_string(),
+            size,
+            mtime,
+        }
+    }
+}
+
+struct CachedContent {
+    key: FileKey,
+    content: Arc<[u8]>,
+}
+
+#[derive(Default)]
+struct Entries {
+    // The least recently used content goes first.
+    contents: Vec<CachedContent>,
+    total_size: usize,
+    // The files recorded right now, a file is recorded by a single reader at a time.
+    recorded: Vec<FileKey>,
+    // The sizes of the recorded files, charged against the budget along with the contents.
+    reserved: usize,
+}
+
+impl Entries {
+    // Makes room for `size` more bytes within the budget, unless it can't be had.
+    fn make_room(&mut self, size: usize, budget: usize) -> bool {
+        while self.total_size + self.reserved + size > budget {
+            if self.contents.is_empty() {
+                return false;
+            }
+            let evicted = self.contents.remove(0);
+            self.total_size -= evicted.content.len();
+        }
+        true
+    }
+}
+
+// Keeps the contents of remote files read through, so peers booting from the same disk source share
+// them instead of reading every file through their own appliances. Clones share the contents.
+#[derive(Clone, Default)]
+pub(super) struct FileCache {
+    budget: usize,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl FileCache {
+    pub(super) fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn is_enabled(&self) -> bool {
+        self.budget > 0
+    }
+
+    pub(super) fn get(&self, key: &FileKey) -> Option<Arc<[u8]>> {
+        let mut entries = self.entries.lock().unwrap();
+        let index = entries
+            .contents
+            .iter()
+            .position(|cached| cached.key == *key)?;
+        let cached = entries.contents.remove(index);
+        let content = cached.content.clone();
+        entries.contents.push(cached);
+        Some(content)
+    }
+
+    pub(super) fn put(&self, key: FileKey, content: Arc<[u8]>) {
+        if !self.is_enabled() || content.len() > self.budget {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(index) = entries.contents.iter().position(|cached| cached.key == key) {
+            let replaced = entries.contents.remove(index);
+            entries.total_size -= replaced.content.len();
+        }
+        if !entries.make_room(content.len(), self.budget) {
+            return;
+        }
+        entries.total_size += content.len();
+        entries.contents.push(CachedContent { key, content });
+    }
+
+    // Serves the file from the cache, or opens it and records its content while it is read through.
+    pub(super) fn open<F: OpenedFile>(
+        &self,
+        key: FileKey,
+        display: String,
+        open: impl FnOnce() -> io::Result<F>,
+    ) -> io::Result<SharedFile<F>> {
+        if let Some(content) = self.get(&key) {
+            eprintln!("{display}: Serving from the file cache");
+            return Ok(SharedFile::Cached(CachedFile {
+                content,
+                offset: 0,
+                mtime: key.mtime,
+                display,
+            }));
+        }
+        let file = open()?;
+        let recording = self.reserve(&key).then(|| {
+            Box::new(Recording {
+                content: Vec::with_capacity(key.size),
+                key,
+                cache: self.clone(),
+            })
+        });
+        Ok(SharedFile::Source(RecordedFile { file, recording }))
+    }
+
+    // The cached contents make way for the recording, which may still be abandoned. A file recorded
+    // already, or one the budget can't take along with the other recordings, isn't recorded at all.
+    fn reserve(&self, key: &FileKey) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.recorded.contains(key)
+            || entries.reserved + key.size > self.budget
+            || !entries.make_room(key.size, self.budget)
+        {
+            return false;
+        }
+        entries.reserved += key.size;
+        entries.recorded.push(key.clone());
+        true
+    }
+
+    fn release(&self, key: &FileKey) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(index) = entries.recorded.iter().position(|recorded| recorded == key) {
+            entries.recorded.swap_remove(index);
+            entries.reserved -= key.size;
+        }
+    }
+
+    fn total_size(&self) -> usize {
+        self.entries.lock().unwrap().total_size
+    }
+}
+
+impl Display for FileCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_enabled() {
+            write!(
+                f,
+                "<FileCache: {} of {} bytes>",
+                self.total_size(),
+                self.budget
+            )
+        } else {
+            write!(f, "<FileCache: disabled>")
+        }
+    }
+}
+
+impl Debug for FileCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct CachedFile {
+    content: Arc<[u8]>,
+    offset: usize,
+    mtime: u64,
+    display: String,
+}
+
+impl Display for CachedFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.display)
+    }
+}
+
+impl OpenedFile for CachedFile {
+    fn read_to(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let remaining = &self.content[self.offset..];
+        let read = remaining.len().min(buffer.len());
+        buffer[..read].copy_from_slice(&remaining[..read]);
+        self.offset += read;
+        Ok(read)
+    }
+
+    fn get_size(&mut self) -> io::Result<usize> {
+        Ok(self.content.len())
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.offset = 0;
+        Ok(())
+    }
+
+    fn mtime(&mut self) -> io::Result<u64> {
+        Ok(self.mtime)
+    }
+
+    fn read_at(&mut self, offset: usize, buffer: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.content.get(offset..).unwrap_or_default();
+        let read = remaining.len().min(buffer.len());
+        buffer[..read].copy_from_slice(&remaining[..read]);
+        Ok(read)
+    }
+}
+
+// Holds its reservation in the cache until it is dropped, finished or not.
+#[derive(Debug)]
+struct Recording {
+    key: FileKey,
+    content: Vec<u8>,
+    cache: FileCache,
+}
+
+impl Recording {
+    fn commit(mut self) {
+        let (key, content) = (self.key.clone(), mem::take(&mut self.content));
+        let cache = self.cache.clone();
+        drop(self);
+        cache.put(key, content.into());
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        self.cache.release(&self.key);
+    }
+}
+
+// Reads go to the file itself, the content read from the start is offered to the cache at the end.
+#[derive(Debug)]
+pub(super) struct RecordedFile<F> {
+    file: F,
+    recording: Option<Box<Recording>>,
+}
+
+impl<F: OpenedFile> OpenedFile for RecordedFile<F> {
+    fn read_to(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_to(buffer)?;
+        if let Some(recording) = &mut self.recording {
+            recording.content.extend_from_slice(&buffer[..read]);
+            let size = recording.key.size;
+            if read < buffer.len() || recording.content.len() >= size {
+                let recording = self.recording.take().unwrap();
+                // The file changed while being read, so the content doesn't match the key.
+                if recording.content.len() == size {
+                    recording.commit();
+                }
+            }
+        }
+        Ok(read)
+    }
+
+    fn get_size(&mut self) -> io::Result<usize> {
+        self.file.get_size()
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        if let Some(recording) = &mut self.recording {
+            recording.content.clear();
+        }
+        self.file.rewind()
+    }
+
+    fn mtime(&mut self) -> io::Result<u64> {
+        self.file.mtime()
+    }
+
+    fn poll_fill(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<io::Result<()>> {
+        self.file.poll_fill(cx, wanted)
+    }
+
+    // Blocks read again don't extend the recording.
+    fn read_at(&mut self, offset: usize, buffer: &mut [u8]) -> io::Result<usize> {
+        self.file.read_at(offset, buffer)
+    }
+}
+
+impl<F: OpenedFile> Display for RecordedFile<F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.file)
+    }
+}
+
+#[derive(Debug)]
+pub(super) enum SharedFile<F> {
+    Cached(CachedFile),
+    Source(RecordedFile<F>),
+}
+
+impl<F: OpenedFile> Display for SharedFile<F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cached(cached_file) => write!(f, "{cached_file}"),
+            Self::Source(recorded_file) => write!(f, "{recorded_file}"),
+        }
+    }
+}
+
+impl<F: OpenedFile> OpenedFile for SharedFile<F> {
+    fn read_to(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Cached(cached_file) => cached_file.read_to(buffer),
+            Self::Source(recorded_file) => recorded_file.read_to(buffer),
+        }
+    }
+
+    fn get_size(&mut self) -> io::Result<usize> {
+        match self {
+            Self::Cached(cached_file) => cached_file.get_size(),
+            Self::Source(recorded_file) => recorded_file.get_size(),
+        }
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        match self {
+            Self::Cached(cached_file) => cached_file.rewind(),
+            Self::Source(recorded_file) => recorded_file.rewind(),
+        }
+    }
+
+    fn mtime(&mut self) -> io::Result<u64> {
+        match self {
+            Self::Cached(cached_file) => cached_file.mtime(),
+            Self::Source(recorded_file) => recorded_file.mtime(),
+        }
+    }
+
+    fn poll_fill(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<io::Result<()>> {
+        match self {
+            Self::Cached(cached_file) => cached_file.poll_fill(cx, wanted),
+            Self::Source(recorded_file) => recorded_file.poll_fill(cx, wanted),
+        }
+    }
+
+    fn read_at(&mut self, offset: usize, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Cached(cached_file) => cached_file.read_at(offset, buffer),
+            Self::Source(recorded_file) => recorded_file.read_at(offset, buffer),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use super::*;
+use crate::tests_common::make_payload;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+// Stands for a file read through an appliance, counting the reads reaching it.
+struct CountedFile {
+    content: Vec<u8>,
+    offset: usize,
+    reads: Arc<AtomicUsize>,
+}
+
+impl CountedFile {
+    fn new(content: Vec<u8>, reads: Arc<AtomicUsize>) -> Self {
+        Self {
+            content,
+            offset: 0,
+            reads,
+        }
+    }
+}
+
+impl fmt::Display for CountedFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CountedFile [{}]", self.offset)
+    }
+}
+
+impl fmt::Debug for CountedFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CountedFile [{}]", self.offset)
+    }
+}
+
+impl OpenedFile for CountedFile {
+    fn read_to(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        let remaining = &self.content[self.offset..];
+        let size = remaining.len().min(buffer.len());
+        buffer[..size].copy_from_slice(&remaining[..size]);
+        self.offset += size;
+        Ok(size)
+    }
+
+    fn get_size(&mut self) -> io::Result<usize> {
+        Ok(self.content.len())
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.offset = 0;
+        Ok(())
+    }
+}
+
+fn key(path: &str, size: usize) -> FileKey {
+    FileKey::new("nbd://127.0.0.1/disk", path, size, 1700000000)
+}
+
+fn open(
+    cache: &FileCache,
+    path: &str,
+    content: &[u8],
+    reads: &Arc<AtomicUsize>,
+) -> SharedFile<CountedFile> {
+    cache
+        .open(key(path, content.len()), path.to_string(), || {
+            Ok(CountedFile::new(content.to_vec(), reads.clone()))
+        })
+        .unwrap()
+}
+
+fn read_all<O: OpenedFile>(opened: &mut O) -> Vec<u8> {
+    let mut content = Vec::new();
+    let mut chunk = [0u8; 512];
+    loop {
+        let read_size = opened.read_to(&mut chunk).unwrap();
+        content.extend_from_slice(&chunk[..read_size]);
+        if read_size < chunk.len() {
+            return content;
+        }
+    }
+}
+
+#[test]
+fn second_peer_skips_disk() {
+    let cache = FileCache::new(1024 * 1024);
+    let content = make_payload(512 * 10 + 100);
+    let reads = Arc::new(AtomicUsize::new(0));
+    let first_peer = {
+        let (cache, content, reads) = (cache.clone(), content.clone(), reads.clone());
+        thread::spawn(move || read_all(&mut open(&cache, "/boot/vmlinuz", &content, &reads)))
+    };
+    assert_eq!(first_peer.join().unwrap(), content);
+    let disk_reads = reads.load(Ordering::SeqCst);
+    assert_eq!(disk_reads, 11);
+    let second_peer = {
+        let (cache, content, reads) = (cache.clone(), content.clone(), reads.clone());
+        thread::spawn(move || {
+            let mut opened = open(&cache, "/boot/vmlinuz", &content, &reads);
+            assert!(matches!(opened, SharedFile::Cached(_)));
+            read_all(&mut opened)
+        })
+    };
+    assert_eq!(second_peer.join().unwrap(), content);
+    assert_eq!(reads.load(Ordering::SeqCst), disk_reads);
+}
+
+#[test]
+fn partial_read_not_cached() {
+    let cache = FileCache::new(1024 * 1024);
+    let content = make_payload(2000);
+    let reads = Arc::new(AtomicUsize::new(0));
+    let mut opened = open(&cache, "/boot/initrd", &content, &reads);
+    opened.read_to(&mut [0u8; 512]).unwrap();
+    drop(opened);
+    assert!(cache.get(&key("/boot/initrd", content.len())).is_none());
+}
+
+#[test]
+fn rewound_read_cached_whole() {
+    let cache = FileCache::new(1024 * 1024);
+    let content = make_payload(2000);
+    let reads = Arc::new(AtomicUsize::new(0));
+    let mut opened = open(&cache, "/boot/initrd", &content, &reads);
+    opened.read_to(&mut [0u8; 512]).unwrap();
+    opened.rewind().unwrap();
+    assert_eq!(read_all(&mut opened), content);
+    let cached = cache.get(&key("/boot/initrd", content.len())).unwrap();
+    assert_eq!(*cached, *content);
+}
+
+#[test]
+fn changed_file_misses() {
+    let cache = FileCache::new(1024 * 1024);
+    cache.put(key("/boot/initrd", 3), Arc::from(&b"abc"[..]));
+    assert!(cache.get(&key("/boot/initrd", 4)).is_none());
+    let other_source = FileKey::new("nbd://127.0.0.2/disk", "/boot/initrd", 3, 1700000000);
+    assert!(cache.get(&other_source).is_none());
+}
+
+#[test]
+fn least_recently_used_evicted() {
+    let cache = FileCache::new(10);
+    cache.put(key("/a", 4), Arc::from(&b"aaaa"[..]));
+    cache.put(key("/b", 4), Arc::from(&b"bbbb"[..]));
+    assert!(cache.get(&key("/a", 4)).is_some());
+    cache.put(key("/c", 4), Arc::from(&b"cccc"[..]));
+    assert!(cache.get(&key("/b", 4)).is_none());
+    assert!(cache.get(&key("/a", 4)).is_some());
+    assert!(cache.get(&key("/c", 4)).is_some());
+    assert_eq!(cache.total_size(), 8);
+}
+
+#[test]
+fn file_over_budget_not_recorded() {
+    let cache = FileCache::new(100);
+    let content = make_payload(1000);
+    let reads = Arc::new(AtomicUsize::new(0));
+    read_all(&mut open(&cache, "/boot/vmlinuz", &content, &reads));
+    assert_eq!(cache.total_size(), 0);
+}
+
+#[test]
+fn disabled_cache_keeps_nothing() {
+    let cache = FileCache::default();
+    let content = make_payload(1000);
+    let reads = Arc::new(AtomicUsize::new(0));
+    read_all(&mut open(&cache, "/boot/vmlinuz", &content, &reads));
+    assert!(cache.get(&key("/boot/vmlinuz", content.len())).is_none());
+}
+
+#[test]
+fn concurrent_readers_record_once() {
+    let cache = FileCache::new(1024 * 1024);
+    let content = make_payload(2000);
+    let reads = Arc::new(AtomicUsize::new(0));
+    let mut first = open(&cache, "/boot/initrd", &content, &reads);
+    let mut second = open(&cache, "/boot/initrd", &content, &reads);
+    assert_eq!(cache.entries.lock().unwrap().reserved, content.len());
+    assert_eq!(read_all(&mut second), content);
+    assert!(cache.get(&key("/boot/initrd", content.len())).is_none());
+    assert_eq!(read_all(&mut first), content);
+    assert!(cache.get(&key("/boot/initrd", content.len())).is_some());
+    assert_eq!(cache.entries.lock().unwrap().reserved, 0);
+}
+
+#[test]
+fn recordings_charged_against_budget() {
+    let cache = FileCache::new(3000);
+    cache.put(key("/a", 1000), Arc::from(make_payload(1000)));
+    let reads = Arc::new(AtomicUsize::new(0));
+    let content = make_payload(2000);
+    let recorded = open(&cache, "/boot/initrd", &content, &reads);
+    assert!(cache.get(&key("/a", 1000)).is_some());
+    // Along with the first recording, the budget can't take another one as large.
+    let mut unrecorded = open(&cache, "/boot/vmlinuz", &content, &reads);
+    assert_eq!(read_all(&mut unrecorded), content);
+    assert!(cache.get(&key("/boot/vmlinuz", content.len())).is_none());
+    // The cached content makes way for a recording which fits.
+    let _second = open(&cache, "/boot/other", &make_payload(1000), &reads);
+    assert!(cache.get(&key("/a", 1000)).is_none());
+    assert_eq!(cache.entries.lock().unwrap().reserved, 3000);
+    // An abandoned recording gives its reservation back.
+    drop(recorded);
+    assert_eq!(cache.entries.lock().unwrap().reserved, 1000);
+}
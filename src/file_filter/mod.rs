@@ -0,0 +1,105 @@
+use std::fmt::{Debug, Display, Formatter};
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Clone, Default)]
+pub(super) struct FileFilter {
+    allowed: Vec<String>,
+    denied: Vec<String>,
+}
+
+impl FileFilter {
+    pub(super) fn new(allowed: Vec<String>, denied: Vec<String>) -> Self {
+        Self { allowed, denied }
+    }
+
+    /// Deny patterns take precedence. An empty allow list permits everything not denied. The path is
+    /// matched the way the roots resolve it, and refused if it climbs above the root.
+    pub(super) fn permits(&self, path: &str) -> bool {
+        let Some(path) = normalize(path) else {
+            return false;
+        };
+        if self.denied.iter().any(|pattern| matches(pattern, &path)) {
+            return false;
+        }
+        self.allowed.is_empty() || self.allowed.iter().any(|pattern| matches(pattern, &path))
+    }
+}
+
+// Drops the empty and `.` components and resolves `..` lexically, so `./private/x` and `a/../private/x`
+// are matched as `private/x`. None if `..` climbs above the root.
+fn normalize(path: &str) -> Option<String> {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop()?;
+            }
+            component => components.push(component),
+        }
+    }
+    Some(components.join("/"))
+}
+
+impl Debug for FileFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "<FileFilter allow {:?}, deny {:?}>",
+            self.allowed, self.denied
+        )
+    }
+}
+
+impl Display for FileFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "<FileFilter allow {:?}, deny {:?}>",
+            self.allowed, self.denied
+        )
+    }
+}
+
+// Patterns without a slash are matched against the file name only, others against the whole path.
+pub(super) fn matches(pattern: &str, path: &str) -> bool {
+    let subject = if pattern.contains('/') {
+        path
+    } else {
+        path.rsplit('/').next().unwrap_or(path)
+    };
+    glob_match(pattern.as_bytes(), subject.as_bytes())
+}
+
+fn glob_match(pattern: &[u8], subject: &[u8]) -> bool {
+    let (mut pattern_index, mut subject_index) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while subject_index < subject.len() {
+        match pattern.get(pattern_index) {
+            Some(b'*') => {
+                backtrack = Some((pattern_index, subject_index));
+                pattern_index += 1;
+                continue;
+            }
+            Some(&symbol) if symbol == b'?' || symbol == subject[subject_index] => {
+                pattern_index += 1;
+                subject_index += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match backtrack {
+            Some((star_index, star_subject_index)) => {
+                backtrack = Some((star_index, star_subject_index + 1));
+                pattern_index = star_index + 1;
+                subject_index = star_subject_index + 1;
+            }
+            None => return false,
+        }
+    }
+    pattern[pattern_index..]
+        .iter()
+        .all(|&symbol| symbol == b'*')
+}
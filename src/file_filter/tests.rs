@@ -0,0 +1,55 @@
+use super::*;
+
+#[test]
+fn glob() {
+    assert!(glob_match(b"*.cfg", b"pxelinux.cfg"));
+    assert!(glob_match(b"boot?.efi", b"boot1.efi"));
+    assert!(glob_match(b"*", b""));
+    assert!(!glob_match(b"*.cfg", b"pxelinux.cfg.bak"));
+    assert!(!glob_match(b"boot?.efi", b"boot.efi"));
+}
+
+#[test]
+fn no_rules_permit_everything() {
+    let filter = FileFilter::default();
+    assert!(filter.permits("arbitrary.file"));
+}
+
+#[test]
+fn allowed_extension() {
+    let filter = FileFilter::new(vec!["*.cfg".to_string(), "*.efi".to_string()], vec![]);
+    assert!(filter.permits("pxelinux.cfg"));
+    assert!(filter.permits("/grub/grubx64.efi"));
+    assert!(!filter.permits("secret.key"));
+}
+
+#[test]
+fn denied_dotfile() {
+    let filter = FileFilter::new(vec![], vec![".*".to_string()]);
+    assert!(!filter.permits(".secret"));
+    assert!(!filter.permits("subdir/.secret"));
+    assert!(filter.permits("visible.file"));
+}
+
+#[test]
+fn deny_takes_precedence() {
+    let filter = FileFilter::new(
+        vec!["*.cfg".to_string()],
+        vec!["private/*".to_string(), ".*".to_string()],
+    );
+    assert!(filter.permits("public/boot.cfg"));
+    assert!(!filter.permits("private/boot.cfg"));
+    assert!(!filter.permits(".hidden.cfg"));
+}
+
+#[test]
+fn denied_path_normalized() {
+    let filter = FileFilter::new(vec![], vec!["private/*".to_string()]);
+    assert!(!filter.permits("./private/x"));
+    assert!(!filter.permits("a/../private/x"));
+    assert!(!filter.permits("private//./x"));
+    assert!(filter.permits("a/../public/x"));
+    // Nothing above the root is served, whatever the patterns.
+    assert!(!FileFilter::default().permits("../x"));
+    assert!(!FileFilter::default().permits("a/../../x"));
+}
@@ -1,12 +1,53 @@
+use crate::exec_root::ExecRoot;
 use crate::local_fs::LocalRoot;
 use crate::remote_fs::RemoteRoot;
+use crate::upstream::UpstreamRoot;
 use std::fmt::{Debug, Display};
 use std::io;
+use std::task::{Context, Poll};
 
 pub(super) trait OpenedFile: Display + Debug {
     fn read_to(&mut self, buffer: &mut [u8]) -> io::Result<usize>;
 
     fn get_size(&mut self) -> io::Result<usize>;
+
+    fn rewind(&mut self) -> io::Result<()>;
+
+    fn mtime(&mut self) -> io::Result<u64> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    // Finishes opening a file whose backend answers asynchronously, before anything else is asked of it.
+    fn poll_open(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    // Makes `wanted` bytes available to `read_to` without blocking, if the file is slow to read.
+    fn poll_fill(&mut self, _cx: &mut Context<'_>, _wanted: usize) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    // Reads at `offset` without moving the position `read_to` goes on from, filling the buffer completely
+    // unless the end of the file is reached. Transformed content can't be read at random.
+    fn read_at(&mut self, _offset: usize, _buffer: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+// Files read without blocking the task. Backends with blocking reads are adapted by `Offloaded`.
+pub(super) trait AsyncOpenedFile: Display + Debug {
+    // Like `OpenedFile::read_to`: the buffer is filled completely unless the end of the file is reached.
+    fn poll_read(&mut self, cx: &mut Context<'_>, buffer: &mut [u8]) -> Poll<io::Result<usize>>;
+
+    // Like `OpenedFile::read_at`, lets the blocks already sent be served again.
+    fn poll_read_at(
+        &mut self,
+        _cx: &mut Context<'_>,
+        _offset: usize,
+        _buffer: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Err(io::ErrorKind::Unsupported.into()))
+    }
 }
 
 pub(super) trait Root: Display + Debug {
@@ -17,4 +58,6 @@ pub(super) trait Root: Display + Debug {
 pub(super) enum RootKind {
     Local(LocalRoot),
     Remote(RemoteRoot),
+    Exec(ExecRoot),
+    Upstream(UpstreamRoot),
 }
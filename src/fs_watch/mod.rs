@@ -6,10 +6,14 @@ use std::fs::File;
 use std::io;
 use std::io::Read;
 use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::Path;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 use tokio::io::unix::AsyncFd;
+use tokio::sync::Notify;
 use tokio::task::JoinHandle;
+use tokio::time::timeout;
 
 mod async_channel;
 #[cfg(test)]
@@ -17,12 +21,14 @@ mod tests;
 
 const EVENT_HEADER_SIZE: usize = size_of::<InotifyEventHeader>();
 const EVENT_BUFFER_SIZE: usize = EVENT_HEADER_SIZE + libc::PATH_MAX as usize + 1;
+const CLOSE_DEADLINE: Duration = Duration::from_millis(500);
 
 pub(super) trait Event: Debug {
     fn file_name(&self) -> String;
     fn is_modify(&self) -> bool;
     #[allow(dead_code)]
     fn is_removal(&self) -> bool;
+    fn is_moved_from(&self) -> bool;
 }
 pub(super) trait Observer: Debug {
     type E: Event;
@@ -40,11 +46,13 @@ struct InotifyEventHeader {
 #[derive(Clone)]
 pub(super) struct InotifyEvent {
     mask: u32,
+    // The events the watch counts as a change.
+    change_mask: u32,
     file_name: Option<String>,
 }
 
 impl InotifyEvent {
-    fn from(buffer: &[u8]) -> Result<(Self, usize), ParseError> {
+    fn from(buffer: &[u8], change_mask: u32) -> Result<(Self, usize), ParseError> {
         if buffer.len() < EVENT_HEADER_SIZE {
             return Err(ParseError::NotEnoughBytes);
         }
@@ -67,6 +75,7 @@ impl InotifyEvent {
         Ok((
             Self {
                 mask: event_header.mask,
+                change_mask,
                 file_name,
             },
             message_offset,
@@ -96,12 +105,16 @@ impl Event for InotifyEvent {
         }
     }
     fn is_modify(&self) -> bool {
-        (self.mask & (libc::IN_MOVED_TO | libc::IN_CLOSE_WRITE)) > 0
+        (self.mask & self.change_mask) > 0
     }
 
     fn is_removal(&self) -> bool {
         (self.mask & libc::IN_DELETE) > 0
     }
+
+    fn is_moved_from(&self) -> bool {
+        (self.mask & libc::IN_MOVED_FROM) > 0
+    }
 }
 
 pub(super) enum ParseError {
@@ -112,10 +125,25 @@ pub(super) struct INotifyObserver {
     fd: Rc<AsyncFd<File>>,
     wd: i32,
     join_handle: JoinHandle<()>,
+    stop: Rc<Notify>,
     rx: async_channel::RX<InotifyEvent>,
     display: String,
 }
 
+impl INotifyObserver {
+    /// Stops the read loop and waits for it to drain the fd, so the fd is closed once this returns.
+    pub(super) async fn close(mut self) {
+        self.stop.notify_one();
+        if timeout(CLOSE_DEADLINE, &mut self.join_handle)
+            .await
+            .is_err()
+        {
+            eprintln!("{self:?}: Read loop didn't stop in {CLOSE_DEADLINE:?}, aborting");
+            self.join_handle.abort();
+        }
+    }
+}
+
 impl Observer for INotifyObserver {
     type E = InotifyEvent;
 
@@ -130,19 +158,61 @@ impl Debug for INotifyObserver {
     }
 }
 
-pub struct Watch(u32);
+pub struct Watch {
+    mask: u32,
+    // The part of the mask reported as a change by `Event::is_modify`.
+    change_mask: u32,
+}
 
 impl Watch {
     pub(super) fn new() -> Self {
-        Watch(0)
+        Watch {
+            mask: 0,
+            change_mask: 0,
+        }
     }
+
+    fn on_change(self, events: u32) -> Self {
+        Self {
+            mask: self.mask | events,
+            change_mask: self.change_mask | events,
+        }
+    }
+
+    // Files written completely, or atomically renamed into place.
     pub(super) fn change(self) -> Self {
-        Self(self.0 | libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO)
+        self.close_write().moved_to()
+    }
+
+    pub(super) fn modify(self) -> Self {
+        self.on_change(libc::IN_MODIFY)
+    }
+
+    pub(super) fn create(self) -> Self {
+        self.on_change(libc::IN_CREATE)
+    }
+
+    pub(super) fn close_write(self) -> Self {
+        self.on_change(libc::IN_CLOSE_WRITE)
+    }
+
+    pub(super) fn moved_to(self) -> Self {
+        self.on_change(libc::IN_MOVED_TO)
     }
 
     #[allow(dead_code)]
     pub(super) fn removal(self) -> Self {
-        Self(self.0 | libc::IN_DELETE)
+        Self {
+            mask: self.mask | libc::IN_DELETE,
+            ..self
+        }
+    }
+
+    pub(super) fn rename_away(self) -> Self {
+        Self {
+            mask: self.mask | libc::IN_MOVED_FROM,
+            ..self
+        }
     }
 
     pub(super) fn observe(&self, directory: &str) -> io::Result<INotifyObserver> {
@@ -152,27 +222,92 @@ impl Watch {
         if raw_fd < 0 {
             return Err(io::Error::last_os_error());
         }
-        let wd = unsafe { libc::inotify_add_watch(raw_fd, path.as_ptr(), self.0) };
+        let file = unsafe { File::from_raw_fd(raw_fd) };
+        let wd = unsafe { libc::inotify_add_watch(raw_fd, path.as_ptr(), self.mask) };
         if wd < 0 {
             return Err(io::Error::last_os_error());
         }
-        let file = unsafe { File::from_raw_fd(raw_fd) };
         let (tx, rx) = async_channel::new::<InotifyEvent>();
         let async_fd = Rc::new(AsyncFd::new(file)?);
-        let join_handle = tokio::task::spawn_local(read_loop(async_fd.clone(), tx));
+        let stop = Rc::new(Notify::new());
+        let join_handle = tokio::task::spawn_local(read_loop(
+            async_fd.clone(),
+            tx,
+            stop.clone(),
+            self.change_mask,
+        ));
         Ok(INotifyObserver {
             fd: async_fd,
             wd,
             join_handle,
+            stop,
             rx,
             display: directory.to_string(),
         })
     }
+
+    // For readers on blocking threads, which wait for the events themselves instead of running a read loop.
+    pub(super) fn observe_blocking(&self, path: &Path) -> io::Result<BlockingObserver> {
+        let path = CString::new(path.as_os_str().as_encoded_bytes())?;
+        let raw_fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if raw_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let file = unsafe { File::from_raw_fd(raw_fd) };
+        if unsafe { libc::inotify_add_watch(raw_fd, path.as_ptr(), self.mask) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(BlockingObserver { file })
+    }
+}
+
+// The watch is removed along with the fd.
+pub(super) struct BlockingObserver {
+    file: File,
+}
+
+impl BlockingObserver {
+    // Whether any event arrived before the deadline. The events are consumed, only their arrival matters.
+    pub(super) fn wait(&self, deadline: Instant) -> io::Result<bool> {
+        let mut poll_fd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            // Rounded up, so the wait never ends short of the deadline.
+            let timeout_ms = remaining
+                .as_micros()
+                .div_ceil(1000)
+                .min(libc::c_int::MAX as u128) as libc::c_int;
+            match unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) } {
+                0 => return Ok(false),
+                polled if polled > 0 => break,
+                _ => {
+                    let error = io::Error::last_os_error();
+                    if error.kind() != io::ErrorKind::Interrupted {
+                        return Err(error);
+                    }
+                }
+            }
+        }
+        let mut buffer: [u8; EVENT_BUFFER_SIZE] = [0; EVENT_BUFFER_SIZE];
+        while matches!((&self.file).read(&mut buffer), Ok(read_bytes) if read_bytes > 0) {}
+        Ok(true)
+    }
 }
 
+impl Debug for BlockingObserver {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<BlockingObserver: {:?}>", self.file)
+    }
+}
+
+// Without `close` the read loop ends on its next turn and only then releases the fd.
 impl Drop for INotifyObserver {
     fn drop(&mut self) {
-        self.join_handle.abort();
+        self.stop.notify_one();
         let result = unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), self.wd) };
         if result != 0 {
             eprintln!(
@@ -183,17 +318,29 @@ impl Drop for INotifyObserver {
     }
 }
 
-async fn read_loop(fd: Rc<AsyncFd<File>>, mut tx: TX<InotifyEvent>) {
+async fn read_loop(
+    fd: Rc<AsyncFd<File>>,
+    mut tx: TX<InotifyEvent>,
+    stop: Rc<Notify>,
+    change_mask: u32,
+) {
     let mut buffer: [u8; EVENT_BUFFER_SIZE] = [0; EVENT_BUFFER_SIZE];
     loop {
-        let mut guard = match fd.readable().await {
+        let readable = tokio::select! {
+            _ = stop.notified() => {
+                drain(fd.get_ref(), &mut buffer, &mut tx, change_mask);
+                return;
+            }
+            readable = fd.readable() => readable,
+        };
+        let mut guard = match readable {
             Ok(guard) => guard,
             Err(error) => panic!("Error reading from fs_watch fd: {error}"),
         };
         match guard.try_io(|inner| inner.get_ref().read(&mut buffer)) {
             Ok(Ok(0)) => return,
             Ok(Ok(read_bytes)) => {
-                for event in parse_events(&buffer, read_bytes) {
+                for event in parse_events(&buffer, read_bytes, change_mask) {
                     eprintln!("Sending fs_watch event: {event:?} ...");
                     tx.push(event);
                 }
@@ -206,11 +353,23 @@ async fn read_loop(fd: Rc<AsyncFd<File>>, mut tx: TX<InotifyEvent>) {
     }
 }
 
-fn parse_events(buffer: &[u8], bytes_read: usize) -> Vec<InotifyEvent> {
+// Passes on the events queued by the moment the loop is stopped.
+fn drain(mut file: &File, buffer: &mut [u8], tx: &mut TX<InotifyEvent>, change_mask: u32) {
+    while let Ok(read_bytes) = file.read(buffer) {
+        if read_bytes == 0 {
+            return;
+        }
+        for event in parse_events(buffer, read_bytes, change_mask) {
+            tx.push(event);
+        }
+    }
+}
+
+fn parse_events(buffer: &[u8], bytes_read: usize, change_mask: u32) -> Vec<InotifyEvent> {
     let mut result = Vec::new();
     let mut offset: usize = 0;
     loop {
-        match InotifyEvent::from(&buffer[offset..bytes_read]) {
+        match InotifyEvent::from(&buffer[offset..bytes_read], change_mask) {
             Ok((event, event_size)) => {
                 offset += event_size;
                 result.push(event);
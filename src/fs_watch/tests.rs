@@ -1,12 +1,30 @@
 use super::*;
 use crate::tests_common::mk_tmp;
-use std::fs::remove_file;
+use std::fs;
+use std::fs::{remove_file, rename};
 use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
 use std::time::Duration;
 use tokio::runtime::Builder;
 use tokio::task::LocalSet;
 use tokio::time::timeout;
 
+// The inotify fds of the process watching `directory`, so the fds of tests running alongside don't count.
+fn inotify_fd_count(directory: &Path) -> usize {
+    let watched = format!(" ino:{:x} ", fs::metadata(directory).unwrap().ino());
+    fs::read_dir("/proc/self/fdinfo")
+        .unwrap()
+        .flatten()
+        .filter(|entry| {
+            fs::read_to_string(entry.path()).is_ok_and(|info| {
+                info.lines()
+                    .any(|line| line.starts_with("inotify ") && line.contains(&watched))
+            })
+        })
+        .count()
+}
+
 #[test]
 fn test_create_delete() {
     LocalSet::new().block_on(
@@ -55,3 +73,174 @@ async fn test_create_delete_coro() {
     );
     assert!(timeout(Duration::from_secs(1), watch.next()).await.is_err());
 }
+
+#[test]
+fn test_rename_away() {
+    LocalSet::new().block_on(
+        &Builder::new_current_thread().enable_all().build().unwrap(),
+        test_rename_away_coro(),
+    );
+}
+
+async fn test_rename_away_coro() {
+    let temp_dir = mk_tmp(test_rename_away);
+    let old_path = temp_dir.join("old_file");
+    File::create(&old_path).unwrap();
+    let watch = Watch::new()
+        .change()
+        .rename_away()
+        .observe(temp_dir.to_str().unwrap())
+        .unwrap();
+    rename(&old_path, temp_dir.join("new_file")).unwrap();
+    let moved_from = watch.next().await;
+    assert_eq!(moved_from.file_name(), "old_file");
+    assert!(moved_from.is_moved_from());
+    assert!(!moved_from.is_modify());
+    let moved_to = watch.next().await;
+    assert_eq!(moved_to.file_name(), "new_file");
+    assert!(moved_to.is_modify());
+    assert!(!moved_to.is_moved_from());
+    assert!(timeout(Duration::from_secs(1), watch.next()).await.is_err());
+}
+
+#[test]
+fn observers_release_fds() {
+    LocalSet::new().block_on(
+        &Builder::new_current_thread().enable_all().build().unwrap(),
+        observers_release_fds_coro(),
+    );
+}
+
+async fn observers_release_fds_coro() {
+    let temp_dir = mk_tmp(observers_release_fds);
+    let fds_before = inotify_fd_count(&temp_dir);
+    for iteration in 0..50 {
+        let watch = Watch::new()
+            .change()
+            .observe(temp_dir.to_str().unwrap())
+            .unwrap();
+        // Leaves events unread by the time the observer goes away.
+        for file_index in 0..3 {
+            fs::write(
+                temp_dir.join(format!("{iteration}_{file_index}")),
+                b"payload",
+            )
+            .unwrap();
+        }
+        assert_eq!(watch.next().await.file_name(), format!("{iteration}_0"));
+        if iteration % 2 == 0 {
+            assert!(inotify_fd_count(&temp_dir) > fds_before);
+            watch.close().await;
+            assert_eq!(inotify_fd_count(&temp_dir), fds_before);
+        } else {
+            drop(watch);
+        }
+    }
+    // Dropped observers release their fds once their read loops get a turn.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(inotify_fd_count(&temp_dir), fds_before);
+}
+
+#[test]
+fn reload_on_modify() {
+    LocalSet::new().block_on(
+        &Builder::new_current_thread().enable_all().build().unwrap(),
+        reload_on_modify_coro(),
+    );
+}
+
+async fn reload_on_modify_coro() {
+    let temp_dir = mk_tmp(reload_on_modify);
+    let path = temp_dir.join("config");
+    let mut fd = File::create(&path).unwrap();
+    let watch = Watch::new()
+        .modify()
+        .removal()
+        .observe(temp_dir.to_str().unwrap())
+        .unwrap();
+    fd.write_all(b"Arbitrary payload").unwrap();
+    let modified = watch.next().await;
+    assert_eq!(modified.file_name(), "config");
+    assert!(modified.is_modify());
+    drop(fd);
+    remove_file(&path).unwrap();
+    let removed = watch.next().await;
+    assert!(removed.is_removal());
+    assert!(!removed.is_modify());
+    assert!(timeout(Duration::from_secs(1), watch.next()).await.is_err());
+}
+
+#[test]
+fn reload_on_create() {
+    LocalSet::new().block_on(
+        &Builder::new_current_thread().enable_all().build().unwrap(),
+        reload_on_create_coro(),
+    );
+}
+
+async fn reload_on_create_coro() {
+    let temp_dir = mk_tmp(reload_on_create);
+    let watch = Watch::new()
+        .create()
+        .observe(temp_dir.to_str().unwrap())
+        .unwrap();
+    // Neither the write nor the close of the new file are reported.
+    fs::write(temp_dir.join("config"), b"Arbitrary payload").unwrap();
+    let created = watch.next().await;
+    assert_eq!(created.file_name(), "config");
+    assert!(created.is_modify());
+    assert!(timeout(Duration::from_secs(1), watch.next()).await.is_err());
+}
+
+#[test]
+fn reload_on_close_write() {
+    LocalSet::new().block_on(
+        &Builder::new_current_thread().enable_all().build().unwrap(),
+        reload_on_close_write_coro(),
+    );
+}
+
+async fn reload_on_close_write_coro() {
+    let temp_dir = mk_tmp(reload_on_close_write);
+    let watch = Watch::new()
+        .close_write()
+        .observe(temp_dir.to_str().unwrap())
+        .unwrap();
+    let mut fd = File::create(temp_dir.join("config")).unwrap();
+    fd.write_all(b"Arbitrary payload").unwrap();
+    assert!(
+        timeout(Duration::from_millis(100), watch.next())
+            .await
+            .is_err()
+    );
+    drop(fd);
+    let closed = watch.next().await;
+    assert_eq!(closed.file_name(), "config");
+    assert!(closed.is_modify());
+    assert!(timeout(Duration::from_secs(1), watch.next()).await.is_err());
+}
+
+#[test]
+fn reload_on_moved_to() {
+    LocalSet::new().block_on(
+        &Builder::new_current_thread().enable_all().build().unwrap(),
+        reload_on_moved_to_coro(),
+    );
+}
+
+async fn reload_on_moved_to_coro() {
+    let temp_dir = mk_tmp(reload_on_moved_to);
+    let watched_dir = temp_dir.join("watched");
+    fs::create_dir(&watched_dir).unwrap();
+    let staged_path = temp_dir.join("config.tmp");
+    fs::write(&staged_path, b"Arbitrary payload").unwrap();
+    let watch = Watch::new()
+        .moved_to()
+        .observe(watched_dir.to_str().unwrap())
+        .unwrap();
+    rename(&staged_path, watched_dir.join("config")).unwrap();
+    let moved_to = watch.next().await;
+    assert_eq!(moved_to.file_name(), "config");
+    assert!(moved_to.is_modify());
+    assert!(timeout(Duration::from_secs(1), watch.next()).await.is_err());
+}
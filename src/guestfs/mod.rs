@@ -1,10 +1,13 @@
+use crate::appliances::appliances;
+use std::cell::Cell;
 use std::error::Error;
 use std::ffi::{CStr, CString};
 use std::fmt::{Debug, Display, Formatter};
+use std::path::Path;
 use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::mpsc::{Receiver, Sender, channel};
-use std::{ptr, slice};
+use std::{env, fs, io, process, ptr, slice};
 
 #[cfg(test)]
 mod tests;
@@ -24,6 +27,9 @@ const GUEST_FS_EVENT_APPLIANCE: u64 = 0x0010;
 // there is a limit of data returned from guestfs_pread() which is somewhere between 2 and 4 mb.
 const CHUNK_SIZE: i32 = 3 * 1024 * 1024;
 
+// Looked up by guestfs_create, and inherited by qemu and the other helpers libguestfs runs.
+const TMP_DIR_VARIABLES: [&str; 3] = ["TMPDIR", "LIBGUESTFS_TMPDIR", "LIBGUESTFS_CACHEDIR"];
+
 type GuestFSEventCallback = Option<
     unsafe extern "C" fn(
         g: *const guestfs_h,
@@ -95,12 +101,32 @@ unsafe extern "C" {
 
     fn guestfs_list_partitions(handle: *const guestfs_h) -> *mut *mut libc::c_char;
 
+    fn guestfs_ls(
+        handle: *const guestfs_h,
+        directory: *const libc::c_char,
+    ) -> *mut *mut libc::c_char;
+
     fn guestfs_mount_ro(
         handle: *const guestfs_h,
         device: *const libc::c_char,
         mountpoint: *const libc::c_char,
     ) -> libc::c_int;
 
+    fn guestfs_mount(
+        handle: *const guestfs_h,
+        mountable: *const libc::c_char,
+        mountpoint: *const libc::c_char,
+    ) -> libc::c_int;
+
+    fn guestfs_write(
+        handle: *const guestfs_h,
+        path: *const libc::c_char,
+        content: *const libc::c_char,
+        content_size: libc::size_t,
+    ) -> libc::c_int;
+
+    fn guestfs_sync(handle: *const guestfs_h) -> libc::c_int;
+
     fn guestfs_set_append(handle: *const guestfs_h, append: *const libc::c_char) -> libc::c_int;
 
     fn guestfs_free_stat(guestfs_free_stat: *const guestfs_stat) -> libc::c_void;
@@ -109,6 +135,10 @@ unsafe extern "C" {
 
     fn guestfs_set_pgroup(handle: *const guestfs_h, pgroup: libc::c_int) -> libc::c_int;
 
+    fn guestfs_set_memsize(handle: *const guestfs_h, memsize: libc::c_int) -> libc::c_int;
+
+    fn guestfs_set_smp(handle: *const guestfs_h, v: libc::c_int) -> libc::c_int;
+
     fn guestfs_pread(
         handle: *const guestfs_h,
         path: *const libc::c_char,
@@ -146,6 +176,31 @@ fn get_last_error(handle: *const guestfs_h) -> GuestFSError {
     }
 }
 
+/// Makes the appliances keep their temporary files and caches in the directory. Must be called before
+/// any thread is spawned, since the environment can't be changed safely while others may read it.
+pub(super) fn use_tmp_dir(tmp_dir: &Path) -> io::Result<()> {
+    for (variable, value) in tmp_dir_environment(tmp_dir)? {
+        unsafe { env::set_var(variable, value) };
+    }
+    Ok(())
+}
+
+// The variables pointing at the directory, once it is found writable.
+fn tmp_dir_environment(tmp_dir: &Path) -> io::Result<Vec<(&'static str, &Path)>> {
+    check_writable(tmp_dir)?;
+    Ok(TMP_DIR_VARIABLES
+        .into_iter()
+        .map(|variable| (variable, tmp_dir))
+        .collect())
+}
+
+// A read-only or full filesystem would otherwise only show up as a failed appliance launch.
+fn check_writable(dir: &Path) -> io::Result<()> {
+    let probe = dir.join(format!(".rtftp-probe-{}", process::id()));
+    fs::write(&probe, b"probe")?;
+    fs::remove_file(probe)
+}
+
 fn disable_signals_propagation(handle: &*const guestfs_h) -> Result<(), GuestFSError> {
     if unsafe { guestfs_set_pgroup(*handle, 1) } == 0 {
         Ok(())
@@ -154,10 +209,18 @@ fn disable_signals_propagation(handle: &*const guestfs_h) -> Result<(), GuestFSE
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub(super) struct FileStat {
+    pub(super) size: usize,
+    pub(super) mtime: u64,
+    pub(super) mode: u32,
+}
+
 pub(super) struct GuestFS {
     handle: *const guestfs_h,
     events_receiver: Receiver<Vec<u8>>,
     _events_sender: Pin<Box<Sender<Vec<u8>>>>, // Ensure proper drop at the end of the structure's lifecycle.
+    launched: Cell<bool>,
 }
 
 impl GuestFS {
@@ -188,6 +251,7 @@ impl GuestFS {
             handle,
             events_receiver: receiver,
             _events_sender: pinned_sender,
+            launched: Cell::new(false),
         }
     }
 
@@ -233,11 +297,15 @@ impl GuestFS {
     }
 
     pub(super) fn launch(&self) -> Result<(), GuestFSError> {
-        if unsafe { guestfs_launch(self.handle) } == 0 {
-            Ok(())
-        } else {
-            Err(get_last_error(self.handle))
-        }
+        appliances().launch(|| {
+            if unsafe { guestfs_launch(self.handle) } == 0 {
+                Ok(())
+            } else {
+                Err(get_last_error(self.handle))
+            }
+        })?;
+        self.launched.set(true);
+        Ok(())
     }
 
     pub(super) fn retrieve_appliance_stderr(&self) -> Vec<String> {
@@ -254,19 +322,17 @@ impl GuestFS {
         if result.is_null() {
             return Err(get_last_error(self.handle));
         };
-        let mut partitions_list: Vec<String> = Vec::new();
-        for index in 0..100usize {
-            let partition_name = unsafe {
-                let entry_ptr = *result.add(index);
-                if entry_ptr.is_null() {
-                    break;
-                }
-                CString::from_raw(entry_ptr)
-            };
-            partitions_list.push(partition_name.into_string().unwrap());
-        }
-        unsafe { libc::free(result as *mut libc::c_void) };
-        Ok(partitions_list)
+        Ok(unsafe { take_string_list(result) })
+    }
+
+    // The names in the directory, without the . and .. entries.
+    pub(super) fn ls<S: AsRef<str>>(&self, directory: S) -> Result<Vec<String>, GuestFSError> {
+        let c_str_directory = CString::new(directory.as_ref()).expect("CString::new failed");
+        let result = unsafe { guestfs_ls(self.handle, c_str_directory.as_ptr()) };
+        if result.is_null() {
+            return Err(get_last_error(self.handle));
+        };
+        Ok(unsafe { take_string_list(result) })
     }
 
     pub(super) fn mount_ro<S: AsRef<str>>(
@@ -290,18 +356,60 @@ impl GuestFS {
         }
     }
 
-    pub(super) fn get_size<S: AsRef<str>>(&self, path: S) -> Result<usize, GuestFSError> {
+    pub(super) fn mount<S: AsRef<str>>(
+        &self,
+        device: S,
+        mountpoint: S,
+    ) -> Result<(), GuestFSError> {
+        let c_str_device = CString::new(device.as_ref()).expect("CString::new failed");
+        let c_str_mountpoint = CString::new(mountpoint.as_ref()).expect("CString::new failed");
+        if unsafe {
+            guestfs_mount(
+                self.handle,
+                c_str_device.as_ptr(),
+                c_str_mountpoint.as_ptr(),
+            )
+        } == 0
+        {
+            Ok(())
+        } else {
+            Err(get_last_error(self.handle))
+        }
+    }
+
+    // Creates or replaces the file, the content reaches the disk on return.
+    pub(super) fn write<S: AsRef<str>>(&self, path: S, content: &[u8]) -> Result<(), GuestFSError> {
         let c_str_path = CString::new(path.as_ref()).expect("CString::new failed");
-        let size = unsafe {
+        let written = unsafe {
+            guestfs_write(
+                self.handle,
+                c_str_path.as_ptr(),
+                content.as_ptr() as *const libc::c_char,
+                content.len(),
+            )
+        };
+        if written != 0 || unsafe { guestfs_sync(self.handle) } != 0 {
+            return Err(get_last_error(self.handle));
+        }
+        Ok(())
+    }
+
+    pub(super) fn stat<S: AsRef<str>>(&self, path: S) -> Result<FileStat, GuestFSError> {
+        let c_str_path = CString::new(path.as_ref()).expect("CString::new failed");
+        let file_stat = unsafe {
             let result = guestfs_stat(self.handle, c_str_path.as_ptr());
             if result.is_null() {
                 return Err(get_last_error(self.handle));
             };
-            let size = (*result).size;
+            let file_stat = FileStat {
+                size: (*result).size as usize,
+                mtime: (*result).mtime.max(0) as u64,
+                mode: (*result).mode as u32,
+            };
             guestfs_free_stat(result);
-            size
+            file_stat
         };
-        Ok(size as usize)
+        Ok(file_stat)
     }
 
     pub(super) fn set_append<S: AsRef<str>>(&self, string: S) -> Result<(), GuestFSError> {
@@ -314,10 +422,35 @@ impl GuestFS {
         }
     }
 
+    pub(super) fn set_memsize(&self, memory_mb: u32) -> Result<(), GuestFSError> {
+        if unsafe { guestfs_set_memsize(self.handle, memory_mb as libc::c_int) } == 0 {
+            Ok(())
+        } else {
+            Err(get_last_error(self.handle))
+        }
+    }
+
+    pub(super) fn set_smp(&self, cpus: u32) -> Result<(), GuestFSError> {
+        if unsafe { guestfs_set_smp(self.handle, cpus as libc::c_int) } == 0 {
+            Ok(())
+        } else {
+            Err(get_last_error(self.handle))
+        }
+    }
+
     pub(super) fn read_chunk<S: AsRef<str>>(
         &self,
         path: S,
         offset: usize,
+    ) -> Result<Vec<u8>, GuestFSError> {
+        self.read_range(path, offset, CHUNK_SIZE as usize)
+    }
+
+    pub(super) fn read_range<S: AsRef<str>>(
+        &self,
+        path: S,
+        offset: usize,
+        count: usize,
     ) -> Result<Vec<u8>, GuestFSError> {
         let c_str_path = CString::new(path.as_ref()).expect("CString::new failed");
         unsafe {
@@ -325,7 +458,7 @@ impl GuestFS {
             let read_buffer = guestfs_pread(
                 self.handle,
                 c_str_path.as_ptr(),
-                CHUNK_SIZE,
+                count.min(CHUNK_SIZE as usize) as libc::c_int,
                 offset as i64,
                 &mut size_r,
             );
@@ -334,16 +467,43 @@ impl GuestFS {
                 eprintln!("Can't read from {c_str_path:?}: {last_error}");
                 Err(last_error)
             } else {
-                Ok(Vec::from_raw_parts(read_buffer as *mut u8, size_r, size_r))
+                Ok(take_buffer(read_buffer, size_r))
             }
         }
     }
 }
 
+// Copies a buffer allocated by libguestfs and frees it with the allocator it came from.
+unsafe fn take_buffer(buffer: *mut libc::c_char, size: usize) -> Vec<u8> {
+    let content = unsafe { slice::from_raw_parts(buffer as *const u8, size) }.to_vec();
+    unsafe { libc::free(buffer as *mut libc::c_void) };
+    content
+}
+
+// Copies a NULL-terminated list of strings allocated by libguestfs, however long, then frees the list along
+// with the strings. They come from malloc, so they are never handed to the Rust allocator.
+unsafe fn take_string_list(list: *mut *mut libc::c_char) -> Vec<String> {
+    let mut strings: Vec<String> = Vec::new();
+    for index in 0.. {
+        let entry_ptr = unsafe { *list.add(index) };
+        if entry_ptr.is_null() {
+            break;
+        }
+        let string = unsafe { CStr::from_ptr(entry_ptr) };
+        strings.push(string.to_string_lossy().into_owned());
+        unsafe { libc::free(entry_ptr as *mut libc::c_void) };
+    }
+    unsafe { libc::free(list as *mut libc::c_void) };
+    strings
+}
+
 impl Drop for GuestFS {
     fn drop(&mut self) {
         unsafe { guestfs_close(self.handle) };
         _ = self.events_receiver.try_iter().collect::<Vec<_>>();
+        if self.launched.get() {
+            appliances().closed();
+        }
     }
 }
 
@@ -102,3 +102,52 @@ fn test_read_nonaligned_file() {
         expected_data.len()
     );
 }
+
+#[test]
+fn tmp_dir_applied_to_environment() {
+    let tmp_dir = env::temp_dir();
+    let environment = tmp_dir_environment(&tmp_dir).unwrap();
+    assert_eq!(
+        environment,
+        TMP_DIR_VARIABLES.map(|variable| (variable, tmp_dir.as_path()))
+    );
+}
+
+#[test]
+fn missing_tmp_dir_refused() {
+    let tmp_dir = env::temp_dir().join("rtftp_missing_tmp_dir");
+    let error = tmp_dir_environment(&tmp_dir).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+}
+
+// Lays the names out the way libguestfs returns a string list: malloc'ed strings and a NULL terminator.
+fn malloc_string_list(names: &[String]) -> *mut *mut libc::c_char {
+    let size = (names.len() + 1) * size_of::<*mut libc::c_char>();
+    let list = unsafe { libc::malloc(size) } as *mut *mut libc::c_char;
+    for (index, name) in names.iter().enumerate() {
+        let name = CString::new(name.as_str()).unwrap();
+        unsafe { *list.add(index) = libc::strdup(name.as_ptr()) };
+    }
+    unsafe { *list.add(names.len()) = ptr::null_mut() };
+    list
+}
+
+#[test]
+fn string_list_taken_whole() {
+    for count in [0, 1, 99, 100, 101, 300] {
+        let names: Vec<String> = (1..=count)
+            .map(|index| format!("/dev/sda{index}"))
+            .collect();
+        let taken = unsafe { take_string_list(malloc_string_list(&names)) };
+        assert_eq!(taken, names);
+    }
+}
+
+#[test]
+fn buffer_taken_whole() {
+    for content in [Vec::new(), make_payload(1), make_payload(4 * 1024 * 1024)] {
+        let buffer = unsafe { libc::malloc(content.len().max(1)) } as *mut libc::c_char;
+        unsafe { ptr::copy_nonoverlapping(content.as_ptr(), buffer as *mut u8, content.len()) };
+        assert_eq!(unsafe { take_buffer(buffer, content.len()) }, content);
+    }
+}
@@ -1,21 +1,159 @@
+use crate::file_filter::FileFilter;
 use crate::fs::{OpenedFile, Root};
+use crate::fs_watch::{BlockingObserver, Watch};
+use flate2::Compression;
+use flate2::read::{GzDecoder, GzEncoder};
+use std::ffi::OsStr;
 use std::fmt::{Debug, Display, Formatter};
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::{Read, Seek, SeekFrom};
-use std::path::PathBuf;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 #[cfg(test)]
 mod tests;
 
+const GZIP_EXTENSION: &str = "gz";
+// _IOR(0x12, 114, size_t), not exported by libc.
+const BLKGETSIZE64: libc::Ioctl = 0x8008_1272;
+// How long a growing file is waited for to fill a block, short of it the block ends the transfer.
+pub(super) const GROWTH_WAIT: Duration = Duration::from_secs(1);
+// A transformed file isn't sized past this unless `--max-file-size` sets the limit, so a gzip bomb is
+// refused rather than inflated whole.
+pub(super) const MAX_TRANSFORMED_SIZE: usize = 4 << 30;
+
+enum Content {
+    Plain(File),
+    // Only `<file>.gz` exists while `<file>` is requested.
+    Decompressed(GzDecoder<File>),
+    // Only `<file>` exists while `<file>.gz` is requested.
+    Compressed(GzEncoder<File>),
+}
+
+impl Content {
+    fn new(file: File, kind: &ContentKind) -> Self {
+        match kind {
+            ContentKind::Plain => Self::Plain(file),
+            ContentKind::Decompressed => Self::Decompressed(GzDecoder::new(file)),
+            ContentKind::Compressed => {
+                Self::Compressed(GzEncoder::new(file, Compression::default()))
+            }
+        }
+    }
+
+    fn open(path: &Path, kind: &ContentKind) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        Ok(Self::new(file, kind))
+    }
+
+    fn file(&self) -> &File {
+        match self {
+            Self::Plain(file) => file,
+            Self::Decompressed(decoder) => decoder.get_ref(),
+            Self::Compressed(encoder) => encoder.get_ref(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ContentKind {
+    Plain,
+    Decompressed,
+    Compressed,
+}
+
+// Transformed streams may return short reads, while a short read means the last block to the sender.
+pub(super) fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..])? {
+            0 => break,
+            read_bytes => read += read_bytes,
+        }
+    }
+    Ok(read)
+}
+
+// Seeking to the end of a block device doesn't reliably report its size.
+fn file_size(file: &mut File) -> io::Result<u64> {
+    if file.metadata()?.file_type().is_block_device() {
+        return block_device_size(file);
+    }
+    let current_pos = file.stream_position()?;
+    let result = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(current_pos))?;
+    Ok(result)
+}
+
+fn block_device_size(file: &File) -> io::Result<u64> {
+    let mut size: u64 = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64, &mut size as *mut u64) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(size)
+}
+
 pub(super) struct LocalOpenedFile {
-    rd: File,
+    rd: Content,
+    kind: ContentKind,
+    source: PathBuf,
+    transformed_size: Option<usize>,
+    max_transformed_size: usize,
     display: String,
+    growing: bool,
+    // Set up on the first short read of a growing file.
+    observer: Option<BlockingObserver>,
+}
+
+impl LocalOpenedFile {
+    fn new(file: File, source: PathBuf, kind: ContentKind, display: String) -> Self {
+        Self {
+            rd: Content::new(file, &kind),
+            kind,
+            source,
+            transformed_size: None,
+            max_transformed_size: MAX_TRANSFORMED_SIZE,
+            display,
+            growing: false,
+            observer: None,
+        }
+    }
+
+    fn growing(mut self, growing: bool) -> Self {
+        self.growing = growing;
+        self
+    }
+
+    fn max_transformed_size(mut self, max_transformed_size: usize) -> Self {
+        self.max_transformed_size = max_transformed_size;
+        self
+    }
+
+    // Reads run on blocking threads, so the appends are waited for right there.
+    fn read_growing(&mut self, buffer: &mut [u8], mut read: usize) -> io::Result<usize> {
+        let Content::Plain(file) = &mut self.rd else {
+            return Ok(read);
+        };
+        let deadline = Instant::now() + GROWTH_WAIT;
+        if self.observer.is_none() {
+            self.observer = Some(Watch::new().modify().observe_blocking(&self.source)?);
+            // Appended before the watch was set up.
+            read += read_full(file, &mut buffer[read..])?;
+        }
+        let observer = self.observer.as_ref().unwrap();
+        while read < buffer.len() && observer.wait(deadline)? {
+            read += read_full(file, &mut buffer[read..])?;
+        }
+        Ok(read)
+    }
 }
 
 impl Debug for LocalOpenedFile {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "LocalOpenedFile: {:?}", self.rd)
+        write!(f, "LocalOpenedFile: {:?} {:?}", self.kind, self.rd.file())
     }
 }
 
@@ -27,41 +165,253 @@ impl Display for LocalOpenedFile {
 
 impl OpenedFile for LocalOpenedFile {
     fn read_to(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
-        let result = self.rd.read(buffer)?;
+        let result = match &mut self.rd {
+            Content::Plain(file) => file.read(buffer)?,
+            Content::Decompressed(decoder) => read_full(decoder, buffer)?,
+            Content::Compressed(encoder) => read_full(encoder, buffer)?,
+        };
+        if self.growing && result < buffer.len() {
+            return self.read_growing(buffer, result);
+        }
         Ok(result)
     }
 
     fn get_size(&mut self) -> io::Result<usize> {
-        let current_pos = self.rd.seek(SeekFrom::Start(0))?;
-        let result = self.rd.seek(SeekFrom::End(0))?;
-        self.rd.seek(SeekFrom::Start(current_pos))?;
-        Ok(result as usize)
+        if let Content::Plain(file) = &mut self.rd {
+            return Ok(file_size(file)? as usize);
+        }
+        if let Some(transformed_size) = self.transformed_size {
+            return Ok(transformed_size);
+        }
+        // The transformed size is only known after passing the whole source through the codec, which stops
+        // right past the limit.
+        let limit = self.max_transformed_size as u64 + 1;
+        let mut probe = Content::open(&self.source, &self.kind)?;
+        let transformed_size = match &mut probe {
+            Content::Plain(file) => io::copy(&mut file.take(limit), &mut io::sink())?,
+            Content::Decompressed(decoder) => io::copy(&mut decoder.take(limit), &mut io::sink())?,
+            Content::Compressed(encoder) => io::copy(&mut encoder.take(limit), &mut io::sink())?,
+        } as usize;
+        if transformed_size > self.max_transformed_size {
+            return Err(io::ErrorKind::FileTooLarge.into());
+        }
+        self.transformed_size = Some(transformed_size);
+        Ok(transformed_size)
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        match &mut self.rd {
+            Content::Plain(file) => {
+                file.seek(SeekFrom::Start(0))?;
+            }
+            _ => self.rd = Content::open(&self.source, &self.kind)?,
+        };
+        Ok(())
+    }
+
+    fn read_at(&mut self, offset: usize, buffer: &mut [u8]) -> io::Result<usize> {
+        let Content::Plain(file) = &self.rd else {
+            return Err(io::ErrorKind::Unsupported.into());
+        };
+        let mut read = 0;
+        while read < buffer.len() {
+            match file.read_at(&mut buffer[read..], (offset + read) as u64)? {
+                0 => break,
+                read_bytes => read += read_bytes,
+            }
+        }
+        Ok(read)
+    }
+
+    fn mtime(&mut self) -> io::Result<u64> {
+        let modified = self.rd.file().metadata()?.modified()?;
+        match modified.duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => Ok(since_epoch.as_secs()),
+            Err(_) => Ok(0),
+        }
     }
 }
 
 pub(super) struct LocalRoot {
     path: PathBuf,
+    transparent_gzip: bool,
+    allow_growing: bool,
+    deny_dangling_symlinks: bool,
+    hidden: Option<fn(&Path) -> bool>,
+    file_filter: FileFilter,
+    max_transformed_size: usize,
 }
 
 impl LocalRoot {
     pub(super) fn new(path: PathBuf) -> Self {
-        Self { path }
+        Self {
+            path,
+            transparent_gzip: false,
+            allow_growing: false,
+            deny_dangling_symlinks: false,
+            hidden: None,
+            file_filter: FileFilter::default(),
+            max_transformed_size: MAX_TRANSFORMED_SIZE,
+        }
+    }
+
+    // Resolves symlinks and `..` so that neither can lead out of the root.
+    fn resolve(&self, file_path: &Path) -> io::Result<PathBuf> {
+        // The per-IP and default directories are optional and may appear at any time, so the root is
+        // resolved on every lookup. A missing root just has no files.
+        let root = match self.path.canonicalize() {
+            Ok(root) => root,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+                ) =>
+            {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+            Err(error) => return Err(error),
+        };
+        let resolved = match file_path.canonicalize() {
+            Ok(resolved) => resolved,
+            // A path component being a regular file just means the requested file is missing.
+            Err(error) if error.kind() == io::ErrorKind::NotADirectory => {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound && file_path.is_symlink() => {
+                return Err(self.dangling_symlink(file_path));
+            }
+            Err(error) => return Err(error),
+        };
+        if !resolved.starts_with(&root) {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        if resolved.parent() == Some(root.as_path())
+            && self.hidden.is_some_and(|hidden| hidden(&resolved))
+        {
+            eprintln!("{self}: Refusing {}, it is not served", resolved.display());
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        Ok(resolved)
+    }
+
+    pub(super) fn transparent_gzip(mut self, enabled: bool) -> Self {
+        self.transparent_gzip = enabled;
+        self
+    }
+
+    // Plain files still being appended are read on as they grow, instead of ending at their current end.
+    pub(super) fn allow_growing(mut self, enabled: bool) -> Self {
+        self.allow_growing = enabled;
+        self
+    }
+
+    // A dangling symlink is rather a misconfiguration than a file not provided, so it is told apart in the
+    // log, and refused outright if configured so.
+    pub(super) fn deny_dangling_symlinks(mut self, enabled: bool) -> Self {
+        self.deny_dangling_symlinks = enabled;
+        self
+    }
+
+    // Files at the top of the root the predicate matches are refused, like the NBD configs sharing the peer
+    // directory with the files served. Symlinks are followed, so no other name reaches them either.
+    pub(super) fn hiding(mut self, hidden: fn(&Path) -> bool) -> Self {
+        self.hidden = Some(hidden);
+        self
+    }
+
+    // The request itself is matched before any root is asked, while a gzip sibling is another file, so its
+    // name is matched here too. Otherwise `secret.key.gz` would serve the denied `secret.key`.
+    pub(super) fn filtered(mut self, file_filter: FileFilter) -> Self {
+        self.file_filter = file_filter;
+        self
+    }
+
+    // Bounds sizing compressed or decompressed siblings, the fixed limit is kept if None.
+    pub(super) fn max_transformed_size(mut self, max_transformed_size: Option<usize>) -> Self {
+        if let Some(max_transformed_size) = max_transformed_size {
+            self.max_transformed_size = max_transformed_size;
+        }
+        self
+    }
+
+    fn dangling_symlink(&self, file_path: &Path) -> io::Error {
+        let target = match std::fs::read_link(file_path) {
+            Ok(target) => target.display().to_string(),
+            Err(error) => format!("<{error}>"),
+        };
+        eprintln!(
+            "{self}: WARNING: {} is a dangling symlink to {target}",
+            file_path.display()
+        );
+        if self.deny_dangling_symlinks {
+            io::ErrorKind::PermissionDenied.into()
+        } else {
+            io::ErrorKind::NotFound.into()
+        }
+    }
+
+    fn open_gzip_sibling(&self, file_path: &Path) -> io::Result<LocalOpenedFile> {
+        let (source, kind) = if file_path.extension() == Some(OsStr::new(GZIP_EXTENSION)) {
+            (file_path.with_extension(""), ContentKind::Compressed)
+        } else {
+            let mut compressed_path = file_path.as_os_str().to_owned();
+            compressed_path.push(".");
+            compressed_path.push(GZIP_EXTENSION);
+            (PathBuf::from(compressed_path), ContentKind::Decompressed)
+        };
+        let sibling_name = source
+            .strip_prefix(&self.path)
+            .ok()
+            .and_then(Path::to_str)
+            .ok_or(io::ErrorKind::PermissionDenied)?;
+        if !self.file_filter.permits(sibling_name) {
+            eprintln!(
+                "{self}: Refusing {sibling_name} for {}, it is denied by {}",
+                file_path.display(),
+                self.file_filter
+            );
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        let source = self.resolve(&source)?;
+        let file = open_regular_file(&source)?;
+        let display = format!(
+            "{} [{kind:?} from {}]",
+            file_path.display(),
+            source.display()
+        );
+        Ok(LocalOpenedFile::new(file, source, kind, display)
+            .max_transformed_size(self.max_transformed_size))
     }
 }
 
+fn open_regular_file(file_path: &Path) -> io::Result<File> {
+    let result = OpenOptions::new().read(true).open(file_path)?;
+    // Opening a directory read-only succeeds, it's only reading from it that fails.
+    if result.metadata()?.is_dir() {
+        return Err(io::ErrorKind::IsADirectory.into());
+    }
+    Ok(result)
+}
+
 impl Root for LocalRoot {
     type OpenedFile = LocalOpenedFile;
     fn open(&self, path: &str) -> io::Result<Self::OpenedFile> {
         let file_path = self.path.join(path.trim_start_matches('/'));
         let printable_path = file_path.display().to_string();
-        if !file_path.starts_with(&self.path) {
-            return Err(io::ErrorKind::PermissionDenied.into());
-        }
-        let result = OpenOptions::new().read(true).open(&file_path)?;
-        Ok(LocalOpenedFile {
-            rd: result,
-            display: printable_path,
-        })
+        let opened = self
+            .resolve(&file_path)
+            .and_then(|resolved| Ok((open_regular_file(&resolved)?, resolved)));
+        let (result, resolved) = match opened {
+            Ok(opened) => opened,
+            Err(error) if error.kind() == io::ErrorKind::NotFound && self.transparent_gzip => {
+                return self.open_gzip_sibling(&file_path);
+            }
+            Err(error) => return Err(error),
+        };
+        Ok(
+            LocalOpenedFile::new(result, resolved, ContentKind::Plain, printable_path)
+                .growing(self.allow_growing),
+        )
     }
 }
 
@@ -1,15 +1,14 @@
 use super::*;
-use crate::tests_common::mk_tmp;
-use std::fs::{Permissions, set_permissions};
+use crate::tests_common::{make_payload, mk_tmp, read_file};
+use std::fs::{self, Permissions, set_permissions};
 use std::io::ErrorKind;
-use std::os::unix::fs::PermissionsExt;
+use std::io::Write;
+use std::os::unix::fs::{PermissionsExt, symlink};
 use std::path::PathBuf;
 
 #[test]
 fn open_non_existent() {
-    let local_root = LocalRoot {
-        path: PathBuf::from("/nonexistent"),
-    };
+    let local_root = LocalRoot::new(PathBuf::from("/nonexistent"));
     let result = local_root.open("nonexistent.file");
     assert_eq!(result.err().unwrap().kind(), ErrorKind::NotFound);
 }
@@ -18,29 +17,137 @@ fn open_non_existent() {
 fn open_access_denied() {
     let unreadable_directory = mk_tmp(open_access_denied);
     set_permissions(&unreadable_directory, Permissions::from_mode(0o055)).unwrap();
-    let local_root = LocalRoot {
-        path: unreadable_directory,
-    };
+    let local_root = LocalRoot::new(unreadable_directory);
     let result = local_root.open("nonexistent");
     assert_eq!(result.err().unwrap().kind(), ErrorKind::PermissionDenied);
 }
 
+#[test]
+fn open_under_regular_file() {
+    let local_root = LocalRoot::new(PathBuf::from(env!("CARGO_MANIFEST_DIR")));
+    let result = local_root.open("Cargo.toml/nonexistent.file");
+    assert_eq!(result.err().unwrap().kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn open_directory() {
+    let local_root = LocalRoot::new(PathBuf::from(env!("CARGO_MANIFEST_DIR")));
+    let result = local_root.open("src");
+    assert_eq!(result.err().unwrap().kind(), ErrorKind::IsADirectory);
+}
+
+#[test]
+fn open_in_symlinked_root() {
+    let real_root = mk_tmp(open_in_symlinked_root);
+    let payload = make_payload(1024);
+    fs::write(real_root.join("file.bin"), &payload).unwrap();
+    let linked_root = real_root.with_extension("link");
+    _ = fs::remove_file(&linked_root);
+    symlink(&real_root, &linked_root).unwrap();
+    let local_root = LocalRoot::new(linked_root);
+    let mut opened = local_root.open("file.bin").unwrap();
+    assert_eq!(read_file(&mut opened), payload);
+}
+
+#[test]
+fn open_in_root_created_later() {
+    let real_root = mk_tmp(open_in_root_created_later).join("created");
+    let linked_root = real_root.with_extension("link");
+    _ = fs::remove_file(&linked_root);
+    symlink(&real_root, &linked_root).unwrap();
+    let local_root = LocalRoot::new(linked_root);
+    let result = local_root.open("file.bin");
+    assert_eq!(result.err().unwrap().kind(), ErrorKind::NotFound);
+    let payload = make_payload(1024);
+    fs::create_dir(&real_root).unwrap();
+    fs::write(real_root.join("file.bin"), &payload).unwrap();
+    let mut opened = local_root.open("file.bin").unwrap();
+    assert_eq!(read_file(&mut opened), payload);
+}
+
+#[test]
+fn open_symlink_outside_root() {
+    let tftp_root = mk_tmp(open_symlink_outside_root);
+    let outside = tftp_root.with_extension("outside");
+    fs::write(&outside, b"Outside the root").unwrap();
+    symlink(&outside, tftp_root.join("escape")).unwrap();
+    let local_root = LocalRoot::new(tftp_root);
+    let result = local_root.open("escape");
+    assert_eq!(result.err().unwrap().kind(), ErrorKind::PermissionDenied);
+    let outside_name = outside.file_name().unwrap().to_str().unwrap();
+    let result = local_root.open(&format!("../{outside_name}"));
+    assert_eq!(result.err().unwrap().kind(), ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn open_dangling_symlink() {
+    let tftp_root = mk_tmp(open_dangling_symlink);
+    symlink(
+        tftp_root.join("missing.bin"),
+        tftp_root.join("dangling.bin"),
+    )
+    .unwrap();
+    let local_root = LocalRoot::new(tftp_root);
+    let result = local_root.open("dangling.bin");
+    assert_eq!(result.err().unwrap().kind(), ErrorKind::NotFound);
+    let local_root = local_root.deny_dangling_symlinks(true);
+    let result = local_root.open("dangling.bin");
+    assert_eq!(result.err().unwrap().kind(), ErrorKind::PermissionDenied);
+    // Plainly missing files are not refused.
+    let result = local_root.open("missing.bin");
+    assert_eq!(result.err().unwrap().kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn open_hidden_file() {
+    let tftp_root = mk_tmp(open_hidden_file);
+    fs::create_dir(tftp_root.join("sub")).unwrap();
+    for name in ["disk.nbd", "sub/disk.nbd", "file.bin"] {
+        fs::write(tftp_root.join(name), b"content").unwrap();
+    }
+    symlink(tftp_root.join("disk.nbd"), tftp_root.join("alias.bin")).unwrap();
+    let local_root =
+        LocalRoot::new(tftp_root).hiding(|path| path.extension().is_some_and(|ext| ext == "nbd"));
+    for name in ["disk.nbd", "alias.bin", "sub/../disk.nbd"] {
+        let result = local_root.open(name);
+        assert_eq!(
+            result.err().unwrap().kind(),
+            ErrorKind::PermissionDenied,
+            "{name}"
+        );
+    }
+    // Only the top of the root is hidden.
+    assert!(local_root.open("sub/disk.nbd").is_ok());
+    assert!(local_root.open("file.bin").is_ok());
+}
+
 #[test]
 fn get_size() {
-    let local_root = LocalRoot {
-        path: PathBuf::from(env!("CARGO_MANIFEST_DIR")),
-    };
+    let local_root = LocalRoot::new(PathBuf::from(env!("CARGO_MANIFEST_DIR")));
     let mut result = local_root.open("Cargo.toml").unwrap();
     let size = result.get_size().unwrap();
     assert!(size > 0);
 }
 
+#[test]
+fn mtime() {
+    let local_root = LocalRoot::new(PathBuf::from(env!("CARGO_MANIFEST_DIR")));
+    let mut result = local_root.open("Cargo.toml").unwrap();
+    let mtime = result.mtime().unwrap();
+    let expected = fs::metadata(local_root.path.join("Cargo.toml"))
+        .unwrap()
+        .modified()
+        .unwrap()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    assert_eq!(mtime, expected);
+}
+
 #[test]
 fn read() {
     let mut buffer = [0u8; 1024];
-    let local_root = LocalRoot {
-        path: PathBuf::from(env!("CARGO_MANIFEST_DIR")),
-    };
+    let local_root = LocalRoot::new(PathBuf::from(env!("CARGO_MANIFEST_DIR")));
     let mut result = local_root.open("Cargo.toml").unwrap();
     let read_size = result.read_to(&mut buffer).unwrap();
     let string = String::from_utf8(buffer[..read_size].to_vec()).unwrap();
@@ -50,11 +157,168 @@ fn read() {
 #[test]
 fn read_leading_slash() {
     let mut buffer = [0u8; 1024];
-    let local_root = LocalRoot {
-        path: PathBuf::from(env!("CARGO_MANIFEST_DIR")),
-    };
+    let local_root = LocalRoot::new(PathBuf::from(env!("CARGO_MANIFEST_DIR")));
     let mut result = local_root.open("/Cargo.toml").unwrap();
     let read_size = result.read_to(&mut buffer).unwrap();
     let string = String::from_utf8(buffer[..read_size].to_vec()).unwrap();
     assert!(string.contains("libc"));
 }
+
+#[test]
+fn gzip_sibling_ignored_by_default() {
+    let tftp_root = mk_tmp(gzip_sibling_ignored_by_default);
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&make_payload(1024)).unwrap();
+    fs::write(tftp_root.join("kernel.gz"), encoder.finish().unwrap()).unwrap();
+    let local_root = LocalRoot::new(tftp_root);
+    let result = local_root.open("kernel");
+    assert_eq!(result.err().unwrap().kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn decompress_gzip_sibling() {
+    let tftp_root = mk_tmp(decompress_gzip_sibling);
+    let payload = make_payload(100_000);
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&payload).unwrap();
+    fs::write(tftp_root.join("kernel.gz"), encoder.finish().unwrap()).unwrap();
+    let local_root = LocalRoot::new(tftp_root).transparent_gzip(true);
+    let mut opened = local_root.open("kernel").unwrap();
+    assert_eq!(opened.get_size().unwrap(), payload.len());
+    assert_eq!(read_file(&mut opened), payload);
+    opened.rewind().unwrap();
+    assert_eq!(read_file(&mut opened), payload);
+}
+
+#[test]
+fn compress_plain_sibling() {
+    let tftp_root = mk_tmp(compress_plain_sibling);
+    let payload = make_payload(100_000);
+    fs::write(tftp_root.join("initrd"), &payload).unwrap();
+    let local_root = LocalRoot::new(tftp_root).transparent_gzip(true);
+    let mut opened = local_root.open("initrd.gz").unwrap();
+    let compressed = read_file(&mut opened);
+    assert_eq!(opened.get_size().unwrap(), compressed.len());
+    assert!(compressed.len() < payload.len());
+    let mut decompressed = Vec::new();
+    GzDecoder::new(compressed.as_slice())
+        .read_to_end(&mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed, payload);
+}
+
+#[test]
+fn existing_file_takes_precedence_over_gzip() {
+    let tftp_root = mk_tmp(existing_file_takes_precedence_over_gzip);
+    let payload = make_payload(1024);
+    fs::write(tftp_root.join("kernel"), &payload).unwrap();
+    fs::write(tftp_root.join("kernel.gz"), b"not a gzip stream").unwrap();
+    let local_root = LocalRoot::new(tftp_root).transparent_gzip(true);
+    let mut opened = local_root.open("kernel").unwrap();
+    assert_eq!(read_file(&mut opened), payload);
+}
+
+#[test]
+fn denied_gzip_sibling() {
+    let tftp_root = mk_tmp(denied_gzip_sibling);
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&make_payload(1024)).unwrap();
+    fs::write(tftp_root.join("vault.img.gz"), encoder.finish().unwrap()).unwrap();
+    fs::write(tftp_root.join("secret.key"), make_payload(1024)).unwrap();
+    let local_root = LocalRoot::new(tftp_root)
+        .transparent_gzip(true)
+        .filtered(FileFilter::new(
+            Vec::new(),
+            vec![String::from("*.key"), String::from("*.img.gz")],
+        ));
+    // Neither name is denied itself, only the file it is made of.
+    for name in ["secret.key.gz", "/secret.key.gz", "vault.img"] {
+        let result = local_root.open(name);
+        assert_eq!(
+            result.err().unwrap().kind(),
+            ErrorKind::PermissionDenied,
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn gzip_sibling_size_bounded() {
+    let tftp_root = mk_tmp(gzip_sibling_size_bounded);
+    let payload = make_payload(100_000);
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&payload).unwrap();
+    fs::write(tftp_root.join("kernel.gz"), encoder.finish().unwrap()).unwrap();
+    let local_root = LocalRoot::new(tftp_root.clone())
+        .transparent_gzip(true)
+        .max_transformed_size(Some(payload.len() - 1));
+    let mut opened = local_root.open("kernel").unwrap();
+    assert_eq!(
+        opened.get_size().unwrap_err().kind(),
+        ErrorKind::FileTooLarge
+    );
+    let local_root = LocalRoot::new(tftp_root)
+        .transparent_gzip(true)
+        .max_transformed_size(Some(payload.len()));
+    let mut opened = local_root.open("kernel").unwrap();
+    assert_eq!(opened.get_size().unwrap(), payload.len());
+}
+
+// Returns the first block device this process can read along with its size reported by sysfs.
+fn readable_block_device() -> Option<(PathBuf, u64)> {
+    for entry in fs::read_dir("/sys/block").ok()?.flatten() {
+        let device = PathBuf::from("/dev").join(entry.file_name());
+        if File::open(&device).is_err() {
+            continue;
+        }
+        let Ok(sectors) = fs::read_to_string(entry.path().join("size")) else {
+            continue;
+        };
+        if let Ok(sectors) = sectors.trim().parse::<u64>() {
+            return Some((device, sectors * 512));
+        }
+    }
+    None
+}
+
+#[test]
+fn get_block_device_size() {
+    let Some((device, expected_size)) = readable_block_device() else {
+        eprintln!("No readable block device, skipping");
+        return;
+    };
+    let local_root = LocalRoot::new(device.parent().unwrap().to_path_buf());
+    let device_name = device.file_name().unwrap().to_str().unwrap();
+    let mut opened = local_root.open(device_name).unwrap();
+    assert_eq!(opened.get_size().unwrap() as u64, expected_size);
+}
+
+#[test]
+fn get_character_device_size() {
+    let mut null = File::open("/dev/null").unwrap();
+    assert_eq!(file_size(&mut null).unwrap(), 0);
+}
+
+#[test]
+fn growing_file_read_on() {
+    let root = mk_tmp(growing_file_read_on);
+    let payload = make_payload(1000);
+    fs::write(root.join("growing.log"), &payload[..100]).unwrap();
+    let local_root = LocalRoot::new(root.clone()).allow_growing(true);
+    let mut opened = local_root.open("growing.log").unwrap();
+    let appender = {
+        let (path, appended) = (root.join("growing.log"), payload[100..].to_vec());
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            let mut file = OpenOptions::new().append(true).open(path).unwrap();
+            file.write_all(&appended).unwrap();
+        })
+    };
+    let mut buffer = [0u8; 512];
+    assert_eq!(opened.read_to(&mut buffer).unwrap(), 512);
+    appender.join().unwrap();
+    assert_eq!(&buffer[..], &payload[..512]);
+    let started = Instant::now();
+    assert_eq!(opened.read_to(&mut buffer).unwrap(), 488);
+    assert!(started.elapsed() >= GROWTH_WAIT);
+}
@@ -2,38 +2,93 @@
 compile_error!(
     "This project does not support building on Windows due to its reliance on libguestfs and inotify."
 );
+mod appliances;
+mod buffer_pool;
+mod checksum;
 mod cursor;
 mod datagram_stream;
+mod decompress;
+mod disk_cache;
+mod disk_worker;
 mod error;
+mod exec_root;
+mod fallback_files;
+mod file_cache;
+mod file_filter;
 mod fs;
 mod fs_watch;
 mod guestfs;
 pub mod local_fs;
 mod messages;
 mod nbd_disk;
+mod netascii;
+mod offloaded;
 mod options;
 mod peer_handler;
+mod quiet_log;
 mod remote_fs;
 mod server;
+mod stats;
+mod subnet_roots;
 #[cfg(test)]
 mod tests_common;
+mod upstream;
+mod worker_pool;
 
+use crate::appliances::appliances;
+use crate::buffer_pool::BufferPool;
+use crate::datagram_stream::{
+    MAX_DSCP, MAX_SOCKET_BUFFER, MIN_SOCKET_BUFFER, SocketBuffers, bind_dual_stack, set_dscp,
+};
+use crate::disk_cache::DiskCache;
+use crate::exec_root::{DEFAULT_EXEC_MAX_OUTPUT, DEFAULT_EXEC_TIMEOUT_MS, ExecHooks};
+use crate::fallback_files::FallbackFiles;
+use crate::file_cache::FileCache;
+use crate::file_filter::FileFilter;
 use crate::fs_watch::Watch;
+use crate::messages::DEFAULT_MAX_OPTIONS;
+use crate::options::{
+    BLOCK_SIZE_BOTTOM_CAP, BLOCK_SIZE_UPPER_CAP, DEFAULT_SESSION_BUFFER_LIMIT,
+    MAX_RETRANSMIT_JITTER, SessionLimits,
+};
+use crate::peer_handler::SessionContext;
+use crate::quiet_log::DEFAULT_QUIET_WINDOW_MS;
+use crate::subnet_roots::SubnetRoots;
+use crate::upstream::{DEFAULT_UPSTREAM_TIMEOUT_MS, UpstreamRoot};
+use crate::worker_pool::WorkerPool;
 use clap::Parser;
 use server::TFTPServer;
+use std::ffi::CString;
 use std::fs::File;
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 use std::string::String;
 use std::time::Duration;
+use std::{env, io};
 use tokio::runtime::Builder;
 use tokio::task::LocalSet;
 
 #[derive(Parser, Debug)]
 #[command(color = clap::ColorChoice::Never)]
 struct Args {
-    #[arg(short = 'l', long, help = "Listen IP")]
-    listen_ip: String,
+    #[arg(
+        short = 'l',
+        long,
+        required_unless_present = "dual_stack",
+        help = "Listen IP (repeatable)",
+        long_help = "An address to serve on. Repeat to serve the same root on several addresses; replies are sent from the address a request was received on."
+    )]
+    listen_ip: Vec<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "listen_ip",
+        help = "Listen on a single socket for both IPv4 and IPv6",
+        long_help = "Bind a single [::] socket accepting IPv4 clients as well, by their IPv4-mapped addresses, instead of the --listen-ip addresses. Configs of IPv4 clients are named by their plain IPv4 addresses."
+    )]
+    dual_stack: bool,
 
     #[arg(short = 'p', long, default_value_t = 69, help = "Listen port")]
     listen_port: u16,
@@ -46,6 +101,37 @@ struct Args {
     )]
     root_dir: PathBuf,
 
+    #[arg(
+        long,
+        help = "Create a missing TFTP root directory",
+        long_help = "Create the TFTP root directory along with its missing parents instead of refusing to start without it."
+    )]
+    create_root: bool,
+
+    #[arg(
+        long,
+        help = "Confine the process to the root directory",
+        long_help = "After binding the sockets, chroot into the TFTP root directory, so even a path traversal bug can't reach the rest of the filesystem. Requires CAP_SYS_CHROOT. NBD disks can't be connected inside the chroot since libguestfs needs its appliance and qemu."
+    )]
+    chroot: bool,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        default_value = "default",
+        help = "Fallback root directory name",
+        long_help = "A directory inside the TFTP root to serve files from when they are found neither in a peer directory nor on its NBD disk."
+    )]
+    default_root_name: String,
+
+    #[arg(
+        long,
+        conflicts_with = "default_root_name",
+        help = "Disable the fallback root",
+        long_help = "Serve peers only from their own directories and NBD disks, requests for other files fail with File not found."
+    )]
+    no_default_root: bool,
+
     #[arg(
         short = 'm',
         long,
@@ -55,13 +141,400 @@ struct Args {
     )]
     monitor_configs: bool,
 
+    #[arg(
+        long,
+        value_name = "EVENT",
+        value_parser = ["modify", "create", "close-write", "moved-to"],
+        help = "Config event reloading a handler (repeatable)",
+        long_help = "The inotify events in the TFTP root that count as a config change and reload the peer handler. close-write and moved-to catch configs written completely or renamed into place; modify and create react earlier, possibly to a half-written config. Defaults to close-write and moved-to."
+    )]
+    reload_on: Vec<String>,
+
     #[arg(
         short = 't',
         long,
         help = "Peer handler inactivity timeout",
-        long_help = "After reaching this timeout of inactivity, a connected remote disk is closed."
+        long_help = "After reaching this timeout of inactivity, a connected remote disk is closed. 0 keeps the handlers and their disks indefinitely."
     )]
     idle_timeout: u64,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Keep the disks of idle handlers connected",
+        long_help = "Remote disks of a handler closed by inactivity stay connected for this long. A handler created for the same unchanged config meanwhile reclaims the disk instead of launching a new appliance."
+    )]
+    disk_cache_ttl: Option<u64>,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Share the files read from remote disks across peers",
+        long_help = "Files read through from a remote disk are kept in memory up to this total size, least recently used first out. Peers with configs of the same NBD URL and mounts get the kept files without reading the disk."
+    )]
+    file_cache_size: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "DIRECTORY",
+        help = "Directory for the temporary files of the appliances",
+        long_help = "The guestfs appliances keep their sockets, overlays and appliance caches here instead of the system temporary directory. TMPDIR, LIBGUESTFS_TMPDIR and LIBGUESTFS_CACHEDIR are set to it. The directory must exist and be writable."
+    )]
+    tmp_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Maximum guestfs appliances launched at once",
+        long_help = "Every connected NBD disk runs a qemu appliance. Launches beyond this limit wait for the running ones to finish, so a burst of new peers doesn't exhaust the host memory. Unlimited if omitted."
+    )]
+    max_concurrent_launches: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "MILLISECONDS",
+        default_value_t = 1000,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Server loop turn duration",
+        long_help = "How often finished peer handlers are reaped. A shorter turn releases handlers sooner at the cost of more wakeups."
+    )]
+    turn_duration_ms: u64,
+
+    #[arg(
+        long = "root-layer",
+        value_name = "NAME",
+        help = "Root directory searched before all others (repeatable)",
+        long_help = "A directory inside the TFTP root searched for every peer ahead of the subnet, peer, NBD and fallback roots. Layers are searched in the order given, so the first layer holding a file serves it."
+    )]
+    root_layers: Vec<String>,
+
+    #[arg(
+        long = "subnet-root",
+        value_name = "CIDR=DIRECTORY",
+        help = "Root directory shared by a subnet (repeatable)",
+        long_help = "Peers inside the subnet are served from this directory inside the TFTP root before their own roots. The longest matching prefix wins."
+    )]
+    subnet_roots: Vec<String>,
+
+    #[arg(
+        long = "fallback-file",
+        value_name = "TEMPLATE",
+        help = "File served when the requested one is missing (repeatable)",
+        long_help = "Tried in order when the requested file is not found in any root. {dir} expands to the directory of the requested file and {name} to its name, e.g. '{dir}/default' serves pxelinux.cfg/default in place of a missing pxelinux.cfg/01-aa-bb-cc-dd-ee-ff."
+    )]
+    fallback_files: Vec<String>,
+
+    #[arg(
+        long = "exec",
+        value_name = "PATTERN=COMMAND",
+        help = "Command generating matching files (repeatable)",
+        long_help = "Requested files matching the glob pattern are generated by running the command with the peer IP and the requested path as arguments, and serving its standard output. The command is run directly, not by a shell. Patterns are matched like --allow ones, searched before all roots, and the first matching one wins."
+    )]
+    exec_hooks: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "MILLISECONDS",
+        default_value_t = DEFAULT_EXEC_TIMEOUT_MS,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Time limit of --exec commands",
+        long_help = "A command still running after this long is killed and the request fails with a timeout error. The session waits for the command on its own, the other requests of the peer are served meanwhile."
+    )]
+    exec_timeout_ms: u64,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = DEFAULT_EXEC_MAX_OUTPUT,
+        help = "Output size limit of --exec commands",
+        long_help = "A command writing more than this is killed and the request fails, as the output is held in memory for the transfer."
+    )]
+    exec_max_output: usize,
+
+    #[arg(
+        long,
+        value_name = "ADDRESS:PORT",
+        help = "Relay the requests of files found nowhere else to this TFTP server",
+        long_help = "A requested file missing from every root is requested from the upstream TFTP server and streamed to the client as its blocks arrive, so rtftp can act as a relay in segmented networks. Errors of the upstream are passed on to the client: missing files stay not found, refused ones are access violations and an upstream not answering times the request out. The upstream is asked for the requested file and then for the --fallback-file names in order, once all of them are missing from the other roots. The session waits for the upstream without stalling the other requests of the peer, and only the latest 1024 blocks received are held in memory for the transfer."
+    )]
+    upstream: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        value_name = "MILLISECONDS",
+        default_value_t = DEFAULT_UPSTREAM_TIMEOUT_MS,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Retransmit timeout towards the --upstream server",
+        long_help = "The request, or the last ACK, is sent to the upstream again after waiting this long for its reply, up to 5 times before the transfer fails with a timeout."
+    )]
+    upstream_timeout_ms: u64,
+
+    #[arg(
+        long = "allow",
+        value_name = "PATTERN",
+        help = "Servable file pattern (repeatable)",
+        long_help = "Only files matching one of these glob patterns are served. Patterns without a slash are matched against the file name, others against the whole requested path. Serve everything if omitted."
+    )]
+    allow_patterns: Vec<String>,
+
+    #[arg(
+        long = "deny",
+        value_name = "PATTERN",
+        help = "Denied file pattern (repeatable)",
+        long_help = "Files matching one of these glob patterns are refused with an access violation. Takes precedence over --allow."
+    )]
+    deny_patterns: Vec<String>,
+
+    #[arg(
+        long,
+        default_value_t = u16::MAX,
+        help = "Maximum negotiated window size",
+        long_help = "A larger windowsize requested by a client is clamped to this value and the clamped value is acknowledged."
+    )]
+    max_window_size: u16,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = BLOCK_SIZE_BOTTOM_CAP as u16,
+        value_parser = clap::value_parser!(u16).range(BLOCK_SIZE_BOTTOM_CAP as i64..),
+        help = "Minimum negotiated block size",
+        long_help = "A smaller blksize requested by a client is raised to this value and the raised value is acknowledged, which avoids transfers of enormous block counts."
+    )]
+    min_blksize: u16,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = BLOCK_SIZE_UPPER_CAP as u16,
+        value_parser = clap::value_parser!(u16).range(BLOCK_SIZE_BOTTOM_CAP as i64..),
+        help = "Maximum negotiated block size",
+        long_help = "A larger blksize requested by a client is clamped to this value and the clamped value is acknowledged."
+    )]
+    max_blksize: u16,
+
+    #[arg(
+        long,
+        value_name = "MTU",
+        value_parser = clap::value_parser!(u16).range(576..),
+        help = "Cap the block size to fit the path MTU",
+        long_help = "A blksize requested by a client is clamped to what fits an unfragmented datagram on a path of this MTU: the MTU less the IP, UDP and TFTP headers, 1468 bytes for an IPv4 peer and 1448 bytes for an IPv6 one on a 1500 MTU. The clamped value is acknowledged, so the client adjusts. This overrides --min-blksize and --max-blksize when they are larger."
+    )]
+    max_blksize_mtu: Option<u16>,
+
+    #[arg(
+        long,
+        default_value_t = DEFAULT_SESSION_BUFFER_LIMIT,
+        help = "Maximum send buffer per session, bytes",
+        long_help = "Requests whose windowsize * (blksize + 4) exceeds this budget are rejected with an option negotiation error."
+    )]
+    max_session_buffer: usize,
+
+    #[arg(
+        long,
+        default_value_t = DEFAULT_MAX_OPTIONS,
+        help = "Maximum options per request",
+        long_help = "Requests carrying more options than this, or more than 4 KiB of option names and values, are rejected with an option negotiation error."
+    )]
+    max_options: usize,
+
+    #[arg(
+        long,
+        help = "Serve gzip siblings of missing files",
+        long_help = "When a requested local file is missing, serve its `.gz` sibling decompressed, or for a missing `.gz` file, serve the uncompressed sibling compressed on the fly. The tsize option then reports the transformed size, which requires passing the whole file through the codec."
+    )]
+    transparent_gzip: bool,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "Maximum served file size",
+        long_help = "Files larger than this are refused with an error at open time. Serve files of any size if omitted."
+    )]
+    max_file_size: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Never send OACK",
+        long_help = "Ignore all options requested by clients and reply with data blocks of 512 bytes straight away. For clients that mishandle OACK."
+    )]
+    no_oack: bool,
+
+    #[arg(
+        long,
+        help = "Leave the last OACK value without its terminating null",
+        long_help = "RFC 2347 terminates every option name and value of an OACK with a null byte, the last value included. Some rigid clients reject an OACK ending with a null, this leaves the last one out for them."
+    )]
+    oack_no_final_null: bool,
+
+    #[arg(
+        long,
+        help = "Adapt the window to packet loss",
+        long_help = "Start every windowed transfer with a single block per window, double it on fully acknowledged windows and halve it on timeouts or partial ACKs. The negotiated windowsize is the ceiling. Clients must acknowledge partial windows without waiting for the rest."
+    )]
+    adaptive_window: bool,
+
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(0..=MAX_RETRANSMIT_JITTER as i64),
+        help = "Random spread of retransmission timeouts",
+        long_help = "Every wait for an ACK lasts the negotiated timeout randomly stretched or shrunk by up to this percentage, so clients booting together don't trigger retransmissions in lockstep."
+    )]
+    retransmit_jitter: u8,
+
+    #[arg(
+        long,
+        value_name = "MICROSECONDS",
+        default_value_t = 0,
+        help = "Pause between the DATA blocks of a window",
+        long_help = "Pace the blocks of every window with this pause, for switches dropping back-to-back UDP bursts. Unlike a bandwidth limit it spreads bursts out rather than capping the average rate. The timer has millisecond resolution, so a nonzero gap lasts at least a millisecond."
+    )]
+    interpacket_gap_us: u64,
+
+    #[arg(
+        long,
+        value_name = "MILLISECONDS",
+        default_value_t = 0,
+        help = "Keep answering repeated ACKs after the final block",
+        long_help = "After the final block is acknowledged, keep the session for this long and send the last block again to a client repeating its ACK, which means it didn't see the transfer end. The session ends right away if 0."
+    )]
+    final_ack_grace_ms: u64,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Maximum duration of a session",
+        long_help = "Cancel any session still running after this many seconds with a timeout error, whatever it is stuck in, like a retransmit loop or a client reading too slowly. Unlimited if omitted."
+    )]
+    max_transfer_time: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "DSCP",
+        value_parser = clap::value_parser!(u8).range(0..=MAX_DSCP as i64),
+        help = "DSCP to mark outgoing packets with",
+        long_help = "Set the DSCP (0 to 63) of the per-session reply sockets, e.g. 8 for CS1 or 10 for AF11, so the TFTP traffic can be prioritized on provisioning networks. IPv6 sockets get the same value as their traffic class."
+    )]
+    dscp: Option<u8>,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = clap::value_parser!(u32).range(MIN_SOCKET_BUFFER as i64..=MAX_SOCKET_BUFFER as i64),
+        help = "Receive buffer size of the sockets",
+        long_help = "Set the receive buffer (SO_RCVBUF) of the listen sockets and of the per-session reply sockets, so the ACK bursts of large windows aren't dropped. The kernel caps it by net.core.rmem_max, the size granted is logged. The system default if omitted."
+    )]
+    socket_rcvbuf: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = clap::value_parser!(u32).range(MIN_SOCKET_BUFFER as i64..=MAX_SOCKET_BUFFER as i64),
+        help = "Send buffer size of the sockets",
+        long_help = "Set the send buffer (SO_SNDBUF) of the listen sockets and of the per-session reply sockets. The kernel caps it by net.core.wmem_max, the size granted is logged. The system default if omitted."
+    )]
+    socket_sndbuf: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Maximum peers served at once",
+        long_help = "Every distinct peer IP gets a handler thread, or a task with --shared-workers. Requests from new IPs beyond this limit are refused with an error, so a flood of spoofed sources can't exhaust the threads and memory. Unlimited if omitted."
+    )]
+    max_peer_ips: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "Handle peers on N shared threads",
+        long_help = "Handle the peers without NBD configs as tasks on a pool of N threads instead of a thread per peer IP, for servers facing thousands of peers. Peers with NBD configs keep threads of their own, as their remote disks are accessed by blocking calls."
+    )]
+    shared_workers: Option<u16>,
+
+    #[arg(
+        long,
+        requires = "max_peer_ips",
+        help = "Evict handlers to admit new peers",
+        long_help = "When --max-peer-ips is reached, shut down the handler of the peer that sent its last request longest ago instead of refusing the new peer."
+    )]
+    evict_idle_peers: bool,
+
+    #[arg(
+        long,
+        help = "Reply from the listen port",
+        long_help = "Send the replies of every transfer from the listen socket instead of a fresh socket with an ephemeral port, for firewalls and clients that only accept replies from the port the request was sent to. The transfers of all clients then share the listen socket."
+    )]
+    announce_port: bool,
+
+    #[arg(
+        long,
+        help = "Validate configs and exit",
+        long_help = "Parse every peer config in the TFTP root without connecting, report invalid ones and exit. The exit code is non-zero if any config is invalid."
+    )]
+    validate_configs: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Stats Unix socket path",
+        long_help = "Listen on this Unix socket and answer every connection with a JSON snapshot of transfer counts, duration, size and throughput percentiles, and peer handler exit reasons. A {\"list_dir\": {\"config\": PATH, \"path\": DIRECTORY}} line sent instead lists a directory of the remote root connected by the config."
+    )]
+    stats_socket: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "ADDRESS:PORT",
+        help = "Prometheus metrics endpoint",
+        long_help = "Listen for HTTP on this address and answer any request with the transfer, error, session and handler counters in the Prometheus text format."
+    )]
+    metrics_addr: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Name of this server instance [default: HOSTNAME/LISTEN_ADDRESS]",
+        long_help = "Name this server in its log lines, stats snapshots and metrics, to tell several rtftp processes apart. Defaults to the hostname and the first listen address."
+    )]
+    instance_name: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        help = "Collapse a log line repeated more than N times [0: never]",
+        long_help = "Write a log line repeated on every request or block, like the session count of a peer or a timeout waiting for ACKs, at most N times within --quiet-window-ms and summarize the suppressed repetitions as a single \"LINE (xCOUNT)\" line once the window ends. Lines differing in their values only, like ports, are counted together and summarized as the last one. 0 writes every line."
+    )]
+    quiet_after: usize,
+
+    #[arg(
+        long,
+        value_name = "MILLISECONDS",
+        default_value_t = DEFAULT_QUIET_WINDOW_MS,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Window of --quiet-after"
+    )]
+    quiet_window_ms: u64,
+
+    #[arg(
+        long,
+        help = "Serve local files that are still being appended",
+        long_help = "When a read of a plain local file reaches its end short of a full block, wait up to a second for the file to grow before sending the block that ends the transfer, so logs still being written are streamed on. The tsize option reports the size at the start of the transfer."
+    )]
+    allow_growing: bool,
+
+    #[arg(
+        long,
+        help = "Refuse files that are dangling symlinks with an access violation",
+        long_help = "A requested local file that is a symlink to a missing target is logged as a warning either way. By default the client is told the file is not found and the lookup goes on to the next root; with this option the request is refused with an access violation instead, so the misconfiguration doesn't go unnoticed."
+    )]
+    deny_dangling_symlinks: bool,
 }
 
 fn warn_if_kvm_unavailable() {
@@ -74,43 +547,325 @@ fn warn_if_kvm_unavailable() {
 }
 
 fn main() -> ExitCode {
+    let args = Args::parse();
+    // Still single-threaded, the environment must not be changed once the runtime starts.
+    if let Some(tmp_dir) = &args.tmp_dir
+        && let Err(error) = guestfs::use_tmp_dir(tmp_dir)
+    {
+        eprintln!("Unusable temporary directory {tmp_dir:?}: {error}");
+        return ExitCode::FAILURE;
+    }
     warn_if_kvm_unavailable();
     LocalSet::new().block_on(
         &Builder::new_current_thread().enable_all().build().unwrap(),
-        async_main(),
+        async_main(args),
     )
 }
 
-async fn async_main() -> ExitCode {
-    let args = Args::parse();
-    let socket = match tokio::net::UdpSocket::bind((args.listen_ip, args.listen_port)).await {
-        Ok(udp_socket) => udp_socket,
+fn is_plain_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/')
+}
+
+// Every path used afterwards, including the TFTP root itself, is resolved inside the new root.
+fn enter_chroot(root_dir: &Path) -> io::Result<()> {
+    let path = CString::new(root_dir.as_os_str().as_bytes())?;
+    if unsafe { libc::chroot(path.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    env::set_current_dir("/")
+}
+
+// Where the path is seen from inside the chroot, if it is reachable there at all.
+fn path_inside(path: &Path, root_dir: &Path) -> Option<PathBuf> {
+    let root_dir = std::fs::canonicalize(root_dir).ok()?;
+    let path = std::path::absolute(path).ok()?;
+    let relative = path.strip_prefix(root_dir).ok()?;
+    Some(Path::new("/").join(relative))
+}
+
+fn reload_watch(events: &[String]) -> Watch {
+    if events.is_empty() {
+        return Watch::new().change();
+    }
+    events
+        .iter()
+        .fold(Watch::new(), |watch, event| match event.as_str() {
+            "modify" => watch.modify(),
+            "create" => watch.create(),
+            "close-write" => watch.close_write(),
+            "moved-to" => watch.moved_to(),
+            _ => unreachable!("rejected by the argument parser"),
+        })
+}
+
+// Without a usable root the server would still start and answer every request with File not found.
+fn check_root(root_dir: &Path, create: bool) -> io::Result<()> {
+    if create && !root_dir.exists() {
+        std::fs::create_dir_all(root_dir)?;
+        eprintln!("Created TFTP root directory {root_dir:?}");
+    }
+    if !std::fs::metadata(root_dir)?.is_dir() {
+        return Err(io::ErrorKind::NotADirectory.into());
+    }
+    std::fs::read_dir(root_dir)?;
+    Ok(())
+}
+
+fn validate_configs(root_dir: &Path) -> ExitCode {
+    let (checked, invalid) = nbd_disk::validate_configs(root_dir);
+    for (config_path, reason) in &invalid {
+        eprintln!("Invalid config {config_path:?}: {reason}");
+    }
+    eprintln!("Checked {checked} configs, {} invalid", invalid.len());
+    if invalid.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+async fn async_main(args: Args) -> ExitCode {
+    if args.validate_configs {
+        return validate_configs(&args.root_dir);
+    }
+    if let Err(error) = check_root(&args.root_dir, args.create_root) {
+        eprintln!("Unusable TFTP root directory {:?}: {error}", args.root_dir);
+        return ExitCode::FAILURE;
+    }
+    let default_root = if args.no_default_root {
+        None
+    } else if is_plain_name(&args.default_root_name) {
+        Some(args.default_root_name)
+    } else {
+        eprintln!(
+            "Invalid default root name {:?}: must be a directory name inside the TFTP root",
+            args.default_root_name
+        );
+        return ExitCode::FAILURE;
+    };
+    if let Some(root_layer) = args.root_layers.iter().find(|name| !is_plain_name(name)) {
+        eprintln!(
+            "Invalid root layer {root_layer:?}: must be a directory name inside the TFTP root"
+        );
+        return ExitCode::FAILURE;
+    }
+    let subnet_roots = match SubnetRoots::parse(&args.subnet_roots) {
+        Ok(subnet_roots) => subnet_roots,
+        Err(error) => {
+            eprintln!("Invalid subnet root: {error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if args.min_blksize > args.max_blksize {
+        eprintln!(
+            "Invalid block size range: --min-blksize {} exceeds --max-blksize {}",
+            args.min_blksize, args.max_blksize
+        );
+        return ExitCode::FAILURE;
+    }
+    let fallback_files = match FallbackFiles::parse(&args.fallback_files) {
+        Ok(fallback_files) => fallback_files,
         Err(error) => {
-            eprintln!("Socket bind error: {error}");
+            eprintln!("Invalid fallback file: {error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let exec_hooks = match ExecHooks::parse(&args.exec_hooks) {
+        Ok(exec_hooks) => exec_hooks.with_limits(
+            Duration::from_millis(args.exec_timeout_ms),
+            args.exec_max_output,
+        ),
+        Err(error) => {
+            eprintln!("Invalid exec hook: {error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let socket_buffers = SocketBuffers::new(args.socket_rcvbuf, args.socket_sndbuf);
+    let mut sockets = Vec::with_capacity(args.listen_ip.len());
+    let listen_ips = if args.dual_stack {
+        vec![String::from("::")]
+    } else {
+        args.listen_ip
+    };
+    for listen_ip in &listen_ips {
+        let bound = if args.dual_stack {
+            bind_dual_stack(args.listen_port).and_then(tokio::net::UdpSocket::from_std)
+        } else {
+            tokio::net::UdpSocket::bind((listen_ip.as_str(), args.listen_port)).await
+        };
+        match bound {
+            Ok(udp_socket) => {
+                // Transfers reply from the listen sockets themselves.
+                if args.announce_port
+                    && let Some(dscp) = args.dscp
+                    && let Err(error) = set_dscp(&udp_socket, dscp)
+                {
+                    eprintln!("Can't mark replies on {listen_ip} with DSCP {dscp}: {error}");
+                }
+                socket_buffers.apply(&udp_socket, format!("Listen socket on {listen_ip}"), true);
+                sockets.push(udp_socket)
+            }
+            Err(error) => {
+                eprintln!("Socket bind error on {listen_ip}: {error}");
+                return ExitCode::FAILURE;
+            }
+        };
+    }
+    let stats_listener = match &args.stats_socket {
+        Some(stats_socket) => match tokio::net::UnixListener::bind(stats_socket) {
+            Ok(listener) => Some(listener),
+            Err(error) => {
+                eprintln!("Stats socket bind error on {stats_socket:?}: {error}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+    let metrics_listener = match args.metrics_addr {
+        Some(metrics_addr) => match tokio::net::TcpListener::bind(metrics_addr).await {
+            Ok(listener) => Some(listener),
+            Err(error) => {
+                eprintln!("Metrics endpoint bind error on {metrics_addr}: {error}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+    if let Some(limit) = args.max_concurrent_launches {
+        appliances().limit_launches(limit as usize);
+    }
+    let mut stats_socket = args.stats_socket.clone();
+    let root_dir = if args.chroot {
+        if nbd_disk::validate_configs(&args.root_dir).0 > 0 {
+            eprintln!("NBD configs are found, their disks can't be connected inside the chroot");
+        }
+        stats_socket = stats_socket.and_then(|path| path_inside(&path, &args.root_dir));
+        if let Err(error) = enter_chroot(&args.root_dir) {
+            eprintln!("Can't chroot into {:?}: {error}", args.root_dir);
             return ExitCode::FAILURE;
         }
+        eprintln!("Chrooted into {:?}", args.root_dir);
+        PathBuf::from("/")
+    } else {
+        args.root_dir.clone()
     };
-    let turn_duration = Duration::from_secs(1);
-    let mut server = TFTPServer::new(socket, args.root_dir.clone(), args.idle_timeout);
-    if args.monitor_configs {
-        let monitor_directory = args.root_dir.to_string_lossy();
-        let watch = match Watch::new().change().observe(&monitor_directory) {
+    let turn_duration = Duration::from_millis(args.turn_duration_ms);
+    let session_context = SessionContext::new(
+        FileFilter::new(args.allow_patterns, args.deny_patterns),
+        SessionLimits::new(args.max_window_size as usize, args.max_session_buffer)
+            .with_block_size_range(args.min_blksize as usize, args.max_blksize as usize)
+            .with_path_mtu(args.max_blksize_mtu.map(usize::from)),
+        BufferPool::default(),
+        args.transparent_gzip,
+        args.max_file_size,
+        args.no_oack,
+        args.adaptive_window,
+    )
+    .with_retransmit_jitter(args.retransmit_jitter)
+    .with_interpacket_gap(Duration::from_micros(args.interpacket_gap_us))
+    .with_final_ack_grace(Duration::from_millis(args.final_ack_grace_ms))
+    .with_max_transfer_time(args.max_transfer_time.map(Duration::from_secs))
+    .with_dscp(args.dscp)
+    .with_socket_buffers(socket_buffers)
+    .with_root_layers(args.root_layers)
+    .with_exec_hooks(exec_hooks)
+    .with_upstream(args.upstream.map(|server| {
+        UpstreamRoot::new(server)
+            .with_timeout(Duration::from_millis(args.upstream_timeout_ms))
+            .with_max_file_size(args.max_file_size)
+    }))
+    .with_subnet_roots(subnet_roots)
+    .with_fallback_files(fallback_files)
+    .with_oack_final_null(!args.oack_no_final_null)
+    .with_allow_growing(args.allow_growing)
+    .with_deny_dangling_symlinks(args.deny_dangling_symlinks);
+    let session_context = match args.disk_cache_ttl {
+        Some(ttl) => session_context.with_disk_cache(DiskCache::new(Duration::from_secs(ttl))),
+        None => session_context,
+    };
+    let session_context = match args.file_cache_size {
+        Some(budget) => session_context.with_file_cache(FileCache::new(budget as usize)),
+        None => session_context,
+    };
+    let mut server = TFTPServer::new(
+        sockets,
+        root_dir.clone(),
+        default_root,
+        args.idle_timeout,
+        args.max_options,
+        session_context,
+    );
+    if let Some(instance_name) = args.instance_name.clone() {
+        server.name_instance(instance_name);
+    }
+    if args.announce_port {
+        server.reply_from_listen_port();
+    }
+    quiet_log::quiet_after(
+        args.quiet_after,
+        Duration::from_millis(args.quiet_window_ms),
+    );
+    if let Some(max_peer_ips) = args.max_peer_ips {
+        server.limit_peers(max_peer_ips as usize, args.evict_idle_peers);
+    }
+    if let Some(shared_workers) = args.shared_workers {
+        match WorkerPool::new(shared_workers as usize) {
+            Ok(worker_pool) => server.share_workers(worker_pool),
+            Err(error) => {
+                eprintln!("Can't start {shared_workers} shared workers: {error}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    server.prewarm();
+    if let Some(listener) = stats_listener {
+        server.expose_stats(listener);
+    }
+    if let Some(listener) = metrics_listener {
+        server.expose_metrics(listener);
+    }
+    let served = if args.monitor_configs {
+        let monitor_directory = root_dir.to_string_lossy();
+        let watch = match reload_watch(&args.reload_on)
+            .rename_away()
+            .observe(&monitor_directory)
+        {
             Ok(watch) => watch,
             Err(error) => {
                 eprintln!("Failed to start watching directory {monitor_directory}: {error}");
                 return ExitCode::FAILURE;
             }
         };
-        tokio::select! {
-            _ = tokio::signal::ctrl_c() => eprintln!("Received SIGINT, shutting down"),
-            _ = server.serve_augmented(turn_duration, &watch) => {}
-        }
+        let served = tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                eprintln!("Received SIGINT, shutting down");
+                Ok(())
+            }
+            served = server.serve_augmented(turn_duration, &watch) => served,
+        };
+        watch.close().await;
+        served
     } else {
         tokio::select! {
-            _ = tokio::signal::ctrl_c() => eprintln!("Received SIGINT, shutting down"),
-            _ = server.serve(turn_duration) => {}
+            _ = tokio::signal::ctrl_c() => {
+                eprintln!("Received SIGINT, shutting down");
+                Ok(())
+            }
+            served = server.serve(turn_duration) => served,
+        }
+    };
+    if let Some(stats_socket) = &stats_socket {
+        _ = std::fs::remove_file(stats_socket);
+    }
+    quiet_log::flush();
+    match served {
+        Ok(()) => {
+            eprintln!("Server is shut down");
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("Server is shut down due to a fatal error: {error}");
+            ExitCode::FAILURE
         }
     }
-    eprintln!("Server is shut down");
-    ExitCode::SUCCESS
 }
@@ -11,9 +11,16 @@ mod tests;
 const RRQ: u16 = 0x01;
 const OACK: u16 = 0x06;
 static OCTET: &str = "octet";
+static NETASCII: &str = "netascii";
+// Obsoleted by RFC 1350.
+static MAIL: &str = "mail";
+pub(super) const DEFAULT_MAX_OPTIONS: usize = 32;
+// Names and values along with their terminating NULs.
+const MAX_OPTIONS_BYTES: usize = 4096;
 
 pub(super) struct ReadRequest {
     filename: String,
+    netascii: bool,
     options: HashMap<String, String>,
 }
 
@@ -30,7 +37,7 @@ impl Debug for ReadRequest {
 }
 
 impl ReadRequest {
-    pub(super) fn parse(raw: &[u8]) -> Result<Self, TFTPError> {
+    pub(super) fn parse(raw: &[u8], max_options: usize) -> Result<Self, TFTPError> {
         let mut cursor = ReadCursor::new(raw);
         let opcode = cursor
             .extract_ushort()
@@ -41,17 +48,31 @@ impl ReadRequest {
         let filename = cursor
             .extract_string()
             .map_err(|_| TFTPError::undefined("Can't obtain filename"))?;
-        if let Ok(mode) = cursor.extract_string() {
-            if mode != OCTET {
-                if mode.is_empty() {
-                    return Err(TFTPError::undefined("Bad format"));
-                }
-                return Err(TFTPError::undefined("Only octet mode is supported"));
-            }
-        } else {
-            return Err(TFTPError::undefined("Bad format"));
+        // No path needs them, while they would forge lines in the logs the filename goes to.
+        if filename.chars().any(char::is_control) {
+            return Err(TFTPError::illegal_operation(
+                "Filename contains control characters",
+            ));
         }
+        let netascii = match cursor.extract_string() {
+            Ok(mode) if mode == OCTET => false,
+            Ok(mode) if mode == NETASCII => true,
+            Ok(mode) if mode.is_empty() => return Err(TFTPError::undefined("Bad format")),
+            Ok(mode) if mode == MAIL => {
+                return Err(TFTPError::illegal_operation(
+                    "mail mode is obsolete and unsupported",
+                ));
+            }
+            Ok(_mode) => {
+                return Err(TFTPError::undefined(
+                    "Unknown transfer mode, only octet and netascii are supported",
+                ));
+            }
+            Err(_) => return Err(TFTPError::undefined("Bad format")),
+        };
         let mut options: HashMap<String, String> = HashMap::new();
+        let mut options_count: usize = 0;
+        let mut options_bytes: usize = 0;
         loop {
             let option_name = match cursor.extract_string() {
                 Ok(name) => name,
@@ -64,19 +85,43 @@ impl ReadRequest {
                 Ok(name) => name,
                 Err(_) => return Err(TFTPError::undefined("Bad format")),
             };
-            options.insert(option_name, option_value);
+            options_count += 1;
+            options_bytes += option_name.len() + option_value.len() + 2;
+            if options_count > max_options || options_bytes > MAX_OPTIONS_BYTES {
+                return Err(TFTPError::option_negotiation("Too many options"));
+            }
+            // Option names are case-insensitive (RFC 2347), a repeated option overrides the previous one.
+            options.insert(option_name.to_ascii_lowercase(), option_value);
         }
-        Ok(ReadRequest { filename, options })
+        Ok(ReadRequest {
+            filename,
+            netascii,
+            options,
+        })
     }
     pub(super) fn open_in<O: OpenedFile>(
         &self,
         filesystem: &impl Root<OpenedFile = O>,
+        requester: &impl Display,
     ) -> io::Result<O> {
         let normalized_path = self.filename.trim_start_matches('/');
-        eprintln!("Opening {normalized_path} in {filesystem} ...");
+        eprintln!("{requester}: Opening {normalized_path} in {filesystem} ...");
         filesystem.open(normalized_path)
     }
 
+    pub(super) fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    // The options stay as requested, only the file to serve changes.
+    pub(super) fn redirect(self, filename: String) -> Self {
+        Self { filename, ..self }
+    }
+
+    pub(super) fn is_netascii(&self) -> bool {
+        self.netascii
+    }
+
     pub(super) fn yield_options(self) -> HashMap<String, String> {
         self.options
     }
@@ -85,29 +130,41 @@ impl ReadRequest {
 #[derive(Debug)]
 pub(super) struct OptionsAcknowledge {
     options: Vec<(String, String)>,
+    final_null: bool,
 }
 
 impl OptionsAcknowledge {
     pub fn new() -> Self {
         Self {
             options: Vec::new(),
+            final_null: true,
         }
     }
 
+    // RFC 2347 terminates every value, the last one included, but a few clients choke on the last null.
+    pub(super) fn with_final_null(mut self, final_null: bool) -> Self {
+        self.final_null = final_null;
+        self
+    }
+
     pub(super) fn serialize(&self, buffer: &mut [u8]) -> Result<usize, BufferError> {
         if buffer.is_empty() {
             return Ok(0);
         }
         let mut datagram = WriteCursor::new(buffer);
-        datagram.put_ushort(OACK)?;
         let offset = {
-            let mut offset: usize = 0;
+            let mut offset = datagram.put_ushort(OACK)?;
             for (key, value) in &self.options {
-                datagram.put_string(key.as_str())?;
-                offset = datagram.put_string(value.as_str())?;
+                offset = datagram
+                    .put_string(key.as_str())
+                    .and_then(|_| datagram.put_string(value.as_str()))
+                    .map_err(|error| BufferError::new(&format!("Option {key}={value}: {error}")))?;
             }
             offset
         };
+        if !self.final_null && self.has_options() {
+            return Ok(offset - 1);
+        }
         Ok(offset)
     }
     pub fn push(&mut self, option: (String, String)) {
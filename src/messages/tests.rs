@@ -11,7 +11,7 @@ fn parse_rrq() {
         vec![0x00],
     ];
     let raw: Vec<u8> = binding.iter().flatten().copied().collect();
-    let rrq = ReadRequest::parse(&raw);
+    let rrq = ReadRequest::parse(&raw, DEFAULT_MAX_OPTIONS);
     assert!(rrq.is_ok());
 }
 #[test]
@@ -23,12 +23,240 @@ fn parse_incomplete_rrq() {
         vec![0x00],
     ];
     let raw: Vec<u8> = binding.iter().flatten().copied().collect();
-    let error = ReadRequest::parse(&raw).err().unwrap();
+    let error = ReadRequest::parse(&raw, DEFAULT_MAX_OPTIONS).err().unwrap();
     assert!(error.to_string().contains("Bad format"));
 }
 
 #[test]
 fn parse_empty_rrq() {
-    let error = ReadRequest::parse(&vec![]).err().unwrap();
+    let error = ReadRequest::parse(&vec![], DEFAULT_MAX_OPTIONS)
+        .err()
+        .unwrap();
     assert!(error.to_string().contains("Bad format"));
 }
+
+fn rrq_for(filename: &[u8]) -> Vec<u8> {
+    [&RRQ.to_be_bytes()[..], filename, b"\x00octet\x00"].concat()
+}
+
+#[test]
+fn reject_filename_with_newline() {
+    let raw = rrq_for(b"pxelinux.0\nFake log line");
+    let error = ReadRequest::parse(&raw, DEFAULT_MAX_OPTIONS).err().unwrap();
+    assert_eq!(
+        error.to_string(),
+        TFTPError::illegal_operation("Filename contains control characters").to_string()
+    );
+}
+
+#[test]
+fn reject_filename_with_control_byte() {
+    let raw = rrq_for(b"boot/\x1b[2Jgrub.cfg");
+    let error = ReadRequest::parse(&raw, DEFAULT_MAX_OPTIONS).err().unwrap();
+    assert_eq!(
+        error.to_string(),
+        TFTPError::illegal_operation("Filename contains control characters").to_string()
+    );
+}
+
+#[test]
+fn accept_nested_filename_with_dots() {
+    let rrq = ReadRequest::parse(
+        &rrq_for(b"/boot/grub/x86_64-efi/..grub.cfg"),
+        DEFAULT_MAX_OPTIONS,
+    );
+    assert_eq!(rrq.unwrap().filename(), "/boot/grub/x86_64-efi/..grub.cfg");
+}
+
+fn rrq_in_mode(mode: &str) -> Vec<u8> {
+    [
+        &RRQ.to_be_bytes()[..],
+        b"irrelevant.file\x00",
+        mode.as_bytes(),
+        b"\x00",
+    ]
+    .concat()
+}
+
+#[test]
+fn reject_mail_mode() {
+    let error = ReadRequest::parse(&rrq_in_mode(MAIL), DEFAULT_MAX_OPTIONS)
+        .err()
+        .unwrap();
+    assert_eq!(
+        error.to_string(),
+        TFTPError::illegal_operation("mail mode is obsolete and unsupported").to_string()
+    );
+}
+
+#[test]
+fn reject_unknown_mode() {
+    let error = ReadRequest::parse(&rrq_in_mode("qwerty"), DEFAULT_MAX_OPTIONS)
+        .err()
+        .unwrap();
+    assert!(error.to_string().starts_with("TFTP ERROR: [0x00]"));
+    assert!(error.to_string().contains("Unknown transfer mode"));
+}
+
+fn build_rrq(options: &[(&str, &str)]) -> Vec<u8> {
+    let mut raw = RRQ.to_be_bytes().to_vec();
+    for field in ["irrelevant.file", OCTET]
+        .into_iter()
+        .chain(options.iter().flat_map(|(name, value)| [*name, *value]))
+    {
+        raw.extend_from_slice(field.as_bytes());
+        raw.push(0x00);
+    }
+    raw
+}
+
+#[test]
+fn parse_mixed_case_options() {
+    let raw = build_rrq(&[("BlkSize", "1024"), ("TSIZE", "0")]);
+    let options = ReadRequest::parse(&raw, DEFAULT_MAX_OPTIONS)
+        .unwrap()
+        .yield_options();
+    assert_eq!(
+        options,
+        HashMap::from([
+            ("blksize".to_string(), "1024".to_string()),
+            ("tsize".to_string(), "0".to_string()),
+        ])
+    );
+}
+
+#[test]
+fn parse_duplicate_options() {
+    let raw = build_rrq(&[("timeout", "3"), ("Timeout", "5"), ("timeout", "7")]);
+    let options = ReadRequest::parse(&raw, DEFAULT_MAX_OPTIONS)
+        .unwrap()
+        .yield_options();
+    assert_eq!(
+        options,
+        HashMap::from([("timeout".to_string(), "7".to_string())])
+    );
+}
+
+#[test]
+fn parse_too_many_options() {
+    let names: Vec<String> = (0..=DEFAULT_MAX_OPTIONS).map(|i| format!("o{i}")).collect();
+    let options: Vec<(&str, &str)> = names.iter().map(|name| (name.as_str(), "1")).collect();
+    let error = ReadRequest::parse(&build_rrq(&options), DEFAULT_MAX_OPTIONS)
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("Too many options"));
+    let within_limit = build_rrq(&options[..DEFAULT_MAX_OPTIONS]);
+    assert!(ReadRequest::parse(&within_limit, DEFAULT_MAX_OPTIONS).is_ok());
+}
+
+#[test]
+fn parse_too_many_option_bytes() {
+    let long_value = "x".repeat(MAX_OPTIONS_BYTES);
+    let error = ReadRequest::parse(&build_rrq(&[("blksize", &long_value)]), DEFAULT_MAX_OPTIONS)
+        .err()
+        .unwrap();
+    assert!(error.to_string().contains("Too many options"));
+}
+
+#[test]
+fn parse_netascii_rrq() {
+    let mut raw = RRQ.to_be_bytes().to_vec();
+    raw.extend_from_slice(b"irrelevant.file\x00netascii\x00");
+    let rrq = ReadRequest::parse(&raw, DEFAULT_MAX_OPTIONS).unwrap();
+    assert!(rrq.is_netascii());
+    assert!(
+        !ReadRequest::parse(&build_rrq(&[]), DEFAULT_MAX_OPTIONS)
+            .unwrap()
+            .is_netascii()
+    );
+}
+
+fn full_oack() -> OptionsAcknowledge {
+    let mut oack = OptionsAcknowledge::new();
+    for (key, value) in [
+        ("timeout", "255"),
+        ("blksize", "65464"),
+        ("tsize", "18446744073709551615"),
+        ("mtime", "18446744073709551615"),
+        ("hash", &format!("sha256:{}", "f".repeat(64))),
+        ("windowsize", "65535"),
+    ] {
+        oack.push((key.to_string(), value.to_string()));
+    }
+    oack
+}
+
+#[test]
+fn serialize_oack_with_all_options() {
+    let oack = full_oack();
+    let mut buffer = [0u8; 65536];
+    let size = oack.serialize(&mut buffer).unwrap();
+    assert_eq!(u16::from_be_bytes([buffer[0], buffer[1]]), OACK);
+    let mut cursor = ReadCursor::new(&buffer[2..size]);
+    let mut fields: Vec<(String, String)> = Vec::new();
+    while let Ok(option) = cursor.extract_string() {
+        fields.push((option, cursor.extract_string().unwrap()));
+    }
+    assert_eq!(fields, oack.options);
+}
+
+fn serialized(oack: &OptionsAcknowledge) -> Vec<u8> {
+    let mut buffer = [0u8; 1024];
+    let size = oack.serialize(&mut buffer).unwrap();
+    buffer[..size].to_vec()
+}
+
+fn oack_of(options: &[(&str, &str)]) -> OptionsAcknowledge {
+    let mut oack = OptionsAcknowledge::new();
+    for (key, value) in options {
+        oack.push((key.to_string(), value.to_string()));
+    }
+    oack
+}
+
+// Pins the wire format, so changes to the cursor can't alter it unnoticed.
+#[test]
+fn oack_byte_layout() {
+    for (options, expected) in [
+        (&[][..], &b"\x00\x06"[..]),
+        (&[("blksize", "1428")][..], b"\x00\x06blksize\x001428\x00"),
+        (&[("tsize", "0")][..], b"\x00\x06tsize\x000\x00"),
+        (
+            &[("timeout", "3"), ("windowsize", "16")][..],
+            b"\x00\x06timeout\x003\x00windowsize\x0016\x00",
+        ),
+        // In the order the options are negotiated.
+        (
+            &[
+                ("timeout", "3"),
+                ("blksize", "1428"),
+                ("tsize", "1048576"),
+                ("windowsize", "16"),
+            ][..],
+            b"\x00\x06timeout\x003\x00blksize\x001428\x00tsize\x001048576\x00windowsize\x0016\x00",
+        ),
+    ] {
+        assert_eq!(serialized(&oack_of(options)), expected, "{options:?}");
+    }
+}
+
+#[test]
+fn oack_without_final_null() {
+    let oack = oack_of(&[("blksize", "1428"), ("tsize", "1048576")]).with_final_null(false);
+    assert_eq!(
+        serialized(&oack),
+        b"\x00\x06blksize\x001428\x00tsize\x001048576"
+    );
+    let oack = oack_of(&[("timeout", "3")]).with_final_null(false);
+    assert_eq!(serialized(&oack), b"\x00\x06timeout\x003");
+    // The opcode alone has no value to end.
+    let oack = oack_of(&[]).with_final_null(false);
+    assert_eq!(serialized(&oack), b"\x00\x06");
+}
+
+#[test]
+fn serialize_oack_overflow_names_option() {
+    let mut buffer = [0u8; 64];
+    let error = full_oack().serialize(&mut buffer).unwrap_err();
+    assert!(error.to_string().contains("Option mtime="), "{error}");
+}
@@ -1,21 +1,90 @@
+use crate::decompress::Codec;
+use crate::disk_cache::{ConfigKey, DiskCache};
+use crate::disk_worker::DiskWorker;
 use crate::guestfs::{GuestFS, GuestFSError};
 use crate::remote_fs::{Config, ConnectedDisk, Mount, RemoteRoot, VirtualRootError};
+use crate::stats;
 use serde::Deserialize;
 use serde_json::{Value, from_value};
+use std::collections::HashMap;
 use std::fmt::Debug;
-use std::fs;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::{fs, io};
 
 #[cfg(test)]
 mod tests;
 
-fn attach_nbd_disk<U: AsRef<str>>(url: U) -> Result<ConnectedDisk, GuestFSError> {
+const CONFIG_EXTENSIONS: [&str; 2] = ["nbd", "json"];
+const APPLIANCE_MEMORY_MB_RANGE: RangeInclusive<u32> = 256..=65536;
+const APPLIANCE_SMP_RANGE: RangeInclusive<u32> = 1..=64;
+const PLAIN_SCHEME: &str = "nbd://";
+const TLS_SCHEMES: [&str; 2] = ["nbds://", "nbd+tls://"];
+const DEFAULT_TLS_CERT_DIR: &str = "/etc/pki/qemu";
+// Keeps the appliance console readable in the logs, whatever else is appended.
+const APPLIANCE_APPEND: &str = "SYSTEMD_COLORS=0";
+
+/// Resources of the qemu appliance. Unset ones are left at the libguestfs defaults.
+#[derive(Debug, Default, Clone)]
+struct Appliance {
+    memory_mb: Option<u32>,
+    smp: Option<u32>,
+    // Extra kernel command line of the appliance, for debugging its boot.
+    append: Option<String>,
+}
+
+impl Appliance {
+    fn configure(&self, handle: &GuestFS) -> Result<(), GuestFSError> {
+        if let Some(memory_mb) = self.memory_mb {
+            handle.set_memsize(memory_mb)?;
+        }
+        if let Some(smp) = self.smp {
+            handle.set_smp(smp)?;
+        }
+        handle.set_append(self.kernel_append())
+    }
+
+    fn kernel_append(&self) -> String {
+        match &self.append {
+            Some(append) => format!("{APPLIANCE_APPEND} {append}"),
+            None => String::from(APPLIANCE_APPEND),
+        }
+    }
+}
+
+/// Client credentials of an NBD connection over TLS.
+#[derive(Debug, Clone)]
+struct Tls {
+    // A qemu x509 credentials directory: ca-cert.pem, plus client-cert.pem and client-key.pem
+    // for servers verifying their clients.
+    cert_dir: String,
+    // The name the server certificate is checked against instead of the URL host.
+    hostname: Option<String>,
+}
+
+fn attach_nbd_disk<U: AsRef<str>>(
+    url: U,
+    tls: Option<Tls>,
+    appliance: Appliance,
+    writable: bool,
+) -> Result<ConnectedDisk, GuestFSError> {
     let owned_url = String::from(url.as_ref());
-    let handle = GuestFS::new();
-    disable_appliance_log_color(&handle)?;
-    add_stub_disk(&handle)?;
-    add_nbd_device_read_only(&handle, owned_url.as_str())?;
+    let worker = DiskWorker::spawn(format!("guestfs {owned_url}"))?;
+    let launch_url = owned_url.clone();
+    worker.call(move |handle| {
+        appliance.configure(handle)?;
+        add_stub_disk(handle)?;
+        add_nbd_device(handle, launch_url.as_str(), tls.as_ref(), writable)?;
+        launch(handle, launch_url)
+    })?;
+    Ok(ConnectedDisk::new(Arc::new(worker), owned_url, writable))
+}
+
+fn launch(handle: &GuestFS, owned_url: String) -> Result<(), GuestFSError> {
     if let Err(_launch_result) = handle.launch() {
         let mut appliance_errors: Vec<String> = vec![];
         for error in handle.retrieve_appliance_stderr() {
@@ -32,25 +101,70 @@ fn attach_nbd_disk<U: AsRef<str>>(url: U) -> Result<ConnectedDisk, GuestFSError>
         Err(GuestFSError::Generic(appliance_errors.join("\n")))
     } else {
         _ = handle.retrieve_appliance_stderr();
-        Ok(ConnectedDisk::new(Rc::new(handle), owned_url))
+        Ok(())
     }
 }
 
-fn disable_appliance_log_color(handle: &GuestFS) -> Result<(), GuestFSError> {
-    handle.set_append("SYSTEMD_COLORS=0")
-}
-
 fn add_stub_disk(handle: &GuestFS) -> Result<(), GuestFSError> {
     // guestfs_launch() does not allow a qemu appliance to be run without explicitly provided device.
     handle.add_disk("/dev/null", true)
 }
 
-fn add_nbd_device_read_only(handle: &GuestFS, url: &str) -> Result<(), GuestFSError> {
-    handle.add_qemu_option("-device", "scsi-hd,drive=nbd0")?;
-    handle.add_qemu_option(
-        "-drive",
-        &format!("id=nbd0,file={url},format=raw,if=none,readonly=on"),
-    )
+fn add_nbd_device(
+    handle: &GuestFS,
+    url: &str,
+    tls: Option<&Tls>,
+    writable: bool,
+) -> Result<(), GuestFSError> {
+    for (option, value) in nbd_device_options(url, tls, writable) {
+        handle.add_qemu_option(option, &value)?;
+    }
+    Ok(())
+}
+
+// Values spliced into the comma separated qemu options, where a literal comma is written twice.
+fn escape_option(value: &str) -> String {
+    value.replace(',', ",,")
+}
+
+// qemu only parses the plain scheme, TLS is requested by attaching the client credentials to the drive.
+fn nbd_device_options(url: &str, tls: Option<&Tls>, writable: bool) -> Vec<(&'static str, String)> {
+    let readonly = if writable { "off" } else { "on" };
+    let mut options = vec![("-device", String::from("scsi-hd,drive=nbd0"))];
+    match tls {
+        Some(tls) => {
+            let address = TLS_SCHEMES
+                .iter()
+                .find_map(|scheme| url.strip_prefix(scheme))
+                .unwrap_or(url);
+            let address = escape_option(address);
+            options.push((
+                "-object",
+                format!(
+                    "tls-creds-x509,id=tls0,dir={},endpoint=client",
+                    escape_option(&tls.cert_dir)
+                ),
+            ));
+            let hostname = match &tls.hostname {
+                Some(hostname) => format!(",file.tls-hostname={}", escape_option(hostname)),
+                None => String::new(),
+            };
+            options.push((
+                "-drive",
+                format!(
+                    "id=nbd0,file={PLAIN_SCHEME}{address},file.tls-creds=tls0{hostname},format=raw,if=none,readonly={readonly}"
+                ),
+            ));
+        }
+        None => options.push((
+            "-drive",
+            format!(
+                "id=nbd0,file={},format=raw,if=none,readonly={readonly}",
+                escape_option(url)
+            ),
+        )),
+    }
+    options
 }
 
 #[derive(Debug, Deserialize)]
@@ -58,6 +172,104 @@ pub(super) struct NBDConfig {
     url: String,
     mounts: Vec<Mount>,
     tftp_root: String,
+    // Connect the disk at startup and keep it connected regardless of the peer activity.
+    #[serde(default)]
+    prewarm: bool,
+    // Among the configs matching a peer, lower numbers take precedence; ties fall back to the file names.
+    #[serde(default)]
+    priority: i32,
+    #[serde(default)]
+    appliance_memory_mb: Option<u32>,
+    #[serde(default)]
+    appliance_smp: Option<u32>,
+    // Appended to the kernel command line of the appliance after the one set internally.
+    #[serde(default)]
+    appliance_append: Option<String>,
+    // Files under the TFTP root served decompressed, e.g. `{"vmlinuz": "gzip"}`.
+    #[serde(default)]
+    decompress: HashMap<String, Codec>,
+    // Used by the TLS schemes only.
+    #[serde(default)]
+    tls_cert_dir: Option<String>,
+    #[serde(default)]
+    tls_hostname: Option<String>,
+    // Attach the drive and mount the partitions read-write, for files written back to the image.
+    #[serde(default)]
+    writable: bool,
+}
+
+impl NBDConfig {
+    fn parse(value: &Value) -> Result<Self, VirtualRootError> {
+        let config = from_value::<Self>(value.clone())
+            .map_err(|error| VirtualRootError::ConfigError(error.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), VirtualRootError> {
+        let Some((scheme, _address)) = self.url.split_once("://") else {
+            return Err(VirtualRootError::ConfigError(format!(
+                "Invalid NBD URL: {}",
+                self.url
+            )));
+        };
+        if !self.url.starts_with(PLAIN_SCHEME) && !self.is_tls() {
+            return Err(VirtualRootError::ConfigError(format!(
+                "Invalid NBD URL: {}: unsupported scheme {scheme}://, expected {PLAIN_SCHEME}, {}",
+                self.url,
+                TLS_SCHEMES.join(", ")
+            )));
+        }
+        for mount in &self.mounts {
+            mount.validate()?;
+        }
+        if let Some(memory_mb) = self.appliance_memory_mb
+            && !APPLIANCE_MEMORY_MB_RANGE.contains(&memory_mb)
+        {
+            return Err(VirtualRootError::ConfigError(format!(
+                "Appliance memory {memory_mb} MB is out of range {APPLIANCE_MEMORY_MB_RANGE:?}"
+            )));
+        }
+        if let Some(smp) = self.appliance_smp
+            && !APPLIANCE_SMP_RANGE.contains(&smp)
+        {
+            return Err(VirtualRootError::ConfigError(format!(
+                "Appliance CPU count {smp} is out of range {APPLIANCE_SMP_RANGE:?}"
+            )));
+        }
+        if let Some(append) = &self.appliance_append
+            && append.chars().any(char::is_control)
+        {
+            return Err(VirtualRootError::ConfigError(format!(
+                "Appliance append {append:?} is not a single line"
+            )));
+        }
+        Ok(())
+    }
+
+    fn is_tls(&self) -> bool {
+        TLS_SCHEMES
+            .iter()
+            .any(|scheme| self.url.starts_with(scheme))
+    }
+
+    fn tls(&self) -> Option<Tls> {
+        self.is_tls().then(|| Tls {
+            cert_dir: self
+                .tls_cert_dir
+                .clone()
+                .unwrap_or_else(|| String::from(DEFAULT_TLS_CERT_DIR)),
+            hostname: self.tls_hostname.clone(),
+        })
+    }
+
+    fn appliance(&self) -> Appliance {
+        Appliance {
+            memory_mb: self.appliance_memory_mb,
+            smp: self.appliance_smp,
+            append: self.appliance_append.clone(),
+        }
+    }
 }
 
 impl<'a> Config<'a> for NBDConfig {
@@ -71,13 +283,17 @@ impl<'a> Config<'a> for NBDConfig {
         }
     }
     fn connect(&self) -> Result<RemoteRoot, VirtualRootError> {
-        if !self.url.starts_with("nbd://") {
-            return Err(VirtualRootError::ConfigError(format!(
-                "Invalid NBD URL: {}",
-                self.url
+        self.validate()?;
+        let tls = self.tls();
+        // qemu would only complain about the missing credentials after launching the appliance.
+        if let Some(tls) = &tls
+            && !Path::new(&tls.cert_dir).is_dir()
+        {
+            return Err(VirtualRootError::SetupError(GuestFSError::Generic(
+                format!("TLS certificate directory {} is not found", tls.cert_dir),
             )));
-        };
-        let mut disk = match attach_nbd_disk(&self.url) {
+        }
+        let mut disk = match attach_nbd_disk(&self.url, tls, self.appliance(), self.writable) {
             Ok(disk) => disk,
             Err(error) => return Err(VirtualRootError::SetupError(error)),
         };
@@ -86,35 +302,166 @@ impl<'a> Config<'a> for NBDConfig {
             Err(error) => return Err(VirtualRootError::SetupError(error)),
         };
         for mountpoint_config in &self.mounts {
-            mountpoint_config.mount_suitable(&partitions)?;
+            mountpoint_config.mount_suitable(&partitions, self.writable)?;
         }
-        Ok(RemoteRoot::new(disk, &self.tftp_root))
+        Ok(RemoteRoot::new(disk, &self.tftp_root)
+            .with_decompress(self.decompress.clone())
+            .with_source(format!("{} {:?}", self.url, self.mounts)))
     }
 }
 
-pub(super) fn open_nbd_root(tftp_root: &PathBuf, ip: &str) -> Option<RemoteRoot> {
+/// Peers with a config asking for its disk to be connected at startup and kept connected.
+pub(super) fn prewarmed_peers(tftp_root: &Path) -> Vec<IpAddr> {
+    let config_peers = files_sorted(tftp_root)
+        .into_iter()
+        .filter_map(|file_path| IpAddr::from_str(file_path.file_stem()?.to_str()?).ok());
+    let directory_peers = fs::read_dir(tftp_root)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| is_peer_directory(&entry.path()))
+        .filter_map(|entry| IpAddr::from_str(entry.file_name().to_str()?).ok());
+    let mut peers: Vec<IpAddr> = config_peers
+        .chain(directory_peers)
+        .filter(|peer| is_prewarmed(tftp_root, &peer.to_string()))
+        .collect();
+    peers.sort();
+    peers.dedup();
+    peers
+}
+
+/// Whether any config in the TFTP root or in the peer directory is there for the peer, valid or not.
+pub(super) fn has_configs(tftp_root: &Path, ip: &str) -> bool {
+    !top_level_configs(tftp_root, ip).is_empty()
+        || !peer_directory_configs(tftp_root, ip).is_empty()
+}
+
+pub(super) fn is_prewarmed(tftp_root: &Path, ip: &str) -> bool {
+    top_level_configs(tftp_root, ip)
+        .into_iter()
+        .chain(peer_directory_configs(tftp_root, ip))
+        .any(|file_path| {
+            read_json(&file_path)
+                .ok()
+                .and_then(|json_struct| from_value::<NBDConfig>(json_struct).ok())
+                .is_some_and(|config| config.prewarm)
+        })
+}
+
+/// Connects the first matching config in the TFTP root, followed by every config in the peer directory.
+/// Disks still warm in the cache are reclaimed instead of being connected anew. Also returns the configs
+/// whose disks failed to connect, which unlike invalid ones may connect on a retry.
+pub(super) fn open_nbd_roots(
+    tftp_root: &Path,
+    ip: &str,
+    disk_cache: &DiskCache,
+) -> (Vec<(ConfigKey, RemoteRoot)>, Vec<PathBuf>) {
+    let mut roots: Vec<(ConfigKey, RemoteRoot)> = Vec::new();
+    let mut failed: Vec<PathBuf> = Vec::new();
     eprintln!("Looking for TFTP root configs in {tftp_root:?} ...");
-    for file_path in files_sorted(tftp_root) {
-        if match_ip(&file_path, ip) {
-            eprintln!("Found TFTP root config {file_path:?}");
-            if let Some(json_struct) = read_json(&file_path) {
-                eprintln!("Found JSON file {file_path:?}");
-                if let Some(nbd_config) = NBDConfig::from_json(&json_struct) {
-                    eprintln!("Found NBD TFTP root config {file_path:?}");
-                    match nbd_config.connect() {
-                        Ok(disk) => {
-                            eprintln!("Connected config {file_path:?}");
-                            return Some(disk);
-                        }
-                        Err(VirtualRootError::ConfigError(error)) => {
-                            eprintln!("Invalid config {file_path:?}: {error}");
-                        }
-                        Err(VirtualRootError::SetupError(error)) => {
-                            eprintln!(
-                                "Failed to connect disk using config {file_path:?}: {error:?}"
-                            );
-                        }
-                    }
+    for file_path in top_level_configs(tftp_root, ip) {
+        if let Some(root) = connect_config(&file_path, tftp_root, disk_cache, &mut failed) {
+            roots.push(root);
+            break;
+        }
+    }
+    let peer_directory = tftp_root.join(ip);
+    if peer_directory.is_dir() {
+        eprintln!("Looking for TFTP root configs in {peer_directory:?} ...");
+        for file_path in peer_directory_configs(tftp_root, ip) {
+            if let Some(root) = connect_config(&file_path, tftp_root, disk_cache, &mut failed) {
+                roots.push(root);
+            }
+        }
+    }
+    (roots, failed)
+}
+
+/// The peer whose handler connects the config: the one it is named after in the TFTP root, or the one of the
+/// peer directory it is in.
+pub(super) fn config_peer(tftp_root: &Path, config: &Path) -> Option<IpAddr> {
+    let parent = config.parent()?;
+    if parent == tftp_root {
+        // The address is followed by the extension, or by a suffix and the extension.
+        let file_name = config.file_name()?.to_str()?;
+        file_name
+            .match_indices('.')
+            .find_map(|(index, _)| IpAddr::from_str(&file_name[..index]).ok())
+    } else if parent.parent()? == tftp_root {
+        IpAddr::from_str(parent.file_name()?.to_str()?).ok()
+    } else {
+        None
+    }
+}
+
+/// Configs in the TFTP root matching the peer, in the order of precedence.
+fn top_level_configs(tftp_root: &Path, ip: &str) -> Vec<PathBuf> {
+    by_priority(
+        files_sorted(tftp_root)
+            .into_iter()
+            .filter(|file_path| match_ip(file_path, ip))
+            .collect(),
+    )
+}
+
+/// Configs in the peer directory, in the order of precedence.
+fn peer_directory_configs(tftp_root: &Path, ip: &str) -> Vec<PathBuf> {
+    by_priority(
+        files_sorted(tftp_root.join(ip))
+            .into_iter()
+            .filter(|file_path| is_config_file(file_path))
+            .collect(),
+    )
+}
+
+// The sort is stable, so configs of the same priority keep the order of their names.
+// Unparseable configs get the default priority and are reported once connected.
+fn by_priority(mut files: Vec<PathBuf>) -> Vec<PathBuf> {
+    files.sort_by_cached_key(|file_path| {
+        read_json(file_path)
+            .ok()
+            .and_then(|json_struct| from_value::<NBDConfig>(json_struct).ok())
+            .map_or(0, |config| config.priority)
+    });
+    files
+}
+
+fn connect_config(
+    file_path: &Path,
+    tftp_root: &Path,
+    disk_cache: &DiskCache,
+    failed: &mut Vec<PathBuf>,
+) -> Option<(ConfigKey, RemoteRoot)> {
+    eprintln!("Found TFTP root config {file_path:?}");
+    let config_key = ConfigKey::of(file_path)?;
+    if let Some(root) = disk_cache.take(&config_key) {
+        eprintln!("Reclaimed cached disk of config {file_path:?}");
+        stats::record_root_connected(file_path);
+        return Some((config_key, root));
+    }
+    if let Ok(json_struct) = read_json(file_path) {
+        eprintln!("Found JSON file {file_path:?}");
+        if let Some(nbd_config) = NBDConfig::from_json(&json_struct) {
+            eprintln!("Found NBD TFTP root config {file_path:?}");
+            if nbd_config.writable
+                && let Err(error) = check_exclusive(file_path, tftp_root)
+            {
+                eprintln!("Invalid config {file_path:?}: {error}");
+                return None;
+            }
+            match nbd_config.connect() {
+                Ok(disk) => {
+                    eprintln!("Connected config {file_path:?}");
+                    stats::record_root_connected(file_path);
+                    return Some((config_key, disk));
+                }
+                Err(VirtualRootError::ConfigError(error)) => {
+                    eprintln!("Invalid config {file_path:?}: {error}");
+                }
+                Err(VirtualRootError::SetupError(error)) => {
+                    eprintln!("Failed to connect disk using config {file_path:?}: {error:?}");
+                    stats::record_root_failed(file_path);
+                    failed.push(file_path.to_path_buf());
                 }
             }
         }
@@ -122,10 +469,46 @@ pub(super) fn open_nbd_root(tftp_root: &PathBuf, ip: &str) -> Option<RemoteRoot>
     None
 }
 
-fn files_sorted<P: AsRef<Path>>(parent: P) -> Vec<PathBuf> {
-    let mut files = fs::read_dir(parent)
+// The appliances of two peers attaching a writable image at once would corrupt it, so a writable config
+// has to be reached by a single peer: neither through a link nor through a peer directory shared by another.
+fn check_exclusive(file_path: &Path, tftp_root: &Path) -> Result<(), String> {
+    let metadata = fs::symlink_metadata(file_path).map_err(|error| error.to_string())?;
+    if metadata.file_type().is_symlink() || metadata.nlink() > 1 {
+        return Err(String::from("Writable config is linked elsewhere"));
+    }
+    let Some(peer_directory) = file_path.parent().filter(|parent| *parent != tftp_root) else {
+        return Ok(());
+    };
+    let resolved = fs::canonicalize(peer_directory).map_err(|error| error.to_string())?;
+    let shared_with = fs::read_dir(tftp_root)
         .into_iter()
         .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path != peer_directory && is_peer_directory(path))
+        .find(|path| fs::canonicalize(path).is_ok_and(|other| other == resolved));
+    match shared_with {
+        Some(other) => Err(format!(
+            "Writable config is in {peer_directory:?}, shared with {other:?}"
+        )),
+        None => Ok(()),
+    }
+}
+
+// The peer directory is served as a local root too, so only files named as configs are treated as such,
+// and the local root hides them.
+pub(super) fn is_config_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|os| os.to_str())
+        .is_some_and(|extension| CONFIG_EXTENSIONS.contains(&extension))
+}
+
+// A missing or unreadable directory has no files, the peer directories and even the root are optional.
+fn files_sorted<P: AsRef<Path>>(parent: P) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(parent) else {
+        return Vec::new();
+    };
+    let mut files = entries
         .filter_map(|entry| {
             if let Ok(entry) = entry {
                 let path = entry.path();
@@ -140,19 +523,77 @@ fn files_sorted<P: AsRef<Path>>(parent: P) -> Vec<PathBuf> {
     files
 }
 
+// The whole address leads the name, so `10.0.0.1` matches `10.0.0.1.nbd` and `10.0.0.1.boot.nbd`,
+// but not `10.0.0.10.nbd`.
 fn match_ip(path: &Path, ip: &str) -> bool {
     if let Some(file_name) = path.file_name().and_then(|os| os.to_str()) {
-        file_name.starts_with(ip)
+        file_name
+            .strip_prefix(ip)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
     } else {
         false
     }
 }
 
-fn read_json(path: &Path) -> Option<Value> {
-    if let Ok(content) = fs::read_to_string(path)
-        && let Ok(json_struct) = serde_json::from_str::<Value>(&content)
-    {
-        return Some(json_struct);
+fn read_json(path: &Path) -> io::Result<Value> {
+    let content = fs::read_to_string(path)?;
+    serde_json::from_str::<Value>(&content)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+fn is_peer_directory(path: &Path) -> bool {
+    path.is_dir()
+        && path
+            .file_name()
+            .and_then(|os| os.to_str())
+            .is_some_and(|name| IpAddr::from_str(name).is_ok())
+}
+
+/// Parses every peer config in the TFTP root and peer directories without connecting and returns the invalid ones with reasons.
+pub(super) fn validate_configs(tftp_root: &Path) -> (usize, Vec<(PathBuf, String)>) {
+    let mut checked: usize = 0;
+    let mut invalid: Vec<(PathBuf, String)> = Vec::new();
+    let top_level_configs = files_sorted(tftp_root).into_iter().filter(|file_path| {
+        file_path
+            .file_name()
+            .and_then(|os| os.to_str())
+            .and_then(|file_name| file_name.rsplit_once('.'))
+            .is_some_and(|(stem, _extension)| IpAddr::from_str(stem).is_ok())
+    });
+    let mut peer_directories: Vec<PathBuf> = fs::read_dir(tftp_root)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| is_peer_directory(path))
+        .collect();
+    peer_directories.sort();
+    let peer_directory_configs = peer_directories
+        .into_iter()
+        .flat_map(files_sorted)
+        .filter(|file_path| is_config_file(file_path));
+    for file_path in top_level_configs.chain(peer_directory_configs) {
+        checked += 1;
+        let json_struct = match read_json(&file_path) {
+            Ok(json_struct) => json_struct,
+            Err(error) => {
+                invalid.push((file_path, format!("Can't read JSON: {error}")));
+                continue;
+            }
+        };
+        match NBDConfig::parse(&json_struct) {
+            Ok(config) => {
+                if config.writable
+                    && let Err(error) = check_exclusive(&file_path, tftp_root)
+                {
+                    invalid.push((file_path, error));
+                }
+            }
+            Err(VirtualRootError::ConfigError(error)) => invalid.push((file_path, error)),
+            Err(VirtualRootError::SetupError(error)) => {
+                invalid.push((file_path, error.to_string()))
+            }
+        }
     }
-    None
+    (checked, invalid)
 }
@@ -1,10 +1,13 @@
 use super::*;
+use crate::checksum::{Algorithm, digest_of};
 use crate::fs::{OpenedFile, Root};
-use crate::tests_common::{ensure_prerequisite_disk, make_payload, read_file};
+use crate::remote_fs::open_error;
+use crate::tests_common::{ensure_prerequisite_disk, make_payload, mk_tmp, read_file};
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::io::{BufRead, ErrorKind};
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command};
+use std::process::{Child, Command, Stdio};
 use std::{fs, io, thread, time};
 
 struct NBDServerProcess {
@@ -26,21 +29,57 @@ impl Drop for NBDServerProcess {
 }
 
 fn run_nbd_server(listen_ip: &str) -> NBDServerProcess {
+    spawn_nbd_server(listen_ip, "nbd", &[])
+}
+
+fn run_tls_nbd_server(listen_ip: &str, cert_dir: &Path) -> NBDServerProcess {
+    let tls_args = [
+        format!(
+            "--object=tls-creds-x509,id=tls0,dir={},endpoint=server,verify-peer=off",
+            cert_dir.display()
+        ),
+        String::from("--tls-creds=tls0"),
+    ];
+    spawn_nbd_server(listen_ip, "nbds", &tls_args)
+}
+
+// Serves a copy of the test disk, so the writes don't reach the disk other tests read.
+fn run_writable_nbd_server(listen_ip: &str, copy_dir: &Path) -> NBDServerProcess {
+    let (test_disk, locked_tests_directory) = ensure_prerequisite_disk();
+    let disk_copy = copy_dir.join("test_disk.qcow2");
+    fs::copy(test_disk, &disk_copy).unwrap();
+    drop(locked_tests_directory);
+    serve_nbd_disk(listen_ip, "nbd", &disk_copy, &[])
+}
+
+fn spawn_nbd_server(listen_ip: &str, scheme: &str, extra_args: &[String]) -> NBDServerProcess {
     let (test_disk, locked_tests_directory) = ensure_prerequisite_disk();
+    let mut args = vec![String::from("--read-only")];
+    args.extend_from_slice(extra_args);
+    let nbd_server = serve_nbd_disk(listen_ip, scheme, &test_disk, &args);
+    drop(locked_tests_directory);
+    nbd_server
+}
+
+fn serve_nbd_disk(
+    listen_ip: &str,
+    scheme: &str,
+    disk: &Path,
+    extra_args: &[String],
+) -> NBDServerProcess {
     let export_name = "disk";
     let nbd_process = Command::new("qemu-nbd")
+        .args(extra_args)
         .arg(format!("--bind={listen_ip}"))
         .arg("--port=0")
         .arg(format!("--export-name={export_name}"))
-        .arg("--read-only")
         .arg("--shared=100")
-        .arg(test_disk)
+        .arg(disk)
         .spawn()
         .unwrap();
     let listen_port = get_listen_tcp_port(nbd_process.id())
         .expect(format!("Could not get listener port for {nbd_process:?}").as_str());
-    drop(locked_tests_directory);
-    let nbd_url = String::from(format!("nbd://{listen_ip}:{listen_port}/{export_name}"));
+    let nbd_url = format!("{scheme}://{listen_ip}:{listen_port}/{export_name}");
     eprintln!("Started NBD server on {nbd_url}");
     NBDServerProcess {
         process: nbd_process,
@@ -157,7 +196,7 @@ fn get_fd_symlink_names(pid: u32) -> io::Result<Vec<String>> {
 fn test_add_nbd_disk() {
     let nbd_process = run_nbd_server("127.0.0.2");
     let start_time = time::Instant::now();
-    let result = attach_nbd_disk(nbd_process.get_url());
+    let result = attach_nbd_disk(nbd_process.get_url(), None, Appliance::default(), false);
     assert!(
         result.is_ok(),
         "Expected Ok, got Err: {:?}",
@@ -175,13 +214,305 @@ fn test_add_nbd_disk() {
     drop(nbd_process);
 }
 
+#[test]
+fn test_add_nbd_disk_custom_appliance() {
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let appliance = Appliance {
+        memory_mb: Some(640),
+        smp: Some(2),
+        ..Default::default()
+    };
+    let mut disk = attach_nbd_disk(nbd_process.get_url(), None, appliance, false).unwrap();
+    assert!(!disk.list_partitions().unwrap().is_empty());
+}
+
+#[test]
+fn test_add_nbd_disk_appended_cmdline() {
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let appliance = Appliance {
+        append: Some(String::from("printk.time=1 loglevel=7")),
+        ..Default::default()
+    };
+    let mut disk = attach_nbd_disk(nbd_process.get_url(), None, appliance, false).unwrap();
+    assert!(!disk.list_partitions().unwrap().is_empty());
+}
+
+#[test]
+fn validate_appliance_append() {
+    let config = |append: &str| {
+        json!({
+            "url": "nbd://127.0.0.1:1000/arbitrary",
+            "mounts": [],
+            "tftp_root": "/boot",
+            "appliance_append": append,
+        })
+    };
+    let appliance = NBDConfig::parse(&config("console=ttyS0 debug"))
+        .unwrap()
+        .appliance();
+    assert_eq!(
+        appliance.kernel_append(),
+        "SYSTEMD_COLORS=0 console=ttyS0 debug"
+    );
+    for append in ["debug\ninit=/bin/sh", "debug\r"] {
+        assert!(matches!(
+            NBDConfig::parse(&config(append)),
+            Err(VirtualRootError::ConfigError(message)) if message.contains("not a single line")
+        ));
+    }
+}
+
+#[test]
+fn validate_appliance_resources() {
+    let config = |memory_mb: u32, smp: u32| {
+        json!({
+            "url": "nbd://127.0.0.1:1000/arbitrary",
+            "mounts": [],
+            "tftp_root": "/boot",
+            "appliance_memory_mb": memory_mb,
+            "appliance_smp": smp,
+        })
+    };
+    assert!(NBDConfig::parse(&config(1024, 4)).is_ok());
+    assert!(matches!(
+        NBDConfig::parse(&config(64, 4)),
+        Err(VirtualRootError::ConfigError(message)) if message.contains("Appliance memory")
+    ));
+    assert!(matches!(
+        NBDConfig::parse(&config(1024, 0)),
+        Err(VirtualRootError::ConfigError(message)) if message.contains("Appliance CPU count")
+    ));
+}
+
+fn url_config(url: &str) -> Value {
+    json!({
+        "url": url,
+        "mounts": [],
+        "tftp_root": "/boot",
+    })
+}
+
+#[test]
+fn accept_tls_schemes() {
+    for url in [
+        "nbds://127.0.0.1:1000/arbitrary",
+        "nbd+tls://127.0.0.1:1000/arbitrary",
+    ] {
+        let config = NBDConfig::parse(&url_config(url)).unwrap();
+        let tls = config.tls().unwrap();
+        assert_eq!(tls.cert_dir, DEFAULT_TLS_CERT_DIR, "{url}");
+        assert_eq!(tls.hostname, None, "{url}");
+    }
+    let mut custom_tls = url_config("nbds://127.0.0.1:1000/arbitrary");
+    custom_tls["tls_cert_dir"] = json!("/etc/rtftp/nbd");
+    custom_tls["tls_hostname"] = json!("nbd.example.com");
+    let tls = NBDConfig::parse(&custom_tls).unwrap().tls().unwrap();
+    assert_eq!(tls.cert_dir, "/etc/rtftp/nbd");
+    assert_eq!(tls.hostname.as_deref(), Some("nbd.example.com"));
+    let config = NBDConfig::parse(&url_config("nbd://127.0.0.1:1000/arbitrary")).unwrap();
+    assert!(config.tls().is_none());
+}
+
+#[test]
+fn reject_unsupported_scheme() {
+    for url in [
+        "http://127.0.0.1:1000/arbitrary",
+        "ftp://127.0.0.1:1000/arbitrary",
+    ] {
+        assert!(matches!(
+            NBDConfig::parse(&url_config(url)),
+            Err(VirtualRootError::ConfigError(message)) if message.contains("unsupported scheme")
+        ));
+    }
+    assert!(matches!(
+        NBDConfig::parse(&url_config("127.0.0.1:1000/arbitrary")),
+        Err(VirtualRootError::ConfigError(message)) if message.starts_with("Invalid NBD URL")
+    ));
+}
+
+#[test]
+fn tls_device_options() {
+    let plain = nbd_device_options("nbd://127.0.0.1:1000/arbitrary", None, false);
+    assert!(plain.iter().all(|(option, _value)| *option != "-object"));
+    assert!(plain.contains(&(
+        "-drive",
+        String::from("id=nbd0,file=nbd://127.0.0.1:1000/arbitrary,format=raw,if=none,readonly=on")
+    )));
+    let mut tls = Tls {
+        cert_dir: String::from("/etc/pki/qemu"),
+        hostname: None,
+    };
+    let options = nbd_device_options("nbds://127.0.0.1:1000/arbitrary", Some(&tls), false);
+    assert!(options.contains(&(
+        "-object",
+        String::from("tls-creds-x509,id=tls0,dir=/etc/pki/qemu,endpoint=client")
+    )));
+    assert!(options.contains(&(
+        "-drive",
+        String::from(
+            "id=nbd0,file=nbd://127.0.0.1:1000/arbitrary,file.tls-creds=tls0,format=raw,if=none,readonly=on"
+        )
+    )));
+    tls.hostname = Some(String::from("nbd.example.com"));
+    let options = nbd_device_options("nbd+tls://127.0.0.1:1000/arbitrary", Some(&tls), false);
+    assert!(options.contains(&(
+        "-drive",
+        String::from(
+            "id=nbd0,file=nbd://127.0.0.1:1000/arbitrary,file.tls-creds=tls0,file.tls-hostname=nbd.example.com,format=raw,if=none,readonly=on"
+        )
+    )));
+}
+
+#[test]
+fn tls_options_commas_escaped() {
+    let tls = Tls {
+        cert_dir: String::from("/etc/pki/qemu,verify-peer=off"),
+        hostname: Some(String::from("nbd.example.com,file.tls-creds=other")),
+    };
+    let options = nbd_device_options("nbds://127.0.0.1:1000/arbitrary", Some(&tls), false);
+    assert!(options.contains(&(
+        "-object",
+        String::from("tls-creds-x509,id=tls0,dir=/etc/pki/qemu,,verify-peer=off,endpoint=client")
+    )));
+    assert!(options.contains(&(
+        "-drive",
+        String::from(
+            "id=nbd0,file=nbd://127.0.0.1:1000/arbitrary,file.tls-creds=tls0,file.tls-hostname=nbd.example.com,,file.tls-creds=other,format=raw,if=none,readonly=on"
+        )
+    )));
+}
+
+#[test]
+fn writable_device_options() {
+    let options = nbd_device_options("nbd://127.0.0.1:1000/arbitrary", None, true);
+    assert!(options.contains(&(
+        "-drive",
+        String::from("id=nbd0,file=nbd://127.0.0.1:1000/arbitrary,format=raw,if=none,readonly=off")
+    )));
+}
+
+#[test]
+fn missing_tls_cert_dir() {
+    let cert_dir = mk_tmp(missing_tls_cert_dir).join("missing");
+    let mut config = url_config("nbds://127.0.0.1:1000/arbitrary");
+    config["tls_cert_dir"] = json!(cert_dir);
+    let result = NBDConfig::parse(&config).unwrap().connect();
+    assert!(matches!(
+        result,
+        Err(VirtualRootError::SetupError(GuestFSError::Generic(message)))
+            if message.contains("TLS certificate directory")
+    ));
+}
+
+fn tls_tools_available() -> bool {
+    ["qemu-nbd", "openssl"].iter().all(|tool| {
+        Command::new(tool)
+            .arg("--version")
+            .output()
+            .is_ok_and(|output| output.status.success())
+    })
+}
+
+fn openssl(cert_dir: &Path, args: &[&str]) {
+    let status = Command::new("openssl")
+        .current_dir(cert_dir)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success(), "openssl {args:?} failed");
+}
+
+// A CA, and a server certificate for the `rtftp-test-nbd` name signed by it.
+fn make_tls_certs(cert_dir: &Path) {
+    let subject_alt_name = cert_dir.join("server.ext");
+    fs::write(&subject_alt_name, "subjectAltName=DNS:rtftp-test-nbd\n").unwrap();
+    openssl(
+        cert_dir,
+        &[
+            "req",
+            "-x509",
+            "-newkey",
+            "rsa:2048",
+            "-nodes",
+            "-days",
+            "1",
+            "-subj",
+            "/CN=rtftp-test-ca",
+            "-keyout",
+            "ca-key.pem",
+            "-out",
+            "ca-cert.pem",
+        ],
+    );
+    openssl(
+        cert_dir,
+        &[
+            "req",
+            "-newkey",
+            "rsa:2048",
+            "-nodes",
+            "-subj",
+            "/CN=rtftp-test-nbd",
+            "-keyout",
+            "server-key.pem",
+            "-out",
+            "server.csr",
+        ],
+    );
+    openssl(
+        cert_dir,
+        &[
+            "x509",
+            "-req",
+            "-days",
+            "1",
+            "-in",
+            "server.csr",
+            "-CA",
+            "ca-cert.pem",
+            "-CAkey",
+            "ca-key.pem",
+            "-CAcreateserial",
+            "-extfile",
+            "server.ext",
+            "-out",
+            "server-cert.pem",
+        ],
+    );
+}
+
+#[test]
+fn test_add_tls_nbd_disk() {
+    if !tls_tools_available() {
+        eprintln!("Skipping: qemu-nbd or openssl is unavailable");
+        return;
+    }
+    let cert_dir = mk_tmp(test_add_tls_nbd_disk);
+    make_tls_certs(&cert_dir);
+    let nbd_process = run_tls_nbd_server("127.0.0.2", &cert_dir);
+    let tls = Tls {
+        cert_dir: cert_dir.to_string_lossy().into_owned(),
+        hostname: Some(String::from("rtftp-test-nbd")),
+    };
+    let mut disk = attach_nbd_disk(
+        nbd_process.get_url(),
+        Some(tls),
+        Appliance::default(),
+        false,
+    )
+    .unwrap();
+    assert!(!disk.list_partitions().unwrap().is_empty());
+}
+
 #[test]
 fn test_add_non_existing_share_disk() {
     let nbd_process = run_nbd_server("127.0.0.2");
     let non_existing_share = "non_existing_share";
     let (url_prefix, _existing_share) = nbd_process.get_url().rsplit_once("/").unwrap();
     let non_exising_share = vec![url_prefix, non_existing_share].join("/");
-    let result = attach_nbd_disk(non_exising_share);
+    let result = attach_nbd_disk(non_exising_share, None, Appliance::default(), false);
     assert!(result.is_err(), "Unexpected success received");
     assert!(matches!(
         result.err().unwrap(),
@@ -192,7 +523,7 @@ fn test_add_non_existing_share_disk() {
 #[test]
 fn test_add_invalid_url() {
     let non_existent_nbd_url = "nbd://127.1.1.1:1/invalid";
-    let result = attach_nbd_disk(non_existent_nbd_url);
+    let result = attach_nbd_disk(non_existent_nbd_url, None, Appliance::default(), false);
     assert!(result.is_err());
     assert!(matches!(
         result.err().unwrap(),
@@ -203,7 +534,8 @@ fn test_add_invalid_url() {
 #[test]
 fn open_existing_file() {
     let nbd_process = run_nbd_server("127.0.0.2");
-    let mut disk = attach_nbd_disk(nbd_process.get_url()).unwrap();
+    let mut disk =
+        attach_nbd_disk(nbd_process.get_url(), None, Appliance::default(), false).unwrap();
     let partitions = disk.list_partitions().unwrap();
     let boot = partitions.get(0).unwrap();
     let root = partitions.get(1).unwrap();
@@ -215,10 +547,48 @@ fn open_existing_file() {
     assert_eq!(opened.unwrap().get_size().unwrap(), 4194304);
 }
 
+#[test]
+fn open_existing_file_mtime() {
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let mut disk =
+        attach_nbd_disk(nbd_process.get_url(), None, Appliance::default(), false).unwrap();
+    let partitions = disk.list_partitions().unwrap();
+    let boot = partitions.first().unwrap();
+    let root = partitions.get(1).unwrap();
+    assert!(root.mount_ro("/").is_ok());
+    assert!(boot.mount_ro("/boot").is_ok());
+    let mut opened = disk.open("/boot/aligned.file").unwrap();
+    assert!(opened.mtime().unwrap() > 0);
+}
+
+#[test]
+fn digest_of_existing_file() {
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let mut disk =
+        attach_nbd_disk(nbd_process.get_url(), None, Appliance::default(), false).unwrap();
+    let partitions = disk.list_partitions().unwrap();
+    let boot = partitions.first().unwrap();
+    let root = partitions.get(1).unwrap();
+    assert!(root.mount_ro("/").is_ok());
+    assert!(boot.mount_ro("/boot").is_ok());
+    let mut opened = disk.open("/boot/nonaligned.file").unwrap();
+    let expected_data = make_payload(opened.get_size().unwrap());
+    let mut buffer = [0u8; 1468];
+    let digest = digest_of(&mut opened, Algorithm::Sha256, &mut buffer).unwrap();
+    let expected_digest: String = Sha256::digest(&expected_data)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    assert_eq!(digest, expected_digest);
+    let digest = digest_of(&mut opened, Algorithm::Crc32, &mut buffer).unwrap();
+    assert_eq!(digest, format!("{:08x}", crc32fast::hash(&expected_data)));
+}
+
 #[test]
 fn open_non_existing_file() {
     let nbd_process = run_nbd_server("127.0.0.2");
-    let mut disk = attach_nbd_disk(nbd_process.get_url()).unwrap();
+    let mut disk =
+        attach_nbd_disk(nbd_process.get_url(), None, Appliance::default(), false).unwrap();
     let partitions = disk.list_partitions().unwrap();
     let root = partitions.get(1).unwrap();
     assert!(root.mount_ro("/").is_ok());
@@ -227,10 +597,99 @@ fn open_non_existing_file() {
     assert_eq!(result.err().unwrap().kind(), ErrorKind::NotFound);
 }
 
+#[test]
+fn open_unreadable_file() {
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let mut disk =
+        attach_nbd_disk(nbd_process.get_url(), None, Appliance::default(), false).unwrap();
+    let partitions = disk.list_partitions().unwrap();
+    let root = partitions.get(1).unwrap();
+    assert!(root.mount_ro("/").is_ok());
+    assert!(
+        disk.list_dir("/")
+            .unwrap()
+            .contains(&"unreadable.file".to_string())
+    );
+    let error = disk.open("/unreadable.file").unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn permission_denied_maps_to_access_violation() {
+    let guestfs_error =
+        GuestFSError::Generic(String::from("stat: /unreadable.file: Permission denied"));
+    assert_eq!(
+        open_error(guestfs_error).kind(),
+        ErrorKind::PermissionDenied
+    );
+    let guestfs_error = GuestFSError::Generic(String::from(
+        "stat: /missing.file: No such file or directory",
+    ));
+    assert_eq!(open_error(guestfs_error).kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn distinct_open_failures_keep_their_kind() {
+    let guestfs_error = GuestFSError::Generic(String::from("open: /huge.file: File too large"));
+    assert_eq!(open_error(guestfs_error).kind(), ErrorKind::FileTooLarge);
+    let guestfs_error =
+        GuestFSError::Generic(String::from("read: /slow.file: connection timed out"));
+    assert_eq!(open_error(guestfs_error).kind(), ErrorKind::TimedOut);
+    // The appliance is built in the local cache directory, which may run out of space.
+    let guestfs_error = GuestFSError::Generic(String::from(
+        "launch: /var/tmp/.guestfs-0: No space left on device",
+    ));
+    assert_eq!(open_error(guestfs_error).kind(), ErrorKind::StorageFull);
+}
+
+#[test]
+fn open_file_on_unmounted_disk() {
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let disk = attach_nbd_disk(nbd_process.get_url(), None, Appliance::default(), false).unwrap();
+    let result = disk.open("/boot/aligned.file");
+    assert_eq!(result.err().unwrap().kind(), ErrorKind::NotADirectory);
+}
+
+#[test]
+fn open_file_in_misconfigured_mountpoint() {
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let mut disk =
+        attach_nbd_disk(nbd_process.get_url(), None, Appliance::default(), false).unwrap();
+    let partitions = disk.list_partitions().unwrap();
+    let root = partitions.get(1).unwrap();
+    let boot = partitions.first().unwrap();
+    assert!(root.mount_ro("/").is_ok());
+    assert!(boot.mount_ro("/boot").is_ok());
+    let chroot = RemoteRoot::new(disk, "/boot/aligned.file");
+    let result = chroot.open("nonaligned.file");
+    assert_eq!(result.err().unwrap().kind(), ErrorKind::NotADirectory);
+}
+#[test]
+fn list_boot_directory() {
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let mut disk =
+        attach_nbd_disk(nbd_process.get_url(), None, Appliance::default(), false).unwrap();
+    let partitions = disk.list_partitions().unwrap();
+    let boot = partitions.first().unwrap();
+    let root = partitions.get(1).unwrap();
+    assert!(root.mount_ro("/").is_ok());
+    assert!(boot.mount_ro("/boot").is_ok());
+    let names = disk.list_dir("/boot").unwrap();
+    for known in ["aligned.file", "nonaligned.file"] {
+        assert!(names.iter().any(|name| name == known), "{names:?}");
+    }
+    let missing = disk.list_dir("/missing").unwrap_err();
+    assert_eq!(missing.kind(), ErrorKind::NotFound);
+    let root = RemoteRoot::new(disk, "/boot");
+    assert_eq!(root.list_dir("").unwrap(), names);
+    assert_eq!(root.list_dir("/").unwrap(), names);
+}
+
 #[test]
 fn read_existing_aligned_file() {
     let nbd_process = run_nbd_server("127.0.0.2");
-    let mut disk = attach_nbd_disk(nbd_process.get_url()).unwrap();
+    let mut disk =
+        attach_nbd_disk(nbd_process.get_url(), None, Appliance::default(), false).unwrap();
     let partitions = disk.list_partitions().unwrap();
     let root = partitions.get(1).unwrap();
     let boot = partitions.get(0).unwrap();
@@ -247,7 +706,8 @@ fn read_existing_aligned_file() {
 #[test]
 fn read_existing_nonaligned_file() {
     let nbd_process = run_nbd_server("127.0.0.2");
-    let mut disk = attach_nbd_disk(nbd_process.get_url()).unwrap();
+    let mut disk =
+        attach_nbd_disk(nbd_process.get_url(), None, Appliance::default(), false).unwrap();
     let partitions = disk.list_partitions().unwrap();
     let root = partitions.get(1).unwrap();
     let boot = partitions.get(0).unwrap();
@@ -304,3 +764,273 @@ fn connect_from_config() {
     let running_disk = nbd_config.connect();
     assert!(running_disk.is_ok());
 }
+
+#[test]
+fn write_to_writable_export() {
+    let copy_dir = mk_tmp(write_to_writable_export);
+    let nbd_process = run_writable_nbd_server("127.0.0.2", &copy_dir);
+    let config = json!({
+        "url": nbd_process.get_url(),
+        "mounts": [
+            {
+                "partition": 2,
+                "mountpoint": "/",
+            },
+            {
+                "partition": 1,
+                "mountpoint": "/boot",
+            }
+        ],
+        "tftp_root": "/boot",
+        "writable": true,
+    });
+    let remote_root = NBDConfig::from_json(&config).unwrap().connect().unwrap();
+    let content = make_payload(1500);
+    remote_root.create("first-boot.artifact", &content).unwrap();
+    let mut opened = remote_root.open("first-boot.artifact").unwrap();
+    assert_eq!(read_file(&mut opened), content);
+}
+
+#[test]
+fn read_only_export_refuses_write() {
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let disk = attach_nbd_disk(nbd_process.get_url(), None, Appliance::default(), false).unwrap();
+    let error = disk
+        .create("/boot/first-boot.artifact", b"artifact")
+        .unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::ReadOnlyFilesystem);
+}
+
+#[test]
+fn validate_configs_report() {
+    let tftp_root = mk_tmp(validate_configs_report);
+    let valid_config = json!({
+        "url": "nbd://127.0.0.1:1000/arbitrary",
+        "mounts": [
+            {
+                "partition": 1,
+                "mountpoint": "/",
+            }
+        ],
+        "tftp_root": "/boot",
+    });
+    fs::write(tftp_root.join("127.0.0.11.nbd"), valid_config.to_string()).unwrap();
+    fs::write(tftp_root.join("127.0.0.12.nbd"), "{\"url\": ").unwrap();
+    let wrong_scheme_config = json!({
+        "url": "http://127.0.0.1:1000/arbitrary",
+        "mounts": [],
+        "tftp_root": "/boot",
+    });
+    fs::write(
+        tftp_root.join("127.0.0.13.nbd"),
+        wrong_scheme_config.to_string(),
+    )
+    .unwrap();
+    fs::write(tftp_root.join("README"), "Not a config").unwrap();
+    let peer_directory = tftp_root.join("127.0.0.14");
+    fs::create_dir(&peer_directory).unwrap();
+    fs::write(peer_directory.join("boot.nbd"), valid_config.to_string()).unwrap();
+    fs::write(peer_directory.join("extra.json"), "[]").unwrap();
+    fs::write(peer_directory.join("payload.bin"), "Not a config").unwrap();
+    let (checked, invalid) = validate_configs(&tftp_root);
+    assert_eq!(checked, 5);
+    let invalid_names: Vec<_> = invalid
+        .iter()
+        .map(|(path, _reason)| path.file_name().unwrap().to_str().unwrap())
+        .collect();
+    assert_eq!(
+        invalid_names,
+        vec!["127.0.0.12.nbd", "127.0.0.13.nbd", "extra.json"]
+    );
+    assert!(invalid[1].1.contains("Invalid NBD URL"));
+}
+
+#[test]
+fn prewarmed_peers_found() {
+    let tftp_root = mk_tmp(prewarmed_peers_found);
+    let config = |prewarm: bool| {
+        json!({
+            "url": "nbd://127.0.0.1:1000/arbitrary",
+            "mounts": [],
+            "tftp_root": "/boot",
+            "prewarm": prewarm,
+        })
+        .to_string()
+    };
+    fs::write(tftp_root.join("127.0.0.11.nbd"), config(true)).unwrap();
+    fs::write(tftp_root.join("127.0.0.12.nbd"), config(false)).unwrap();
+    fs::write(tftp_root.join("127.0.0.13.nbd"), "{\"url\": ").unwrap();
+    let peer_directory = tftp_root.join("127.0.0.14");
+    fs::create_dir(&peer_directory).unwrap();
+    fs::write(peer_directory.join("boot.json"), config(true)).unwrap();
+    fs::create_dir(tftp_root.join("127.0.0.15")).unwrap();
+    assert_eq!(
+        prewarmed_peers(&tftp_root),
+        vec![
+            IpAddr::from_str("127.0.0.11").unwrap(),
+            IpAddr::from_str("127.0.0.14").unwrap(),
+        ]
+    );
+    assert!(!is_prewarmed(&tftp_root, "127.0.0.12"));
+    assert!(!is_prewarmed(&tftp_root, "127.0.0.16"));
+}
+
+#[test]
+fn configs_ordered_by_priority() {
+    let tftp_root = mk_tmp(configs_ordered_by_priority);
+    let config = |priority: i32| {
+        json!({
+            "url": "nbd://127.0.0.1:1000/arbitrary",
+            "mounts": [],
+            "tftp_root": "/boot",
+            "priority": priority,
+        })
+        .to_string()
+    };
+    fs::write(tftp_root.join("127.0.0.11.a.nbd"), config(5)).unwrap();
+    fs::write(tftp_root.join("127.0.0.11.b.nbd"), config(1)).unwrap();
+    fs::write(tftp_root.join("127.0.0.11.c.nbd"), config(1)).unwrap();
+    let peer_directory = tftp_root.join("127.0.0.11");
+    fs::create_dir(&peer_directory).unwrap();
+    fs::write(peer_directory.join("boot.nbd"), config(2)).unwrap();
+    fs::write(peer_directory.join("extra.json"), config(-1)).unwrap();
+    let names = |files: Vec<PathBuf>| -> Vec<String> {
+        files
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
+            .collect()
+    };
+    assert_eq!(
+        names(top_level_configs(&tftp_root, "127.0.0.11")),
+        vec!["127.0.0.11.b.nbd", "127.0.0.11.c.nbd", "127.0.0.11.a.nbd"]
+    );
+    assert_eq!(
+        names(peer_directory_configs(&tftp_root, "127.0.0.11")),
+        vec!["extra.json", "boot.nbd"]
+    );
+}
+
+#[test]
+fn top_level_configs_match_whole_ip() {
+    let tftp_root = mk_tmp(top_level_configs_match_whole_ip);
+    for name in [
+        "127.0.0.1.nbd",
+        "127.0.0.1.boot.nbd",
+        "127.0.0.10.nbd",
+        "127.0.0.100",
+        // Nothing but a '.' may follow the address.
+        "127.0.0.1-foo.nbd",
+        "127.0.0.1_boot.json",
+    ] {
+        fs::write(tftp_root.join(name), "{}").unwrap();
+    }
+    let mut names: Vec<_> = top_level_configs(&tftp_root, "127.0.0.1")
+        .into_iter()
+        .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["127.0.0.1.boot.nbd", "127.0.0.1.nbd"]);
+    assert!(!has_configs(&tftp_root, "127.0.0.11"));
+}
+
+#[test]
+fn config_peer_found() {
+    let tftp_root = Path::new("/srv/tftp");
+    let peer = |config: &str| config_peer(tftp_root, &tftp_root.join(config));
+    assert_eq!(peer("10.0.0.1.nbd"), Some("10.0.0.1".parse().unwrap()));
+    assert_eq!(
+        peer("10.0.0.1.boot.json"),
+        Some("10.0.0.1".parse().unwrap())
+    );
+    assert_eq!(peer("fe80::1.nbd"), Some("fe80::1".parse().unwrap()));
+    assert_eq!(peer("10.0.0.2/disk.nbd"), Some("10.0.0.2".parse().unwrap()));
+    assert_eq!(peer("default/disk.nbd"), None);
+    assert_eq!(peer("10.0.0.2/sub/disk.nbd"), None);
+    assert_eq!(config_peer(tftp_root, Path::new("/etc/10.0.0.1.nbd")), None);
+}
+
+#[test]
+fn shared_writable_configs_refused() {
+    let tftp_root = mk_tmp(shared_writable_configs_refused);
+    let config = json!({
+        "url": "nbd://127.0.0.1:1000/arbitrary",
+        "mounts": [],
+        "tftp_root": "/boot",
+        "writable": true,
+    })
+    .to_string();
+    fs::write(tftp_root.join("127.0.0.11.nbd"), &config).unwrap();
+    fs::write(tftp_root.join("127.0.0.12.nbd"), &config).unwrap();
+    fs::hard_link(
+        tftp_root.join("127.0.0.12.nbd"),
+        tftp_root.join("127.0.0.13.nbd"),
+    )
+    .unwrap();
+    let peer_directory = tftp_root.join("127.0.0.14");
+    fs::create_dir(&peer_directory).unwrap();
+    fs::write(peer_directory.join("disk.nbd"), &config).unwrap();
+    std::os::unix::fs::symlink(&peer_directory, tftp_root.join("127.0.0.15")).unwrap();
+    let peer_directory = tftp_root.join("127.0.0.16");
+    fs::create_dir(&peer_directory).unwrap();
+    std::os::unix::fs::symlink(
+        tftp_root.join("127.0.0.11.nbd"),
+        peer_directory.join("disk.nbd"),
+    )
+    .unwrap();
+    assert!(check_exclusive(&tftp_root.join("127.0.0.11.nbd"), &tftp_root).is_ok());
+    let (_checked, invalid) = validate_configs(&tftp_root);
+    let invalid_paths: Vec<_> = invalid
+        .iter()
+        .map(|(path, _reason)| path.strip_prefix(&tftp_root).unwrap().to_str().unwrap())
+        .collect();
+    assert_eq!(
+        invalid_paths,
+        vec![
+            "127.0.0.12.nbd",
+            "127.0.0.13.nbd",
+            "127.0.0.14/disk.nbd",
+            "127.0.0.15/disk.nbd",
+            "127.0.0.16/disk.nbd",
+        ]
+    );
+    assert!(invalid[2].1.contains("shared with"), "{}", invalid[2].1);
+}
+
+#[test]
+fn reject_unknown_codec() {
+    let config = json!({
+        "url": "nbd://127.0.0.1:1000/arbitrary",
+        "mounts": [],
+        "tftp_root": "/boot",
+        "decompress": {"vmlinuz": "zstd"},
+    });
+    assert!(matches!(
+        NBDConfig::parse(&config),
+        Err(VirtualRootError::ConfigError(_))
+    ));
+}
+
+#[test]
+fn read_decompressed_file() {
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let config = json!({
+        "url": nbd_process.get_url(),
+        "mounts": [
+            {
+                "partition": 2,
+                "mountpoint": "/",
+            },
+                {
+                "partition": 1,
+                "mountpoint": "/boot",
+            }
+        ],
+        "tftp_root": "/boot",
+        "decompress": {"compressed.file.gz": "gzip"},
+    });
+    let root = NBDConfig::from_json(&config).unwrap().connect().unwrap();
+    let mut opened = root.open("compressed.file.gz").unwrap();
+    let expected_data = make_payload(65537);
+    assert_eq!(opened.get_size().unwrap(), expected_data.len());
+    assert_eq!(read_file(&mut opened), expected_data);
+}
@@ -0,0 +1,148 @@
+use crate::fs::OpenedFile;
+use std::collections::VecDeque;
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::task::{Context, Poll};
+
+#[cfg(test)]
+mod tests;
+
+// Translates line endings on the fly: a bare LF becomes CR LF, a bare CR becomes CR NUL (RFC 1350).
+// CR LF pairs already in the file are passed as is.
+#[derive(Default)]
+struct Translator {
+    after_cr: bool,
+}
+
+impl Translator {
+    fn push(&mut self, byte: u8, output: &mut impl FnMut(u8)) {
+        if byte == b'\n' {
+            if !self.after_cr {
+                output(b'\r');
+            }
+            output(b'\n');
+            self.after_cr = false;
+            return;
+        }
+        if self.after_cr {
+            output(0);
+        }
+        output(byte);
+        self.after_cr = byte == b'\r';
+    }
+
+    fn finish(&mut self, output: &mut impl FnMut(u8)) {
+        if self.after_cr {
+            output(0);
+            self.after_cr = false;
+        }
+    }
+}
+
+pub(super) struct Netascii<O: OpenedFile> {
+    inner: O,
+    translator: Translator,
+    raw: Vec<u8>,
+    translated: VecDeque<u8>,
+    finished: bool,
+    translated_size: Option<usize>,
+}
+
+impl<O: OpenedFile> Netascii<O> {
+    pub(super) fn new(inner: O) -> Self {
+        Self {
+            inner,
+            translator: Translator::default(),
+            raw: Vec::new(),
+            translated: VecDeque::new(),
+            finished: false,
+            translated_size: None,
+        }
+    }
+
+    // Passes the whole file through the translator, the file is rewound afterwards.
+    fn count_translated(&mut self) -> io::Result<usize> {
+        self.inner.rewind()?;
+        let mut translator = Translator::default();
+        let mut chunk = vec![0u8; 64 * 1024];
+        let mut size: usize = 0;
+        loop {
+            let read_size = self.inner.read_to(&mut chunk)?;
+            if read_size == 0 {
+                break;
+            }
+            for &byte in &chunk[..read_size] {
+                translator.push(byte, &mut |_| size += 1);
+            }
+        }
+        translator.finish(&mut |_| size += 1);
+        self.rewind()?;
+        Ok(size)
+    }
+}
+
+impl<O: OpenedFile> OpenedFile for Netascii<O> {
+    fn read_to(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.translated.len() < buffer.len() && !self.finished {
+            // The translated data is at least as long as the raw data.
+            self.raw.resize(buffer.len() - self.translated.len(), 0);
+            let read_size = self.inner.read_to(&mut self.raw)?;
+            let translated = &mut self.translated;
+            if read_size == 0 {
+                self.translator
+                    .finish(&mut |byte| translated.push_back(byte));
+                self.finished = true;
+            }
+            for &byte in &self.raw[..read_size] {
+                self.translator
+                    .push(byte, &mut |byte| translated.push_back(byte));
+            }
+        }
+        let size = buffer.len().min(self.translated.len());
+        for (slot, byte) in buffer.iter_mut().zip(self.translated.drain(..size)) {
+            *slot = byte;
+        }
+        Ok(size)
+    }
+
+    fn get_size(&mut self) -> io::Result<usize> {
+        if let Some(size) = self.translated_size {
+            return Ok(size);
+        }
+        let size = self.count_translated()?;
+        self.translated_size = Some(size);
+        Ok(size)
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.inner.rewind()?;
+        self.translator = Translator::default();
+        self.translated.clear();
+        self.finished = false;
+        Ok(())
+    }
+
+    fn mtime(&mut self) -> io::Result<u64> {
+        self.inner.mtime()
+    }
+
+    fn poll_open(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.poll_open(cx)
+    }
+
+    fn poll_fill(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<io::Result<()>> {
+        self.inner.poll_fill(cx, wanted)
+    }
+}
+
+impl<O: OpenedFile> Display for Netascii<O> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<Netascii {}>", self.inner)
+    }
+}
+
+impl<O: OpenedFile> Debug for Netascii<O> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<Netascii {:?}>", self.inner)
+    }
+}
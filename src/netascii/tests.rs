@@ -0,0 +1,57 @@
+use super::*;
+use crate::fs::Root;
+use crate::local_fs::{LocalOpenedFile, LocalRoot};
+use crate::options::TSize;
+use crate::tests_common::{mk_tmp, read_file};
+use std::fs;
+use std::path::PathBuf;
+
+fn open_netascii(directory: PathBuf, content: &[u8]) -> Netascii<LocalOpenedFile> {
+    fs::write(directory.join("text.file"), content).unwrap();
+    Netascii::new(LocalRoot::new(directory).open("text.file").unwrap())
+}
+
+#[test]
+fn translate_line_endings() {
+    let mut opened = open_netascii(
+        mk_tmp(translate_line_endings),
+        b"bare lf\ncrlf\r\nbare cr\rend\r",
+    );
+    assert_eq!(
+        read_file(&mut opened),
+        b"bare lf\r\ncrlf\r\nbare cr\r\0end\r\0"
+    );
+}
+
+#[test]
+fn tsize_counts_bare_newlines() {
+    let lines = 1000;
+    let content = "line\n".repeat(lines);
+    let mut opened = open_netascii(mk_tmp(tsize_counts_bare_newlines), content.as_bytes());
+    let tsize = TSize::obtain(&mut opened).unwrap();
+    assert_eq!(
+        tsize.as_key_pair(),
+        (String::from("tsize"), (content.len() + lines).to_string())
+    );
+    // Counting the size must not consume the file.
+    assert_eq!(read_file(&mut opened), "line\r\n".repeat(lines).as_bytes());
+}
+
+#[test]
+fn translate_across_reads() {
+    let content = b"\r\r\n\n\r".repeat(100);
+    let mut opened = open_netascii(mk_tmp(translate_across_reads), &content);
+    let expected_size = opened.get_size().unwrap();
+    // A CR at the end of a read must still be paired with what follows it.
+    let mut translated = Vec::new();
+    let mut chunk = [0u8; 3];
+    loop {
+        let read_size = opened.read_to(&mut chunk).unwrap();
+        translated.extend_from_slice(&chunk[..read_size]);
+        if read_size < chunk.len() {
+            break;
+        }
+    }
+    assert_eq!(translated, b"\r\0\r\n\r\n\r\0".repeat(100)[..]);
+    assert_eq!(translated.len(), expected_size);
+}
@@ -0,0 +1,140 @@
+use crate::fs::{AsyncOpenedFile, OpenedFile};
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::task::JoinHandle;
+
+#[cfg(test)]
+mod tests;
+
+// Every trip to the blocking pool reads at least this much ahead.
+const READ_AHEAD: usize = 64 * 1024;
+
+type Staged<O> = (O, Vec<u8>, io::Result<bool>);
+type ReadAt<O> = (O, Vec<u8>, io::Result<usize>);
+
+/// Reads a file with blocking reads on the blocking thread pool, so the task serving it never stalls.
+pub(super) struct Offloaded<O: OpenedFile + Send + 'static> {
+    // Away on the blocking pool while a read is pending.
+    file: Option<O>,
+    pending: Option<JoinHandle<Staged<O>>>,
+    pending_at: Option<JoinHandle<ReadAt<O>>>,
+    staged: Vec<u8>,
+    consumed: usize,
+    eof: bool,
+    display: String,
+}
+
+impl<O: OpenedFile + Send + 'static> Offloaded<O> {
+    pub(super) fn new(file: O) -> Self {
+        let display = file.to_string();
+        Self {
+            file: Some(file),
+            pending: None,
+            pending_at: None,
+            staged: Vec::new(),
+            consumed: 0,
+            eof: false,
+            display,
+        }
+    }
+
+    fn poll_stage(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<io::Result<()>> {
+        if self.pending.is_none() {
+            let Some(file) = self.file.as_mut() else {
+                return Poll::Ready(Err(io::Error::other("File is lost by a failed read")));
+            };
+            let target = wanted.max(READ_AHEAD);
+            // Slow backends get the data ready without occupying a blocking thread.
+            ready!(file.poll_fill(cx, target))?;
+            let mut file = self.file.take().unwrap();
+            let mut staged = std::mem::take(&mut self.staged);
+            staged.drain(..self.consumed);
+            self.consumed = 0;
+            self.pending = Some(tokio::task::spawn_blocking(move || {
+                let result = stage(&mut file, &mut staged, target);
+                (file, staged, result)
+            }));
+        }
+        let joined = ready!(Pin::new(self.pending.as_mut().unwrap()).poll(cx));
+        self.pending = None;
+        let (file, staged, result) = joined.map_err(io::Error::other)?;
+        self.file = Some(file);
+        self.staged = staged;
+        self.eof = result?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Reads until `target` bytes are staged, returns whether the end of the file is reached.
+fn stage<O: OpenedFile>(file: &mut O, staged: &mut Vec<u8>, target: usize) -> io::Result<bool> {
+    while staged.len() < target {
+        let start = staged.len();
+        staged.resize(target, 0);
+        let read_size = match file.read_to(&mut staged[start..]) {
+            Ok(read_size) => read_size,
+            Err(error) => {
+                staged.truncate(start);
+                return Err(error);
+            }
+        };
+        staged.truncate(start + read_size);
+        if read_size == 0 {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+impl<O: OpenedFile + Send + 'static> AsyncOpenedFile for Offloaded<O> {
+    fn poll_read(&mut self, cx: &mut Context<'_>, buffer: &mut [u8]) -> Poll<io::Result<usize>> {
+        if self.staged.len() - self.consumed < buffer.len() && !self.eof {
+            ready!(self.poll_stage(cx, buffer.len()))?;
+        }
+        let available = &self.staged[self.consumed..];
+        let size = available.len().min(buffer.len());
+        buffer[..size].copy_from_slice(&available[..size]);
+        self.consumed += size;
+        Poll::Ready(Ok(size))
+    }
+
+    // The staged data is left for `poll_read` to go on with.
+    fn poll_read_at(
+        &mut self,
+        cx: &mut Context<'_>,
+        offset: usize,
+        buffer: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.pending_at.is_none() {
+            let Some(mut file) = self.file.take() else {
+                return Poll::Ready(Err(io::Error::other("File is lost by a failed read")));
+            };
+            let size = buffer.len();
+            self.pending_at = Some(tokio::task::spawn_blocking(move || {
+                let mut read = vec![0; size];
+                let result = file.read_at(offset, &mut read);
+                (file, read, result)
+            }));
+        }
+        let joined = ready!(Pin::new(self.pending_at.as_mut().unwrap()).poll(cx));
+        self.pending_at = None;
+        let (file, read, result) = joined.map_err(io::Error::other)?;
+        self.file = Some(file);
+        let size = result?;
+        buffer[..size].copy_from_slice(&read[..size]);
+        Poll::Ready(Ok(size))
+    }
+}
+
+impl<O: OpenedFile + Send + 'static> Display for Offloaded<O> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.display)
+    }
+}
+
+impl<O: OpenedFile + Send + 'static> Debug for Offloaded<O> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<Offloaded {}>", self.display)
+    }
+}
@@ -0,0 +1,104 @@
+use super::*;
+use crate::fs::Root;
+use crate::local_fs::LocalRoot;
+use crate::tests_common::{make_payload, mk_tmp};
+use std::fmt;
+use std::fs;
+use std::future::poll_fn;
+
+async fn read_all<O: AsyncOpenedFile>(opened: &mut O, chunk_size: usize) -> Vec<u8> {
+    let mut content = Vec::new();
+    let mut chunk = vec![0u8; chunk_size];
+    loop {
+        let read_size = poll_fn(|cx| opened.poll_read(cx, &mut chunk))
+            .await
+            .unwrap();
+        content.extend_from_slice(&chunk[..read_size]);
+        if read_size < chunk_size {
+            return content;
+        }
+    }
+}
+
+// Returns at most a few bytes per read and fails once the content is over, if asked to.
+struct ChoppyFile {
+    content: Vec<u8>,
+    offset: usize,
+    fail_at_end: bool,
+}
+
+impl ChoppyFile {
+    fn new(content: Vec<u8>, fail_at_end: bool) -> Self {
+        Self {
+            content,
+            offset: 0,
+            fail_at_end,
+        }
+    }
+}
+
+impl fmt::Display for ChoppyFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ChoppyFile [{}]", self.offset)
+    }
+}
+
+impl fmt::Debug for ChoppyFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ChoppyFile [{}]", self.offset)
+    }
+}
+
+impl OpenedFile for ChoppyFile {
+    fn read_to(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let remaining = &self.content[self.offset..];
+        if remaining.is_empty() && self.fail_at_end {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let size = remaining.len().min(buffer.len()).min(7);
+        buffer[..size].copy_from_slice(&remaining[..size]);
+        self.offset += size;
+        Ok(size)
+    }
+
+    fn get_size(&mut self) -> io::Result<usize> {
+        Ok(self.content.len())
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.offset = 0;
+        Ok(())
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn read_local_file() {
+    let tftp_root = mk_tmp(read_local_file);
+    // Spans several trips to the blocking pool.
+    let content = make_payload(READ_AHEAD * 3 + 100);
+    fs::write(tftp_root.join("payload.file"), &content).unwrap();
+    let opened = LocalRoot::new(tftp_root).open("payload.file").unwrap();
+    let mut offloaded = Offloaded::new(opened);
+    assert_eq!(read_all(&mut offloaded, 1428).await, content);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn short_reads_fill_buffers() {
+    let content = make_payload(10_000);
+    let mut offloaded = Offloaded::new(ChoppyFile::new(content.clone(), false));
+    let mut chunk = [0u8; 512];
+    // A short read would be taken for the end of the file.
+    let read_size = poll_fn(|cx| offloaded.poll_read(cx, &mut chunk))
+        .await
+        .unwrap();
+    assert_eq!(read_size, chunk.len());
+    assert_eq!(chunk[..], content[..512]);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn read_error_reported() {
+    let mut offloaded = Offloaded::new(ChoppyFile::new(make_payload(100), true));
+    let mut chunk = [0u8; 512];
+    let result = poll_fn(|cx| offloaded.poll_read(cx, &mut chunk)).await;
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+}
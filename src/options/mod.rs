@@ -1,6 +1,10 @@
+use crate::checksum::{Algorithm, digest_of};
 use crate::fs::OpenedFile;
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::hash::{BuildHasher, RandomState};
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
 use std::time::Duration;
 use std::{fmt, io};
 use tokio::time::timeout;
@@ -16,15 +20,33 @@ static BLKSIZE: &str = "blksize";
 
 const WINDOW_SIZE: &str = "windowsize";
 
-const BLOCK_SIZE_BOTTOM_CAP: usize = 8;
-const BLOCK_SIZE_UPPER_CAP: usize = u16::MAX as usize;
+const MTIME: &str = "mtime";
+
+const HASH: &str = "hash";
+
+const HASH_READ_SIZE: usize = 64 * 1024;
+
+const CONNECT_TIMEOUT: &str = "connecttimeout";
+
+pub(super) const BLOCK_SIZE_BOTTOM_CAP: usize = 8;
+pub(super) const BLOCK_SIZE_UPPER_CAP: usize = u16::MAX as usize;
 
 const ACK_TIMEOUT_BOTTOM_CAP: usize = 1;
 const ACK_TIMEOUT_UPPER_CAP: usize = 255;
+pub(super) const MAX_RETRANSMIT_JITTER: u8 = 50;
+
+const CONNECT_TIMEOUT_BOTTOM_CAP: usize = 1;
+const CONNECT_TIMEOUT_UPPER_CAP: usize = 24 * 60 * 60;
 
 const WINDOW_SIZE_BOTTOM_CAP: usize = 1;
 const WINDOW_SIZE_UPPER_CAP: usize = u16::MAX as usize;
 
+const DATA_HEADER_SIZE: usize = 2 * size_of::<u16>();
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const UDP_HEADER_SIZE: usize = 8;
+pub(super) const DEFAULT_SESSION_BUFFER_LIMIT: usize = 64 * 1024 * 1024;
+
 #[derive(Clone)]
 pub(super) struct Blksize {
     block_size: usize,
@@ -46,6 +68,19 @@ impl Blksize {
         None
     }
 
+    // Unlike the window size, a too small block size is raised as well, so the server may acknowledge
+    // a value larger than requested.
+    pub(super) fn clamp(self, range: &RangeInclusive<usize>) -> Self {
+        let block_size = self.block_size.clamp(*range.start(), *range.end());
+        if block_size != self.block_size {
+            eprintln!(
+                "Requested block size {} is clamped to {block_size}",
+                self.block_size
+            );
+        }
+        Self { block_size }
+    }
+
     pub(super) fn as_key_pair(&self) -> (String, String) {
         (String::from(BLKSIZE), self.block_size.to_string())
     }
@@ -64,11 +99,15 @@ impl Default for Blksize {
 #[derive(Clone)]
 pub(super) struct AckTimeout {
     timeout: usize,
+    jitter_percent: u8,
 }
 
 impl Default for AckTimeout {
     fn default() -> Self {
-        Self { timeout: 5 }
+        Self {
+            timeout: 5,
+            jitter_percent: 0,
+        }
     }
 }
 
@@ -77,7 +116,25 @@ impl AckTimeout {
         &self,
         fut: F,
     ) -> Result<T, tokio::time::error::Elapsed> {
-        timeout(Duration::from_secs(self.timeout as u64), fut).await
+        timeout(self.duration(), fut).await
+    }
+
+    // Every wait lasts the timeout randomly stretched or shrunk by up to this share, so that clients
+    // booted together don't retransmit in lockstep.
+    pub(super) fn with_jitter(mut self, percent: u8) -> Self {
+        self.jitter_percent = percent.min(MAX_RETRANSMIT_JITTER);
+        self
+    }
+
+    fn duration(&self) -> Duration {
+        let base = Duration::from_secs(self.timeout as u64);
+        if self.jitter_percent == 0 {
+            return base;
+        }
+        // A fresh RandomState is randomly keyed, which is enough to spread the retries.
+        let unit = RandomState::new().hash_one(0u8) as f64 / u64::MAX as f64;
+        let spread = self.jitter_percent as f64 / 100.0;
+        base.mul_f64(1.0 - spread + 2.0 * spread * unit)
     }
 
     pub(super) fn find_in(options: &HashMap<String, String>) -> Option<Self> {
@@ -85,7 +142,10 @@ impl AckTimeout {
             && let Ok(timeout) = timeout_string.parse::<usize>()
         {
             if (ACK_TIMEOUT_BOTTOM_CAP..=ACK_TIMEOUT_UPPER_CAP).contains(&timeout) {
-                return Some(Self { timeout });
+                return Some(Self {
+                    timeout,
+                    jitter_percent: 0,
+                });
             } else {
                 eprintln!(
                     "Requested timeout {timeout} doesn't fit in range {ACK_TIMEOUT_BOTTOM_CAP} .. ={ACK_TIMEOUT_UPPER_CAP}"
@@ -98,6 +158,11 @@ impl AckTimeout {
     pub(super) fn as_key_pair(&self) -> (String, String) {
         (String::from(TIMEOUT), self.timeout.to_string())
     }
+
+    // The negotiated seconds, before any jitter.
+    pub(super) fn seconds(&self) -> usize {
+        self.timeout
+    }
 }
 
 impl Display for AckTimeout {
@@ -115,14 +180,131 @@ impl TSize {
         options.contains_key(TSIZE)
     }
 
+    // Zero on reads, where the client asks for the size. Only a write announces the size of the file to come.
+    pub(super) fn parse(options: &HashMap<String, String>) -> Option<usize> {
+        options.get(TSIZE)?.parse().ok()
+    }
+
     pub(super) fn obtain(opened_file: &mut dyn OpenedFile) -> io::Result<Self> {
         let file_size = opened_file.get_size()?;
         Ok(Self { file_size })
     }
 
+    // Sizing a transformed file passes it whole through the codec, so it is done on the blocking pool, like
+    // hashing, and the file handed back along with its size.
+    pub(super) async fn obtain_offloaded<O: OpenedFile + Send + 'static>(
+        mut opened_file: O,
+    ) -> io::Result<(O, io::Result<Self>)> {
+        let sizing = tokio::task::spawn_blocking(move || {
+            let tsize = Self::obtain(&mut opened_file);
+            (opened_file, tsize)
+        });
+        sizing.await.map_err(io::Error::other)
+    }
+
     pub(super) fn as_key_pair(&self) -> (String, String) {
         (String::from(TSIZE), self.file_size.to_string())
     }
+
+    pub(super) fn file_size(&self) -> usize {
+        self.file_size
+    }
+}
+
+// Unlike the per-block timeout, bounds the duration of the whole transfer.
+pub(super) struct ConnectTimeout {
+    timeout: usize,
+}
+
+impl ConnectTimeout {
+    pub(super) fn find_in(options: &HashMap<String, String>) -> Option<Self> {
+        if let Some(timeout_string) = options.get(CONNECT_TIMEOUT)
+            && let Ok(timeout) = timeout_string.parse::<usize>()
+        {
+            if (CONNECT_TIMEOUT_BOTTOM_CAP..=CONNECT_TIMEOUT_UPPER_CAP).contains(&timeout) {
+                return Some(Self { timeout });
+            } else {
+                eprintln!(
+                    "Requested connect timeout {timeout} doesn't fit in range {CONNECT_TIMEOUT_BOTTOM_CAP} .. ={CONNECT_TIMEOUT_UPPER_CAP}"
+                );
+            }
+        }
+        None
+    }
+
+    pub(super) async fn bound<T, F: Future<Output = T>>(
+        &self,
+        fut: F,
+    ) -> Result<T, tokio::time::error::Elapsed> {
+        timeout(Duration::from_secs(self.timeout as u64), fut).await
+    }
+
+    pub(super) fn as_key_pair(&self) -> (String, String) {
+        (String::from(CONNECT_TIMEOUT), self.timeout.to_string())
+    }
+}
+
+impl Display for ConnectTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} s", self.timeout)
+    }
+}
+
+pub(super) struct Mtime {
+    seconds_since_epoch: u64,
+}
+
+impl Mtime {
+    // Like tsize, a client asks for the value by sending 0.
+    pub(super) fn is_requested(options: &HashMap<String, String>) -> bool {
+        options.get(MTIME).is_some_and(|value| value == "0")
+    }
+
+    pub(super) fn obtain(opened_file: &mut dyn OpenedFile) -> io::Result<Self> {
+        let seconds_since_epoch = opened_file.mtime()?;
+        Ok(Self {
+            seconds_since_epoch,
+        })
+    }
+
+    pub(super) fn as_key_pair(&self) -> (String, String) {
+        (String::from(MTIME), self.seconds_since_epoch.to_string())
+    }
+}
+
+pub(super) struct FileHash {
+    digest: String,
+}
+
+impl FileHash {
+    pub(super) fn find_in(options: &HashMap<String, String>) -> Option<Algorithm> {
+        if let Some(algorithm) = options.get(HASH) {
+            match algorithm.parse::<Algorithm>() {
+                Ok(algorithm) => return Some(algorithm),
+                Err(error) => eprintln!("{error}"),
+            }
+        }
+        None
+    }
+
+    // The file is read whole on the blocking pool, so a large image doesn't stall the peer, and handed back
+    // rewound along with its digest. It is only lost if the hashing task panics.
+    pub(super) async fn obtain<O: OpenedFile + Send + 'static>(
+        mut opened_file: O,
+        algorithm: Algorithm,
+    ) -> io::Result<(O, io::Result<Self>)> {
+        let hashing = tokio::task::spawn_blocking(move || {
+            let mut buffer = vec![0u8; HASH_READ_SIZE];
+            let digest = digest_of(&mut opened_file, algorithm, &mut buffer);
+            (opened_file, digest)
+        });
+        let (opened_file, digest) = hashing.await.map_err(io::Error::other)?;
+        Ok((opened_file, digest.map(|digest| Self { digest })))
+    }
+
+    pub(super) fn as_key_pair(&self) -> (String, String) {
+        (String::from(HASH), self.digest.clone())
+    }
 }
 
 pub(super) struct WindowSize(usize);
@@ -152,6 +334,16 @@ impl WindowSize {
     pub(super) fn get_size(&self) -> usize {
         self.0
     }
+
+    pub(super) fn clamp(self, upper_cap: usize) -> Self {
+        if self.0 > upper_cap {
+            eprintln!("Requested window size {} is clamped to {upper_cap}", self.0);
+            Self(upper_cap)
+        } else {
+            self
+        }
+    }
+
     pub(super) fn as_key_pair(&self) -> (String, String) {
         (String::from(WINDOW_SIZE), self.0.to_string())
     }
@@ -162,3 +354,80 @@ impl Default for WindowSize {
         Self(1)
     }
 }
+
+/// Server-side policy bounding the memory a single session may allocate for its send window.
+#[derive(Clone, Debug)]
+pub(super) struct SessionLimits {
+    max_window_size: usize,
+    max_buffer_size: usize,
+    block_size_range: RangeInclusive<usize>,
+    path_mtu: Option<usize>,
+}
+
+impl SessionLimits {
+    pub(super) fn new(max_window_size: usize, max_buffer_size: usize) -> Self {
+        Self {
+            max_window_size: max_window_size.clamp(WINDOW_SIZE_BOTTOM_CAP, WINDOW_SIZE_UPPER_CAP),
+            max_buffer_size,
+            block_size_range: BLOCK_SIZE_BOTTOM_CAP..=BLOCK_SIZE_UPPER_CAP,
+            path_mtu: None,
+        }
+    }
+
+    pub(super) fn with_block_size_range(
+        mut self,
+        min_block_size: usize,
+        max_block_size: usize,
+    ) -> Self {
+        self.block_size_range =
+            min_block_size.max(BLOCK_SIZE_BOTTOM_CAP)..=max_block_size.min(BLOCK_SIZE_UPPER_CAP);
+        self
+    }
+
+    pub(super) fn max_window_size(&self) -> usize {
+        self.max_window_size
+    }
+
+    // A block too large for the path MTU goes out fragmented, and a single fragment lost loses the block.
+    pub(super) fn with_path_mtu(mut self, path_mtu: Option<usize>) -> Self {
+        self.path_mtu = path_mtu;
+        self
+    }
+
+    // The path MTU caps the range below even the minimum, as a fragmented block is worse than a small one.
+    pub(super) fn block_size_range(&self, peer: IpAddr) -> RangeInclusive<usize> {
+        let Some(path_mtu) = self.path_mtu else {
+            return self.block_size_range.clone();
+        };
+        let ip_header_size = match peer.to_canonical() {
+            IpAddr::V4(_) => IPV4_HEADER_SIZE,
+            IpAddr::V6(_) => IPV6_HEADER_SIZE,
+        };
+        let unfragmented = path_mtu
+            .saturating_sub(ip_header_size + UDP_HEADER_SIZE + DATA_HEADER_SIZE)
+            .max(BLOCK_SIZE_BOTTOM_CAP);
+        let max_block_size = (*self.block_size_range.end()).min(unfragmented);
+        (*self.block_size_range.start()).min(max_block_size)..=max_block_size
+    }
+
+    pub(super) fn admits(&self, block_size: &Blksize, window_size: &WindowSize) -> bool {
+        let required = window_size.get_size() * (block_size.get_size() + DATA_HEADER_SIZE);
+        required <= self.max_buffer_size
+    }
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        Self::new(WINDOW_SIZE_UPPER_CAP, DEFAULT_SESSION_BUFFER_LIMIT)
+    }
+}
+
+impl Display for SessionLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[max window size: {}, max session buffer: {}]",
+            self.max_window_size, self.max_buffer_size
+        )
+    }
+}
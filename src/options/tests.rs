@@ -1,4 +1,7 @@
 use super::*;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+const PEER: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
 
 #[test]
 fn find_block_size() {
@@ -19,6 +22,43 @@ fn find_tsize() {
     assert!(TSize::is_requested(&options));
 }
 
+#[test]
+fn parse_tsize_of_read() {
+    let mut options = HashMap::new();
+    assert_eq!(TSize::parse(&options), None);
+    options.insert(TSIZE.to_string(), "0".to_string());
+    assert_eq!(TSize::parse(&options), Some(0));
+    options.insert(TSIZE.to_string(), "garbage".to_string());
+    assert_eq!(TSize::parse(&options), None);
+    assert!(TSize::is_requested(&options));
+}
+
+#[test]
+fn parse_tsize_of_write() {
+    let mut options = HashMap::new();
+    options.insert(TSIZE.to_string(), "12345".to_string());
+    assert_eq!(TSize::parse(&options), Some(12345));
+}
+
+#[test]
+fn find_mtime() {
+    let mut options = HashMap::new();
+    options.insert(MTIME.to_string(), "0".to_string());
+    assert!(Mtime::is_requested(&options));
+    options.insert(MTIME.to_string(), "1700000000".to_string());
+    assert!(!Mtime::is_requested(&options));
+}
+
+#[test]
+fn find_hash() {
+    let mut options = HashMap::new();
+    assert!(FileHash::find_in(&options).is_none());
+    options.insert(HASH.to_string(), "sha256".to_string());
+    assert_eq!(FileHash::find_in(&options), Some(Algorithm::Sha256));
+    options.insert(HASH.to_string(), "md5".to_string());
+    assert!(FileHash::find_in(&options).is_none());
+}
+
 #[test]
 fn find_timeout() {
     let mut options = HashMap::new();
@@ -72,6 +112,18 @@ fn test_window_size() {
     assert!(find_result.is_some());
 }
 
+#[test]
+fn test_window_size_one() {
+    let mut options = HashMap::new();
+    options.insert(WINDOW_SIZE.to_string(), 1.to_string());
+    let window_size = WindowSize::find_in(&options).unwrap();
+    assert_eq!(window_size.get_size(), WindowSize::default().get_size());
+    assert_eq!(
+        window_size.as_key_pair(),
+        (WINDOW_SIZE.to_string(), "1".to_string())
+    );
+}
+
 #[test]
 fn test_window_bottom() {
     let mut options = HashMap::new();
@@ -89,3 +141,110 @@ fn test_window_cap() {
     let find_result = WindowSize::find_in(&options);
     assert!(find_result.is_none());
 }
+
+#[test]
+fn test_window_clamp() {
+    let mut options = HashMap::new();
+    options.insert(WINDOW_SIZE.to_string(), 64.to_string());
+    let window_size = WindowSize::find_in(&options).unwrap().clamp(16);
+    assert_eq!(window_size.get_size(), 16);
+    assert_eq!(
+        window_size.as_key_pair(),
+        (WINDOW_SIZE.to_string(), "16".to_string())
+    );
+}
+
+#[test]
+fn test_window_within_clamp() {
+    let mut options = HashMap::new();
+    options.insert(WINDOW_SIZE.to_string(), 8.to_string());
+    let window_size = WindowSize::find_in(&options).unwrap().clamp(16);
+    assert_eq!(window_size.get_size(), 8);
+}
+
+#[test]
+fn test_session_buffer_budget() {
+    let limits = SessionLimits::new(WINDOW_SIZE_UPPER_CAP, 4 * (1024 + 4));
+    let block_size = Blksize { block_size: 1024 };
+    assert!(limits.admits(&block_size, &WindowSize(4)));
+    assert!(!limits.admits(&block_size, &WindowSize(5)));
+}
+
+#[test]
+fn test_timeout_jitter() {
+    let options = HashMap::from([(String::from("timeout"), String::from("2"))]);
+    let timeout = AckTimeout::find_in(&options).unwrap().with_jitter(20);
+    let durations: Vec<Duration> = (0..100).map(|_| timeout.duration()).collect();
+    for duration in &durations {
+        assert!(
+            (Duration::from_millis(1600)..=Duration::from_millis(2400)).contains(duration),
+            "{duration:?} is out of the jitter band"
+        );
+    }
+    assert!(durations.iter().any(|duration| *duration != durations[0]));
+    let steady = AckTimeout::find_in(&options).unwrap();
+    assert_eq!(steady.duration(), Duration::from_secs(2));
+}
+
+#[test]
+fn test_block_size_clamp_up() {
+    let options = HashMap::from([(BLKSIZE.to_string(), "16".to_string())]);
+    let limits = SessionLimits::default().with_block_size_range(512, 1468);
+    let block_size = Blksize::find_in(&options)
+        .unwrap()
+        .clamp(&limits.block_size_range(PEER));
+    assert_eq!(block_size.get_size(), 512);
+    assert_eq!(
+        block_size.as_key_pair(),
+        (BLKSIZE.to_string(), "512".to_string())
+    );
+}
+
+#[test]
+fn test_block_size_clamp_down() {
+    let options = HashMap::from([(BLKSIZE.to_string(), "8192".to_string())]);
+    let limits = SessionLimits::default().with_block_size_range(512, 1468);
+    let block_size = Blksize::find_in(&options)
+        .unwrap()
+        .clamp(&limits.block_size_range(PEER));
+    assert_eq!(block_size.get_size(), 1468);
+    let default_limits = SessionLimits::default();
+    let block_size = Blksize::find_in(&options)
+        .unwrap()
+        .clamp(&default_limits.block_size_range(PEER));
+    assert_eq!(block_size.get_size(), 8192);
+}
+
+#[test]
+fn block_size_fits_path_mtu() {
+    let limits = SessionLimits::default().with_path_mtu(Some(1500));
+    assert_eq!(limits.block_size_range(PEER), 8..=1468);
+    assert_eq!(
+        limits.block_size_range(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        8..=1448
+    );
+    let mapped = IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped());
+    assert_eq!(limits.block_size_range(mapped), 8..=1468);
+    // The configured maximum stays when smaller, the minimum gives way to the path MTU.
+    let limits = SessionLimits::default()
+        .with_block_size_range(1024, 1200)
+        .with_path_mtu(Some(1500));
+    assert_eq!(limits.block_size_range(PEER), 1024..=1200);
+    let limits = SessionLimits::default()
+        .with_block_size_range(1024, 8192)
+        .with_path_mtu(Some(576));
+    assert_eq!(limits.block_size_range(PEER), 544..=544);
+}
+
+#[test]
+fn find_connect_timeout() {
+    let mut options = HashMap::new();
+    options.insert(CONNECT_TIMEOUT.to_string(), "30".to_string());
+    let connect_timeout = ConnectTimeout::find_in(&options).unwrap();
+    assert_eq!(
+        connect_timeout.as_key_pair(),
+        (CONNECT_TIMEOUT.to_string(), "30".to_string())
+    );
+    options.insert(CONNECT_TIMEOUT.to_string(), "0".to_string());
+    assert!(ConnectTimeout::find_in(&options).is_none());
+}
@@ -1,34 +1,60 @@
+use crate::buffer_pool::{BufferPool, PooledBuffer};
 use crate::cursor::ReadCursor;
-use crate::datagram_stream::DatagramStream;
-use crate::error::{ERROR, TFTPError};
-use crate::fs::{OpenedFile, RootKind};
+use crate::datagram_stream::{DatagramStream, SocketBuffers, set_dscp};
+use crate::disk_cache::{ConfigKey, DiskCache};
+use crate::error::{ERROR, OPTION_NEGOTIATION, TFTPError};
+use crate::exec_root::{ExecHooks, ExecRoot};
+use crate::fallback_files::FallbackFiles;
+use crate::file_cache::FileCache;
+use crate::file_filter::FileFilter;
+use crate::fs::{AsyncOpenedFile, OpenedFile, RootKind};
 use crate::local_fs::LocalRoot;
 use crate::messages::{OptionsAcknowledge, ReadRequest};
-use crate::nbd_disk::open_nbd_root;
-use crate::options::{AckTimeout, Blksize, TSize, WindowSize};
+use crate::nbd_disk::{is_config_file, is_prewarmed, open_nbd_roots};
+use crate::netascii::Netascii;
+use crate::offloaded::Offloaded;
+use crate::options::{
+    AckTimeout, Blksize, ConnectTimeout, FileHash, Mtime, SessionLimits, TSize, WindowSize,
+};
+use crate::quiet_log;
+use crate::remote_fs::RemoteRoot;
+use crate::stats;
+use crate::stats::{ActiveSession, StatsReporter, TransferRecord};
+use crate::subnet_roots::SubnetRoots;
+use crate::upstream::UpstreamRoot;
+use crate::worker_pool::{PooledTask, WorkerPool};
 use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::fmt::{Debug, Display, Formatter};
+use std::future::poll_fn;
 use std::io;
 use std::net::{IpAddr, SocketAddr};
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread::Builder;
 use std::time::Duration;
-use std::{fmt, thread, time};
+use std::{fmt, iter, mem, thread, time};
 use tokio::net::UdpSocket;
 use tokio::runtime;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender, WeakSender};
+use tokio::sync::{mpsc, oneshot};
 use tokio::task::{JoinHandle, LocalSet};
 use tokio::time::timeout;
 
 #[cfg(test)]
 mod tests;
 
-const ACK: u16 = 0x04;
-const DATA: u16 = 0x03;
+pub(super) const ACK: u16 = 0x04;
+pub(super) const DATA: u16 = 0x03;
 const MAX_SESSIONS_PER_IP: usize = 128;
 const SEND_ATTEMPTS: u16 = 5;
+// A config failing to connect is retried after this, twice as long after every failure in a row.
+const ROOT_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_ROOT_RETRY_BACKOFF: Duration = Duration::from_secs(300);
+
+// Numbers the accepted requests across all peers, so every transfer has an id of its own in the log.
+static REQUEST_IDS: AtomicU64 = AtomicU64::new(1);
 
 async fn fire_error<D: Borrow<DatagramStream>>(
     error: TFTPError,
@@ -43,6 +69,7 @@ async fn fire_error<D: Borrow<DatagramStream>>(
                 eprintln!("{borrowed_stream}: Error sending {error}: {send_error}");
             } else {
                 eprintln!("{borrowed_stream}: Sent {error}");
+                stats::count_error_sent(error.code());
             }
         }
         Err(buffer_error) => {
@@ -53,41 +80,100 @@ async fn fire_error<D: Borrow<DatagramStream>>(
 
 struct Window {
     block_size: u16,
-    buffers: Vec<Vec<u8>>,
+    buffers: Vec<PooledBuffer>,
+    // Blocks sent before waiting for an ACK, never above the negotiated window size.
+    limit: u16,
+    adaptive: bool,
+    // Pause between the blocks of a window, for switches dropping back-to-back bursts.
+    gap: Duration,
+    // How long repeated ACKs are answered after the final one.
+    grace: Duration,
 }
 
 impl Window {
-    fn new(block_size: u16, window_size: u16) -> Self {
+    fn new(block_size: u16, window_size: u16, buffer_pool: &BufferPool) -> Self {
         Self {
             block_size,
             buffers: (0..window_size)
-                .map(|_| vec![0; block_size as usize + 2 * size_of::<u16>()])
+                .map(|_| buffer_pool.lease(block_size as usize + 2 * size_of::<u16>()))
                 .collect(),
+            limit: window_size,
+            adaptive: false,
+            gap: Duration::ZERO,
+            grace: Duration::ZERO,
         }
     }
 
+    fn paced(mut self, gap: Duration) -> Self {
+        self.gap = gap;
+        self
+    }
+
+    fn dallying(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+
+    // Slow start: begin with a single block, double on every fully acknowledged window and halve on loss.
+    fn adaptive(mut self) -> Self {
+        self.limit = 1;
+        self.adaptive = true;
+        self
+    }
+
     fn size(&self) -> u16 {
         self.buffers.capacity() as u16
     }
 
-    fn push_block(
+    fn limit(&self) -> u16 {
+        self.limit
+    }
+
+    fn grow(&mut self) {
+        if self.adaptive {
+            self.limit = self.limit.saturating_mul(2).min(self.size());
+        }
+    }
+
+    fn shrink(&mut self) {
+        if self.adaptive {
+            self.limit = (self.limit / 2).max(1);
+        }
+    }
+
+    async fn push_block<O: AsyncOpenedFile>(
         &mut self,
-        opened_file: &mut dyn OpenedFile,
+        opened_file: &mut O,
         index: u16,
     ) -> io::Result<(usize, bool)> {
         let buffer = self.buffer(index);
-        buffer[0] = 0;
-        buffer[1] = DATA as u8;
-        buffer[2] = (index >> 8) as u8;
-        buffer[3] = index as u8;
-        let read_bytes = opened_file.read_to(&mut buffer[4..])?;
+        let read_bytes = poll_fn(|cx| opened_file.poll_read(cx, &mut buffer[4..])).await?;
         buffer.truncate(read_bytes + 4);
         Ok((read_bytes, read_bytes < self.block_size as usize))
     }
+
+    // Reads a block already sent once, the sequential reads go on where they were.
+    async fn push_block_at<O: AsyncOpenedFile>(
+        &mut self,
+        opened_file: &mut O,
+        index: u16,
+        offset: usize,
+    ) -> io::Result<()> {
+        let buffer = self.buffer(index);
+        let read_bytes =
+            poll_fn(|cx| opened_file.poll_read_at(cx, offset, &mut buffer[4..])).await?;
+        buffer.truncate(read_bytes + 4);
+        Ok(())
+    }
+
     fn buffer(&mut self, index: u16) -> &mut Vec<u8> {
         let window_length = self.buffers.len();
-        let buffer = &mut self.buffers[index as usize % window_length];
+        let buffer: &mut Vec<u8> = &mut self.buffers[index as usize % window_length];
         unsafe { buffer.set_len(buffer.capacity()) }
+        buffer[0] = 0;
+        buffer[1] = DATA as u8;
+        buffer[2] = (index >> 8) as u8;
+        buffer[3] = index as u8;
         buffer
     }
 
@@ -98,7 +184,7 @@ impl Window {
     }
 }
 
-async fn send_file<O: OpenedFile>(
+async fn send_file<O: AsyncOpenedFile>(
     mut opened_file: O,
     datagram_stream: &DatagramStream,
     mut window: Window,
@@ -107,25 +193,36 @@ async fn send_file<O: OpenedFile>(
 ) -> Result<(usize, usize), TFTPError> {
     let mut bytes_sent: usize = 0;
     let mut blocks_sent: usize = 0;
+    // Unlike the block indexes this never wraps, so the offsets of the blocks sent can be told.
+    let mut blocks_read: usize = 0;
     let mut last_acknowledged_index: u16 = 0;
     let mut last_read_index: u16 = 0;
     let mut done = false;
-    while !done {
+    // A partial ACK of the last window leaves its tail to be sent again.
+    while !done || last_acknowledged_index != last_read_index {
         let unacknowledged_count = last_read_index.wrapping_sub(last_acknowledged_index);
         debug_assert!(unacknowledged_count <= window.size());
         let mut to_send = unacknowledged_count;
-        while to_send < window.size() {
+        while !done && to_send < window.limit() {
             last_read_index = last_read_index.wrapping_add(1);
-            if let Ok((read_bytes, is_last)) = window.push_block(&mut opened_file, last_read_index)
-            {
-                to_send += 1;
-                bytes_sent += read_bytes;
-                if is_last {
-                    done = true;
-                    break;
+            match window.push_block(&mut opened_file, last_read_index).await {
+                Ok((read_bytes, is_last)) => {
+                    to_send += 1;
+                    blocks_read += 1;
+                    bytes_sent += read_bytes;
+                    if is_last {
+                        done = true;
+                        break;
+                    }
+                }
+                Err(error) if error.kind() == io::ErrorKind::FileTooLarge => {
+                    eprintln!("{datagram_stream}: {opened_file} is too large: {error}");
+                    return Err(TFTPError::file_too_large());
+                }
+                Err(error) => {
+                    eprintln!("{datagram_stream}: Failed to read {opened_file}: {error}");
+                    return Err(TFTPError::undefined("Read file error occurred"));
                 }
-            } else {
-                return Err(TFTPError::undefined("Read file error occurred"));
             }
         }
         debug_assert!(to_send <= window.size());
@@ -139,23 +236,149 @@ async fn send_file<O: OpenedFile>(
         )
         .await
         {
-            Ok(received_acknowledged) => received_acknowledged,
-            Err(SendError::Timeout) => {
-                return Err(TFTPError::undefined("Send timeout occurred"));
+            Ok(received_acknowledged) => {
+                // A partial ACK means the client lost a block of the window.
+                if received_acknowledged == last_read_index {
+                    window.grow();
+                } else {
+                    window.shrink();
+                }
+                received_acknowledged
             }
-            Err(SendError::ClientError(code, string)) => {
-                eprintln!("{datagram_stream}: Early termination [{code}] {string}");
-                blocks_sent += to_send as usize;
-                return Ok((bytes_sent, blocks_sent));
+            Err(SendError::PastAck(past_ack)) => {
+                let acknowledged_blocks = blocks_read - to_send as usize;
+                let behind = last_acknowledged_index.wrapping_sub(past_ack) as usize;
+                // A repeated last ACK leaves nothing to serve again, so it is refused.
+                let past_blocks = if (1..=acknowledged_blocks).contains(&behind) {
+                    eprintln!(
+                        "{datagram_stream}: ACK {past_ack} is behind the window, sending {behind} blocks again"
+                    );
+                    let past_blocks = acknowledged_blocks - behind..acknowledged_blocks;
+                    send_past_blocks(
+                        &mut opened_file,
+                        &window,
+                        &ack_timeout,
+                        datagram_stream,
+                        buffer,
+                        past_ack,
+                        past_blocks,
+                    )
+                    .await
+                } else {
+                    eprintln!(
+                        "{datagram_stream}: Received ACK {past_ack} while expected > {last_acknowledged_index}"
+                    );
+                    Err(SendError::ACKError)
+                };
+                if let Err(send_error) = past_blocks {
+                    return end_transfer(datagram_stream, send_error, bytes_sent, blocks_sent);
+                }
+                last_acknowledged_index
             }
-            Err(_) => {
-                return Err(TFTPError::undefined("Unknown error occurred"));
+            Err(send_error) => {
+                blocks_sent += to_send as usize;
+                return end_transfer(datagram_stream, send_error, bytes_sent, blocks_sent);
             }
         };
     }
+    if !window.grace.is_zero() {
+        dally(&mut window, datagram_stream, buffer, last_read_index).await;
+    }
     Ok((bytes_sent, blocks_sent))
 }
 
+// What the transfer ends with when the blocks can't be delivered.
+fn end_transfer(
+    datagram_stream: &DatagramStream,
+    send_error: SendError,
+    bytes_sent: usize,
+    blocks_sent: usize,
+) -> Result<(usize, usize), TFTPError> {
+    match send_error {
+        SendError::Timeout(block_index, attempts) => Err(TFTPError::undefined(format!(
+            "Send timeout occurred at block {block_index} after {attempts} attempts"
+        ))),
+        SendError::ClientGone => Err(TFTPError::undefined("Client is gone")),
+        SendError::ClientError(code, string) => {
+            eprintln!("{datagram_stream}: Early termination [{code}] {string}");
+            Ok((bytes_sent, blocks_sent))
+        }
+        SendError::ACKError => Err(TFTPError::undefined("Received ACK from the past")),
+        SendError::Read => Err(TFTPError::undefined("Read file error occurred")),
+        _ => Err(TFTPError::undefined("Unknown error occurred")),
+    }
+}
+
+// The client lost the blocks after `past_ack` once the window moved past them. They are read again at their
+// offsets and sent one by one, since the window buffers hold the blocks still unacknowledged.
+async fn send_past_blocks<O: AsyncOpenedFile>(
+    opened_file: &mut O,
+    window: &Window,
+    ack_timeout: &AckTimeout,
+    datagram_stream: &DatagramStream,
+    buffer: &mut [u8],
+    past_ack: u16,
+    past_blocks: Range<usize>,
+) -> Result<(), SendError> {
+    let mut past_window = Window::new(window.block_size, 1, &BufferPool::default());
+    for (index, block) in (1..).map(|v| past_ack.wrapping_add(v)).zip(past_blocks) {
+        let offset = block * window.block_size as usize;
+        if let Err(error) = past_window.push_block_at(opened_file, index, offset).await {
+            return if error.kind() == io::ErrorKind::Unsupported {
+                eprintln!("{datagram_stream}: {opened_file} can't serve block {index} again");
+                Err(SendError::ACKError)
+            } else {
+                eprintln!("{datagram_stream}: Failed to read {opened_file}: {error}");
+                Err(SendError::Read)
+            };
+        }
+        send_reliably(
+            &mut past_window,
+            ack_timeout,
+            datagram_stream,
+            buffer,
+            index,
+            1,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+// A client repeating its ACK after the final one was received can't know the transfer is over, so the last
+// block is sent again. The answers are limited, so a client echoing every block can't keep the session up.
+async fn dally(
+    window: &mut Window,
+    datagram_stream: &DatagramStream,
+    buffer: &mut [u8],
+    last_index: u16,
+) {
+    let deadline = tokio::time::Instant::now() + window.grace;
+    for _ in 0..SEND_ATTEMPTS {
+        let recv_future = datagram_stream.recv(buffer, 4);
+        let Ok(Ok(read_size)) = tokio::time::timeout_at(deadline, recv_future).await else {
+            return;
+        };
+        let Ok(repeated_ack) = parse_acknowledge(datagram_stream, &buffer[..read_size]) else {
+            return;
+        };
+        eprintln!(
+            "{datagram_stream}: Repeated ACK {repeated_ack}, sending block {last_index} again"
+        );
+        if window.send(last_index, datagram_stream).await.is_err() {
+            return;
+        }
+    }
+}
+
+// The ICMP port unreachable means nobody listens anymore, so retransmitting is pointless.
+fn is_client_gone(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+    )
+}
+
 async fn read_acknowledge(
     datagram_stream: &DatagramStream,
     buffer: &mut [u8],
@@ -163,54 +386,625 @@ async fn read_acknowledge(
 ) -> Result<u16, RecvError> {
     let recv_future = datagram_stream.recv(buffer, 4);
     if let Ok(read_result) = ack_timeout.timeout(recv_future).await {
-        let _read_size = match read_result {
+        let read_size = match read_result {
             Ok(size) => size,
+            Err(err) if is_client_gone(&err) => {
+                eprintln!("{datagram_stream}: Client is gone: {err}");
+                return Err(RecvError::ClientGone);
+            }
             Err(err) => {
                 eprintln!("{datagram_stream}: Read error: {:?}", err);
                 return Err(RecvError::Network);
             }
         };
-        let mut datagram = ReadCursor::new(buffer);
-        match datagram.extract_ushort() {
-            Ok(opcode) if opcode == ACK => {
-                Ok(datagram.extract_ushort().map_err(|_| RecvError::ACKError)?)
-            }
-            Ok(opcode) if opcode == ERROR => {
-                let error_code = datagram.extract_ushort().map_err(|_| RecvError::ACKError)?;
-                let error_message = datagram.extract_string().map_err(|_| RecvError::ACKError)?;
-                Err(RecvError::ClientError(error_code, error_message))
+        // The buffer is not zeroed between uses, so never look past the received datagram.
+        parse_acknowledge(datagram_stream, &buffer[..read_size])
+    } else {
+        Err(RecvError::Timeout)
+    }
+}
+
+fn parse_acknowledge(datagram_stream: &DatagramStream, datagram: &[u8]) -> Result<u16, RecvError> {
+    let mut datagram = ReadCursor::new(datagram);
+    match datagram.extract_ushort() {
+        Ok(opcode) if opcode == ACK => {
+            Ok(datagram.extract_ushort().map_err(|_| RecvError::ACKError)?)
+        }
+        Ok(opcode) if opcode == ERROR => {
+            let error_code = datagram.extract_ushort().map_err(|_| RecvError::ACKError)?;
+            let error_message = datagram.extract_string().map_err(|_| RecvError::ACKError)?;
+            Err(RecvError::ClientError(error_code, error_message))
+        }
+        Ok(opcode) => {
+            eprintln!("{datagram_stream}: Received unknown opcode 0x{opcode:02x}");
+            Err(RecvError::ACKError)
+        }
+        Err(_) => Err(RecvError::ACKError),
+    }
+}
+
+// Consumes the ACKs already received without waiting for more and returns the one acknowledging the most
+// blocks of the window starting at `window_index`. ACKs outside the window are stale and skipped.
+fn drain_acknowledges(
+    datagram_stream: &DatagramStream,
+    buffer: &mut [u8],
+    window_index: u16,
+    count: u16,
+    mut highest: Option<u16>,
+) -> Result<Option<u16>, RecvError> {
+    loop {
+        let read_size = match datagram_stream.try_recv(buffer, 4) {
+            Ok(size) => size,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(highest),
+            Err(err) if is_client_gone(&err) => {
+                eprintln!("{datagram_stream}: Client is gone: {err}");
+                return Err(RecvError::ClientGone);
             }
-            Ok(opcode) => {
-                eprintln!("{datagram_stream}: Received unknown opcode 0x{opcode:02x}");
-                Err(RecvError::ACKError)
+            Err(err) => {
+                eprintln!("{datagram_stream}: Read error: {:?}", err);
+                return Err(RecvError::Network);
             }
-            Err(_) => Err(RecvError::ACKError),
+        };
+        let received_ack = parse_acknowledge(datagram_stream, &buffer[..read_size])?;
+        let offset = received_ack.wrapping_sub(window_index);
+        if offset >= count {
+            quiet_log::log!("{datagram_stream}: Skip stale ACK {received_ack}");
+        } else if highest.is_none_or(|highest| offset > highest.wrapping_sub(window_index)) {
+            highest = Some(received_ack);
         }
-    } else {
-        Err(RecvError::Timeout)
     }
 }
 
 #[derive(Debug)]
 pub(super) enum SendError {
     Network,
-    Timeout,
+    ClientGone,
+    // The first unacknowledged block index and the number of attempts made.
+    Timeout(u16, u16),
     ClientError(u16, String),
     ACKError,
+    // An ACK older than the window start.
+    PastAck(u16),
+    Read,
+}
+
+// What a session serves and where from, for the line summarizing the transfer once it starts.
+#[derive(Debug, Default)]
+struct ServedFile {
+    name: String,
+    root: String,
+}
+
+// The settings a transfer runs with, as agreed with the client.
+struct Negotiated {
+    window: Window,
+    ack_timeout: AckTimeout,
+    connect_timeout: Option<ConnectTimeout>,
+    // Only obtained when the client asks for it.
+    tsize: Option<usize>,
+}
+
+impl Negotiated {
+    fn defaults(buffer_pool: &BufferPool) -> Self {
+        let window = Window::new(
+            Blksize::default().get_size() as u16,
+            WindowSize::default().get_size() as u16,
+            buffer_pool,
+        );
+        Self {
+            window,
+            ack_timeout: Default::default(),
+            connect_timeout: None,
+            tsize: None,
+        }
+    }
+
+    // A single key=value line, the values with spaces or quotes in them are quoted and escaped.
+    fn summary(&self, peer: SocketAddr, served_file: &ServedFile) -> String {
+        let tsize = self
+            .tsize
+            .map_or_else(|| String::from("-"), |tsize| tsize.to_string());
+        format!(
+            "peer={peer} file={:?} root={:?} blksize={} windowsize={} timeout={} tsize={tsize}",
+            served_file.name,
+            served_file.root,
+            self.window.block_size,
+            self.window.size(),
+            self.ack_timeout.seconds(),
+        )
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum OptionsReply {
+    Acknowledged,
+    // The client ignored the OACK and acknowledged the first data block, expecting default options.
+    Skipped,
+    // The client refused the acknowledged options with an option negotiation error.
+    Declined(String),
 }
 
 #[derive(Debug)]
 pub(super) enum RecvError {
     Network,
+    ClientGone,
     Timeout,
     ClientError(u16, String),
     ACKError,
 }
 
+// Per-session settings and resources shared by all peer handlers.
+#[derive(Clone, Default)]
+pub(super) struct SessionContext {
+    file_filter: FileFilter,
+    session_limits: SessionLimits,
+    buffer_pool: BufferPool,
+    transparent_gzip: bool,
+    allow_growing: bool,
+    deny_dangling_symlinks: bool,
+    max_file_size: Option<usize>,
+    no_oack: bool,
+    oack_final_null: bool,
+    adaptive_window: bool,
+    retransmit_jitter: u8,
+    interpacket_gap: Duration,
+    final_ack_grace: Duration,
+    max_transfer_time: Option<Duration>,
+    dscp: Option<u8>,
+    socket_buffers: SocketBuffers,
+    root_layers: Vec<String>,
+    exec_hooks: ExecHooks,
+    upstream: Option<UpstreamRoot>,
+    subnet_roots: SubnetRoots,
+    fallback_files: FallbackFiles,
+    disk_cache: DiskCache,
+    file_cache: FileCache,
+    stats_reporter: StatsReporter,
+}
+
+impl SessionContext {
+    pub(super) fn new(
+        file_filter: FileFilter,
+        session_limits: SessionLimits,
+        buffer_pool: BufferPool,
+        transparent_gzip: bool,
+        max_file_size: Option<usize>,
+        no_oack: bool,
+        adaptive_window: bool,
+    ) -> Self {
+        Self {
+            file_filter,
+            session_limits,
+            buffer_pool,
+            transparent_gzip,
+            max_file_size,
+            no_oack,
+            adaptive_window,
+            oack_final_null: true,
+            allow_growing: false,
+            deny_dangling_symlinks: false,
+            retransmit_jitter: 0,
+            interpacket_gap: Duration::ZERO,
+            final_ack_grace: Duration::ZERO,
+            max_transfer_time: None,
+            dscp: None,
+            socket_buffers: SocketBuffers::default(),
+            root_layers: Vec::new(),
+            exec_hooks: ExecHooks::default(),
+            upstream: None,
+            subnet_roots: SubnetRoots::default(),
+            fallback_files: FallbackFiles::default(),
+            disk_cache: DiskCache::default(),
+            file_cache: FileCache::default(),
+            stats_reporter: StatsReporter::default(),
+        }
+    }
+
+    pub(super) fn with_retransmit_jitter(mut self, percent: u8) -> Self {
+        self.retransmit_jitter = percent;
+        self
+    }
+
+    pub(super) fn with_interpacket_gap(mut self, gap: Duration) -> Self {
+        self.interpacket_gap = gap;
+        self
+    }
+
+    pub(super) fn with_final_ack_grace(mut self, grace: Duration) -> Self {
+        self.final_ack_grace = grace;
+        self
+    }
+
+    pub(super) fn with_max_transfer_time(mut self, max_transfer_time: Option<Duration>) -> Self {
+        self.max_transfer_time = max_transfer_time;
+        self
+    }
+
+    pub(super) fn with_dscp(mut self, dscp: Option<u8>) -> Self {
+        self.dscp = dscp;
+        self
+    }
+
+    pub(super) fn with_socket_buffers(mut self, socket_buffers: SocketBuffers) -> Self {
+        self.socket_buffers = socket_buffers;
+        self
+    }
+
+    pub(super) fn with_disk_cache(mut self, disk_cache: DiskCache) -> Self {
+        self.disk_cache = disk_cache;
+        self
+    }
+
+    pub(super) fn disk_cache(&self) -> &DiskCache {
+        &self.disk_cache
+    }
+
+    pub(super) fn with_file_cache(mut self, file_cache: FileCache) -> Self {
+        self.file_cache = file_cache;
+        self
+    }
+
+    pub(super) fn with_oack_final_null(mut self, oack_final_null: bool) -> Self {
+        self.oack_final_null = oack_final_null;
+        self
+    }
+
+    pub(super) fn with_allow_growing(mut self, allow_growing: bool) -> Self {
+        self.allow_growing = allow_growing;
+        self
+    }
+
+    pub(super) fn with_deny_dangling_symlinks(mut self, deny_dangling_symlinks: bool) -> Self {
+        self.deny_dangling_symlinks = deny_dangling_symlinks;
+        self
+    }
+
+    pub(super) fn with_fallback_files(mut self, fallback_files: FallbackFiles) -> Self {
+        self.fallback_files = fallback_files;
+        self
+    }
+
+    pub(super) fn with_root_layers(mut self, root_layers: Vec<String>) -> Self {
+        self.root_layers = root_layers;
+        self
+    }
+
+    pub(super) fn with_exec_hooks(mut self, exec_hooks: ExecHooks) -> Self {
+        self.exec_hooks = exec_hooks;
+        self
+    }
+
+    pub(super) fn with_upstream(mut self, upstream: Option<UpstreamRoot>) -> Self {
+        self.upstream = upstream;
+        self
+    }
+
+    pub(super) fn with_subnet_roots(mut self, subnet_roots: SubnetRoots) -> Self {
+        self.subnet_roots = subnet_roots;
+        self
+    }
+
+    pub(super) fn reporting_to(mut self, stats_reporter: StatsReporter) -> Self {
+        self.stats_reporter = stats_reporter;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum HandlerExitReason {
+    IdleTimeout,
+    ShutdownRequested,
+    Error(String),
+    Panicked,
+}
+
+impl HandlerExitReason {
+    pub(super) fn label(&self) -> &'static str {
+        match self {
+            Self::IdleTimeout => "idle timeout",
+            Self::ShutdownRequested => "shutdown requested",
+            Self::Error(_) => "error",
+            Self::Panicked => "panicked",
+        }
+    }
+}
+
+impl Display for HandlerExitReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error(message) => write!(f, "{}: {message}", self.label()),
+            _ => write!(f, "{}", self.label()),
+        }
+    }
+}
+
+// Where the replies of a session are sent from.
+pub(super) enum ReplySource {
+    // A fresh socket bound to the address the request was received on, as TFTP prescribes.
+    Ephemeral(IpAddr),
+    // A duplicate of the listen socket the request was received on. Only the server reads the listen
+    // socket, so it forwards the datagrams the peer sends back.
+    Listen(std::net::UdpSocket),
+}
+
+enum HandlerMessage {
+    Request(ReplySource, u16, ReadRequest),
+    // The configs of the peer have changed.
+    ReloadRoots,
+    // Lists a directory of the remote root connected by the config, for the stats socket.
+    ListDir(PathBuf, String, oneshot::Sender<io::Result<Vec<String>>>),
+}
+
+// Passes the directory listings asked on the stats socket to a handler, which alone holds the disks of its
+// peer. Doesn't keep the handler from shutting down.
+#[derive(Clone)]
+pub(super) struct RootLister(WeakSender<HandlerMessage>);
+
+impl RootLister {
+    pub(super) async fn list_dir(&self, config: PathBuf, path: String) -> io::Result<Vec<String>> {
+        let handler_gone = || io::Error::new(io::ErrorKind::NotFound, "The handler is gone");
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.0
+            .upgrade()
+            .ok_or_else(handler_gone)?
+            .send(HandlerMessage::ListDir(config, path, reply_tx))
+            .await
+            .map_err(|_| handler_gone())?;
+        reply_rx.await.map_err(|_| handler_gone())?
+    }
+}
+
+// The remote roots of a peer with their configs, and the configs failed to connect.
+type Connected = (Vec<(ConfigKey, RemoteRoot)>, Vec<PathBuf>);
+
+// The roots searched for the files of a peer, in the order of precedence.
+struct PeerRoots {
+    peer: IpAddr,
+    tftp_root: PathBuf,
+    default_root: Option<String>,
+    roots: Vec<RootKind>,
+    // The configs the remote roots are connected by, in the order of the roots.
+    config_keys: Vec<ConfigKey>,
+    // The configs failed to connect with the failures in a row and when to try again.
+    retries: HashMap<PathBuf, (u32, time::Instant)>,
+    retry_backoff: Duration,
+    // Any config of the peer asks for its disks to stay connected however long the peer is idle.
+    prewarmed: bool,
+    // The remote roots of a reload, connected on a thread of its own while the current roots are served.
+    pending: Option<oneshot::Receiver<Connected>>,
+    // Another reload is requested while one is pending.
+    reload_again: bool,
+}
+
+impl PeerRoots {
+    fn new(peer: IpAddr, tftp_root: PathBuf, default_root: Option<String>) -> Self {
+        Self {
+            peer,
+            tftp_root,
+            default_root,
+            roots: Vec::new(),
+            config_keys: Vec::new(),
+            retries: HashMap::new(),
+            retry_backoff: ROOT_RETRY_BACKOFF,
+            prewarmed: false,
+            pending: None,
+            reload_again: false,
+        }
+    }
+
+    // Remote roots are taken from the cache when their configs are unchanged and connected otherwise.
+    fn populate(&mut self, session_context: &SessionContext, disk_cache: &DiskCache) {
+        let connected = open_nbd_roots(&self.tftp_root, &self.peer.to_string(), disk_cache);
+        self.assemble(session_context, connected);
+    }
+
+    // Lays the roots out around the remote ones.
+    fn assemble(&mut self, session_context: &SessionContext, (remote_roots, failed): Connected) {
+        let peer = self.peer;
+        let tftp_root = &self.tftp_root;
+        let local_root = |path: PathBuf| {
+            LocalRoot::new(path)
+                .transparent_gzip(session_context.transparent_gzip)
+                .allow_growing(session_context.allow_growing)
+                .deny_dangling_symlinks(session_context.deny_dangling_symlinks)
+                .filtered(session_context.file_filter.clone())
+                .max_transformed_size(session_context.max_file_size)
+        };
+        if !session_context.exec_hooks.is_empty() {
+            self.roots.push(RootKind::Exec(ExecRoot::new(
+                session_context.exec_hooks.clone(),
+                peer,
+            )));
+        }
+        for root_layer in &session_context.root_layers {
+            self.roots
+                .push(RootKind::Local(local_root(tftp_root.join(root_layer))));
+        }
+        if let Some(subnet_root) = session_context.subnet_roots.lookup(peer) {
+            self.roots
+                .push(RootKind::Local(local_root(tftp_root.join(subnet_root))));
+        }
+        self.roots.push(RootKind::Local(
+            local_root(tftp_root.join(peer.to_string())).hiding(is_config_file),
+        ));
+        self.prewarmed = is_prewarmed(tftp_root, &peer.to_string());
+        for (config_key, remote_root) in remote_roots {
+            self.config_keys.push(config_key);
+            self.roots.push(RootKind::Remote(
+                remote_root.with_file_cache(session_context.file_cache.clone()),
+            ))
+        }
+        if let Some(default_root) = &self.default_root {
+            self.roots
+                .push(RootKind::Local(local_root(tftp_root.join(default_root))));
+        }
+        if let Some(upstream) = &session_context.upstream {
+            self.roots.push(RootKind::Upstream(upstream.clone()));
+        }
+        self.schedule_retries(failed);
+    }
+
+    // Looks the roots up anew on a thread of its own, the disks of the configs left intact are shared with
+    // the new roots. The current roots are served until `swap_in` replaces them, except the writable disks
+    // of the configs edited or removed.
+    fn reload(&mut self) {
+        if self.pending.is_some() {
+            self.reload_again = true;
+            return;
+        }
+        // Two appliances must never have the same image attached read-write, so such a disk is closed
+        // before its edited config connects again.
+        let withdrawn = self.withdraw_stale_writable();
+        let previous_disks = DiskCache::new(Duration::MAX);
+        for (config_key, remote_root) in self.remote_roots() {
+            previous_disks.put(config_key.clone(), remote_root.clone());
+        }
+        let (tftp_root, ip) = (self.tftp_root.clone(), self.peer.to_string());
+        let (connected_tx, connected_rx) = oneshot::channel();
+        thread::spawn(move || {
+            drop(withdrawn);
+            _ = connected_tx.send(open_nbd_roots(&tftp_root, &ip, &previous_disks));
+        });
+        self.pending = Some(connected_rx);
+    }
+
+    // Resolves once the pending reload is connected, never if there is none. None if the reload is lost.
+    async fn connected(&mut self) -> Option<Connected> {
+        let Some(pending) = self.pending.as_mut() else {
+            return std::future::pending().await;
+        };
+        let connected = pending.await.ok();
+        self.pending = None;
+        connected
+    }
+
+    fn swap_in(&mut self, session_context: &SessionContext, connected: Connected) {
+        let previous_roots = mem::take(&mut self.roots);
+        self.config_keys.clear();
+        self.assemble(session_context, connected);
+        // The roots of removed or edited configs hold their disks, and closing one waits for its appliance.
+        thread::spawn(move || drop(previous_roots));
+        if mem::take(&mut self.reload_again) {
+            self.reload();
+        }
+    }
+
+    // Configs connected or gone meanwhile are not retried anymore.
+    fn schedule_retries(&mut self, failed: Vec<PathBuf>) {
+        let mut retries = HashMap::new();
+        for config in failed {
+            let failures = self
+                .retries
+                .get(&config)
+                .map_or(0, |(failures, _)| *failures)
+                + 1;
+            let backoff = self
+                .retry_backoff
+                .saturating_mul(2u32.saturating_pow(failures - 1))
+                .min(MAX_ROOT_RETRY_BACKOFF);
+            eprintln!(
+                "{}: Retrying config {config:?} in {backoff:?} after {failures} failures",
+                self.peer
+            );
+            retries.insert(config, (failures, time::Instant::now() + backoff));
+        }
+        self.retries = retries;
+    }
+
+    // Not while a reload is pending: it retries the configs already, and its outcome reschedules them.
+    fn is_retry_due(&self) -> bool {
+        let now = time::Instant::now();
+        self.pending.is_none() && self.retries.values().any(|(_, retry_at)| *retry_at <= now)
+    }
+
+    // The configs of the remote roots, connected or retried.
+    fn configs(&self) -> impl Iterator<Item = &Path> {
+        self.config_keys
+            .iter()
+            .map(ConfigKey::path)
+            .chain(self.retries.keys().map(PathBuf::as_path))
+    }
+
+    fn remote_roots(&self) -> impl Iterator<Item = (&ConfigKey, &RemoteRoot)> {
+        let remote_roots = self.roots.iter().filter_map(|root| match root {
+            RootKind::Remote(remote_root) => Some(remote_root),
+            RootKind::Local(_) | RootKind::Exec(_) | RootKind::Upstream(_) => None,
+        });
+        self.config_keys.iter().zip(remote_roots)
+    }
+
+    // Takes the writable disks of the configs edited or removed out of service.
+    fn withdraw_stale_writable(&mut self) -> Vec<RemoteRoot> {
+        let mut config_keys = mem::take(&mut self.config_keys).into_iter();
+        let mut withdrawn = Vec::new();
+        for root in mem::take(&mut self.roots) {
+            let RootKind::Remote(remote_root) = root else {
+                self.roots.push(root);
+                continue;
+            };
+            let config_key = config_keys
+                .next()
+                .expect("Every remote root has its config key");
+            if remote_root.is_writable() && !config_key.is_current() {
+                eprintln!(
+                    "{}: Closing writable {remote_root} of changed config {:?}",
+                    self.peer,
+                    config_key.path()
+                );
+                withdrawn.push(remote_root);
+            } else {
+                self.config_keys.push(config_key);
+                self.roots.push(RootKind::Remote(remote_root));
+            }
+        }
+        withdrawn
+    }
+
+    // Lists on a thread of its own, since the disk answers through its worker.
+    fn list_dir(
+        &self,
+        config: &Path,
+        path: String,
+        reply: oneshot::Sender<io::Result<Vec<String>>>,
+    ) {
+        let Some((_, remote_root)) = self.remote_roots().find(|(key, _)| key.path() == config)
+        else {
+            _ = reply.send(Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No disk of {config:?} is connected"),
+            )));
+            return;
+        };
+        let remote_root = remote_root.clone();
+        thread::spawn(move || _ = reply.send(remote_root.list_dir(&path)));
+    }
+
+    fn take_remote_roots(&mut self) -> Vec<(ConfigKey, RemoteRoot)> {
+        let remote_roots = mem::take(&mut self.roots)
+            .into_iter()
+            .filter_map(|root| match root {
+                RootKind::Remote(remote_root) => Some(remote_root),
+                RootKind::Local(_) | RootKind::Exec(_) | RootKind::Upstream(_) => None,
+            });
+        mem::take(&mut self.config_keys)
+            .into_iter()
+            .zip(remote_roots)
+            .collect()
+    }
+}
+
+// Where a handler runs: a thread of its own, or a task on a worker shared with other peers.
+enum HandlerRun {
+    Thread(thread::JoinHandle<HandlerExitReason>),
+    Pooled(PooledTask<HandlerExitReason>),
+}
+
 pub(super) struct PeerHandler {
     sender_address: IpAddr,
-    requests_channel: Sender<(u16, ReadRequest)>,
-    thread_handle: thread::JoinHandle<()>,
+    requests_channel: Sender<HandlerMessage>,
+    datagrams_channel: UnboundedSender<(u16, Vec<u8>)>,
+    run: HandlerRun,
+    last_fed: time::Instant,
 }
 
 impl Display for PeerHandler {
@@ -228,11 +1022,13 @@ impl Debug for PeerHandler {
 impl PeerHandler {
     pub(super) fn new(
         peer: IpAddr,
-        local_address: IpAddr,
         tftp_root: PathBuf,
+        default_root: Option<String>,
         idle_timeout: Duration,
+        session_context: SessionContext,
     ) -> Self {
-        let (tx, rx) = mpsc::channel::<(u16, ReadRequest)>(10);
+        let (tx, rx) = mpsc::channel::<HandlerMessage>(10);
+        let (datagrams_tx, datagrams_rx) = mpsc::unbounded_channel();
         let handle = Builder::new()
             .name(format!("Handler {peer}"))
             .spawn(move || {
@@ -242,93 +1038,364 @@ impl PeerHandler {
                     .build()
                     .unwrap();
                 let local_task_set = LocalSet::new();
-                let mut available_roots = vec![RootKind::Local(LocalRoot::new(
-                    tftp_root.join(peer.to_string()),
-                ))];
-                if let Some(remote_root) = open_nbd_root(&tftp_root, &peer.to_string()) {
-                    available_roots.push(RootKind::Remote(remote_root))
-                }
-                available_roots.push(RootKind::Local(LocalRoot::new(tftp_root.join("default"))));
-                local_task_set.spawn_local(peer_requests_handler(
-                    peer,
-                    local_address,
-                    available_roots,
-                    rx,
-                    idle_timeout,
-                ));
-                runtime.block_on(local_task_set);
-                eprintln!("{peer}: Handler closed");
+                local_task_set.block_on(
+                    &runtime,
+                    serve_peer(
+                        peer,
+                        tftp_root,
+                        default_root,
+                        rx,
+                        datagrams_rx,
+                        idle_timeout,
+                        session_context,
+                    ),
+                )
             })
             .unwrap();
         Self {
             sender_address: peer,
             requests_channel: tx,
-            thread_handle: handle,
+            datagrams_channel: datagrams_tx,
+            run: HandlerRun::Thread(handle),
+            last_fed: time::Instant::now(),
+        }
+    }
+
+    // Runs on a worker of the pool instead of a thread of its own. Connecting a remote disk would stall the
+    // other peers of the worker, so only peers without NBD configs are pooled.
+    pub(super) fn pooled(
+        peer: IpAddr,
+        tftp_root: PathBuf,
+        default_root: Option<String>,
+        idle_timeout: Duration,
+        session_context: SessionContext,
+        worker_pool: &mut WorkerPool,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel::<HandlerMessage>(10);
+        let (datagrams_tx, datagrams_rx) = mpsc::unbounded_channel();
+        let task = worker_pool.spawn(move || {
+            serve_peer(
+                peer,
+                tftp_root,
+                default_root,
+                rx,
+                datagrams_rx,
+                idle_timeout,
+                session_context,
+            )
+        });
+        Self {
+            sender_address: peer,
+            requests_channel: tx,
+            datagrams_channel: datagrams_tx,
+            run: HandlerRun::Pooled(task),
+            last_fed: time::Instant::now(),
         }
     }
 
-    pub(super) fn shutdown(self) {
+    pub(super) fn shutdown(self) -> HandlerExitReason {
         eprintln!("{self}: Shutdown requested");
+        self.join()
+    }
+
+    // Doesn't block if the handler is already finished.
+    pub(super) fn join(self) -> HandlerExitReason {
         drop(self.requests_channel);
-        self.thread_handle.join().expect("Can't join thread");
+        match self.run {
+            HandlerRun::Thread(handle) => handle.join().ok(),
+            HandlerRun::Pooled(task) => task.join(),
+        }
+        .unwrap_or(HandlerExitReason::Panicked)
     }
 
-    pub(super) async fn feed(&mut self, sender_port: u16, request: ReadRequest) -> bool {
+    pub(super) async fn feed(
+        &mut self,
+        reply_source: ReplySource,
+        sender_port: u16,
+        request: ReadRequest,
+    ) -> bool {
+        self.last_fed = time::Instant::now();
         self.requests_channel
-            .send((sender_port, request))
+            .send(HandlerMessage::Request(reply_source, sender_port, request))
             .await
             .is_ok()
     }
 
+    // False if the handler is gone or too busy to take the message right away.
+    pub(super) fn reload_roots(&self) -> bool {
+        eprintln!("{self}: Reload of roots requested");
+        self.requests_channel
+            .try_send(HandlerMessage::ReloadRoots)
+            .is_ok()
+    }
+
+    // Passes a datagram received on the listen socket to the session replying to the sender port.
+    pub(super) fn forward(&self, sender_port: u16, datagram: &[u8]) {
+        _ = self
+            .datagrams_channel
+            .send((sender_port, datagram.to_vec()));
+    }
+
+    // When the handler was created or last given a request.
+    pub(super) fn last_fed(&self) -> time::Instant {
+        self.last_fed
+    }
+
     pub(super) fn is_finished(&self) -> bool {
-        self.thread_handle.is_finished()
+        match &self.run {
+            HandlerRun::Thread(handle) => handle.is_finished(),
+            HandlerRun::Pooled(task) => task.is_finished(),
+        }
+    }
+
+    pub(super) fn is_pooled(&self) -> bool {
+        matches!(self.run, HandlerRun::Pooled(_))
+    }
+
+    pub(super) fn root_lister(&self) -> RootLister {
+        RootLister(self.requests_channel.downgrade())
     }
 }
 
+// Sessions of a peer by the peer port. Every session reports its end, so its entry goes away at once.
+struct Sessions {
+    handles: HashMap<u16, JoinHandle<()>>,
+    // Sessions replying from the listen port receive the datagrams of the peer through these.
+    inboxes: HashMap<u16, UnboundedSender<Vec<u8>>>,
+    finished_tx: UnboundedSender<u16>,
+    finished_rx: UnboundedReceiver<u16>,
+}
+
+impl Sessions {
+    fn new() -> Self {
+        let (finished_tx, finished_rx) = mpsc::unbounded_channel();
+        Self {
+            handles: HashMap::with_capacity(MAX_SESSIONS_PER_IP),
+            inboxes: HashMap::new(),
+            finished_tx,
+            finished_rx,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    fn contains(&self, peer_port: u16) -> bool {
+        self.handles.contains_key(&peer_port)
+    }
+
+    fn insert(
+        &mut self,
+        peer_port: u16,
+        session: JoinHandle<()>,
+        inbox: Option<UnboundedSender<Vec<u8>>>,
+    ) {
+        if let Some(inbox) = inbox {
+            self.inboxes.insert(peer_port, inbox);
+        }
+        let finished_tx = self.finished_tx.clone();
+        let handle = tokio::task::spawn_local(async move {
+            _ = session.await;
+            _ = finished_tx.send(peer_port);
+        });
+        self.handles.insert(peer_port, handle);
+    }
+
+    // Waits for any session to end and returns its peer port.
+    async fn finished(&mut self) -> u16 {
+        // The channel can't close, as the sender is kept along with the receiver.
+        let peer_port = self.finished_rx.recv().await.unwrap();
+        self.handles.remove(&peer_port);
+        self.inboxes.remove(&peer_port);
+        peer_port
+    }
+
+    fn remove_finished(&mut self) {
+        while let Ok(peer_port) = self.finished_rx.try_recv() {
+            self.handles.remove(&peer_port);
+            self.inboxes.remove(&peer_port);
+        }
+    }
+
+    // False if no session replying from the listen port serves the peer port.
+    fn deliver(&self, peer_port: u16, datagram: Vec<u8>) -> bool {
+        self.inboxes
+            .get(&peer_port)
+            .is_some_and(|inbox| inbox.send(datagram).is_ok())
+    }
+
+    async fn join(self) {
+        for (_peer_port, handle) in self.handles {
+            _ = handle.await;
+        }
+    }
+}
+
+async fn serve_peer(
+    peer: IpAddr,
+    tftp_root: PathBuf,
+    default_root: Option<String>,
+    rx_channel: Receiver<HandlerMessage>,
+    datagrams: UnboundedReceiver<(u16, Vec<u8>)>,
+    idle_timeout: Duration,
+    session_context: SessionContext,
+) -> HandlerExitReason {
+    let disk_cache = session_context.disk_cache.clone();
+    let mut peer_roots = PeerRoots::new(peer, tftp_root, default_root);
+    peer_roots.populate(&session_context, &disk_cache);
+    let exit_reason = peer_requests_handler(
+        peer,
+        &mut peer_roots,
+        rx_channel,
+        datagrams,
+        idle_timeout,
+        session_context,
+    )
+    .await;
+    eprintln!("{peer}: Handler closed: {exit_reason}");
+    stats::forget_roots(peer_roots.configs());
+    // Only an idle handler is likely to be recreated soon, a shut down one is gone for good.
+    if exit_reason == HandlerExitReason::IdleTimeout && disk_cache.is_enabled() {
+        for (config_key, remote_root) in peer_roots.take_remote_roots() {
+            eprintln!("{peer}: Caching {remote_root}");
+            disk_cache.put(config_key, remote_root);
+        }
+    }
+    exit_reason
+}
+
 async fn peer_requests_handler(
     peer: IpAddr,
-    local_address: IpAddr,
-    available_roots: Vec<RootKind>,
-    mut rx_channel: Receiver<(u16, ReadRequest)>,
+    peer_roots: &mut PeerRoots,
+    mut rx_channel: Receiver<HandlerMessage>,
+    mut datagrams: UnboundedReceiver<(u16, Vec<u8>)>,
     idle_timeout: Duration,
-) {
-    let mut send_sessions: HashMap<u16, JoinHandle<()>> =
-        HashMap::with_capacity(MAX_SESSIONS_PER_IP);
+    session_context: SessionContext,
+) -> HandlerExitReason {
+    let mut send_sessions = Sessions::new();
     let mut last_active = time::Instant::now();
-    loop {
-        let (peer_port, request) = match timeout(Duration::from_secs(1), rx_channel.recv()).await {
-            Ok(Some(result)) => result,
-            Ok(None) => {
-                eprintln!("{peer}: Handler shutdown is requested");
-                break;
-            }
-            Err(_elapsed) => {
-                send_sessions.retain(|_peer_port, handle| !handle.is_finished());
-                if send_sessions.is_empty() {
-                    if time::Instant::now() - last_active > idle_timeout {
-                        eprintln!("{peer}: Handler inactive, shutting down");
-                        break;
+    let exit_reason = loop {
+        if peer_roots.is_retry_due() {
+            eprintln!("{peer}: Retrying the configs failed to connect");
+            peer_roots.reload();
+        }
+        let (reply_source, peer_port, request) = tokio::select! {
+            received = timeout(Duration::from_secs(1), rx_channel.recv()) => match received {
+                Ok(Some(HandlerMessage::Request(reply_source, peer_port, request))) => {
+                    (reply_source, peer_port, request)
+                }
+                Ok(Some(HandlerMessage::ReloadRoots)) => {
+                    eprintln!("{peer}: Reloading roots");
+                    peer_roots.reload();
+                    continue;
+                }
+                Ok(Some(HandlerMessage::ListDir(config, path, reply))) => {
+                    eprintln!("{peer}: Listing {path:?} of config {config:?}");
+                    peer_roots.list_dir(&config, path, reply);
+                    continue;
+                }
+                Ok(None) => {
+                    eprintln!("{peer}: Handler shutdown is requested");
+                    break HandlerExitReason::ShutdownRequested;
+                }
+                Err(_elapsed) => {
+                    if send_sessions.is_empty() {
+                        // A zero timeout keeps the handler until it is shut down, and so does a prewarm
+                        // config.
+                        if !idle_timeout.is_zero()
+                            && !peer_roots.prewarmed
+                            && last_active.elapsed() > idle_timeout
+                        {
+                            eprintln!("{peer}: Handler inactive, shutting down");
+                            break HandlerExitReason::IdleTimeout;
+                        }
+                    } else {
+                        last_active = time::Instant::now();
                     }
-                } else {
-                    last_active = time::Instant::now();
+                    continue;
+                }
+            },
+            connected = peer_roots.connected() => {
+                match connected {
+                    Some(connected) => {
+                        eprintln!("{peer}: Reloaded roots are connected");
+                        peer_roots.swap_in(&session_context, connected);
+                    }
+                    None => eprintln!("{peer}: Reload of roots is lost"),
+                }
+                continue;
+            }
+            peer_port = send_sessions.finished() => {
+                eprintln!("{peer}: Session from port {peer_port} is finished");
+                last_active = time::Instant::now();
+                continue;
+            }
+            Some((peer_port, datagram)) = datagrams.recv() => {
+                if !send_sessions.deliver(peer_port, datagram) {
+                    quiet_log::log!(
+                        "{peer}: Ignore datagram from port {peer_port} without a session"
+                    );
                 }
                 continue;
             }
         };
-        eprintln!("{peer}: sessions: {:?}", send_sessions.len());
-        if send_sessions.contains_key(&peer_port) {
-            eprintln!("{peer}: Ignore repeated request from port {peer_port}");
+        send_sessions.remove_finished();
+        quiet_log::log!("{peer}: sessions: {:?}", send_sessions.len());
+        // Requests are taken one at a time and a session is registered before the next one is taken,
+        // so a retransmitted RRQ never starts a second session for the same port.
+        if send_sessions.contains(peer_port) {
+            quiet_log::log!("{peer}: Ignore repeated request from port {peer_port}");
             continue;
         };
-        let local_socket = UdpSocket::bind(SocketAddr::new(local_address, 0))
-            .await
-            .unwrap_or_else(|err| {
-                panic!("Can't bind to address {local_address} to random port dues to {err}")
-            });
-        let datagram_stream = DatagramStream::new(local_socket, SocketAddr::new(peer, peer_port));
-        let mut buffer: Vec<u8> = vec![0; u16::MAX as usize];
-        send_sessions.retain(|_peer_port, handle| !handle.is_finished());
-        if send_sessions.len() >= send_sessions.capacity() {
+        let peer_address = SocketAddr::new(peer, peer_port);
+        let (datagram_stream, inbox) = match reply_source {
+            ReplySource::Ephemeral(local_address) => {
+                let local_socket = match UdpSocket::bind(SocketAddr::new(local_address, 0)).await {
+                    Ok(local_socket) => local_socket,
+                    Err(err) => {
+                        break HandlerExitReason::Error(format!(
+                            "Can't bind to address {local_address} to random port due to {err}"
+                        ));
+                    }
+                };
+                if let Some(dscp) = session_context.dscp
+                    && let Err(err) = set_dscp(&local_socket, dscp)
+                {
+                    eprintln!("{peer}: Can't mark replies with DSCP {dscp}: {err}");
+                }
+                session_context
+                    .socket_buffers
+                    .apply(&local_socket, peer, false);
+                match DatagramStream::connect(local_socket, peer_address).await {
+                    Ok(datagram_stream) => (datagram_stream, None),
+                    Err(err) => {
+                        eprintln!("{peer}: Can't connect to port {peer_port}: {err}");
+                        continue;
+                    }
+                }
+            }
+            ReplySource::Listen(listen_socket) => match UdpSocket::from_std(listen_socket) {
+                Ok(local_socket) => {
+                    let (inbox_tx, inbox_rx) = mpsc::unbounded_channel();
+                    let datagram_stream =
+                        DatagramStream::shared(local_socket, peer_address, inbox_rx);
+                    (datagram_stream, Some(inbox_tx))
+                }
+                Err(err) => {
+                    eprintln!("{peer}: Can't reply from the listen socket: {err}");
+                    continue;
+                }
+            },
+        };
+        let datagram_stream =
+            datagram_stream.with_request_id(REQUEST_IDS.fetch_add(1, Ordering::Relaxed));
+        let mut buffer = session_context.buffer_pool.lease(u16::MAX as usize);
+        if send_sessions.len() >= MAX_SESSIONS_PER_IP {
             let error_message = "Maximum sessions per IP exceeded";
             eprintln!("{peer}: {error_message}");
             let tftp_error = TFTPError::undefined(error_message);
@@ -336,67 +1403,189 @@ async fn peer_requests_handler(
         };
         send_sessions.insert(
             peer_port,
-            schedule_task(request, datagram_stream, &available_roots, buffer),
+            schedule_task(
+                request,
+                datagram_stream,
+                &peer_roots.roots,
+                &session_context,
+                buffer,
+            ),
+            inbox,
         );
-    }
+    };
     rx_channel.close();
     if !send_sessions.is_empty() {
         eprintln!("{peer}: Waiting sessions to finish ...");
     }
-    for (_peer_port, handle) in send_sessions {
-        _ = handle.await;
+    send_sessions.join().await;
+    exit_reason
+}
+
+// Any open error but a missing file stops the search through the roots.
+fn open_error_reply(error: &io::Error) -> TFTPError {
+    match error.kind() {
+        io::ErrorKind::NotFound => TFTPError::file_not_found(),
+        io::ErrorKind::PermissionDenied => TFTPError::access_violation(),
+        io::ErrorKind::IsADirectory => TFTPError::is_a_directory(),
+        io::ErrorKind::FileTooLarge => TFTPError::file_too_large(),
+        io::ErrorKind::TimedOut => TFTPError::timed_out(),
+        io::ErrorKind::StorageFull => TFTPError::disk_full(),
+        io::ErrorKind::NotADirectory => TFTPError::undefined("Server root is misconfigured"),
+        _ => TFTPError::undefined("Server Error"),
     }
 }
 
-fn schedule_task(
+fn spawn_send<O: OpenedFile + Send + 'static>(
+    opened_file: O,
     request: ReadRequest,
+    root: &dyn Display,
+    datagram_stream: DatagramStream,
+    session_context: &SessionContext,
+    buffer: PooledBuffer,
+) -> JoinHandle<()> {
+    let served_file = ServedFile {
+        name: request.filename().to_string(),
+        root: root.to_string(),
+    };
+    let netascii = request.is_netascii();
+    let options = request.yield_options();
+    let session_context = session_context.clone();
+    if netascii {
+        let opened_file = Netascii::new(opened_file);
+        tokio::task::spawn_local(send(
+            opened_file,
+            served_file,
+            datagram_stream,
+            options,
+            session_context,
+            buffer,
+        ))
+    } else {
+        tokio::task::spawn_local(send(
+            opened_file,
+            served_file,
+            datagram_stream,
+            options,
+            session_context,
+            buffer,
+        ))
+    }
+}
+
+fn schedule_task(
+    mut request: ReadRequest,
     datagram_stream: DatagramStream,
     available_roots: &[RootKind],
-    buffer: Vec<u8>,
+    session_context: &SessionContext,
+    buffer: PooledBuffer,
 ) -> JoinHandle<()> {
     'done: {
-        for root in available_roots {
-            let error = match root {
-                RootKind::Local(local_root) => match request.open_in(local_root) {
-                    Ok(opened_local_file) => {
-                        break 'done tokio::task::spawn_local(send(
-                            opened_local_file,
-                            datagram_stream,
-                            request.yield_options(),
-                            buffer,
-                        ));
+        let file_filter = &session_context.file_filter;
+        if !file_filter.permits(request.filename()) {
+            eprintln!("{datagram_stream}: {request} is denied by {file_filter}");
+            break 'done tokio::task::spawn_local(fire_error(
+                TFTPError::access_violation(),
+                datagram_stream,
+                buffer,
+            ));
+        }
+        let fallbacks = session_context
+            .fallback_files
+            .candidates(request.filename());
+        let filename = request.filename().to_string();
+        // The upstream is asked last, for the requested file and then for the fallbacks in order.
+        let upstream_paths: Vec<String> = iter::once(filename.trim_start_matches('/').to_string())
+            .chain(fallbacks.iter().cloned())
+            .collect();
+        let mut fallbacks = fallbacks.into_iter();
+        loop {
+            for root in available_roots {
+                let error = match root {
+                    RootKind::Local(local_root) => {
+                        match request.open_in(local_root, &datagram_stream) {
+                            Ok(opened_local_file) => {
+                                break 'done spawn_send(
+                                    opened_local_file,
+                                    request,
+                                    local_root,
+                                    datagram_stream,
+                                    session_context,
+                                    buffer,
+                                );
+                            }
+                            Err(err) => err,
+                        }
                     }
-                    Err(err) => err,
-                },
-                RootKind::Remote(remote_root) => match request.open_in(remote_root) {
-                    Ok(opened_local_file) => {
-                        break 'done tokio::task::spawn_local(send(
-                            opened_local_file,
-                            datagram_stream,
-                            request.yield_options(),
-                            buffer,
-                        ));
+                    RootKind::Remote(remote_root) => {
+                        match request.open_in(remote_root, &datagram_stream) {
+                            Ok(opened_remote_file) => {
+                                break 'done spawn_send(
+                                    opened_remote_file,
+                                    request,
+                                    remote_root,
+                                    datagram_stream,
+                                    session_context,
+                                    buffer,
+                                );
+                            }
+                            Err(err) => err,
+                        }
                     }
-                    Err(err) => err,
-                },
-            };
-            match error.kind() {
-                io::ErrorKind::NotFound => continue,
-                io::ErrorKind::PermissionDenied => {
-                    break 'done tokio::task::spawn_local(fire_error(
-                        TFTPError::access_violation(),
-                        datagram_stream,
-                        buffer,
-                    ));
-                }
-                _error => {
-                    break 'done tokio::task::spawn_local(fire_error(
-                        TFTPError::undefined("Server Error"),
-                        datagram_stream,
-                        buffer,
-                    ));
+                    RootKind::Exec(exec_root) => match request.open_in(exec_root, &datagram_stream)
+                    {
+                        Ok(generated_file) => {
+                            break 'done spawn_send(
+                                generated_file,
+                                request,
+                                exec_root,
+                                datagram_stream,
+                                session_context,
+                                buffer,
+                            );
+                        }
+                        Err(err) => err,
+                    },
+                    RootKind::Upstream(_) => continue,
+                };
+                match error.kind() {
+                    io::ErrorKind::NotFound => continue,
+                    io::ErrorKind::NotADirectory => {
+                        eprintln!("{datagram_stream}: Root is misconfigured: {error}");
+                    }
+                    io::ErrorKind::TimedOut => {
+                        eprintln!("{datagram_stream}: Timed out opening {request}: {error}");
+                    }
+                    _ => {}
                 }
+                break 'done tokio::task::spawn_local(fire_error(
+                    open_error_reply(&error),
+                    datagram_stream,
+                    buffer,
+                ));
             }
+            let Some(fallback) = fallbacks.next() else {
+                break;
+            };
+            eprintln!("{datagram_stream}: {request} is not found, falling back to {fallback}");
+            request = request.redirect(fallback);
+        }
+        let upstream_root = available_roots.iter().find_map(|root| match root {
+            RootKind::Upstream(upstream_root) => Some(upstream_root),
+            _ => None,
+        });
+        if let Some(upstream_root) = upstream_root {
+            eprintln!(
+                "{datagram_stream}: Opening {} in {upstream_root} ...",
+                upstream_paths.join(", ")
+            );
+            break 'done spawn_send(
+                upstream_root.open_first(upstream_paths),
+                request.redirect(filename),
+                upstream_root,
+                datagram_stream,
+                session_context,
+                buffer,
+            );
         }
         tokio::task::spawn_local(fire_error(
             TFTPError::file_not_found(),
@@ -406,31 +1595,212 @@ fn schedule_task(
     }
 }
 
-async fn send<O: OpenedFile>(
-    mut opened_file: O,
+async fn send<O: OpenedFile + Send + 'static>(
+    opened_file: O,
+    served_file: ServedFile,
     datagram_stream: DatagramStream,
     options: HashMap<String, String>,
-    mut buffer: Vec<u8>,
+    session_context: SessionContext,
+    mut buffer: PooledBuffer,
 ) {
-    if let Some((window, ack_timeout)) =
-        negotiate_options(&datagram_stream, &mut opened_file, &mut buffer, &options).await
-    {
-        match send_file(
-            opened_file,
+    let _active_session = ActiveSession::begin();
+    let started = time::Instant::now();
+    let session = send_session(
+        opened_file,
+        &served_file,
+        &datagram_stream,
+        options,
+        &session_context,
+        &mut buffer,
+        started,
+    );
+    let Some(max_transfer_time) = session_context.max_transfer_time else {
+        return session.await;
+    };
+    // Unlike the ACK timeout, the watchdog catches a session stuck anywhere, in retransmits or reads alike.
+    let finished = tokio::select! {
+        () = session => true,
+        () = tokio::time::sleep(max_transfer_time) => false,
+    };
+    if !finished {
+        eprintln!(
+            "{datagram_stream}: Session exceeded the maximum transfer time {max_transfer_time:?}, cancelled"
+        );
+        fire_error(
+            TFTPError::transfer_timed_out(),
             &datagram_stream,
+            &mut buffer,
+        )
+        .await;
+        session_context.stats_reporter.report(TransferRecord {
+            bytes: 0,
+            duration: started.elapsed(),
+            completed: false,
+        });
+    }
+}
+
+async fn send_session<O: OpenedFile + Send + 'static>(
+    mut opened_file: O,
+    served_file: &ServedFile,
+    datagram_stream: &DatagramStream,
+    options: HashMap<String, String>,
+    session_context: &SessionContext,
+    buffer: &mut [u8],
+    started: time::Instant,
+) {
+    // Files generated or relayed are only opened here, their errors are answered like any open error.
+    if let Err(error) = poll_fn(|cx| opened_file.poll_open(cx)).await {
+        eprintln!("{datagram_stream}: Failed to open {opened_file}: {error}");
+        return fire_error(open_error_reply(&error), datagram_stream, buffer).await;
+    }
+    // Taken once for both the size limit and the tsize option.
+    let mut tsize = None;
+    if session_context.max_file_size.is_some() || TSize::is_requested(&options) {
+        match TSize::obtain_offloaded(opened_file).await {
+            Ok((sized_file, obtained)) => {
+                opened_file = sized_file;
+                tsize = Some(obtained);
+            }
+            Err(error) => {
+                eprintln!("{datagram_stream}: File is lost while sizing: {error}");
+                return fire_error(
+                    TFTPError::undefined("Read file error occurred"),
+                    datagram_stream,
+                    buffer,
+                )
+                .await;
+            }
+        }
+    }
+    match (&tsize, session_context.max_file_size) {
+        (Some(Ok(obtained)), Some(max_file_size)) if obtained.file_size() > max_file_size => {
+            eprintln!(
+                "{datagram_stream}: {opened_file} size {} exceeds {max_file_size}",
+                obtained.file_size()
+            );
+            return fire_error(TFTPError::file_too_large(), datagram_stream, buffer).await;
+        }
+        // Past the limit a transformed file isn't sized at all.
+        (Some(Err(error)), _) if error.kind() == io::ErrorKind::FileTooLarge => {
+            eprintln!("{datagram_stream}: {opened_file} is too large to size");
+            return fire_error(TFTPError::file_too_large(), datagram_stream, buffer).await;
+        }
+        // Relayed without tsize, the size is only known as the file arrives, where the limit is kept.
+        (Some(Err(error)), _) if error.kind() == io::ErrorKind::Unsupported => {}
+        (Some(Err(error)), Some(_)) => {
+            eprintln!("{datagram_stream}: Failed to get {opened_file} size: {error}");
+            return fire_error(
+                TFTPError::undefined("Read file error occurred"),
+                datagram_stream,
+                buffer,
+            )
+            .await;
+        }
+        _ => {}
+    }
+    let mut file_hash = None;
+    if let Some(algorithm) = FileHash::find_in(&options) {
+        match FileHash::obtain(opened_file, algorithm).await {
+            Ok((hashed_file, obtained)) => {
+                opened_file = hashed_file;
+                match obtained {
+                    Ok(obtained) => file_hash = Some(obtained),
+                    Err(err) => {
+                        eprintln!("{datagram_stream}: Can't obtain {algorithm} hash due to {err:?}")
+                    }
+                }
+            }
+            Err(error) => {
+                eprintln!("{datagram_stream}: File is lost while hashing: {error}");
+                return fire_error(
+                    TFTPError::undefined("Read file error occurred"),
+                    datagram_stream,
+                    buffer,
+                )
+                .await;
+            }
+        }
+    }
+    if let Some(negotiated) = negotiate_options(
+        datagram_stream,
+        &mut opened_file,
+        buffer,
+        &options,
+        tsize,
+        file_hash,
+        session_context,
+    )
+    .await
+    {
+        eprintln!(
+            "{datagram_stream}: Transfer started {}",
+            negotiated.summary(datagram_stream.peer_address(), served_file)
+        );
+        let Negotiated {
             window,
             ack_timeout,
-            &mut buffer,
+            connect_timeout,
+            ..
+        } = negotiated;
+        let window = if session_context.adaptive_window {
+            window.adaptive()
+        } else {
+            window
+        };
+        let window = window
+            .paced(session_context.interpacket_gap)
+            .dallying(session_context.final_ack_grace);
+        let ack_timeout = ack_timeout.with_jitter(session_context.retransmit_jitter);
+        let record = match send_bounded(
+            Offloaded::new(opened_file),
+            datagram_stream,
+            window,
+            ack_timeout,
+            connect_timeout,
+            buffer,
         )
         .await
         {
             Ok((sent_bytes, sent_blocks)) => {
-                eprintln!("{datagram_stream}: Sent {sent_bytes} bytes, {sent_blocks} blocks")
+                eprintln!("{datagram_stream}: Sent {sent_bytes} bytes, {sent_blocks} blocks");
+                TransferRecord {
+                    bytes: sent_bytes,
+                    duration: started.elapsed(),
+                    completed: true,
+                }
+            }
+            Err(tftp_error) => {
+                fire_error(tftp_error, datagram_stream, buffer).await;
+                TransferRecord {
+                    bytes: 0,
+                    duration: started.elapsed(),
+                    completed: false,
+                }
             }
-            Err(tftp_error) => fire_error(tftp_error, &datagram_stream, &mut buffer).await,
         };
-        drop(buffer);
-        drop(datagram_stream);
+        session_context.stats_reporter.report(record);
+    }
+}
+
+async fn send_bounded<O: AsyncOpenedFile>(
+    opened_file: O,
+    datagram_stream: &DatagramStream,
+    window: Window,
+    ack_timeout: AckTimeout,
+    connect_timeout: Option<ConnectTimeout>,
+    buffer: &mut [u8],
+) -> Result<(usize, usize), TFTPError> {
+    let transfer = send_file(opened_file, datagram_stream, window, ack_timeout, buffer);
+    let Some(connect_timeout) = connect_timeout else {
+        return transfer.await;
+    };
+    match connect_timeout.bound(transfer).await {
+        Ok(result) => result,
+        Err(_elapsed) => {
+            eprintln!("{datagram_stream}: Transfer exceeded the connect timeout {connect_timeout}");
+            Err(TFTPError::transfer_timed_out())
+        }
     }
 }
 
@@ -444,37 +1814,63 @@ async fn send_reliably(
 ) -> Result<u16, SendError> {
     for attempt in 1..=SEND_ATTEMPTS {
         for block_index in (0..count).map(|v| window_index.wrapping_add(v)) {
+            if block_index != window_index && !window.gap.is_zero() {
+                tokio::time::sleep(window.gap).await;
+            }
             if let Err(send_error) = window.send(block_index, datagram_stream).await {
+                if is_client_gone(&send_error) {
+                    eprintln!("{datagram_stream}: Client is gone: {send_error}");
+                    return Err(SendError::ClientGone);
+                }
                 eprintln!(
                     "{datagram_stream}: Network error while sending block {block_index}: {send_error}"
                 );
                 return Err(SendError::Network);
             }
         }
-        return match read_acknowledge(datagram_stream, buffer, ack_timeout).await {
-            Ok(received_ack) if received_ack >= window_index => Ok(received_ack),
-            Ok(unexpected_ack) => {
-                let tftp_error = TFTPError::undefined("Received ACK from the past");
-                eprintln!(
-                    "{datagram_stream}: Received ACK {unexpected_ack} while expected > {window_index}"
-                );
-                fire_error(tftp_error, datagram_stream, buffer).await;
-                Err(SendError::ACKError)
+        // ACKs queued behind the one read, or arrived right after the timeout, may cover more of the window,
+        // so the blocks they acknowledge are not sent again.
+        let received_ack = match read_acknowledge(datagram_stream, buffer, ack_timeout).await {
+            Ok(received_ack) => drain_acknowledges(
+                datagram_stream,
+                buffer,
+                window_index,
+                count,
+                Some(received_ack),
+            )
+            .map(|highest| highest.unwrap_or(received_ack)),
+            Err(RecvError::Timeout) => {
+                match drain_acknowledges(datagram_stream, buffer, window_index, count, None) {
+                    Ok(Some(late_ack)) => {
+                        quiet_log::log!("{datagram_stream}: ACK {late_ack} arrived late");
+                        Ok(late_ack)
+                    }
+                    Ok(None) => Err(RecvError::Timeout),
+                    Err(error) => Err(error),
+                }
             }
+            Err(error) => Err(error),
+        };
+        // Block indexes wrap around, so an ACK is told to be in the window by its offset from the start.
+        return match received_ack {
+            Ok(received_ack) if received_ack.wrapping_sub(window_index) < count => Ok(received_ack),
+            Ok(past_ack) => Err(SendError::PastAck(past_ack)),
             Err(RecvError::Timeout) => {
                 let window_end_index = window_index.wrapping_add(count);
-                eprintln!(
+                quiet_log::log!(
                     "{datagram_stream}: Timeout waiting for {window_index} .. {window_end_index}, attempt {attempt}"
                 );
+                window.shrink();
                 continue;
             }
             Err(RecvError::ClientError(error_code, error_message)) => {
                 Err(SendError::ClientError(error_code, error_message))
             }
+            Err(RecvError::ClientGone) => Err(SendError::ClientGone),
             Err(_) => Err(SendError::Network),
         };
     }
-    Err(SendError::Timeout)
+    Err(SendError::Timeout(window_index, SEND_ATTEMPTS))
 }
 
 async fn send_oack_reliably(
@@ -482,7 +1878,7 @@ async fn send_oack_reliably(
     datagram_stream: &DatagramStream,
     ack_timeout: &AckTimeout,
     buffer: &mut [u8],
-) -> io::Result<()> {
+) -> io::Result<OptionsReply> {
     let oack_index = 0;
     let oack_size = match oack.serialize(buffer) {
         Ok(size) => size,
@@ -497,7 +1893,13 @@ async fn send_oack_reliably(
     for attempt in 1..=SEND_ATTEMPTS {
         datagram_stream.send(&buffer[..oack_size]).await?;
         match read_acknowledge(datagram_stream, buffer, ack_timeout).await {
-            Ok(ack_num) if ack_num == oack_index => return Ok(()),
+            Ok(ack_num) if ack_num == oack_index => return Ok(OptionsReply::Acknowledged),
+            Ok(ack_num) if ack_num == oack_index + 1 => {
+                eprintln!(
+                    "{datagram_stream}: Received ACK {ack_num} while expecting {oack_index}, proceed with default options"
+                );
+                return Ok(OptionsReply::Skipped);
+            }
             Ok(ack_num) => {
                 let tftp_error = TFTPError::undefined("Unexpected non-zero ACK");
                 fire_error(tftp_error, datagram_stream, buffer).await;
@@ -509,6 +1911,9 @@ async fn send_oack_reliably(
                 eprintln!("Timeout waiting for ACK {oack_index}, attempt {attempt}");
                 continue;
             }
+            Err(RecvError::ClientError(OPTION_NEGOTIATION, string)) => {
+                return Ok(OptionsReply::Declined(string));
+            }
             Err(RecvError::ClientError(code, string)) => {
                 return Err(io::Error::other(format!(
                     "Early termination while options negotiation [{code}] {string}"
@@ -532,8 +1937,19 @@ async fn negotiate_options<O: OpenedFile>(
     opened_file: &mut O,
     buffer: &mut [u8],
     options: &HashMap<String, String>,
-) -> Option<(Window, AckTimeout)> {
-    let mut oack = OptionsAcknowledge::new();
+    obtained_tsize: Option<io::Result<TSize>>,
+    file_hash: Option<FileHash>,
+    session_context: &SessionContext,
+) -> Option<Negotiated> {
+    if session_context.no_oack {
+        // The client isn't told about any option, so it can only expect the defaults.
+        if !options.is_empty() {
+            eprintln!("{datagram_stream}: Ignoring options {options:?}");
+        }
+        return Some(Negotiated::defaults(&session_context.buffer_pool));
+    }
+    let session_limits = &session_context.session_limits;
+    let mut oack = OptionsAcknowledge::new().with_final_null(session_context.oack_final_null);
     let ack_timeout = {
         if let Some(timeout) = AckTimeout::find_in(options) {
             oack.push(timeout.as_key_pair());
@@ -542,37 +1958,93 @@ async fn negotiate_options<O: OpenedFile>(
             Default::default()
         }
     };
+    let connect_timeout = ConnectTimeout::find_in(options);
+    if let Some(connect_timeout) = &connect_timeout {
+        oack.push(connect_timeout.as_key_pair());
+    }
     let block_size = {
         if let Some(block_size) = Blksize::find_in(options) {
+            let peer = datagram_stream.peer_address().ip();
+            let block_size = block_size.clamp(&session_limits.block_size_range(peer));
             oack.push(block_size.as_key_pair());
             block_size
         } else {
             Default::default()
         }
     };
+    let mut tsize = None;
     if TSize::is_requested(options) {
-        match TSize::obtain(opened_file) {
-            Ok(tsize) => oack.push(tsize.as_key_pair()),
-            Err(err) => {
+        // Some clients send garbage, it is answered with the file size all the same.
+        match TSize::parse(options) {
+            Some(0) => {}
+            Some(size) => eprintln!("{datagram_stream}: Ignore tsize {size} requested on read"),
+            None => eprintln!("{datagram_stream}: Ignore malformed tsize requested on read"),
+        }
+        match obtained_tsize {
+            Some(Ok(obtained)) => {
+                oack.push(obtained.as_key_pair());
+                tsize = Some(obtained.file_size());
+            }
+            Some(Err(err)) => {
                 eprintln!("{datagram_stream}: Can't obtain TSize due to {err:?}")
             }
+            None => {}
         }
     };
+    if Mtime::is_requested(options) {
+        match Mtime::obtain(opened_file) {
+            Ok(mtime) => oack.push(mtime.as_key_pair()),
+            Err(err) => {
+                eprintln!("{datagram_stream}: Can't obtain mtime due to {err:?}")
+            }
+        }
+    };
+    if let Some(file_hash) = file_hash {
+        oack.push(file_hash.as_key_pair());
+    };
     let window_size = {
         if let Some(window_size) = WindowSize::find_in(options) {
+            let window_size = window_size.clamp(session_limits.max_window_size());
             oack.push(window_size.as_key_pair());
             window_size
         } else {
             Default::default()
         }
     };
-    if oack.has_options()
-        && let Err(oack_negotiation_error) =
-            send_oack_reliably(&oack, datagram_stream, &ack_timeout, buffer).await
-    {
-        eprintln!("{datagram_stream}: {oack_negotiation_error}");
+    if !session_limits.admits(&block_size, &window_size) {
+        eprintln!(
+            "{datagram_stream}: Window of {window_size} blocks of {} bytes exceeds {session_limits}",
+            block_size.get_size()
+        );
+        let tftp_error = TFTPError::option_negotiation("Requested window exceeds the server limit");
+        fire_error(tftp_error, datagram_stream, buffer).await;
         return None;
+    }
+    if oack.has_options() {
+        match send_oack_reliably(&oack, datagram_stream, &ack_timeout, buffer).await {
+            Ok(OptionsReply::Acknowledged) => {}
+            Ok(OptionsReply::Skipped) => {
+                return Some(Negotiated::defaults(&session_context.buffer_pool));
+            }
+            Ok(OptionsReply::Declined(message)) => {
+                eprintln!("{datagram_stream}: Client declined the options: {message}");
+                return None;
+            }
+            Err(oack_negotiation_error) => {
+                eprintln!("{datagram_stream}: {oack_negotiation_error}");
+                return None;
+            }
+        }
     };
-    let window = Window::new(block_size.get_size() as u16, window_size.get_size() as u16);
-    Some((window, ack_timeout))
+    let window = Window::new(
+        block_size.get_size() as u16,
+        window_size.get_size() as u16,
+        &session_context.buffer_pool,
+    );
+    Some(Negotiated {
+        window,
+        ack_timeout,
+        connect_timeout,
+        tsize,
+    })
 }
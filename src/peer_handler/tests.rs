@@ -1,74 +1,66 @@
-use crate::datagram_stream::DatagramStream;
-use crate::fs::OpenedFile;
-use crate::options::AckTimeout;
-use crate::peer_handler::{ACK, DATA, Window, send_file};
-use std::time::Duration;
-use std::{fmt, io};
+use crate::buffer_pool::BufferPool;
+use crate::datagram_stream::{
+    DatagramStream, MAX_ALIEN_DATAGRAMS, MIN_SOCKET_BUFFER, SocketBuffer, SocketBuffers,
+    set_buffer_size, set_dscp,
+};
+use crate::error::TFTPError;
+use crate::fs::{AsyncOpenedFile, OpenedFile, Root};
+use crate::offloaded::Offloaded;
+use crate::options::{AckTimeout, ConnectTimeout};
+use crate::peer_handler::{
+    ACK, DATA, HandlerExitReason, PeerHandler, PeerRoots, ServedFile, SessionContext, Sessions,
+    Window, fire_error, open_error_reply, send, send_bounded, send_file,
+};
+use crate::stats;
+use crate::stats::ServerStats;
+use crate::tests_common::client::{TFTPClient, TransferError, receive_blocks};
+use crate::tests_common::virtual_fs::{
+    Seeded, VirtualOpenedFile, VirtualRoot, generate_data, weak_pseudo_random_data,
+};
+use crate::tests_common::{ensure_prerequisite_disk, mk_tmp};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::pin::Pin;
+use std::process::{Child, Command};
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, Instant};
+use std::{fmt, io, thread};
 use tokio::join;
 use tokio::net::UdpSocket;
-use tokio::time::timeout;
+use tokio::sync::mpsc;
+use tokio::task::LocalSet;
+use tokio::time::{Sleep, sleep, timeout};
 
-fn xorshift64star(index: usize, seed: usize) -> usize {
-    let mut x = index ^ seed;
-    x ^= x >> 12;
-    x ^= x << 25;
-    x ^= x >> 27;
-    (x.wrapping_mul(0x2545F4914F6CDD1D)) >> 56
+// An async backend taking a while to read every block.
+struct SlowOpenedFile {
+    file: VirtualOpenedFile,
+    delay: Duration,
+    pending: Option<Pin<Box<Sleep>>>,
 }
 
-fn weak_pseudo_random_data(len: usize, seed: usize) -> Vec<u8> {
-    (0..len).map(|i| xorshift64star(i, seed) as u8).collect()
-}
-
-fn generate_data(size: usize) -> Vec<u8> {
-    weak_pseudo_random_data(size, size)
-}
-
-struct VirtualOpenedFile {
-    buffer: Vec<u8>,
-    offset: usize,
-}
-
-impl VirtualOpenedFile {
-    fn new(buffer: Vec<u8>) -> Self {
-        Self { buffer, offset: 0 }
-    }
-}
-
-impl fmt::Display for VirtualOpenedFile {
+impl fmt::Display for SlowOpenedFile {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "VirtualOpenedFile size {} [{}]",
-            self.buffer.len(),
-            self.offset
-        )
+        write!(f, "SlowOpenedFile {}", self.file)
     }
 }
 
-impl fmt::Debug for VirtualOpenedFile {
+impl fmt::Debug for SlowOpenedFile {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "VirtualOpenedFile size {} [{}]",
-            self.buffer.len(),
-            self.offset
-        )
+        write!(f, "SlowOpenedFile {}", self.file)
     }
 }
 
-impl OpenedFile for VirtualOpenedFile {
-    fn read_to(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
-        let slice_length = buffer.len().min(self.buffer.len() - self.offset);
-        eprintln!("{}: {} {}", self, slice_length, buffer.len());
-        buffer[..slice_length]
-            .copy_from_slice(&self.buffer[self.offset..self.offset + slice_length]);
-        self.offset += slice_length;
-        Ok(slice_length)
-    }
-
-    fn get_size(&mut self) -> io::Result<usize> {
-        Ok(self.buffer.len())
+impl AsyncOpenedFile for SlowOpenedFile {
+    fn poll_read(&mut self, cx: &mut Context<'_>, buffer: &mut [u8]) -> Poll<io::Result<usize>> {
+        let delay = self.delay;
+        let pending = self.pending.get_or_insert_with(|| Box::pin(sleep(delay)));
+        ready!(pending.as_mut().poll(cx));
+        self.pending = None;
+        Poll::Ready(self.file.read_to(buffer))
     }
 }
 
@@ -83,83 +75,45 @@ async fn make_streams() -> (DatagramStream, DatagramStream) {
     )
 }
 
-#[derive(Debug)]
-pub(crate) struct DownloadError(String);
-
-impl fmt::Display for DownloadError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0.clone())
-    }
-}
-
-impl From<io::Error> for DownloadError {
-    fn from(value: io::Error) -> Self {
-        DownloadError(value.to_string())
-    }
+// The server end of a send session and a client expecting data from it.
+async fn make_session() -> (DatagramStream, TFTPClient) {
+    let server_socket = UdpSocket::bind("127.0.0.10:0").await.unwrap();
+    let client_socket = UdpSocket::bind("127.0.0.20:0").await.unwrap();
+    let server_address = server_socket.local_addr().unwrap();
+    let client_address = client_socket.local_addr().unwrap();
+    (
+        DatagramStream::new(server_socket, client_address),
+        TFTPClient::new(client_socket, server_address),
+    )
 }
 
 async fn download_stream(
-    datagram_stream: &DatagramStream,
+    client: TFTPClient,
     block_size: u16,
     window_size: u16,
-) -> Result<Vec<u8>, DownloadError> {
-    let mut read_data: Vec<u8> = Vec::new();
-    let block_header_size = 4;
-    let expected_message_size = block_size as usize + block_header_size;
-    let mut buffer = vec![0u8; expected_message_size];
-    let mut last_received_block_index: u16 = 0;
-    let mut done = false;
-    while !done {
-        for _ in 0..window_size {
-            let recv_fut = datagram_stream.recv(&mut buffer, block_header_size);
-            let received_bytes = match timeout(Duration::from_secs(5), recv_fut).await {
-                Ok(result) => result?,
-                Err(_timeout) => return Err(DownloadError("timeout".to_string())),
-            };
-            let opcode = ((buffer[0] as u16) << 8) | buffer[1] as u16;
-            if opcode != DATA {
-                return Err(DownloadError("Wrong opcode received: {opcode}".to_string()));
-            }
-            last_received_block_index = ((buffer[2] as u16) << 8) | (buffer[3] as u16);
-            read_data.extend_from_slice(&buffer[block_header_size..received_bytes]);
-            if received_bytes < expected_message_size {
-                eprintln!(
-                    "Received {received_bytes}, expected {expected_message_size} bytes. Break"
-                );
-                done = true;
-                break;
-            }
-        }
-
-        buffer[0] = 0;
-        buffer[1] = ACK as u8;
-        buffer[2] = (last_received_block_index >> 8) as u8;
-        buffer[3] = (last_received_block_index & 0xFF) as u8;
-        datagram_stream.send(&buffer[..block_header_size]).await?;
-        eprintln!("Sent ACK for {}", last_received_block_index);
-    }
-    eprintln!("Done");
-    Ok(read_data)
+) -> Result<Vec<u8>, TransferError> {
+    let first_block = client.expect_data().read_next(5).await?;
+    receive_blocks(first_block, block_size as usize, window_size).await
 }
 
 #[tokio::test(flavor = "current_thread")]
 async fn send_aligned_data() {
     let test_data = generate_data(100);
     let opened_file = VirtualOpenedFile::new(test_data.clone());
-    let (server_stream, client_stream) = make_streams().await;
+    let (server_stream, client) = make_session().await;
     let ack_timeout = AckTimeout::default();
     let block_size = 100;
     let window_size = 1;
-    let window = Window::new(block_size, window_size);
+    let window = Window::new(block_size, window_size, &BufferPool::default());
     let mut buffer = vec![0; 1024];
     let send_coro = send_file(
-        opened_file,
+        Offloaded::new(opened_file),
         &server_stream,
         window,
         ack_timeout,
         &mut buffer,
     );
-    let recv_coro = download_stream(&client_stream, block_size, window_size);
+    let recv_coro = download_stream(client, block_size, window_size);
     let (_send_result, recv_result) = join!(send_coro, recv_coro);
     assert_eq!(recv_result.unwrap(), test_data);
 }
@@ -167,20 +121,20 @@ async fn send_aligned_data() {
 async fn send_unaligned_data() {
     let test_data = generate_data(512);
     let opened_file = VirtualOpenedFile::new(test_data.clone());
-    let (server_stream, client_stream) = make_streams().await;
+    let (server_stream, client) = make_session().await;
     let ack_timeout = AckTimeout::default();
     let block_size = 100;
     let window_size = 1;
-    let window = Window::new(block_size, window_size);
+    let window = Window::new(block_size, window_size, &BufferPool::default());
     let mut buffer = vec![0; 1024];
     let send_coro = send_file(
-        opened_file,
+        Offloaded::new(opened_file),
         &server_stream,
         window,
         ack_timeout,
         &mut buffer,
     );
-    let recv_coro = download_stream(&client_stream, block_size, window_size);
+    let recv_coro = download_stream(client, block_size, window_size);
     let (_send_result, recv_result) = join!(send_coro, recv_coro);
     assert_eq!(recv_result.unwrap(), test_data);
 }
@@ -188,20 +142,20 @@ async fn send_unaligned_data() {
 async fn send_aligned_data_windowed() {
     let test_data = generate_data(100);
     let opened_file = VirtualOpenedFile::new(test_data.clone());
-    let (server_stream, client_stream) = make_streams().await;
+    let (server_stream, client) = make_session().await;
     let ack_timeout = AckTimeout::default();
     let block_size = 100;
     let window_size = 5;
-    let window = Window::new(block_size, window_size);
+    let window = Window::new(block_size, window_size, &BufferPool::default());
     let mut buffer = vec![0; 1024];
     let send_coro = send_file(
-        opened_file,
+        Offloaded::new(opened_file),
         &server_stream,
         window,
         ack_timeout,
         &mut buffer,
     );
-    let recv_coro = download_stream(&client_stream, block_size, window_size);
+    let recv_coro = download_stream(client, block_size, window_size);
     let (_send_result, recv_result) = join!(send_coro, recv_coro);
     assert_eq!(recv_result.unwrap(), test_data);
 }
@@ -209,20 +163,1003 @@ async fn send_aligned_data_windowed() {
 async fn send_unaligned_data_windowed() {
     let test_data = generate_data(512);
     let opened_file = VirtualOpenedFile::new(test_data.clone());
-    let (server_stream, client_stream) = make_streams().await;
+    let (server_stream, client) = make_session().await;
     let ack_timeout = AckTimeout::default();
     let block_size = 100;
     let window_size = 5;
-    let window = Window::new(block_size, window_size);
+    let window = Window::new(block_size, window_size, &BufferPool::default());
     let mut buffer = vec![0; 1024];
     let send_coro = send_file(
-        opened_file,
+        Offloaded::new(opened_file),
         &server_stream,
         window,
         ack_timeout,
         &mut buffer,
     );
-    let recv_coro = download_stream(&client_stream, block_size, window_size);
+    let recv_coro = download_stream(client, block_size, window_size);
     let (_send_result, recv_result) = join!(send_coro, recv_coro);
     assert_eq!(recv_result.unwrap(), test_data);
 }
+
+const PROPERTY_CASES: usize = 40;
+
+// The seed of the random cases, overridden by RTFTP_TEST_SEED to reproduce a failure.
+fn property_seed() -> u64 {
+    std::env::var("RTFTP_TEST_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or(0x5EED_1140)
+}
+
+async fn transfer_virtual(
+    root: &VirtualRoot,
+    path: &str,
+    block_size: u16,
+    window_size: u16,
+) -> (
+    Result<(usize, usize), TFTPError>,
+    Result<Vec<u8>, TransferError>,
+) {
+    let opened_file = root.open(path).unwrap();
+    let (server_stream, client) = make_session().await;
+    let window = Window::new(block_size, window_size, &BufferPool::default());
+    let mut buffer = vec![0; u16::MAX as usize];
+    let send_coro = send_file(
+        Offloaded::new(opened_file),
+        &server_stream,
+        window,
+        AckTimeout::default(),
+        &mut buffer,
+    );
+    let recv_coro = download_stream(client, block_size, window_size);
+    join!(send_coro, recv_coro)
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn random_transfers_round_trip() {
+    let seed = property_seed();
+    let mut random = Seeded::new(seed);
+    for case in 0..PROPERTY_CASES {
+        let block_size = random.pick(8..=1468) as u16;
+        let window_size = random.pick(1..=16) as u16;
+        // Sizes at and around the block and window boundaries end the transfers in every possible way.
+        let blocks = random.pick(0..=window_size as usize * 3);
+        let tail = [0, 1, block_size as usize - 1][random.pick(0..=2)];
+        let size = blocks * block_size as usize + tail;
+        let content = weak_pseudo_random_data(size, random.next() as usize);
+        let root = VirtualRoot::default().with_file("payload.bin", content.clone());
+        let (send_result, recv_result) =
+            transfer_virtual(&root, "payload.bin", block_size, window_size).await;
+        let context = format!(
+            "seed {seed}, case {case}: {size} bytes, blksize {block_size}, windowsize {window_size}"
+        );
+        assert!(send_result.is_ok(), "{context}: {send_result:?}");
+        assert_eq!(recv_result.expect(&context), content, "{context}");
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn empty_file_sends_single_empty_block() {
+    let (server_stream, client_stream) = make_streams().await;
+    let buffer_pool = BufferPool::default();
+    let window = Window::new(512, 4, &buffer_pool);
+    let mut buffer = vec![0u8; u16::MAX as usize];
+    let send_coro = send_file(
+        Offloaded::new(VirtualOpenedFile::new(Vec::new())),
+        &server_stream,
+        window,
+        AckTimeout::default(),
+        &mut buffer,
+    );
+    let recv_coro = async {
+        let mut datagram = vec![0u8; u16::MAX as usize];
+        let size = client_stream.recv(&mut datagram, 4).await.unwrap();
+        assert_eq!(datagram[..size], [0x00, DATA as u8, 0x00, 0x01]);
+        client_stream
+            .send(&[0x00, ACK as u8, 0x00, 0x01])
+            .await
+            .unwrap();
+        // Nothing follows the final ACK.
+        let next = timeout(
+            Duration::from_millis(500),
+            client_stream.recv(&mut datagram, 4),
+        )
+        .await;
+        assert!(next.is_err(), "Unexpected {next:?}");
+    };
+    let (send_result, ()) = join!(send_coro, recv_coro);
+    assert_eq!(send_result.unwrap().0, 0);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn transfer_wraps_block_index() {
+    let block_size: u16 = 8;
+    let content = generate_data(block_size as usize * (u16::MAX as usize + 10) + 3);
+    let root = VirtualRoot::default().with_file("payload.bin", content.clone());
+    let (send_result, recv_result) = transfer_virtual(&root, "payload.bin", block_size, 16).await;
+    assert!(send_result.is_ok());
+    assert_eq!(recv_result.unwrap(), content);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn read_error_ends_transfer() {
+    let block_size: u16 = 100;
+    let root = VirtualRoot::default()
+        .with_file("payload.bin", generate_data(1000))
+        .failing_at(550);
+    let (send_result, recv_result) = transfer_virtual(&root, "payload.bin", block_size, 2).await;
+    assert_eq!(
+        send_result.unwrap_err().to_string(),
+        TFTPError::undefined("Read file error occurred").to_string()
+    );
+    assert!(recv_result.is_err());
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn queued_acks_spare_acknowledged_blocks() {
+    let block_size: u16 = 100;
+    let window_size: u16 = 3;
+    let test_data = generate_data(block_size as usize * 2 + 50);
+    let opened_file = VirtualOpenedFile::new(test_data);
+    let (server_stream, client_stream) = make_streams().await;
+    let window = Window::new(block_size, window_size, &BufferPool::default());
+    let mut buffer = vec![0u8; 1024];
+    let send_coro = send_file(
+        Offloaded::new(opened_file),
+        &server_stream,
+        window,
+        AckTimeout::default(),
+        &mut buffer,
+    );
+    // Every block but the last is acknowledged on its own, as if the ACK of the window end was lost.
+    let recv_coro = async {
+        let mut datagram = vec![0u8; 1024];
+        for _ in 0..window_size {
+            client_stream.recv(&mut datagram, 4).await.unwrap();
+        }
+        for acknowledged in 1..window_size {
+            let ack = [0x00, ACK as u8, 0x00, acknowledged as u8];
+            client_stream.send(&ack).await.unwrap();
+        }
+        let mut resent = Vec::new();
+        while let Ok(received) = timeout(
+            Duration::from_millis(200),
+            client_stream.recv(&mut datagram, 4),
+        )
+        .await
+        {
+            received.unwrap();
+            resent.push(datagram[3]);
+            let ack = [0x00, ACK as u8, 0x00, datagram[3]];
+            client_stream.send(&ack).await.unwrap();
+        }
+        resent
+    };
+    let (send_result, resent) = join!(send_coro, recv_coro);
+    assert!(send_result.is_ok());
+    assert_eq!(resent, [window_size as u8]);
+}
+
+fn alien_datagrams() -> u64 {
+    ServerStats::default().to_json()["alien_datagrams"]
+        .as_u64()
+        .unwrap()
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn spoofed_datagrams_ignored() {
+    let test_data = generate_data(1000);
+    let opened_file = VirtualOpenedFile::new(test_data.clone());
+    let server_socket = UdpSocket::bind("127.0.0.10:0").await.unwrap();
+    let client_socket = UdpSocket::bind("127.0.0.20:0").await.unwrap();
+    let server_address = server_socket.local_addr().unwrap();
+    let server_stream = DatagramStream::new(server_socket, client_socket.local_addr().unwrap());
+    let client = TFTPClient::new(client_socket, server_address);
+    let spoofer = UdpSocket::bind("127.0.0.30:0").await.unwrap();
+    let aliens_before = alien_datagrams();
+    let block_size = 100;
+    let window = Window::new(block_size, 1, &BufferPool::default());
+    let mut buffer = vec![0; 1024];
+    let send_coro = send_file(
+        Offloaded::new(opened_file),
+        &server_stream,
+        window,
+        AckTimeout::default(),
+        &mut buffer,
+    );
+    // Acknowledges blocks never sent, which would end the session if taken for the client's.
+    for _ in 0..10 {
+        let ack = [0x00, ACK as u8, 0x00, 0x10];
+        spoofer.send_to(&ack, server_address).await.unwrap();
+    }
+    let recv_coro = download_stream(client, block_size, 1);
+    let (send_result, recv_result) = join!(send_coro, recv_coro);
+    assert!(send_result.is_ok());
+    assert_eq!(recv_result.unwrap(), test_data);
+    assert!(alien_datagrams() >= aliens_before + 10);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn alien_flood_leaves_session_served() {
+    let test_data = generate_data(1000);
+    let opened_file = VirtualOpenedFile::new(test_data.clone());
+    let server_socket = UdpSocket::bind("127.0.0.10:0").await.unwrap();
+    let client_socket = UdpSocket::bind("127.0.0.20:0").await.unwrap();
+    let server_address = server_socket.local_addr().unwrap();
+    let server_stream = DatagramStream::new(server_socket, client_socket.local_addr().unwrap());
+    let client = TFTPClient::new(client_socket, server_address);
+    let spoofer = UdpSocket::bind("127.0.0.30:0").await.unwrap();
+    let aliens_before = alien_datagrams();
+    let block_size = 100;
+    let window = Window::new(block_size, 1, &BufferPool::default());
+    let mut buffer = vec![0; 1024];
+    let send_coro = send_file(
+        Offloaded::new(opened_file),
+        &server_stream,
+        window,
+        AckTimeout::default(),
+        &mut buffer,
+    );
+    for _ in 0..=MAX_ALIEN_DATAGRAMS {
+        let ack = [0x00, ACK as u8, 0x00, 0x01];
+        spoofer.send_to(&ack, server_address).await.unwrap();
+    }
+    let recv_coro = download_stream(client, block_size, 1);
+    let (send_result, recv_result) = join!(send_coro, recv_coro);
+    assert!(send_result.is_ok());
+    assert_eq!(recv_result.unwrap(), test_data);
+    assert!(alien_datagrams() > aliens_before + MAX_ALIEN_DATAGRAMS as u64);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn lost_final_ack_and_repeated_ack_answered() {
+    let block_size: u16 = 100;
+    let test_data = generate_data(block_size as usize * 2 + 50);
+    let opened_file = VirtualOpenedFile::new(test_data.clone());
+    let (server_stream, client_stream) = make_streams().await;
+    let window =
+        Window::new(block_size, 1, &BufferPool::default()).dallying(Duration::from_millis(500));
+    let ack_timeout = AckTimeout::find_in(&HashMap::from([("timeout".into(), "1".into())]));
+    let mut buffer = vec![0u8; 1024];
+    let send_coro = send_file(
+        Offloaded::new(opened_file),
+        &server_stream,
+        window,
+        ack_timeout.unwrap(),
+        &mut buffer,
+    );
+    let recv_coro = async {
+        let mut datagram = vec![0u8; 1024];
+        let mut received = Vec::new();
+        for index in 1..=3u8 {
+            let size = client_stream.recv(&mut datagram, 4).await.unwrap();
+            assert_eq!(datagram[3], index);
+            received.extend_from_slice(&datagram[4..size]);
+            // The first ACK of the final block is lost.
+            if index < 3 {
+                client_stream
+                    .send(&[0x00, ACK as u8, 0x00, index])
+                    .await
+                    .unwrap();
+            }
+        }
+        let retransmitted = timeout(Duration::from_secs(3), client_stream.recv(&mut datagram, 4));
+        retransmitted.await.unwrap().unwrap();
+        assert_eq!(datagram[3], 3);
+        client_stream
+            .send(&[0x00, ACK as u8, 0x00, 3])
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(100)).await;
+        // The client repeats its ACK, as if it didn't know the first one arrived.
+        client_stream
+            .send(&[0x00, ACK as u8, 0x00, 3])
+            .await
+            .unwrap();
+        let answered = timeout(
+            Duration::from_millis(300),
+            client_stream.recv(&mut datagram, 4),
+        );
+        answered.await.unwrap().unwrap();
+        assert_eq!(datagram[3], 3);
+        received
+    };
+    let (send_result, received) = join!(send_coro, recv_coro);
+    assert!(send_result.is_ok());
+    assert_eq!(received, test_data);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn ack_behind_window_served_again() {
+    let block_size: u16 = 100;
+    let test_data = generate_data(block_size as usize * 6 + 50);
+    let opened_file = VirtualOpenedFile::new(test_data.clone());
+    let (server_stream, client_stream) = make_streams().await;
+    let window = Window::new(block_size, 2, &BufferPool::default());
+    let mut buffer = vec![0u8; 1024];
+    let send_coro = send_file(
+        Offloaded::new(opened_file),
+        &server_stream,
+        window,
+        AckTimeout::default(),
+        &mut buffer,
+    );
+    let recv_coro = async {
+        let mut datagram = vec![0u8; 1024];
+        let mut blocks: HashMap<u8, Vec<u8>> = HashMap::new();
+        let mut receive = async |expected: u8, ack: u8| {
+            let size = client_stream.recv(&mut datagram, 4).await.unwrap();
+            assert_eq!(datagram[3], expected);
+            let block = datagram[4..size].to_vec();
+            if let Some(first) = blocks.insert(expected, block.clone()) {
+                assert_eq!(first, block);
+            }
+            if ack > 0 {
+                client_stream
+                    .send(&[0x00, ACK as u8, 0x00, ack])
+                    .await
+                    .unwrap();
+            }
+        };
+        for (expected, ack) in [(1, 0), (2, 2), (3, 0), (4, 4), (5, 0)] {
+            receive(expected, ack).await;
+        }
+        // The client lost blocks 3 and 4 after acknowledging them, so it asks for them again.
+        receive(6, 2).await;
+        for (expected, ack) in [(3, 3), (4, 4), (5, 0), (6, 6), (7, 7)] {
+            receive(expected, ack).await;
+        }
+        (1..=7)
+            .flat_map(|index| blocks[&index].clone())
+            .collect::<Vec<u8>>()
+    };
+    let (send_result, received) = join!(send_coro, recv_coro);
+    assert!(send_result.is_ok());
+    assert_eq!(received, test_data);
+}
+
+// Acknowledges the blocks received in order once the server goes quiet. The first arrival of
+// `lost_block` is dropped. Returns the data and the number of blocks in every burst.
+async fn download_lossy(
+    datagram_stream: &DatagramStream,
+    block_size: u16,
+    lost_block: u16,
+) -> (Vec<u8>, Vec<u16>) {
+    let block_header_size = 4;
+    let expected_message_size = block_size as usize + block_header_size;
+    let mut buffer = vec![0u8; expected_message_size];
+    let mut read_data: Vec<u8> = Vec::new();
+    let mut bursts: Vec<u16> = Vec::new();
+    let mut last_in_order: u16 = 0;
+    let mut lost = false;
+    let mut done = false;
+    while !done {
+        let mut burst = 0;
+        loop {
+            let recv_fut = datagram_stream.recv(&mut buffer, block_header_size);
+            let Ok(received_bytes) = timeout(Duration::from_millis(100), recv_fut).await else {
+                break;
+            };
+            let received_bytes = received_bytes.unwrap();
+            let block_index = ((buffer[2] as u16) << 8) | (buffer[3] as u16);
+            burst += 1;
+            if block_index == lost_block && !lost {
+                lost = true;
+                continue;
+            }
+            if block_index == last_in_order.wrapping_add(1) {
+                read_data.extend_from_slice(&buffer[block_header_size..received_bytes]);
+                last_in_order = block_index;
+                done = received_bytes < expected_message_size;
+            }
+        }
+        bursts.push(burst);
+        buffer[0] = 0;
+        buffer[1] = ACK as u8;
+        buffer[2] = (last_in_order >> 8) as u8;
+        buffer[3] = (last_in_order & 0xFF) as u8;
+        datagram_stream
+            .send(&buffer[..block_header_size])
+            .await
+            .unwrap();
+    }
+    (read_data, bursts)
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn adaptive_window_shrinks_on_loss() {
+    let block_size = 100;
+    let test_data = generate_data(block_size as usize * 40 + 50);
+    let opened_file = VirtualOpenedFile::new(test_data.clone());
+    let (server_stream, client_stream) = make_streams().await;
+    let window = Window::new(block_size, 8, &BufferPool::default()).adaptive();
+    let mut buffer = vec![0; 1024];
+    let send_coro = send_file(
+        Offloaded::new(opened_file),
+        &server_stream,
+        window,
+        AckTimeout::default(),
+        &mut buffer,
+    );
+    let recv_coro = download_lossy(&client_stream, block_size, 20);
+    let (send_result, (read_data, bursts)) = join!(send_coro, recv_coro);
+    assert!(send_result.is_ok());
+    assert_eq!(read_data, test_data);
+    // Slow start up to the ceiling, block 20 of the fifth burst is lost, then the window recovers.
+    assert_eq!(bursts[..7], [1, 2, 4, 8, 8, 4, 8]);
+}
+
+#[test]
+fn window_limit_grows_and_shrinks() {
+    let mut window = Window::new(100, 8, &BufferPool::default());
+    window.shrink();
+    assert_eq!(window.limit(), 8);
+    let mut window = window.adaptive();
+    assert_eq!(window.limit(), 1);
+    for _ in 0..5 {
+        window.grow();
+    }
+    assert_eq!(window.limit(), 8);
+    window.shrink();
+    assert_eq!(window.limit(), 4);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn buffers_reused_across_sessions() {
+    let buffer_pool = BufferPool::default();
+    let block_size = 100;
+    let window_size = 4;
+    let sessions = 200;
+    for session in 0..sessions {
+        let test_data = generate_data(1000 + session);
+        let opened_file = VirtualOpenedFile::new(test_data.clone());
+        let (server_stream, client) = make_session().await;
+        let window = Window::new(block_size, window_size, &buffer_pool);
+        let mut buffer = buffer_pool.lease(u16::MAX as usize);
+        let send_coro = send_file(
+            Offloaded::new(opened_file),
+            &server_stream,
+            window,
+            AckTimeout::default(),
+            &mut buffer,
+        );
+        let recv_coro = download_stream(client, block_size, window_size);
+        let (send_result, recv_result) = join!(send_coro, recv_coro);
+        assert!(send_result.is_ok());
+        assert_eq!(recv_result.unwrap(), test_data);
+    }
+    let leases_per_session = window_size as usize + 1;
+    assert_eq!(buffer_pool.allocated(), leases_per_session);
+    assert_eq!(buffer_pool.reused(), (sessions - 1) * leases_per_session);
+}
+
+#[test]
+fn idle_handler_exit_reason() {
+    let tftp_root = mk_tmp(idle_handler_exit_reason);
+    let peer: IpAddr = "127.0.0.20".parse().unwrap();
+    let handler = PeerHandler::new(
+        peer,
+        tftp_root,
+        Some(String::from("default")),
+        Duration::from_millis(100),
+        SessionContext::default(),
+    );
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !handler.is_finished() {
+        assert!(Instant::now() < deadline, "{handler} didn't time out");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(handler.join(), HandlerExitReason::IdleTimeout);
+}
+
+#[test]
+fn zero_idle_timeout_never_idles_out() {
+    let tftp_root = mk_tmp(zero_idle_timeout_never_idles_out);
+    let peer: IpAddr = "127.0.0.21".parse().unwrap();
+    let handler = PeerHandler::new(
+        peer,
+        tftp_root,
+        Some(String::from("default")),
+        Duration::ZERO,
+        SessionContext::default(),
+    );
+    // The handler checks for inactivity every second.
+    thread::sleep(Duration::from_millis(2500));
+    assert!(!handler.is_finished(), "{handler} idled out");
+    assert_eq!(handler.shutdown(), HandlerExitReason::ShutdownRequested);
+}
+
+#[test]
+fn shutdown_handler_exit_reason() {
+    let tftp_root = mk_tmp(shutdown_handler_exit_reason);
+    let peer: IpAddr = "127.0.0.20".parse().unwrap();
+    let handler = PeerHandler::new(
+        peer,
+        tftp_root,
+        Some(String::from("default")),
+        Duration::from_secs(60),
+        SessionContext::default(),
+    );
+    assert_eq!(handler.shutdown(), HandlerExitReason::ShutdownRequested);
+}
+
+#[test]
+fn open_errors_map_to_distinct_replies() {
+    for (kind, expected) in [
+        (io::ErrorKind::NotFound, TFTPError::file_not_found()),
+        (
+            io::ErrorKind::PermissionDenied,
+            TFTPError::access_violation(),
+        ),
+        (io::ErrorKind::IsADirectory, TFTPError::is_a_directory()),
+        (io::ErrorKind::FileTooLarge, TFTPError::file_too_large()),
+        (io::ErrorKind::TimedOut, TFTPError::timed_out()),
+        (io::ErrorKind::StorageFull, TFTPError::disk_full()),
+        (io::ErrorKind::Other, TFTPError::undefined("Server Error")),
+    ] {
+        let reply = open_error_reply(&io::Error::from(kind));
+        assert_eq!(reply.to_string(), expected.to_string(), "{kind:?}");
+    }
+}
+
+#[test]
+fn storage_full_replies_disk_full() {
+    let reply = open_error_reply(&io::Error::from(io::ErrorKind::StorageFull));
+    let mut buffer = [0u8; 64];
+    let size = reply.serialize(&mut buffer).unwrap();
+    assert_eq!(buffer[..4], [0x00, 0x05, 0x00, 0x03]);
+    assert_eq!(&buffer[4..size - 1], b"Disk full or allocation exceeded");
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn connect_timeout_aborts_slow_transfer() {
+    let block_size: u16 = 512;
+    let opened_file = SlowOpenedFile {
+        file: VirtualOpenedFile::new(generate_data(block_size as usize * 100)),
+        delay: Duration::from_millis(100),
+        pending: None,
+    };
+    let options = HashMap::from([(String::from("connecttimeout"), String::from("1"))]);
+    let connect_timeout = ConnectTimeout::find_in(&options);
+    let (server_stream, client) = make_session().await;
+    let buffer_pool = BufferPool::default();
+    let window = Window::new(block_size, 1, &buffer_pool);
+    let mut buffer = vec![0u8; u16::MAX as usize];
+    let send_coro = async {
+        let started = Instant::now();
+        let send_result = send_bounded(
+            opened_file,
+            &server_stream,
+            window,
+            AckTimeout::default(),
+            connect_timeout,
+            &mut buffer,
+        )
+        .await;
+        (send_result, started.elapsed())
+    };
+    let recv_coro = timeout(
+        Duration::from_secs(3),
+        download_stream(client, block_size, 1),
+    );
+    let ((send_result, elapsed), _recv_result) = join!(send_coro, recv_coro);
+    let reply = send_result.unwrap_err();
+    assert_eq!(
+        reply.to_string(),
+        TFTPError::transfer_timed_out().to_string()
+    );
+    assert!(elapsed < Duration::from_secs(2), "Aborted in {elapsed:?}");
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn watchdog_cancels_stuck_session() {
+    LocalSet::new()
+        .run_until(async {
+            let opened_file =
+                VirtualOpenedFile::new(generate_data(512 * 100)).stalling(Duration::from_secs(1));
+            let (server_stream, client_stream) = make_streams().await;
+            let (stats_reporter, mut records) = stats::channel();
+            let session_context = SessionContext::default()
+                .with_max_transfer_time(Some(Duration::from_millis(300)))
+                .reporting_to(stats_reporter);
+            let buffer = BufferPool::default().lease(u16::MAX as usize);
+            let started = Instant::now();
+            let mut sessions = Sessions::new();
+            sessions.insert(
+                1001,
+                tokio::task::spawn_local(send(
+                    opened_file,
+                    ServedFile::default(),
+                    server_stream,
+                    HashMap::new(),
+                    session_context,
+                    buffer,
+                )),
+                None,
+            );
+            // The first block never gets read in time.
+            let mut reply = vec![0u8; 1024];
+            let size = timeout(Duration::from_secs(5), client_stream.recv(&mut reply, 4))
+                .await
+                .expect("The watchdog didn't fire")
+                .unwrap();
+            let elapsed = started.elapsed();
+            assert!(elapsed < Duration::from_secs(1), "Cancelled in {elapsed:?}");
+            let mut expected = [0u8; 64];
+            let expected_size = TFTPError::transfer_timed_out()
+                .serialize(&mut expected)
+                .unwrap();
+            assert_eq!(reply[..size], expected[..expected_size]);
+            let finished = timeout(Duration::from_secs(1), sessions.finished())
+                .await
+                .expect("The cancelled session is not reported");
+            assert_eq!(finished, 1001);
+            assert!(sessions.is_empty());
+            assert!(!records.recv().await.unwrap().completed);
+        })
+        .await;
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn gone_client_ends_session_promptly() {
+    let block_size: u16 = 512;
+    let server_socket = UdpSocket::bind("127.0.0.10:0").await.unwrap();
+    let client_socket = UdpSocket::bind("127.0.0.20:0").await.unwrap();
+    let client_address = client_socket.local_addr().unwrap();
+    let server_stream = DatagramStream::connect(server_socket, client_address)
+        .await
+        .unwrap();
+    let opened_file = VirtualOpenedFile::new(generate_data(block_size as usize * 10));
+    let buffer_pool = BufferPool::default();
+    let window = Window::new(block_size, 1, &buffer_pool);
+    let mut buffer = vec![0u8; u16::MAX as usize];
+    let send_coro = async {
+        let started = Instant::now();
+        let send_result = send_file(
+            Offloaded::new(opened_file),
+            &server_stream,
+            window,
+            AckTimeout::default(),
+            &mut buffer,
+        )
+        .await;
+        (send_result, started.elapsed())
+    };
+    // Acknowledges the first block and closes the socket.
+    let recv_coro = async move {
+        let mut datagram = [0u8; 1024];
+        let (_size, server_address) = client_socket.recv_from(&mut datagram).await.unwrap();
+        let ack = [0x00, ACK as u8, 0x00, 0x01];
+        client_socket.send_to(&ack, server_address).await.unwrap();
+    };
+    let ((send_result, elapsed), ()) = join!(send_coro, recv_coro);
+    let reply = send_result.unwrap_err();
+    assert_eq!(
+        reply.to_string(),
+        TFTPError::undefined("Client is gone").to_string()
+    );
+    assert!(elapsed < Duration::from_secs(1), "Ended in {elapsed:?}");
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn session_removed_once_finished() {
+    LocalSet::new()
+        .run_until(async {
+            let mut sessions = Sessions::new();
+            let (server_stream, _client_stream) = make_streams().await;
+            // Fails right away, like a session for a missing file.
+            sessions.insert(
+                1001,
+                tokio::task::spawn_local(fire_error(
+                    TFTPError::file_not_found(),
+                    server_stream,
+                    vec![0u8; 512],
+                )),
+                Some(mpsc::unbounded_channel().0),
+            );
+            let (inbox_tx, mut inbox_rx) = mpsc::unbounded_channel();
+            sessions.insert(
+                1002,
+                tokio::task::spawn_local(sleep(Duration::from_secs(60))),
+                Some(inbox_tx),
+            );
+            assert_eq!(sessions.len(), 2);
+            let finished = timeout(Duration::from_millis(500), sessions.finished())
+                .await
+                .expect("The failed session is not reported");
+            assert_eq!(finished, 1001);
+            assert_eq!(sessions.len(), 1);
+            assert!(sessions.contains(1002));
+            assert!(!sessions.deliver(1001, vec![0x00, 0x04, 0x00, 0x01]));
+            assert!(sessions.deliver(1002, vec![0x00, 0x04, 0x00, 0x01]));
+            assert_eq!(inbox_rx.recv().await.unwrap(), [0x00, 0x04, 0x00, 0x01]);
+        })
+        .await;
+}
+
+fn traffic_class(socket: &UdpSocket, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+    let mut value: libc::c_int = 0;
+    let mut size = size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut size,
+        )
+    };
+    assert_eq!(result, 0, "{}", io::Error::last_os_error());
+    value
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn dscp_marks_reply_sockets() {
+    let socket = UdpSocket::bind("127.0.0.10:0").await.unwrap();
+    set_dscp(&socket, 10).unwrap();
+    assert_eq!(
+        traffic_class(&socket, libc::IPPROTO_IP, libc::IP_TOS),
+        10 << 2
+    );
+    // IPv6 may be disabled on the host.
+    if let Ok(socket) = UdpSocket::bind("[::1]:0").await {
+        set_dscp(&socket, 63).unwrap();
+        assert_eq!(
+            traffic_class(&socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+            63 << 2
+        );
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn socket_buffers_resized() {
+    let socket = UdpSocket::bind("127.0.0.10:0").await.unwrap();
+    let default = set_buffer_size(&socket, SocketBuffer::Receive, MIN_SOCKET_BUFFER).unwrap();
+    // Capped by net.core.rmem_max, which may be lower than asked for but never lower than the minimum.
+    let granted = set_buffer_size(&socket, SocketBuffer::Receive, 1 << 20).unwrap();
+    assert!(granted >= default, "{granted} < {default}");
+    let granted = set_buffer_size(&socket, SocketBuffer::Send, 1 << 20).unwrap();
+    assert!(granted >= MIN_SOCKET_BUFFER as usize);
+    // Well under any cap, granted as asked for rather than doubled.
+    let granted = set_buffer_size(&socket, SocketBuffer::Send, 16384).unwrap();
+    assert_eq!(granted, 16384);
+    SocketBuffers::new(Some(1 << 20), Some(1 << 20)).apply(&socket, "socket", true);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn send_from_async_backend() {
+    let block_size = 512;
+    let test_data = generate_data(block_size as usize * 5 + 100);
+    let opened_file = SlowOpenedFile {
+        file: VirtualOpenedFile::new(test_data.clone()),
+        delay: Duration::from_millis(1),
+        pending: None,
+    };
+    let (server_stream, client) = make_session().await;
+    let buffer_pool = BufferPool::default();
+    let window = Window::new(block_size, 2, &buffer_pool);
+    let mut buffer = vec![0u8; u16::MAX as usize];
+    let send_coro = send_file(
+        opened_file,
+        &server_stream,
+        window,
+        AckTimeout::default(),
+        &mut buffer,
+    );
+    let recv_coro = download_stream(client, block_size, 2);
+    let (send_result, recv_result) = join!(send_coro, recv_coro);
+    assert!(send_result.is_ok());
+    assert_eq!(recv_result.unwrap(), test_data);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn interpacket_gap_paces_window() {
+    let block_size: u16 = 512;
+    let window_size: u16 = 4;
+    let gap = Duration::from_millis(20);
+    let test_data = generate_data(block_size as usize * 3 + 100);
+    let opened_file = VirtualOpenedFile::new(test_data);
+    let (server_stream, client_stream) = make_streams().await;
+    let buffer_pool = BufferPool::default();
+    let window = Window::new(block_size, window_size, &buffer_pool).paced(gap);
+    let mut buffer = vec![0u8; u16::MAX as usize];
+    let send_coro = send_file(
+        Offloaded::new(opened_file),
+        &server_stream,
+        window,
+        AckTimeout::default(),
+        &mut buffer,
+    );
+    // The whole file fits into a single window.
+    let recv_coro = async {
+        let mut datagram = vec![0u8; u16::MAX as usize];
+        let mut arrivals = Vec::new();
+        for _ in 0..window_size {
+            client_stream.recv(&mut datagram, 4).await.unwrap();
+            arrivals.push(Instant::now());
+        }
+        let ack = [0x00, ACK as u8, 0x00, window_size as u8];
+        client_stream.send(&ack).await.unwrap();
+        arrivals
+    };
+    let (send_result, arrivals) = join!(send_coro, recv_coro);
+    assert!(send_result.is_ok());
+    for pair in arrivals.windows(2) {
+        let interval = pair[1] - pair[0];
+        assert!(interval >= gap / 2, "Blocks {interval:?} apart");
+    }
+}
+
+struct NBDServer(Child);
+
+impl Drop for NBDServer {
+    fn drop(&mut self) {
+        _ = self.0.kill();
+        _ = self.0.wait();
+    }
+}
+
+fn root_state(config: &Path) -> serde_json::Value {
+    ServerStats::default().to_json()["roots"][config.display().to_string()]["state"].clone()
+}
+
+#[test]
+fn unreachable_nbd_root_connects_on_retry() {
+    let tftp_root = mk_tmp(unreachable_nbd_root_connects_on_retry);
+    let peer: IpAddr = "127.0.0.31".parse().unwrap();
+    let nbd_port = std::net::TcpListener::bind("127.0.0.2:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config = tftp_root.join("127.0.0.31.nbd");
+    let config_json = json!({
+        "url": format!("nbd://127.0.0.2:{nbd_port}/disk"),
+        "mounts": [{"partition": 2, "mountpoint": "/"}, {"partition": 1, "mountpoint": "/boot"}],
+        "tftp_root": "/boot",
+    });
+    fs::write(&config, config_json.to_string()).unwrap();
+    let session_context = SessionContext::default();
+    let mut peer_roots = PeerRoots::new(peer, tftp_root, None);
+    peer_roots.retry_backoff = Duration::from_millis(100);
+    peer_roots.populate(&session_context, &session_context.disk_cache);
+    assert!(peer_roots.config_keys.is_empty());
+    assert_eq!(root_state(&config), json!("down"));
+    let (test_disk, locked_tests_directory) = ensure_prerequisite_disk();
+    let _nbd_server = NBDServer(
+        Command::new("qemu-nbd")
+            .arg("--bind=127.0.0.2")
+            .arg(format!("--port={nbd_port}"))
+            .arg("--export-name=disk")
+            .arg("--read-only")
+            .arg("--shared=100")
+            .arg(test_disk)
+            .spawn()
+            .unwrap(),
+    );
+    drop(locked_tests_directory);
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while peer_roots.config_keys.is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(200));
+        if peer_roots.is_retry_due() {
+            peer_roots.reload();
+            let connected = peer_roots.pending.take().unwrap().blocking_recv().unwrap();
+            peer_roots.swap_in(&session_context, connected);
+        }
+    }
+    assert_eq!(peer_roots.config_keys.len(), 1);
+    assert_eq!(root_state(&config), json!("connected"));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn reloaded_roots_swapped_in_once_connected() {
+    let tftp_root = mk_tmp(reloaded_roots_swapped_in_once_connected);
+    let peer: IpAddr = "127.0.0.32".parse().unwrap();
+    let session_context = SessionContext::default();
+    let mut peer_roots = PeerRoots::new(peer, tftp_root.clone(), None);
+    peer_roots.populate(&session_context, &session_context.disk_cache);
+    let roots_count = peer_roots.roots.len();
+    // Refused before any appliance is launched.
+    let config_json = json!({
+        "url": "ftp://127.0.0.2/disk",
+        "mounts": [],
+        "tftp_root": "/",
+        "prewarm": true,
+    });
+    fs::write(tftp_root.join("127.0.0.32.nbd"), config_json.to_string()).unwrap();
+    peer_roots.reload();
+    assert!(!peer_roots.prewarmed);
+    let connected = peer_roots.connected().await.unwrap();
+    assert!(peer_roots.pending.is_none());
+    peer_roots.swap_in(&session_context, connected);
+    assert!(peer_roots.prewarmed);
+    assert_eq!(peer_roots.roots.len(), roots_count);
+    assert!(peer_roots.config_keys.is_empty());
+}
+
+#[test]
+fn edited_writable_root_closed_before_reload() {
+    let tftp_root = mk_tmp(edited_writable_root_closed_before_reload);
+    let peer: IpAddr = "127.0.0.33".parse().unwrap();
+    let nbd_port = std::net::TcpListener::bind("127.0.0.2:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let (test_disk, locked_tests_directory) = ensure_prerequisite_disk();
+    // Writes go to a temporary overlay, the test disk is left intact.
+    let _nbd_server = NBDServer(
+        Command::new("qemu-nbd")
+            .arg("--bind=127.0.0.2")
+            .arg(format!("--port={nbd_port}"))
+            .arg("--export-name=disk")
+            .arg("--snapshot")
+            .arg("--shared=100")
+            .arg(test_disk)
+            .spawn()
+            .unwrap(),
+    );
+    drop(locked_tests_directory);
+    let config = tftp_root.join("127.0.0.33.nbd");
+    let config_json = json!({
+        "url": format!("nbd://127.0.0.2:{nbd_port}/disk"),
+        "mounts": [{"partition": 1, "mountpoint": "/"}],
+        "tftp_root": "/",
+        "writable": true,
+    });
+    fs::write(&config, config_json.to_string()).unwrap();
+    let session_context = SessionContext::default();
+    let mut peer_roots = PeerRoots::new(peer, tftp_root, None);
+    let deadline = Instant::now() + Duration::from_secs(60);
+    peer_roots.populate(&session_context, &session_context.disk_cache);
+    while peer_roots.config_keys.is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(200));
+        peer_roots.reload();
+        let connected = peer_roots.pending.take().unwrap().blocking_recv().unwrap();
+        peer_roots.swap_in(&session_context, connected);
+    }
+    assert_eq!(peer_roots.config_keys.len(), 1);
+    let roots_count = peer_roots.roots.len();
+    fs::File::options()
+        .write(true)
+        .open(&config)
+        .unwrap()
+        .set_modified(std::time::SystemTime::now() + Duration::from_secs(1))
+        .unwrap();
+    peer_roots.reload();
+    // Out of service while the edited config connects.
+    assert!(peer_roots.config_keys.is_empty());
+    assert_eq!(peer_roots.roots.len(), roots_count - 1);
+    let connected = peer_roots.pending.take().unwrap().blocking_recv().unwrap();
+    peer_roots.swap_in(&session_context, connected);
+    assert_eq!(peer_roots.config_keys.len(), 1);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn retry_connects_in_background() {
+    let tftp_root = mk_tmp(retry_connects_in_background);
+    let peer: IpAddr = "127.0.0.34".parse().unwrap();
+    // Refused before any appliance is launched, yet counted as failed to connect.
+    let config = tftp_root.join("127.0.0.34.nbd");
+    let config_json = json!({
+        "url": "nbds://127.0.0.2/disk",
+        "mounts": [],
+        "tftp_root": "/",
+        "tls_cert_dir": tftp_root.join("missing").to_str().unwrap(),
+    });
+    fs::write(&config, config_json.to_string()).unwrap();
+    let session_context = SessionContext::default();
+    let mut peer_roots = PeerRoots::new(peer, tftp_root, None);
+    peer_roots.retry_backoff = Duration::ZERO;
+    peer_roots.populate(&session_context, &session_context.disk_cache);
+    let roots_count = peer_roots.roots.len();
+    assert!(peer_roots.is_retry_due());
+    peer_roots.reload();
+    // The current roots are served meanwhile, and the pending retry isn't started again.
+    assert_eq!(peer_roots.roots.len(), roots_count);
+    assert!(!peer_roots.is_retry_due());
+    let connected = peer_roots.connected().await.unwrap();
+    peer_roots.swap_in(&session_context, connected);
+    assert!(!peer_roots.reload_again);
+    assert_eq!(peer_roots.retries[&config].0, 2);
+}
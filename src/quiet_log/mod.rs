@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(test)]
+mod tests;
+
+// Repetitions of a line are counted over this long by default before the suppressed ones are summarized.
+pub(super) const DEFAULT_QUIET_WINDOW_MS: u64 = 5000;
+
+// Lines are logged from the server and every peer handler thread, so repetitions are counted process-wide.
+static QUIET_LOG: Mutex<QuietLog> = Mutex::new(QuietLog::new(
+    0,
+    Duration::from_millis(DEFAULT_QUIET_WINDOW_MS),
+));
+
+struct Repeats {
+    since: Instant,
+    count: usize,
+    // The latest line suppressed, standing for all of them in the summary.
+    last: String,
+}
+
+// Writes the first lines of a kind as they come and collapses the rest into a single "line (xN)" summary
+// once its window ends. Lines are of the same kind if they are formatted from the same template, so the
+// lines differing in a port or a block index only are counted together. Zero repetitions allowed means
+// nothing is suppressed.
+pub(super) struct QuietLog {
+    quiet_after: usize,
+    window: Duration,
+    repeats: BTreeMap<&'static str, Repeats>,
+}
+
+impl QuietLog {
+    pub(super) const fn new(quiet_after: usize, window: Duration) -> Self {
+        Self {
+            quiet_after,
+            window,
+            repeats: BTreeMap::new(),
+        }
+    }
+
+    // The lines to write now: the summary of the window of the template if it ended meanwhile, then the
+    // line itself unless suppressed. The other templates are left to `sweep`.
+    pub(super) fn lines(
+        &mut self,
+        template: &'static str,
+        line: String,
+        now: Instant,
+    ) -> Vec<String> {
+        if self.quiet_after == 0 {
+            return vec![line];
+        }
+        let mut lines = Vec::new();
+        if let Some(repeats) = self.repeats.get(template)
+            && now.duration_since(repeats.since) >= self.window
+        {
+            lines.extend(summary(repeats, self.quiet_after));
+            self.repeats.remove(template);
+        }
+        let repeats = self.repeats.entry(template).or_insert(Repeats {
+            since: now,
+            count: 0,
+            last: String::new(),
+        });
+        repeats.count += 1;
+        if repeats.count <= self.quiet_after {
+            lines.push(line);
+        } else {
+            repeats.last = line;
+        }
+        lines
+    }
+
+    // The summaries of the windows ended by now, taken on a timer.
+    pub(super) fn sweep(&mut self, now: Instant) -> Vec<String> {
+        let window = self.window;
+        self.summarize(|repeats| now.duration_since(repeats.since) >= window)
+    }
+
+    // The summaries of every template still being counted, for the shutdown.
+    pub(super) fn flush(&mut self) -> Vec<String> {
+        self.summarize(|_| true)
+    }
+
+    fn summarize(&mut self, ended: impl Fn(&Repeats) -> bool) -> Vec<String> {
+        let mut summaries = Vec::new();
+        let quiet_after = self.quiet_after;
+        self.repeats.retain(|_, repeats| {
+            if !ended(repeats) {
+                return true;
+            }
+            summaries.extend(summary(repeats, quiet_after));
+            false
+        });
+        summaries
+    }
+}
+
+fn summary(repeats: &Repeats, quiet_after: usize) -> Option<String> {
+    (repeats.count > quiet_after)
+        .then(|| format!("{} (x{})", repeats.last, repeats.count - quiet_after))
+}
+
+// The summaries are written once a window ends, even if nothing is logged after it.
+pub(super) fn quiet_after(repetitions: usize, window: Duration) {
+    {
+        let mut quiet_log = QUIET_LOG.lock().unwrap();
+        quiet_log.quiet_after = repetitions;
+        quiet_log.window = window;
+    }
+    if repetitions > 0 {
+        thread::spawn(move || {
+            loop {
+                thread::sleep(window);
+                let summaries = QUIET_LOG.lock().unwrap().sweep(Instant::now());
+                summaries.iter().for_each(|summary| eprintln!("{summary}"));
+            }
+        });
+    }
+}
+
+pub(super) fn write(template: &'static str, line: String) {
+    let lines = QUIET_LOG
+        .lock()
+        .unwrap()
+        .lines(template, line, Instant::now());
+    lines.iter().for_each(|line| eprintln!("{line}"));
+}
+
+pub(super) fn flush() {
+    let summaries = QUIET_LOG.lock().unwrap().flush();
+    summaries.iter().for_each(|summary| eprintln!("{summary}"));
+}
+
+// For the lines repeated on every request or block, which would flood the log under load. Formats the
+// line like `format!` and counts its repetitions by the template.
+macro_rules! log {
+    ($template:literal $(, $argument:expr)* $(,)?) => {
+        $crate::quiet_log::write($template, format!($template $(, $argument)*))
+    };
+}
+
+pub(super) use log;
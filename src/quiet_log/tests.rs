@@ -0,0 +1,95 @@
+use super::*;
+
+const WINDOW: Duration = Duration::from_millis(DEFAULT_QUIET_WINDOW_MS);
+
+fn line(text: &str) -> String {
+    text.to_string()
+}
+
+#[test]
+fn repeated_lines_collapsed() {
+    let mut quiet_log = QuietLog::new(2, WINDOW);
+    let start = Instant::now();
+    let mut written = Vec::new();
+    for _ in 0..5 {
+        written.extend(quiet_log.lines(
+            "{peer}: sessions: {}",
+            line("127.0.0.1: sessions: 1"),
+            start,
+        ));
+    }
+    assert_eq!(
+        written,
+        ["127.0.0.1: sessions: 1", "127.0.0.1: sessions: 1"]
+    );
+    let written = quiet_log.lines(
+        "{peer}: sessions: {}",
+        line("127.0.0.1: sessions: 0"),
+        start + WINDOW,
+    );
+    assert_eq!(
+        written,
+        ["127.0.0.1: sessions: 1 (x3)", "127.0.0.1: sessions: 0"]
+    );
+}
+
+#[test]
+fn lines_of_template_counted_together() {
+    let mut quiet_log = QuietLog::new(1, WINDOW);
+    let start = Instant::now();
+    let template = "{peer}: Ignore repeated request from port {port}";
+    for port in [1000, 1001, 1002] {
+        quiet_log.lines(
+            template,
+            format!("127.0.0.1: Ignore repeated request from port {port}"),
+            start,
+        );
+    }
+    assert_eq!(
+        quiet_log.flush(),
+        ["127.0.0.1: Ignore repeated request from port 1002 (x2)"]
+    );
+}
+
+#[test]
+fn other_lines_pass() {
+    let mut quiet_log = QuietLog::new(1, WINDOW);
+    let start = Instant::now();
+    assert_eq!(quiet_log.lines("a", line("a"), start), ["a"]);
+    assert_eq!(quiet_log.lines("b", line("b"), start), ["b"]);
+    assert!(quiet_log.lines("a", line("a"), start).is_empty());
+    assert_eq!(quiet_log.flush(), ["a (x1)"]);
+    assert!(quiet_log.flush().is_empty());
+}
+
+#[test]
+fn ended_windows_swept() {
+    let mut quiet_log = QuietLog::new(1, WINDOW);
+    let start = Instant::now();
+    for _ in 0..3 {
+        quiet_log.lines("a", line("a"), start);
+    }
+    quiet_log.lines("b", line("b"), start + WINDOW / 2);
+    quiet_log.lines("b", line("b"), start + WINDOW / 2);
+    assert!(quiet_log.sweep(start + WINDOW / 2).is_empty());
+    assert_eq!(quiet_log.sweep(start + WINDOW), ["a (x2)"]);
+    assert_eq!(quiet_log.flush(), ["b (x1)"]);
+}
+
+#[test]
+fn line_written_again_after_window() {
+    let mut quiet_log = QuietLog::new(1, WINDOW);
+    let start = Instant::now();
+    assert_eq!(quiet_log.lines("a", line("a"), start), ["a"]);
+    assert_eq!(quiet_log.lines("a", line("a"), start + WINDOW), ["a"]);
+}
+
+#[test]
+fn nothing_suppressed_when_disabled() {
+    let mut quiet_log = QuietLog::new(0, WINDOW);
+    let start = Instant::now();
+    for _ in 0..3 {
+        assert_eq!(quiet_log.lines("a", line("a"), start), ["a"]);
+    }
+    assert!(quiet_log.flush().is_empty());
+}
@@ -1,35 +1,95 @@
+use crate::decompress::{Codec, Decompressed};
+use crate::disk_worker::{DiskWorker, PendingResult};
+use crate::file_cache::{FileCache, FileKey, SharedFile};
 use crate::fs::{OpenedFile, Root};
-use crate::guestfs::{GuestFS, GuestFSError};
+use crate::guestfs::{FileStat, GuestFSError};
 use serde::Deserialize;
 use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::{Debug, Display, Formatter};
 use std::io;
 use std::path::PathBuf;
-use std::rc::Rc;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
 
+// Clones share the disk, which is closed along with the last of them.
+#[derive(Clone)]
 pub(super) struct RemoteRoot {
     disk: ConnectedDisk,
     chroot_path: PathBuf,
+    // Files served decompressed, by their path relative to the root.
+    decompress: HashMap<String, Codec>,
+    // What the disk is connected to, roots of the same source share the cached files.
+    source: String,
+    file_cache: FileCache,
 }
 
 impl RemoteRoot {
     pub(super) fn new(disk: ConnectedDisk, chroot_path: &str) -> Self {
+        let source = disk.to_string();
         Self {
             disk,
             chroot_path: PathBuf::from(chroot_path),
+            decompress: HashMap::new(),
+            source,
+            file_cache: FileCache::default(),
         }
     }
+
+    pub(super) fn with_source(mut self, source: String) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub(super) fn with_file_cache(mut self, file_cache: FileCache) -> Self {
+        self.file_cache = file_cache;
+        self
+    }
+
+    pub(super) fn with_decompress(mut self, decompress: HashMap<String, Codec>) -> Self {
+        self.decompress = decompress
+            .into_iter()
+            .map(|(path, codec)| (path.trim_start_matches('/').to_string(), codec))
+            .collect();
+        self
+    }
+
+    pub(super) fn is_writable(&self) -> bool {
+        self.disk.writable
+    }
+
+    // Only meaningful for write requests, which are not served yet.
+    #[allow(dead_code)]
+    pub(super) fn create(&self, path: &str, content: &[u8]) -> io::Result<()> {
+        let absolute_path = self.chroot_path.join(path);
+        self.disk.create(absolute_path.to_str().unwrap(), content)
+    }
+
+    // What the root serves in a directory, to tell a wrong partition or root path from a missing file.
+    pub(super) fn list_dir(&self, path: &str) -> io::Result<Vec<String>> {
+        let absolute_path = self.chroot_path.join(path.trim_start_matches('/'));
+        self.disk.list_dir(absolute_path.to_str().unwrap())
+    }
 }
 
 impl Root for RemoteRoot {
-    type OpenedFile = FileReader;
+    type OpenedFile = RemoteFile;
     fn open(&self, path: &str) -> io::Result<Self::OpenedFile> {
-        match self
-            .disk
-            .open(self.chroot_path.join(path).to_str().unwrap())
-        {
-            Ok(opened_file) => Ok(opened_file),
-            Err(err) => Err(err),
+        let absolute_path = self.chroot_path.join(path);
+        let absolute_path = absolute_path.to_str().unwrap();
+        let file_stat = self.disk.stat(absolute_path)?;
+        let key = FileKey::new(&self.source, absolute_path, file_stat.size, file_stat.mtime);
+        let display = self.disk.display_file(absolute_path);
+        let file_reader = self.file_cache.open(key, display.clone(), || {
+            self.disk.open_stat(absolute_path, file_stat, display)
+        })?;
+        match self.decompress.get(path.trim_start_matches('/')) {
+            Some(codec) => Ok(RemoteFile::Decompressed(Box::new(Decompressed::new(
+                file_reader,
+                *codec,
+            )))),
+            None => Ok(RemoteFile::Plain(file_reader)),
         }
     }
 }
@@ -46,6 +106,65 @@ impl Display for RemoteRoot {
     }
 }
 
+#[derive(Debug)]
+pub(super) enum RemoteFile {
+    Plain(SharedFile<FileReader>),
+    Decompressed(Box<Decompressed<SharedFile<FileReader>>>),
+}
+
+impl Display for RemoteFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Plain(file_reader) => write!(f, "{file_reader}"),
+            Self::Decompressed(decompressed) => write!(f, "{decompressed}"),
+        }
+    }
+}
+
+impl OpenedFile for RemoteFile {
+    fn read_to(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(file_reader) => file_reader.read_to(buffer),
+            Self::Decompressed(decompressed) => decompressed.read_to(buffer),
+        }
+    }
+
+    fn get_size(&mut self) -> io::Result<usize> {
+        match self {
+            Self::Plain(file_reader) => file_reader.get_size(),
+            Self::Decompressed(decompressed) => decompressed.get_size(),
+        }
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(file_reader) => file_reader.rewind(),
+            Self::Decompressed(decompressed) => decompressed.rewind(),
+        }
+    }
+
+    fn mtime(&mut self) -> io::Result<u64> {
+        match self {
+            Self::Plain(file_reader) => file_reader.mtime(),
+            Self::Decompressed(decompressed) => decompressed.mtime(),
+        }
+    }
+
+    fn poll_fill(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<io::Result<()>> {
+        match self {
+            Self::Plain(file_reader) => file_reader.poll_fill(cx, wanted),
+            Self::Decompressed(decompressed) => decompressed.poll_fill(cx, wanted),
+        }
+    }
+
+    fn read_at(&mut self, offset: usize, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(file_reader) => file_reader.read_at(offset, buffer),
+            Self::Decompressed(decompressed) => decompressed.read_at(offset, buffer),
+        }
+    }
+}
+
 pub(super) trait Config<'a>: Deserialize<'a> {
     fn from_json(value: &Value) -> Option<Self>;
 
@@ -59,13 +178,13 @@ pub(super) enum VirtualRootError {
 }
 
 pub(super) struct Partition {
-    handle: Rc<GuestFS>,
+    worker: Arc<DiskWorker>,
     device: String,
 }
 
 impl Partition {
-    pub(crate) fn new(handle: Rc<GuestFS>, device: String) -> Self {
-        Self { handle, device }
+    pub(crate) fn new(worker: Arc<DiskWorker>, device: String) -> Self {
+        Self { worker, device }
     }
 }
 
@@ -84,7 +203,18 @@ impl Debug for Partition {
 impl Partition {
     pub(crate) fn mount_ro(&self, mountpoint: &str) -> Result<(), GuestFSError> {
         eprintln!("{self}: Mounting to {mountpoint}");
-        self.handle.mount_ro(self.device.as_str(), mountpoint)
+        let device = self.device.clone();
+        let mountpoint = mountpoint.to_string();
+        self.worker
+            .call(move |handle| handle.mount_ro(device, mountpoint))
+    }
+
+    pub(crate) fn mount_rw(&self, mountpoint: &str) -> Result<(), GuestFSError> {
+        eprintln!("{self}: Mounting to {mountpoint} writable");
+        let device = self.device.clone();
+        let mountpoint = mountpoint.to_string();
+        self.worker
+            .call(move |handle| handle.mount(device, mountpoint))
     }
 }
 
@@ -95,9 +225,28 @@ pub(super) struct Mount {
 }
 
 impl Mount {
-    pub(super) fn mount_suitable(&self, available: &[Partition]) -> Result<(), VirtualRootError> {
+    pub(super) fn validate(&self) -> Result<(), VirtualRootError> {
+        if self.partition == 0 {
+            return Err(VirtualRootError::ConfigError(format!(
+                "Partitions are numbered from 1, got {} for {}",
+                self.partition, self.mountpoint
+            )));
+        }
+        Ok(())
+    }
+
+    pub(super) fn mount_suitable(
+        &self,
+        available: &[Partition],
+        writable: bool,
+    ) -> Result<(), VirtualRootError> {
         if let Some(partition) = available.get(self.partition - 1) {
-            if let Err(guestfs_error) = partition.mount_ro(self.mountpoint.as_str()) {
+            let mounted = if writable {
+                partition.mount_rw(self.mountpoint.as_str())
+            } else {
+                partition.mount_ro(self.mountpoint.as_str())
+            };
+            if let Err(guestfs_error) = mounted {
                 Err(VirtualRootError::SetupError(guestfs_error))
             } else {
                 Ok(())
@@ -131,6 +280,11 @@ impl FileChunk {
     pub(super) fn new(buffer: Vec<u8>) -> Self {
         Self { buffer, offset: 0 }
     }
+
+    fn remaining(&self) -> usize {
+        self.buffer.len() - self.offset
+    }
+
     pub(super) fn fill(&mut self, buffer: &mut [u8]) -> usize {
         let available_bytes = &self.buffer[self.offset..];
         if available_bytes.is_empty() {
@@ -150,42 +304,66 @@ impl FileChunk {
 
 #[derive(Debug)]
 pub(super) struct FileReader {
-    handle: Rc<GuestFS>,
+    worker: Arc<DiskWorker>,
     path: String,
     file_size: usize,
+    mtime: u64,
     current_offset: usize,
-    chunk: FileChunk,
+    chunks: VecDeque<FileChunk>,
+    // Where the chunk after the buffered ones starts.
+    fetch_offset: usize,
+    pending_chunk: Option<PendingResult<Vec<u8>>>,
     display: String,
 }
 
 impl FileReader {
     pub(super) fn open(
-        handle: Rc<GuestFS>,
+        worker: Arc<DiskWorker>,
         path: String,
-        file_size: usize,
+        file_stat: FileStat,
         display: String,
     ) -> Result<Self, GuestFSError> {
-        let first_chunk = handle.read_chunk(&path, 0)?;
-        Ok(Self {
-            handle,
+        let chunk_path = path.clone();
+        let first_chunk = worker.call(move |handle| handle.read_chunk(chunk_path, 0))?;
+        let mut file_reader = Self {
+            worker,
             path,
-            file_size,
+            file_size: file_stat.size,
+            mtime: file_stat.mtime,
             current_offset: 0,
-            chunk: FileChunk::new(first_chunk),
+            chunks: VecDeque::new(),
+            fetch_offset: 0,
+            pending_chunk: None,
             display,
-        })
+        };
+        file_reader.accept_chunk(first_chunk);
+        Ok(file_reader)
     }
 
-    fn buffer_new_chunk(&mut self) -> Result<bool, GuestFSError> {
-        let next_chunk = self
-            .handle
-            .read_chunk(self.path.as_str(), self.current_offset)?;
-        if next_chunk.is_empty() {
-            Ok(false)
-        } else {
-            self.chunk = FileChunk::new(next_chunk);
-            Ok(true)
+    fn buffered(&self) -> usize {
+        self.chunks.iter().map(FileChunk::remaining).sum()
+    }
+
+    fn accept_chunk(&mut self, chunk: Vec<u8>) -> bool {
+        if chunk.is_empty() {
+            // The file is shorter than it was at open time.
+            self.file_size = self.fetch_offset;
+            return false;
         }
+        self.fetch_offset += chunk.len();
+        self.chunks.push_back(FileChunk::new(chunk));
+        true
+    }
+
+    // Used by synchronous readers only, a transfer makes the data ready with `poll_fill` first.
+    fn fetch_blocking(&mut self) -> Result<bool, GuestFSError> {
+        self.pending_chunk = None;
+        let path = self.path.clone();
+        let fetch_offset = self.fetch_offset;
+        let chunk = self
+            .worker
+            .call(move |handle| handle.read_chunk(path, fetch_offset))?;
+        Ok(self.accept_chunk(chunk))
     }
 }
 
@@ -199,18 +377,20 @@ impl OpenedFile for FileReader {
     fn read_to(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
         let mut read: usize = 0;
         while self.current_offset < self.file_size && read < buffer.len() {
-            let copied = self.chunk.fill(&mut buffer[read..]);
+            let copied = match self.chunks.front_mut() {
+                Some(chunk) => chunk.fill(&mut buffer[read..]),
+                None => 0,
+            };
             if copied == 0 {
-                let chunk_has_data = match self.buffer_new_chunk() {
-                    Ok(result) => result,
-                    Err(guestfs_error) => {
-                        return Err(io::Error::other(guestfs_error));
-                    }
-                };
-                if !chunk_has_data {
-                    break;
+                if self.chunks.pop_front().is_some() {
+                    continue;
                 }
-            };
+                match self.fetch_blocking() {
+                    Ok(true) => continue,
+                    Ok(false) => break,
+                    Err(guestfs_error) => return Err(io::Error::other(guestfs_error)),
+                }
+            }
             read += copied;
             self.current_offset += copied;
         }
@@ -220,63 +400,199 @@ impl OpenedFile for FileReader {
     fn get_size(&mut self) -> io::Result<usize> {
         Ok(self.file_size)
     }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.current_offset = 0;
+        self.fetch_offset = 0;
+        self.chunks.clear();
+        self.pending_chunk = None;
+        Ok(())
+    }
+
+    fn mtime(&mut self) -> io::Result<u64> {
+        Ok(self.mtime)
+    }
+
+    fn poll_fill(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<io::Result<()>> {
+        while self.buffered() < wanted && self.fetch_offset < self.file_size {
+            let pending_chunk = self.pending_chunk.get_or_insert_with(|| {
+                let path = self.path.clone();
+                let fetch_offset = self.fetch_offset;
+                self.worker
+                    .submit(move |handle| handle.read_chunk(path, fetch_offset))
+            });
+            let chunk = ready!(Pin::new(pending_chunk).poll(cx));
+            self.pending_chunk = None;
+            match chunk {
+                Ok(chunk) => {
+                    if !self.accept_chunk(chunk) {
+                        break;
+                    }
+                }
+                Err(guestfs_error) => return Poll::Ready(Err(io::Error::other(guestfs_error))),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // Goes to the appliance directly, the chunks buffered ahead for `read_to` stay as they are.
+    fn read_at(&mut self, offset: usize, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut read: usize = 0;
+        while offset + read < self.file_size && read < buffer.len() {
+            let path = self.path.clone();
+            let (range_offset, count) = (offset + read, buffer.len() - read);
+            let range = self
+                .worker
+                .call(move |handle| handle.read_range(path, range_offset, count))
+                .map_err(io::Error::other)?;
+            if range.is_empty() {
+                break;
+            }
+            buffer[read..read + range.len()].copy_from_slice(&range);
+            read += range.len();
+        }
+        Ok(read)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub(super) struct ConnectedDisk {
-    handle: Rc<GuestFS>,
+    worker: Arc<DiskWorker>,
     url: String,
+    writable: bool,
 }
 
 impl Display for ConnectedDisk {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write! {f, "<NBDDisk: {} [{}]>", self.url, self.handle}
+        let access = if self.writable { ", writable" } else { "" };
+        write! {f, "<NBDDisk: {}{access} [{}]>", self.url, self.worker}
     }
 }
 
 impl ConnectedDisk {
-    pub(super) fn new(handle: Rc<GuestFS>, url: String) -> Self {
-        Self { handle, url }
+    pub(super) fn new(worker: Arc<DiskWorker>, url: String, writable: bool) -> Self {
+        Self {
+            worker,
+            url,
+            writable,
+        }
     }
 }
 
 impl ConnectedDisk {
     pub(super) fn list_partitions(&mut self) -> Result<Vec<Partition>, GuestFSError> {
-        let partitions = self.handle.list_partitions()?;
+        let partitions = self.worker.call(|handle| handle.list_partitions())?;
         eprintln!("{self}: Found partitions: {partitions:?}");
         let mut result: Vec<Partition> = Vec::new();
         for partition_name in partitions {
-            result.push(Partition::new(self.handle.clone(), partition_name));
+            result.push(Partition::new(self.worker.clone(), partition_name));
         }
-        for warning in self.handle.retrieve_appliance_stderr() {
+        let warnings = self
+            .worker
+            .call(|handle| Ok(handle.retrieve_appliance_stderr()))?;
+        for warning in warnings {
             eprintln!("{self}: {warning}");
         }
         Ok(result)
     }
 
+    // Roots open files through the file cache, this opens one directly.
+    #[cfg(test)]
     pub(super) fn open(&self, absolute_path: &str) -> io::Result<FileReader> {
-        let file_size = match self.handle.get_size(absolute_path) {
-            Ok(file_size) => file_size,
-            Err(guestfs_error) => {
-                return if guestfs_error
-                    .to_string()
-                    .contains("No such file or directory")
-                {
-                    Err(io::ErrorKind::NotFound.into())
-                } else {
-                    Err(io::Error::other(guestfs_error))
-                };
-            }
-        };
-        let display = format!("<{absolute_path} on {self}>");
+        let file_stat = self.stat(absolute_path)?;
+        self.open_stat(absolute_path, file_stat, self.display_file(absolute_path))
+    }
+
+    // The appliance reads any file as root, so files readable by nobody are refused here, as a local root
+    // refuses them.
+    pub(super) fn stat(&self, absolute_path: &str) -> io::Result<FileStat> {
+        let stat_path = absolute_path.to_string();
+        let file_stat = self
+            .worker
+            .call(move |handle| handle.stat(stat_path))
+            .map_err(open_error)?;
+        if file_stat.mode & 0o444 == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is readable by nobody", self.display_file(absolute_path)),
+            ));
+        }
+        Ok(file_stat)
+    }
+
+    pub(super) fn list_dir(&self, absolute_path: &str) -> io::Result<Vec<String>> {
+        let directory = absolute_path.to_string();
+        let mut names = self
+            .worker
+            .call(move |handle| handle.ls(directory))
+            .map_err(open_error)?;
+        names.sort();
+        Ok(names)
+    }
+
+    // Only meaningful for write requests, which are not served yet.
+    #[allow(dead_code)]
+    pub(super) fn create(&self, absolute_path: &str, content: &[u8]) -> io::Result<()> {
+        if !self.writable {
+            return Err(io::Error::new(
+                io::ErrorKind::ReadOnlyFilesystem,
+                format!("{self} is attached read-only"),
+            ));
+        }
+        eprintln!("{self}: Writing {} bytes to {absolute_path}", content.len());
+        let write_path = absolute_path.to_string();
+        let content = content.to_vec();
+        self.worker
+            .call(move |handle| handle.write(write_path, &content))
+            .map_err(open_error)
+    }
+
+    fn display_file(&self, absolute_path: &str) -> String {
+        format!("<{absolute_path} on {self}>")
+    }
+
+    fn open_stat(
+        &self,
+        absolute_path: &str,
+        file_stat: FileStat,
+        display: String,
+    ) -> io::Result<FileReader> {
         match FileReader::open(
-            self.handle.clone(),
+            self.worker.clone(),
             absolute_path.to_string(),
-            file_size,
+            file_stat,
             display,
         ) {
             Ok(file_reader) => Ok(file_reader),
-            Err(guestfs_error) => Err(io::Error::other(guestfs_error)),
+            Err(guestfs_error) => Err(open_error(guestfs_error)),
         }
     }
 }
+
+pub(super) fn open_error(guestfs_error: GuestFSError) -> io::Error {
+    let message = guestfs_error.to_string();
+    if message.contains("No such file or directory") {
+        io::ErrorKind::NotFound.into()
+    } else if message.contains("Is a directory") {
+        io::ErrorKind::IsADirectory.into()
+    } else if message.contains("Permission denied") {
+        io::Error::new(io::ErrorKind::PermissionDenied, guestfs_error)
+    } else if message.contains("File too large") {
+        io::Error::new(io::ErrorKind::FileTooLarge, guestfs_error)
+    } else if message.contains("No space left on device") {
+        io::Error::new(io::ErrorKind::StorageFull, guestfs_error)
+    } else if message.contains("timed out") {
+        io::Error::new(io::ErrorKind::TimedOut, guestfs_error)
+    } else if is_misconfiguration(&message) {
+        io::Error::new(io::ErrorKind::NotADirectory, guestfs_error)
+    } else {
+        io::Error::other(guestfs_error)
+    }
+}
+
+// Errors meaning the disk is mounted not the way the config expects rather than the file is missing.
+fn is_misconfiguration(guestfs_message: &str) -> bool {
+    guestfs_message.contains("you must call 'mount' first")
+        || guestfs_message.contains("mount point does not exist")
+        || guestfs_message.contains("Not a directory")
+}
@@ -0,0 +1,584 @@
+use crate::appliances::appliances;
+use crate::error::{ERROR, TFTPError};
+use crate::fs_watch::{Event, Observer};
+use crate::messages::ReadRequest;
+use crate::nbd_disk;
+use crate::peer_handler::{
+    ACK, DATA, HandlerExitReason, PeerHandler, ReplySource, RootLister, SessionContext,
+};
+use crate::stats;
+use crate::stats::{ServerStats, StatsRequest, TransferRecord};
+use crate::worker_pool::WorkerPool;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::poll_fn;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::ReadBuf;
+use tokio::net::{TcpListener, TcpStream, UdpSocket, UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+#[cfg(test)]
+mod tests;
+
+const BUFFER_SIZE: usize = u16::MAX as _;
+
+fn local_addresses(sockets: &[UdpSocket]) -> Vec<String> {
+    sockets
+        .iter()
+        .map(|socket| {
+            let local_addr = socket
+                .local_addr()
+                .unwrap_or_else(|err| panic!("Failed to get {socket:?} address: {err}"));
+            format!("{}:{}", local_addr.ip(), local_addr.port())
+        })
+        .collect()
+}
+
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+    if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } != 0 {
+        return String::from("localhost");
+    }
+    let length = name
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..length]).into_owned()
+}
+
+// Waits for a datagram on any of the sockets, starting the poll from the given one for fairness.
+async fn recv_from_any(
+    sockets: &[UdpSocket],
+    first: usize,
+    buffer: &mut [u8],
+) -> (usize, io::Result<(usize, SocketAddr)>) {
+    poll_fn(|cx| {
+        for index in (0..sockets.len()).map(|offset| (first + offset) % sockets.len()) {
+            let mut read_buf = ReadBuf::new(&mut *buffer);
+            if let Poll::Ready(result) = sockets[index].poll_recv_from(cx, &mut read_buf) {
+                let read_bytes = read_buf.filled().len();
+                return Poll::Ready((index, result.map(|remote| (read_bytes, remote))));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+fn opcode(datagram: &[u8]) -> Option<u16> {
+    match datagram {
+        [high, low, ..] => Some(u16::from_be_bytes([*high, *low])),
+        _ => None,
+    }
+}
+
+// What clients send to a session rather than to the server.
+fn is_session_datagram(datagram: &[u8]) -> bool {
+    matches!(opcode(datagram), Some(ACK | ERROR))
+}
+
+// A dual-stack socket doesn't tell the IPv4 address a request was received on, so an IPv4 peer is replied
+// from the IPv4 wildcard and the routing picks the address.
+fn reply_address(local_ip: IpAddr, peer_ip: IpAddr) -> IpAddr {
+    match (local_ip, peer_ip) {
+        (IpAddr::V6(local_ip), IpAddr::V4(_)) if local_ip.is_unspecified() => {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        }
+        _ => local_ip,
+    }
+}
+
+// Never resolves when the stats socket is not configured.
+async fn accept_stats_client(listener: &Option<UnixListener>) -> io::Result<UnixStream> {
+    match listener {
+        Some(listener) => listener.accept().await.map(|(stream, _addr)| stream),
+        None => std::future::pending().await,
+    }
+}
+
+// Asked of the handler of the peer the config belongs to, which alone holds its disk.
+async fn list_dir(
+    tftp_root: &Path,
+    root_listers: &HashMap<IpAddr, RootLister>,
+    config: PathBuf,
+    path: String,
+) -> serde_json::Value {
+    let mut reply = serde_json::json!({"config": config, "path": path});
+    let listed =
+        match nbd_disk::config_peer(tftp_root, &config).and_then(|peer| root_listers.get(&peer)) {
+            Some(root_lister) => root_lister.list_dir(config, path).await,
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No handler of the config is running",
+            )),
+        };
+    match listed {
+        Ok(names) => reply["files"] = names.into(),
+        Err(error) => reply["error"] = error.to_string().into(),
+    }
+    reply
+}
+
+// Never resolves when the metrics endpoint is not configured.
+async fn accept_metrics_client(listener: &Option<TcpListener>) -> io::Result<TcpStream> {
+    match listener {
+        Some(listener) => listener.accept().await.map(|(stream, _addr)| stream),
+        None => std::future::pending().await,
+    }
+}
+
+pub(super) struct TFTPServer {
+    sockets: Vec<UdpSocket>,
+    next_socket: usize,
+    root_dir: PathBuf,
+    default_root: Option<String>,
+    peer_handlers: HashMap<IpAddr, PeerHandler>,
+    // Evicted handlers finish their sessions on blocking threads and report their exits through these.
+    retired_tx: UnboundedSender<(IpAddr, HandlerExitReason)>,
+    retired_rx: UnboundedReceiver<(IpAddr, HandlerExitReason)>,
+    stats: ServerStats,
+    stats_receiver: UnboundedReceiver<TransferRecord>,
+    stats_listener: Option<UnixListener>,
+    metrics_listener: Option<TcpListener>,
+    // Sessions reply from the listen sockets instead of fresh ones.
+    listen_port_replies: bool,
+    max_peers: Option<usize>,
+    evict_idle_peers: bool,
+    max_idle_time: Duration,
+    max_options: usize,
+    session_context: SessionContext,
+    // Peers without NBD configs are handled here rather than on threads of their own.
+    worker_pool: Option<WorkerPool>,
+    buffer: [u8; BUFFER_SIZE],
+    // Tells the logs and stats of several servers apart.
+    instance_name: String,
+    display: String,
+}
+
+impl TFTPServer {
+    pub(super) fn new(
+        sockets: Vec<UdpSocket>,
+        root_dir: PathBuf,
+        default_root: Option<String>,
+        idle_timeout: u64,
+        max_options: usize,
+        session_context: SessionContext,
+    ) -> Self {
+        let (stats_reporter, stats_receiver) = stats::channel();
+        let (retired_tx, retired_rx) = mpsc::unbounded_channel();
+        let max_idle_time = Duration::from_secs(idle_timeout);
+        let local_addresses = local_addresses(&sockets);
+        let instance_name = format!("{}/{}", hostname(), local_addresses[0]);
+        let display = format!("<TFTP {instance_name} on {}>", local_addresses.join(", "));
+        Self {
+            sockets,
+            next_socket: 0,
+            root_dir,
+            default_root,
+            peer_handlers: HashMap::new(),
+            retired_tx,
+            retired_rx,
+            stats: ServerStats::default(),
+            stats_receiver,
+            stats_listener: None,
+            metrics_listener: None,
+            listen_port_replies: false,
+            max_peers: None,
+            evict_idle_peers: false,
+            max_idle_time,
+            max_options,
+            session_context: session_context.reporting_to(stats_reporter),
+            worker_pool: None,
+            buffer: [0; BUFFER_SIZE],
+            instance_name,
+            display,
+        }
+    }
+
+    pub(super) fn name_instance(&mut self, instance_name: String) {
+        let local_addresses = local_addresses(&self.sockets);
+        self.display = format!("<TFTP {instance_name} on {}>", local_addresses.join(", "));
+        self.instance_name = instance_name;
+    }
+
+    // Opens handlers for peers with prewarm configs, so their disks are connected before the first request.
+    pub(super) fn prewarm(&mut self) {
+        for peer in nbd_disk::prewarmed_peers(&self.root_dir) {
+            eprintln!("{self}: Prewarm the disks of {peer}");
+            let handler = self.open_handler(peer);
+            self.peer_handlers.insert(peer, handler);
+        }
+    }
+
+    pub(super) fn expose_stats(&mut self, listener: UnixListener) {
+        self.stats_listener = Some(listener);
+    }
+
+    pub(super) fn expose_metrics(&mut self, listener: TcpListener) {
+        self.metrics_listener = Some(listener);
+    }
+
+    pub(super) fn reply_from_listen_port(&mut self) {
+        self.listen_port_replies = true;
+    }
+
+    pub(super) fn share_workers(&mut self, worker_pool: WorkerPool) {
+        eprintln!("{self}: Peers without NBD configs are served by {worker_pool}");
+        self.worker_pool = Some(worker_pool);
+    }
+
+    // Every peer IP takes a thread, so a flood of spoofed sources can't be allowed to spawn them unbounded.
+    pub(super) fn limit_peers(&mut self, max_peers: usize, evict_idle_peers: bool) {
+        self.max_peers = Some(max_peers);
+        self.evict_idle_peers = evict_idle_peers;
+    }
+
+    // Serves until a listen socket fails, which is fatal.
+    pub(super) async fn serve_augmented<T: Observer>(
+        &mut self,
+        turn_duration: Duration,
+        fs_observer: &T,
+    ) -> io::Result<()> {
+        eprintln!("{self}: Listening");
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(turn_duration) => self.reap_finished_handlers(),
+                Some(record) = self.stats_receiver.recv() => self.stats.record_transfer(record),
+                Some((peer, exit_reason)) = self.retired_rx.recv() => self.record_exit(peer, exit_reason),
+                accept_result = accept_stats_client(&self.stats_listener) => self.serve_stats(accept_result),
+                accept_result = accept_metrics_client(&self.metrics_listener) => self.serve_metrics(accept_result),
+                event = fs_observer.next() => self.handle_config_event(event),
+                (socket_index, read_result) = recv_from_any(&self.sockets, self.next_socket, &mut self.buffer) => {
+                    self.next_socket = socket_index + 1;
+                    match read_result {
+                        Ok((read_bytes, remote)) => self.handle_request(socket_index, read_bytes, remote).await,
+                        Err(error) => return Err(self.read_error(socket_index, error)),
+                    }
+                }
+            }
+        }
+    }
+
+    // Serves until a listen socket fails, which is fatal.
+    pub(super) async fn serve(&mut self, turn_duration: Duration) -> io::Result<()> {
+        eprintln!("{self}: Listening");
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(turn_duration) => self.reap_finished_handlers(),
+                Some(record) = self.stats_receiver.recv() => self.stats.record_transfer(record),
+                Some((peer, exit_reason)) = self.retired_rx.recv() => self.record_exit(peer, exit_reason),
+                accept_result = accept_stats_client(&self.stats_listener) => self.serve_stats(accept_result),
+                accept_result = accept_metrics_client(&self.metrics_listener) => self.serve_metrics(accept_result),
+                (socket_index, read_result) = recv_from_any(&self.sockets, self.next_socket, &mut self.buffer) => {
+                    self.next_socket = socket_index + 1;
+                    match read_result {
+                        Ok((read_bytes, remote)) => self.handle_request(socket_index, read_bytes, remote).await,
+                        Err(error) => return Err(self.read_error(socket_index, error)),
+                    }
+                }
+            }
+        }
+    }
+
+    fn read_error(&self, socket_index: usize, error: io::Error) -> io::Error {
+        let local_address = match self.sockets[socket_index].local_addr() {
+            Ok(local_address) => local_address.to_string(),
+            Err(_) => format!("socket #{socket_index}"),
+        };
+        io::Error::new(
+            error.kind(),
+            format!("Failed to read from {local_address}: {error}"),
+        )
+    }
+
+    fn handle_config_event<E: Event>(&mut self, event: E) {
+        let file_name = event.file_name();
+        let Some((stem, _extension)) = file_name.rsplit_once('.') else {
+            return;
+        };
+        let Ok(remote_ip) = IpAddr::from_str(stem) else {
+            return;
+        };
+        if event.is_modify() {
+            // A running handler keeps serving its sessions while it looks its roots up anew, unless the
+            // configs now call for it to run elsewhere.
+            if let Some(handler) = self.peer_handlers.get(&remote_ip)
+                && handler.is_pooled() == self.is_pooled(remote_ip)
+                && handler.reload_roots()
+            {
+                eprintln!(
+                    "{self}: Config for {remote_ip} is modified, reload the roots of its handler"
+                );
+                return;
+            }
+            eprintln!("{self}: Config for {remote_ip} is modified, explicitly open a new handle");
+            let new_handler = self.open_handler(remote_ip);
+            if let Some(previous_handler) = self.peer_handlers.insert(remote_ip, new_handler) {
+                self.record_exit(remote_ip, previous_handler.shutdown());
+            }
+        } else if event.is_moved_from() {
+            // The config may be replaced by one with a different name, roots opened from it are stale.
+            if let Some(handler) = self.peer_handlers.remove(&remote_ip) {
+                eprintln!("{self}: Config for {remote_ip} is moved away, release its roots");
+                self.record_exit(remote_ip, handler.shutdown());
+            }
+        }
+    }
+
+    // Remote disks are connected and read by blocking calls, which would stall the other peers of a worker.
+    fn is_pooled(&self, peer: IpAddr) -> bool {
+        self.worker_pool.is_some() && !nbd_disk::has_configs(&self.root_dir, &peer.to_string())
+    }
+
+    fn open_handler(&mut self, peer: IpAddr) -> PeerHandler {
+        self.stats.record_handler_created(peer);
+        let is_pooled = self.is_pooled(peer);
+        let idle_timeout = self.max_idle_time;
+        let (root_dir, default_root) = (self.root_dir.clone(), self.default_root.clone());
+        let session_context = self.session_context.clone();
+        match &mut self.worker_pool {
+            Some(worker_pool) if is_pooled => PeerHandler::pooled(
+                peer,
+                root_dir,
+                default_root,
+                idle_timeout,
+                session_context,
+                worker_pool,
+            ),
+            _ => PeerHandler::new(peer, root_dir, default_root, idle_timeout, session_context),
+        }
+    }
+
+    fn reap_finished_handlers(&mut self) {
+        self.session_context.disk_cache().evict_expired();
+        let finished: Vec<IpAddr> = self
+            .peer_handlers
+            .iter()
+            .filter(|(_ip_addr, handler)| handler.is_finished())
+            .map(|(ip_addr, _handler)| *ip_addr)
+            .collect();
+        for ip_addr in finished {
+            if let Some(handler) = self.peer_handlers.remove(&ip_addr) {
+                self.record_exit(ip_addr, handler.join());
+            }
+        }
+    }
+
+    // A full server refuses new peers unless it may evict the handler given a request longest ago.
+    fn admit_peer(&mut self, peer: IpAddr) -> bool {
+        let Some(max_peers) = self.max_peers else {
+            return true;
+        };
+        if self.peer_handlers.len() >= max_peers {
+            self.reap_finished_handlers();
+        }
+        if self.peer_handlers.len() < max_peers {
+            return true;
+        }
+        if !self.evict_idle_peers {
+            eprintln!("{self}: Refuse {peer}, the limit of {max_peers} peers is reached");
+            return false;
+        }
+        let oldest_idle = self
+            .peer_handlers
+            .iter()
+            .min_by_key(|(_peer, handler)| handler.last_fed())
+            .map(|(peer, _handler)| *peer);
+        if let Some(oldest_idle) = oldest_idle
+            && let Some(handler) = self.peer_handlers.remove(&oldest_idle)
+        {
+            eprintln!("{self}: Evict the handler of {oldest_idle} to admit {peer}");
+            // Its running transfers are completed first, which the server doesn't wait for.
+            let retired_tx = self.retired_tx.clone();
+            tokio::task::spawn_blocking(move || {
+                _ = retired_tx.send((oldest_idle, handler.shutdown()));
+            });
+        }
+        true
+    }
+
+    fn record_exit(&mut self, peer: IpAddr, exit_reason: HandlerExitReason) {
+        eprintln!("{self}: Handler for {peer} exited: {exit_reason}");
+        self.stats.record_handler_exit(exit_reason.label());
+        self.stats.record_handler_destroyed(peer);
+    }
+
+    fn serve_stats(&mut self, accept_result: io::Result<UnixStream>) {
+        let stream = match accept_result {
+            Ok(stream) => stream,
+            Err(error) => {
+                eprintln!("{self}: Stats socket accept error: {error}");
+                return;
+            }
+        };
+        self.collect_records();
+        let mut snapshot = self.stats.to_json();
+        snapshot["instance"] = self.instance_name.as_str().into();
+        snapshot["handlers"] = self.peer_handlers.len().into();
+        snapshot["appliances"] = appliances().to_json();
+        let root_listers: HashMap<IpAddr, RootLister> = self
+            .peer_handlers
+            .iter()
+            .map(|(peer, handler)| (*peer, handler.root_lister()))
+            .collect();
+        let tftp_root = self.root_dir.clone();
+        tokio::task::spawn_local(async move {
+            let reply = match stats::read_stats_request(&stream).await {
+                Ok(StatsRequest::Snapshot) => snapshot,
+                Ok(StatsRequest::ListDir { config, path }) => {
+                    list_dir(&tftp_root, &root_listers, config, path).await
+                }
+                Err(error) => serde_json::json!({"error": error.to_string()}),
+            };
+            if let Err(error) = stats::write_snapshot(stream, reply).await {
+                eprintln!("Failed to write stats: {error}");
+            }
+        });
+    }
+
+    fn serve_metrics(&mut self, accept_result: io::Result<TcpStream>) {
+        let stream = match accept_result {
+            Ok(stream) => stream,
+            Err(error) => {
+                eprintln!("{self}: Metrics endpoint accept error: {error}");
+                return;
+            }
+        };
+        self.collect_records();
+        let metrics = self
+            .stats
+            .to_prometheus(&self.instance_name, self.peer_handlers.len());
+        tokio::task::spawn_local(async move {
+            if let Err(error) = stats::write_metrics(stream, metrics).await {
+                eprintln!("Failed to write metrics: {error}");
+            }
+        });
+    }
+
+    // Records of the sessions and evicted handlers finished meanwhile, so a snapshot doesn't lag behind.
+    fn collect_records(&mut self) {
+        while let Ok(record) = self.stats_receiver.try_recv() {
+            self.stats.record_transfer(record);
+        }
+        while let Ok((peer, exit_reason)) = self.retired_rx.try_recv() {
+            self.record_exit(peer, exit_reason);
+        }
+    }
+
+    async fn handle_request(&mut self, socket_index: usize, size: usize, remote: SocketAddr) {
+        // IPv4 peers of a dual-stack socket come IPv4-mapped, their handlers and configs are keyed by IPv4.
+        let remote_ip = remote.ip().to_canonical();
+        if self.listen_port_replies && is_session_datagram(&self.buffer[..size]) {
+            match self.peer_handlers.get(&remote_ip) {
+                Some(handler) => handler.forward(remote.port(), &self.buffer[..size]),
+                None => eprintln!("{remote}: Ignore datagram {size} long without a session"),
+            }
+            return;
+        }
+        // Transfers go on between the client port and the session port, the listen port takes only requests.
+        // An error is never answered, so two parties can't keep erring at each other.
+        match opcode(&self.buffer[..size]) {
+            Some(DATA | ACK) => {
+                eprintln!("{remote}: Datagram {size} long is sent to the listen port");
+                let tftp_error = TFTPError::unknown_transfer_id();
+                self.reply_error(socket_index, remote, tftp_error).await;
+                return;
+            }
+            Some(ERROR) => {
+                eprintln!("{remote}: Ignore error sent to the listen port");
+                return;
+            }
+            _ => {}
+        }
+        match ReadRequest::parse(&self.buffer[..size], self.max_options) {
+            Ok(rrq) => {
+                eprintln!("Received {rrq} from {remote}");
+                if !self.peer_handlers.contains_key(&remote_ip) && !self.admit_peer(remote_ip) {
+                    let tftp_error = TFTPError::undefined("Too many peers, try again later");
+                    self.reply_error(socket_index, remote, tftp_error).await;
+                    return;
+                }
+                let socket = &self.sockets[socket_index];
+                // Replies go out from the address the request was received on.
+                let reply_source = if self.listen_port_replies {
+                    match socket.as_fd().try_clone_to_owned() {
+                        Ok(listen_fd) => ReplySource::Listen(listen_fd.into()),
+                        Err(error) => {
+                            eprintln!("{remote}: Can't share the listen socket: {error}");
+                            return;
+                        }
+                    }
+                } else {
+                    ReplySource::Ephemeral(reply_address(
+                        socket.local_addr().unwrap().ip(),
+                        remote_ip,
+                    ))
+                };
+                if !self.peer_handlers.contains_key(&remote_ip) {
+                    let handler = self.open_handler(remote_ip);
+                    self.peer_handlers.insert(remote_ip, handler);
+                }
+                let handler = self.peer_handlers.get_mut(&remote_ip).unwrap();
+                if !handler.feed(reply_source, remote.port(), rrq).await {
+                    eprintln!("{handler}: Failed to feed. Shutting down ...");
+                    if let Some(handler) = self.peer_handlers.remove(&remote_ip) {
+                        self.record_exit(remote_ip, handler.shutdown());
+                    }
+                }
+            }
+            Err(tftp_error) => {
+                eprintln!("{remote}: RRQ parsing error: {tftp_error}");
+                self.reply_error(socket_index, remote, tftp_error).await;
+            }
+        }
+    }
+
+    async fn reply_error(
+        &mut self,
+        socket_index: usize,
+        remote: SocketAddr,
+        tftp_error: TFTPError,
+    ) {
+        let Ok(size) = tftp_error.serialize(&mut self.buffer) else {
+            return;
+        };
+        match self.sockets[socket_index]
+            .send_to(&self.buffer[..size], remote)
+            .await
+        {
+            Ok(_) => stats::count_error_sent(tftp_error.code()),
+            Err(_) => eprintln!("{remote}: Error sending {tftp_error:?}"),
+        }
+    }
+}
+
+impl Display for TFTPServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.display)
+    }
+}
+
+impl Drop for TFTPServer {
+    fn drop(&mut self) {
+        let handlers: Vec<_> = self.peer_handlers.drain().collect();
+        for (ip_addr, handler) in handlers {
+            let exit_reason = handler.shutdown();
+            self.record_exit(ip_addr, exit_reason);
+        }
+        let mut exit_reasons: Vec<_> = self.stats.handler_exits().iter().collect();
+        exit_reasons.sort();
+        let summary = exit_reasons
+            .iter()
+            .map(|(label, count)| format!("{label}: {count}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        eprintln!("{self}: Handler exit reasons: [{summary}]");
+    }
+}
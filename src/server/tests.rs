@@ -0,0 +1,41 @@
+use crate::peer_handler::SessionContext;
+use crate::server::TFTPServer;
+use crate::tests_common::mk_tmp;
+use std::io;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+#[tokio::test(flavor = "current_thread")]
+async fn fatal_socket_error_ends_serving() {
+    // A connected UDP socket is told about the ICMP port unreachable, the read after the last queued
+    // datagram fails with it.
+    let socket = UdpSocket::bind("127.0.0.10:0").await.unwrap();
+    let gone_peer = UdpSocket::bind("127.0.0.20:0").await.unwrap();
+    socket
+        .connect(gone_peer.local_addr().unwrap())
+        .await
+        .unwrap();
+    gone_peer
+        .send_to(b"\x00\x09", socket.local_addr().unwrap())
+        .await
+        .unwrap();
+    drop(gone_peer);
+    socket.send(b"unanswered").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let mut server = TFTPServer::new(
+        vec![socket],
+        mk_tmp(fatal_socket_error_ends_serving),
+        None,
+        30,
+        32,
+        SessionContext::default(),
+    );
+    let served = timeout(
+        Duration::from_secs(5),
+        server.serve(Duration::from_millis(100)),
+    )
+    .await
+    .expect("The server kept serving");
+    assert_eq!(served.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+}
@@ -0,0 +1,559 @@
+use hdrhistogram::Histogram;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Debug, Formatter, Write};
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+#[cfg(test)]
+mod tests;
+
+const HISTOGRAM_SIGNIFICANT_FIGURES: u8 = 3;
+const QUANTILES: [(&str, f64); 4] = [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("p999", 0.999)];
+// A scrape request is never larger, its content is ignored anyway.
+const MAX_HTTP_REQUEST: usize = 8192;
+// A scraper that connects and never completes its request doesn't hold the connection longer.
+const HTTP_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// A stats client sending nothing within this long, or closing its end, is answered with the snapshot.
+const STATS_REQUEST_TIMEOUT: Duration = Duration::from_millis(200);
+const MAX_STATS_REQUEST: usize = 8192;
+// The handlers of so many peers are followed, the peers without one the longest are forgotten first.
+const MAX_HANDLER_LIFECYCLES: usize = 1024;
+
+// Sessions run on the peer handler threads and errors are sent from both them and the server, so these are
+// counted process-wide, like the appliances.
+static ACTIVE_SESSIONS: AtomicUsize = AtomicUsize::new(0);
+static ERRORS_SENT: Mutex<BTreeMap<u16, u64>> = Mutex::new(BTreeMap::new());
+static ALIEN_DATAGRAMS: AtomicU64 = AtomicU64::new(0);
+// Remote roots are connected by the peer handlers, by the paths of their configs.
+static ROOT_CONNECTIONS: Mutex<BTreeMap<PathBuf, RootConnection>> = Mutex::new(BTreeMap::new());
+
+pub(super) fn count_error_sent(code: u16) {
+    *ERRORS_SENT.lock().unwrap().entry(code).or_default() += 1;
+}
+
+// Datagrams reaching a session from other addresses than its peer's.
+pub(super) fn count_alien_datagram() {
+    ALIEN_DATAGRAMS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(super) fn record_root_connected(config: &Path) {
+    let mut connections = ROOT_CONNECTIONS.lock().unwrap();
+    let connection = connections.entry(config.to_path_buf()).or_default();
+    if !connection.connected {
+        connection.since = Some(SystemTime::now());
+    }
+    connection.connected = true;
+    connection.failures = 0;
+}
+
+pub(super) fn record_root_failed(config: &Path) {
+    let mut connections = ROOT_CONNECTIONS.lock().unwrap();
+    let connection = connections.entry(config.to_path_buf()).or_default();
+    if connection.connected || connection.since.is_none() {
+        connection.since = Some(SystemTime::now());
+    }
+    connection.connected = false;
+    connection.failures += 1;
+}
+
+// The roots of a closed handler are neither connected nor retried anymore.
+pub(super) fn forget_roots<'a>(configs: impl IntoIterator<Item = &'a Path>) {
+    let mut connections = ROOT_CONNECTIONS.lock().unwrap();
+    for config in configs {
+        connections.remove(config);
+    }
+}
+
+// The connection state of a remote root since it last changed, with the failed attempts in a row.
+#[derive(Debug, Default)]
+struct RootConnection {
+    connected: bool,
+    failures: u64,
+    since: Option<SystemTime>,
+}
+
+impl RootConnection {
+    fn to_json(&self) -> Value {
+        json!({
+            "state": if self.connected { "connected" } else { "down" },
+            "failures": self.failures,
+            "since": self.since.map(unix_seconds),
+        })
+    }
+}
+
+fn roots_to_json() -> Value {
+    ROOT_CONNECTIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(config, connection)| (config.display().to_string(), connection.to_json()))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+// Counts a session as active until dropped, however the session ends.
+pub(super) struct ActiveSession(());
+
+impl ActiveSession {
+    pub(super) fn begin() -> Self {
+        ACTIVE_SESSIONS.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Drop for ActiveSession {
+    fn drop(&mut self) {
+        ACTIVE_SESSIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct TransferRecord {
+    pub(super) bytes: usize,
+    pub(super) duration: Duration,
+    pub(super) completed: bool,
+}
+
+// Carries records from sessions running on peer handler threads back to the server.
+#[derive(Clone, Default)]
+pub(super) struct StatsReporter {
+    sender: Option<UnboundedSender<TransferRecord>>,
+}
+
+impl StatsReporter {
+    pub(super) fn report(&self, record: TransferRecord) {
+        if let Some(sender) = &self.sender {
+            _ = sender.send(record);
+        }
+    }
+}
+
+impl Debug for StatsReporter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<StatsReporter: {}>", self.sender.is_some())
+    }
+}
+
+pub(super) fn channel() -> (StatsReporter, UnboundedReceiver<TransferRecord>) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    (
+        StatsReporter {
+            sender: Some(sender),
+        },
+        receiver,
+    )
+}
+
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new(HISTOGRAM_SIGNIFICANT_FIGURES).expect("Valid histogram precision")
+}
+
+// Histograms grow to fit the recorded value, saturating recording would clamp it to the initial range instead.
+fn record_value(histogram: &mut Histogram<u64>, value: u64) {
+    if let Err(error) = histogram.record(value) {
+        eprintln!("Failed to record {value}: {error}");
+    }
+}
+
+fn summarize(histogram: &Histogram<u64>) -> Value {
+    let mut summary = serde_json::Map::new();
+    summary.insert("count".into(), histogram.len().into());
+    if !histogram.is_empty() {
+        summary.insert("min".into(), histogram.min().into());
+        for (name, quantile) in QUANTILES {
+            summary.insert(name.into(), histogram.value_at_quantile(quantile).into());
+        }
+        summary.insert("max".into(), histogram.max().into());
+        summary.insert("mean".into(), histogram.mean().into());
+    }
+    Value::Object(summary)
+}
+
+// Appends a metric in the Prometheus text format, every sample given as its labels and value.
+fn write_metric(
+    output: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: &[(String, u64)],
+) {
+    _ = writeln!(output, "# HELP {name} {help}");
+    _ = writeln!(output, "# TYPE {name} {kind}");
+    for (labels, value) in samples {
+        _ = writeln!(output, "{name}{labels} {value}");
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or(0)
+}
+
+// Churn of the handlers of a single peer.
+#[derive(Debug, Default)]
+struct HandlerLifecycle {
+    created: u64,
+    destroyed: u64,
+    last_created: Option<SystemTime>,
+    last_destroyed: Option<SystemTime>,
+    // Orders the peers by their last change, however close in time.
+    last_change: u64,
+}
+
+impl HandlerLifecycle {
+    fn is_running(&self) -> bool {
+        self.created > self.destroyed
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "created": self.created,
+            "destroyed": self.destroyed,
+            "last_created": self.last_created.map(unix_seconds),
+            "last_destroyed": self.last_destroyed.map(unix_seconds),
+        })
+    }
+}
+
+pub(super) struct ServerStats {
+    completed_transfers: u64,
+    failed_transfers: u64,
+    bytes_sent: u64,
+    duration_us: Histogram<u64>,
+    bytes: Histogram<u64>,
+    throughput_bps: Histogram<u64>,
+    handler_exits: HashMap<&'static str, usize>,
+    handler_lifecycles: HashMap<IpAddr, HandlerLifecycle>,
+    lifecycle_changes: u64,
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self {
+            completed_transfers: 0,
+            failed_transfers: 0,
+            bytes_sent: 0,
+            duration_us: new_histogram(),
+            bytes: new_histogram(),
+            throughput_bps: new_histogram(),
+            handler_exits: HashMap::new(),
+            handler_lifecycles: HashMap::new(),
+            lifecycle_changes: 0,
+        }
+    }
+}
+
+impl ServerStats {
+    pub(super) fn record_transfer(&mut self, record: TransferRecord) {
+        if !record.completed {
+            self.failed_transfers += 1;
+            return;
+        }
+        self.completed_transfers += 1;
+        self.bytes_sent += record.bytes as u64;
+        let duration_us = record.duration.as_micros().max(1) as u64;
+        record_value(&mut self.duration_us, duration_us);
+        record_value(&mut self.bytes, record.bytes as u64);
+        let throughput_bps = record.bytes as u128 * 1_000_000 / duration_us as u128;
+        record_value(
+            &mut self.throughput_bps,
+            throughput_bps.min(u64::MAX as u128) as u64,
+        );
+    }
+
+    pub(super) fn record_handler_exit(&mut self, label: &'static str) {
+        *self.handler_exits.entry(label).or_default() += 1;
+    }
+
+    pub(super) fn record_handler_created(&mut self, peer: IpAddr) {
+        if !self.handler_lifecycles.contains_key(&peer)
+            && self.handler_lifecycles.len() >= MAX_HANDLER_LIFECYCLES
+        {
+            self.forget_stalest_lifecycle();
+        }
+        let lifecycle = self.lifecycle(peer);
+        lifecycle.created += 1;
+        lifecycle.last_created = Some(SystemTime::now());
+    }
+
+    pub(super) fn record_handler_destroyed(&mut self, peer: IpAddr) {
+        let lifecycle = self.lifecycle(peer);
+        lifecycle.destroyed += 1;
+        lifecycle.last_destroyed = Some(SystemTime::now());
+    }
+
+    fn lifecycle(&mut self, peer: IpAddr) -> &mut HandlerLifecycle {
+        self.lifecycle_changes += 1;
+        let lifecycle = self.handler_lifecycles.entry(peer).or_default();
+        lifecycle.last_change = self.lifecycle_changes;
+        lifecycle
+    }
+
+    // Peers with a handler running are kept however long ago it was created.
+    fn forget_stalest_lifecycle(&mut self) {
+        let stalest = self
+            .handler_lifecycles
+            .iter()
+            .filter(|(_peer, lifecycle)| !lifecycle.is_running())
+            .min_by_key(|(_peer, lifecycle)| lifecycle.last_change)
+            .map(|(peer, _lifecycle)| *peer);
+        if let Some(peer) = stalest {
+            self.handler_lifecycles.remove(&peer);
+        }
+    }
+
+    pub(super) fn handler_exits(&self) -> &HashMap<&'static str, usize> {
+        &self.handler_exits
+    }
+
+    pub(super) fn to_json(&self) -> Value {
+        json!({
+            "transfers": {
+                "completed": self.completed_transfers,
+                "failed": self.failed_transfers,
+            },
+            "bytes_sent": self.bytes_sent,
+            "active_sessions": ACTIVE_SESSIONS.load(Ordering::Relaxed),
+            "errors_sent": ERRORS_SENT
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(code, count)| (code.to_string(), (*count).into()))
+                .collect::<serde_json::Map<_, _>>(),
+            "alien_datagrams": ALIEN_DATAGRAMS.load(Ordering::Relaxed),
+            "transfer_duration_us": summarize(&self.duration_us),
+            "transfer_bytes": summarize(&self.bytes),
+            "transfer_throughput_bps": summarize(&self.throughput_bps),
+            "handler_exits": self.handler_exits,
+            "handler_lifecycles": self
+                .handler_lifecycles
+                .iter()
+                .map(|(peer, lifecycle)| (peer.to_string(), lifecycle.to_json()))
+                .collect::<serde_json::Map<_, _>>(),
+            "roots": roots_to_json(),
+        })
+    }
+
+    pub(super) fn to_prometheus(&self, instance_name: &str, handlers: usize) -> String {
+        let mut output = String::new();
+        write_metric(
+            &mut output,
+            "rtftp_info",
+            "gauge",
+            "The name of the server instance.",
+            &[(
+                format!(r#"{{instance_name="{}"}}"#, escape_label(instance_name)),
+                1,
+            )],
+        );
+        write_metric(
+            &mut output,
+            "rtftp_transfers_total",
+            "counter",
+            "Finished transfers by outcome.",
+            &[
+                (r#"{outcome="completed"}"#.into(), self.completed_transfers),
+                (r#"{outcome="failed"}"#.into(), self.failed_transfers),
+            ],
+        );
+        write_metric(
+            &mut output,
+            "rtftp_bytes_sent_total",
+            "counter",
+            "Bytes sent by completed transfers.",
+            &[(String::new(), self.bytes_sent)],
+        );
+        write_metric(
+            &mut output,
+            "rtftp_active_sessions",
+            "gauge",
+            "Sessions transferring files right now.",
+            &[(
+                String::new(),
+                ACTIVE_SESSIONS.load(Ordering::Relaxed) as u64,
+            )],
+        );
+        let errors_sent: Vec<_> = ERRORS_SENT
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(code, count)| (format!(r#"{{code="{code}"}}"#), *count))
+            .collect();
+        write_metric(
+            &mut output,
+            "rtftp_errors_total",
+            "counter",
+            "TFTP errors sent by error code.",
+            &errors_sent,
+        );
+        write_metric(
+            &mut output,
+            "rtftp_alien_datagrams_total",
+            "counter",
+            "Datagrams ignored by sessions for coming from other addresses than their peers.",
+            &[(String::new(), ALIEN_DATAGRAMS.load(Ordering::Relaxed))],
+        );
+        let roots_up: Vec<_> = ROOT_CONNECTIONS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(config, connection)| {
+                let config = escape_label(&config.display().to_string());
+                (
+                    format!(r#"{{config="{config}"}}"#),
+                    connection.connected as u64,
+                )
+            })
+            .collect();
+        write_metric(
+            &mut output,
+            "rtftp_root_up",
+            "gauge",
+            "Remote roots connected by their configs, 0 while retried after a failure.",
+            &roots_up,
+        );
+        write_metric(
+            &mut output,
+            "rtftp_handlers",
+            "gauge",
+            "Peer handlers running.",
+            &[(String::new(), handlers as u64)],
+        );
+        output
+    }
+}
+
+// Answers any HTTP request with the metrics and closes the connection.
+pub(super) async fn write_metrics(stream: TcpStream, metrics: String) -> io::Result<()> {
+    read_request(&stream, HTTP_REQUEST_TIMEOUT).await?;
+    let payload = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{metrics}",
+        metrics.len()
+    );
+    let payload = payload.as_bytes();
+    let mut written = 0;
+    while written < payload.len() {
+        stream.writable().await?;
+        match stream.try_write(&payload[written..]) {
+            Ok(written_bytes) => written += written_bytes,
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+// Skips the request up to its end, whatever it asks for.
+async fn read_request(stream: &TcpStream, timeout: Duration) -> io::Result<()> {
+    let mut request = vec![0u8; MAX_HTTP_REQUEST];
+    let mut received = 0;
+    let read = async {
+        while !request[..received].windows(4).any(|end| end == b"\r\n\r\n")
+            && received < request.len()
+        {
+            stream.readable().await?;
+            match stream.try_read(&mut request[received..]) {
+                Ok(0) => break,
+                Ok(read_bytes) => received += read_bytes,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    };
+    tokio::time::timeout(timeout, read).await.map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("No complete request in {timeout:?}"),
+        )
+    })?
+}
+
+// What a stats client asks for with a single JSON line, e.g.
+// `{"list_dir": {"config": "/srv/tftp/10.0.0.1.nbd", "path": "grub"}}`.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub(super) enum StatsRequest {
+    Snapshot,
+    // The names in a directory of the remote root connected by the config, relative to its `tftp_root`.
+    ListDir {
+        config: PathBuf,
+        #[serde(default)]
+        path: String,
+    },
+}
+
+pub(super) async fn read_stats_request(stream: &UnixStream) -> io::Result<StatsRequest> {
+    read_stats_request_within(stream, STATS_REQUEST_TIMEOUT).await
+}
+
+// Nothing sent is a request for the snapshot, so the clients just reading the socket are answered as ever.
+async fn read_stats_request_within(
+    stream: &UnixStream,
+    timeout: Duration,
+) -> io::Result<StatsRequest> {
+    let mut request = vec![0u8; MAX_STATS_REQUEST];
+    let mut received = 0;
+    let read = async {
+        while !request[..received].contains(&b'\n') && received < request.len() {
+            stream.readable().await?;
+            match stream.try_read(&mut request[received..]) {
+                Ok(0) => break,
+                Ok(read_bytes) => received += read_bytes,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    };
+    match tokio::time::timeout(timeout, read).await {
+        Ok(result) => result?,
+        Err(_elapsed) if received == 0 => return Ok(StatsRequest::Snapshot),
+        Err(_elapsed) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("No complete request in {timeout:?}"),
+            ));
+        }
+    }
+    let request = String::from_utf8_lossy(&request[..received]);
+    if request.trim().is_empty() {
+        return Ok(StatsRequest::Snapshot);
+    }
+    serde_json::from_str(&request)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+// Writes a single JSON document and closes the connection.
+pub(super) async fn write_snapshot(stream: UnixStream, snapshot: Value) -> io::Result<()> {
+    let mut payload = snapshot.to_string().into_bytes();
+    payload.push(b'\n');
+    let mut written = 0;
+    while written < payload.len() {
+        stream.writable().await?;
+        match stream.try_write(&payload[written..]) {
+            Ok(written_bytes) => written += written_bytes,
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
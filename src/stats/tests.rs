@@ -0,0 +1,184 @@
+use super::*;
+use std::net::Ipv4Addr;
+
+fn completed(bytes: usize, duration_ms: u64) -> TransferRecord {
+    TransferRecord {
+        bytes,
+        duration: Duration::from_millis(duration_ms),
+        completed: true,
+    }
+}
+
+#[test]
+fn empty_stats() {
+    let stats = ServerStats::default();
+    let snapshot = stats.to_json();
+    assert_eq!(snapshot["transfers"]["completed"], 0);
+    assert_eq!(snapshot["transfer_duration_us"], json!({"count": 0}));
+}
+
+#[test]
+fn transfer_percentiles() {
+    let mut stats = ServerStats::default();
+    for duration_ms in 1..=100 {
+        stats.record_transfer(completed(1000, duration_ms));
+    }
+    stats.record_transfer(TransferRecord {
+        bytes: 0,
+        duration: Duration::from_secs(1),
+        completed: false,
+    });
+    let snapshot = stats.to_json();
+    assert_eq!(snapshot["transfers"]["completed"], 100);
+    assert_eq!(snapshot["transfers"]["failed"], 1);
+    let duration = &snapshot["transfer_duration_us"];
+    assert_eq!(duration["count"], 100);
+    let p50 = duration["p50"].as_u64().unwrap();
+    assert!((49_000..=51_000).contains(&p50), "Unexpected p50 {p50}");
+    let max = duration["max"].as_u64().unwrap();
+    assert!((99_000..=101_000).contains(&max), "Unexpected max {max}");
+    assert_eq!(snapshot["transfer_bytes"]["p99"], 1000);
+    // 1000 bytes in 1ms
+    let throughput_max = snapshot["transfer_throughput_bps"]["max"].as_u64().unwrap();
+    assert!((999_000..=1_001_000).contains(&throughput_max));
+}
+
+#[test]
+fn handler_lifecycles() {
+    let mut stats = ServerStats::default();
+    let churning: IpAddr = "127.0.0.11".parse().unwrap();
+    let alive: IpAddr = "fd00::11".parse().unwrap();
+    let started = unix_seconds(SystemTime::now());
+    for _ in 0..3 {
+        stats.record_handler_created(churning);
+        stats.record_handler_destroyed(churning);
+    }
+    stats.record_handler_created(alive);
+    let lifecycles = &stats.to_json()["handler_lifecycles"];
+    assert_eq!(lifecycles["127.0.0.11"]["created"], 3);
+    assert_eq!(lifecycles["127.0.0.11"]["destroyed"], 3);
+    assert!(lifecycles["127.0.0.11"]["last_destroyed"].as_u64().unwrap() >= started);
+    assert_eq!(lifecycles["fd00::11"]["created"], 1);
+    assert_eq!(lifecycles["fd00::11"]["destroyed"], 0);
+    assert!(lifecycles["fd00::11"]["last_created"].as_u64().unwrap() >= started);
+    assert_eq!(lifecycles["fd00::11"]["last_destroyed"], Value::Null);
+}
+
+#[test]
+fn handler_lifecycles_bounded() {
+    let mut stats = ServerStats::default();
+    let peers: Vec<IpAddr> = (0..MAX_HANDLER_LIFECYCLES as u32)
+        .map(|index| IpAddr::from(Ipv4Addr::from(0x0a00_0000 + index)))
+        .collect();
+    let running = peers[0];
+    stats.record_handler_created(running);
+    for peer in &peers[1..] {
+        stats.record_handler_created(*peer);
+        stats.record_handler_destroyed(*peer);
+    }
+    // Back since, unlike the second peer.
+    stats.record_handler_created(peers[1]);
+    stats.record_handler_destroyed(peers[1]);
+    let newcomer: IpAddr = "192.0.2.1".parse().unwrap();
+    stats.record_handler_created(newcomer);
+    assert_eq!(stats.handler_lifecycles.len(), MAX_HANDLER_LIFECYCLES);
+    assert!(stats.handler_lifecycles.contains_key(&running));
+    assert!(stats.handler_lifecycles.contains_key(&peers[1]));
+    assert!(!stats.handler_lifecycles.contains_key(&peers[2]));
+    assert!(stats.handler_lifecycles.contains_key(&newcomer));
+}
+
+#[test]
+fn instant_transfer_recorded() {
+    let mut stats = ServerStats::default();
+    stats.record_transfer(completed(512, 0));
+    assert_eq!(stats.to_json()["transfer_duration_us"]["min"], 1);
+}
+
+#[test]
+fn prometheus_metrics() {
+    let mut stats = ServerStats::default();
+    stats.record_transfer(completed(512, 1));
+    stats.record_transfer(completed(1000, 1));
+    let metrics = stats.to_prometheus("tftp\"1\"", 3);
+    for expected in [
+        "rtftp_info{instance_name=\"tftp\\\"1\\\"\"} 1",
+        "# HELP rtftp_transfers_total Finished transfers by outcome.",
+        "# TYPE rtftp_transfers_total counter",
+        "rtftp_transfers_total{outcome=\"completed\"} 2",
+        "rtftp_transfers_total{outcome=\"failed\"} 0",
+        "rtftp_bytes_sent_total 1512",
+        "# TYPE rtftp_active_sessions gauge",
+        "# TYPE rtftp_errors_total counter",
+        "# TYPE rtftp_alien_datagrams_total counter",
+        "rtftp_handlers 3",
+    ] {
+        assert!(
+            metrics.lines().any(|line| line == expected),
+            "No {expected:?} in {metrics}"
+        );
+    }
+}
+
+#[test]
+fn reporter_without_channel() {
+    StatsReporter::default().report(completed(512, 1));
+}
+
+#[test]
+fn reporter_delivers_records() {
+    let (reporter, mut receiver) = channel();
+    reporter.clone().report(completed(512, 1));
+    let record = receiver.try_recv().unwrap();
+    assert_eq!(record.bytes, 512);
+    assert!(record.completed);
+}
+
+#[test]
+fn handler_exits_counted() {
+    let mut stats = ServerStats::default();
+    stats.record_handler_exit("idle_timeout");
+    stats.record_handler_exit("idle_timeout");
+    assert_eq!(stats.handler_exits().get("idle_timeout"), Some(&2));
+    assert_eq!(stats.to_json()["handler_exits"]["idle_timeout"], 2);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn incomplete_scrape_request_times_out() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    std::io::Write::write_all(&mut client, b"GET /metrics HTTP/1.1\r\n").unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let error = read_request(&stream, Duration::from_millis(50))
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn stats_requests_parsed() {
+    let request = |sent: &'static [u8]| async move {
+        let (server, mut client) = std::os::unix::net::UnixStream::pair().unwrap();
+        std::io::Write::write_all(&mut client, sent).unwrap();
+        let server = UnixStream::from_std({
+            server.set_nonblocking(true).unwrap();
+            server
+        })
+        .unwrap();
+        read_stats_request_within(&server, Duration::from_millis(50)).await
+    };
+    assert_eq!(request(b"").await.unwrap(), StatsRequest::Snapshot);
+    assert_eq!(
+        request(b"{\"list_dir\": {\"config\": \"/srv/tftp/10.0.0.1.nbd\", \"path\": \"grub\"}}\n")
+            .await
+            .unwrap(),
+        StatsRequest::ListDir {
+            config: PathBuf::from("/srv/tftp/10.0.0.1.nbd"),
+            path: String::from("grub"),
+        }
+    );
+    let error = request(b"{\"list_dir\": ").await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    let error = request(b"{\"unknown\": 1}\n").await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
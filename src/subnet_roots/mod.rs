@@ -0,0 +1,107 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Subnet {
+    network: IpAddr,
+    prefix_length: u8,
+}
+
+impl Subnet {
+    fn contains(&self, address: IpAddr) -> bool {
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_length as u32);
+                let mask = mask.unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_length as u32);
+                let mask = mask.unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = String;
+
+    fn from_str(cidr: &str) -> Result<Self, Self::Err> {
+        let (network, prefix_length) = cidr
+            .split_once('/')
+            .ok_or_else(|| format!("{cidr:?} is not in the CIDR notation"))?;
+        let network = IpAddr::from_str(network).map_err(|error| format!("{network:?}: {error}"))?;
+        let max_length = if network.is_ipv4() { 32 } else { 128 };
+        match prefix_length.parse::<u8>() {
+            Ok(prefix_length) if prefix_length <= max_length => Ok(Self {
+                network,
+                prefix_length,
+            }),
+            _ => Err(format!("Invalid prefix length in {cidr:?}")),
+        }
+    }
+}
+
+impl Display for Subnet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_length)
+    }
+}
+
+// Maps subnets to root directories shared by every peer inside them.
+#[derive(Clone, Default)]
+pub(super) struct SubnetRoots {
+    entries: Vec<(Subnet, String)>,
+}
+
+impl SubnetRoots {
+    // Every mapping is `CIDR=DIRECTORY`, the directory is a plain name inside the TFTP root.
+    pub(super) fn parse(mappings: &[String]) -> Result<Self, String> {
+        let mut entries = Vec::with_capacity(mappings.len());
+        for mapping in mappings {
+            let (cidr, directory) = mapping
+                .split_once('=')
+                .ok_or_else(|| format!("{mapping:?} is not in the CIDR=DIRECTORY form"))?;
+            if directory.is_empty() || directory == "." || directory == ".." {
+                return Err(format!("Invalid directory in {mapping:?}"));
+            }
+            if directory.contains('/') {
+                return Err(format!("Directory in {mapping:?} must be a plain name"));
+            }
+            entries.push((Subnet::from_str(cidr)?, directory.to_string()));
+        }
+        Ok(Self { entries })
+    }
+
+    // The longest matching prefix wins.
+    pub(super) fn lookup(&self, address: IpAddr) -> Option<&str> {
+        self.entries
+            .iter()
+            .filter(|(subnet, _directory)| subnet.contains(address))
+            .max_by_key(|(subnet, _directory)| subnet.prefix_length)
+            .map(|(_subnet, directory)| directory.as_str())
+    }
+}
+
+impl Debug for SubnetRoots {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl Display for SubnetRoots {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let entries: Vec<String> = self
+            .entries
+            .iter()
+            .map(|(subnet, directory)| format!("{subnet} => {directory}"))
+            .collect();
+        write!(f, "<SubnetRoots {}>", entries.join(", "))
+    }
+}
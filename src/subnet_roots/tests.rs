@@ -0,0 +1,58 @@
+use super::*;
+
+fn mappings(specs: &[&str]) -> SubnetRoots {
+    let specs: Vec<String> = specs.iter().map(|spec| spec.to_string()).collect();
+    SubnetRoots::parse(&specs).unwrap()
+}
+
+fn ip(address: &str) -> IpAddr {
+    address.parse().unwrap()
+}
+
+#[test]
+fn subnet_match() {
+    let subnet_roots = mappings(&["10.0.5.0/24=vlan5"]);
+    assert_eq!(subnet_roots.lookup(ip("10.0.5.17")), Some("vlan5"));
+    assert_eq!(subnet_roots.lookup(ip("10.0.6.17")), None);
+}
+
+#[test]
+fn longest_prefix_wins() {
+    let subnet_roots = mappings(&["10.0.5.0/28=rack", "10.0.0.0/8=site", "10.0.5.0/24=vlan5"]);
+    assert_eq!(subnet_roots.lookup(ip("10.0.5.3")), Some("rack"));
+    assert_eq!(subnet_roots.lookup(ip("10.0.5.200")), Some("vlan5"));
+    assert_eq!(subnet_roots.lookup(ip("10.1.2.3")), Some("site"));
+}
+
+#[test]
+fn no_matching_subnet() {
+    let subnet_roots = mappings(&["10.0.5.0/24=vlan5", "fd00::/64=v6"]);
+    assert_eq!(subnet_roots.lookup(ip("192.168.0.1")), None);
+    assert_eq!(subnet_roots.lookup(ip("fd00::1")), Some("v6"));
+    assert_eq!(subnet_roots.lookup(ip("fd01::1")), None);
+    assert_eq!(SubnetRoots::default().lookup(ip("10.0.5.1")), None);
+}
+
+#[test]
+fn zero_prefix_matches_everything() {
+    let subnet_roots = mappings(&["0.0.0.0/0=all"]);
+    assert_eq!(subnet_roots.lookup(ip("203.0.113.9")), Some("all"));
+    assert_eq!(subnet_roots.lookup(ip("::1")), None);
+}
+
+#[test]
+fn invalid_mappings() {
+    for spec in [
+        "10.0.5.0/24",
+        "10.0.5.0=vlan5",
+        "10.0.5.0/33=vlan5",
+        "10.0.5.x/24=vlan5",
+        "10.0.5.0/24=../etc",
+        "10.0.5.0/24=",
+    ] {
+        assert!(
+            SubnetRoots::parse(&[spec.to_string()]).is_err(),
+            "{spec} is accepted"
+        );
+    }
+}
@@ -0,0 +1,183 @@
+// An in-memory backend for the transfer tests. Its contents and the random choices of the property tests
+// derive from seeds, so a failing case is reproduced by its seed.
+use crate::fs::{OpenedFile, Root};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::ops::RangeInclusive;
+use std::thread;
+use std::time::Duration;
+
+// Advances the state and returns the scrambled output.
+fn xorshift64star(state: &mut u64) -> u64 {
+    *state ^= *state >> 12;
+    *state ^= *state << 25;
+    *state ^= *state >> 27;
+    state.wrapping_mul(0x2545F4914F6CDD1D)
+}
+
+pub(crate) fn weak_pseudo_random_data(len: usize, seed: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| (xorshift64star(&mut ((i ^ seed) as u64)) >> 56) as u8)
+        .collect()
+}
+
+pub(crate) fn generate_data(size: usize) -> Vec<u8> {
+    weak_pseudo_random_data(size, size)
+}
+
+// The random choices of a property test.
+pub(crate) struct Seeded {
+    state: u64,
+}
+
+impl Seeded {
+    pub(crate) fn new(seed: u64) -> Self {
+        // Xorshift never leaves the zero state.
+        Self { state: seed.max(1) }
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        xorshift64star(&mut self.state)
+    }
+
+    pub(crate) fn pick(&mut self, range: RangeInclusive<usize>) -> usize {
+        let span = (range.end() - range.start()) as u64 + 1;
+        range.start() + (self.next() % span) as usize
+    }
+}
+
+pub(crate) struct VirtualOpenedFile {
+    buffer: Vec<u8>,
+    offset: usize,
+    // Reads reaching this offset fail, as a disk going bad in the middle of a file.
+    fail_at: Option<usize>,
+    // Every read blocks this long, as a backend stalling.
+    delay: Duration,
+}
+
+impl VirtualOpenedFile {
+    pub(crate) fn new(buffer: Vec<u8>) -> Self {
+        Self {
+            buffer,
+            offset: 0,
+            fail_at: None,
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn failing_at(mut self, offset: usize) -> Self {
+        self.fail_at = Some(offset);
+        self
+    }
+
+    pub(crate) fn stalling(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    fn check_read(&self, offset: usize, size: usize) -> io::Result<()> {
+        if !self.delay.is_zero() {
+            thread::sleep(self.delay);
+        }
+        match self.fail_at {
+            Some(fail_at) if (offset..offset + size).contains(&fail_at) => Err(io::Error::other(
+                format!("Injected read error at {fail_at}"),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for VirtualOpenedFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "VirtualOpenedFile size {} [{}]",
+            self.buffer.len(),
+            self.offset
+        )
+    }
+}
+
+impl fmt::Debug for VirtualOpenedFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl OpenedFile for VirtualOpenedFile {
+    fn read_to(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.check_read(self.offset, buffer.len())?;
+        let slice_length = buffer.len().min(self.buffer.len() - self.offset);
+        buffer[..slice_length]
+            .copy_from_slice(&self.buffer[self.offset..self.offset + slice_length]);
+        self.offset += slice_length;
+        Ok(slice_length)
+    }
+
+    fn get_size(&mut self) -> io::Result<usize> {
+        Ok(self.buffer.len())
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.offset = 0;
+        Ok(())
+    }
+
+    fn read_at(&mut self, offset: usize, buffer: &mut [u8]) -> io::Result<usize> {
+        self.check_read(offset, buffer.len())?;
+        let remaining = self.buffer.get(offset..).unwrap_or_default();
+        let slice_length = buffer.len().min(remaining.len());
+        buffer[..slice_length].copy_from_slice(&remaining[..slice_length]);
+        Ok(slice_length)
+    }
+}
+
+// Opens copies of its files, all failing at the offset set for the root.
+#[derive(Default)]
+pub(crate) struct VirtualRoot {
+    files: HashMap<String, Vec<u8>>,
+    fail_at: Option<usize>,
+}
+
+impl VirtualRoot {
+    pub(crate) fn with_file(mut self, path: &str, content: Vec<u8>) -> Self {
+        self.files
+            .insert(path.trim_start_matches('/').to_string(), content);
+        self
+    }
+
+    pub(crate) fn failing_at(mut self, offset: usize) -> Self {
+        self.fail_at = Some(offset);
+        self
+    }
+}
+
+impl Root for VirtualRoot {
+    type OpenedFile = VirtualOpenedFile;
+
+    fn open(&self, path: &str) -> io::Result<Self::OpenedFile> {
+        let content = self
+            .files
+            .get(path.trim_start_matches('/'))
+            .ok_or(io::ErrorKind::NotFound)?;
+        let opened = VirtualOpenedFile::new(content.clone());
+        Ok(match self.fail_at {
+            Some(offset) => opened.failing_at(offset),
+            None => opened,
+        })
+    }
+}
+
+impl fmt::Display for VirtualRoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<Virtual: {} files>", self.files.len())
+    }
+}
+
+impl fmt::Debug for VirtualRoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
@@ -0,0 +1,474 @@
+use crate::cursor::{ReadCursor, WriteCursor};
+use crate::fs::{OpenedFile, Root};
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+#[cfg(test)]
+mod tests;
+
+const RRQ: u16 = 0x01;
+const DATA: u16 = 0x03;
+const ACK: u16 = 0x04;
+const ERROR: u16 = 0x05;
+const OACK: u16 = 0x06;
+const FILE_NOT_FOUND: u16 = 0x01;
+const ACCESS_VIOLATION: u16 = 0x02;
+const DISK_FULL: u16 = 0x03;
+const DEFAULT_BLOCK_SIZE: usize = 512;
+// Fits an Ethernet frame, the upstream may settle for less or ignore the option altogether.
+const REQUESTED_BLOCK_SIZE: usize = 1428;
+pub(super) const DEFAULT_UPSTREAM_TIMEOUT_MS: u64 = 1000;
+const ATTEMPTS: usize = 5;
+// The blocks kept behind the position read to, for the clients repeating an older ACK.
+const RETAINED_BLOCKS: usize = 1024;
+
+// Relays the files found nowhere else from another TFTP server.
+#[derive(Clone)]
+pub(super) struct UpstreamRoot {
+    server: SocketAddr,
+    timeout: Duration,
+    max_file_size: Option<usize>,
+}
+
+impl UpstreamRoot {
+    pub(super) fn new(server: SocketAddr) -> Self {
+        Self {
+            server,
+            timeout: Duration::from_millis(DEFAULT_UPSTREAM_TIMEOUT_MS),
+            max_file_size: None,
+        }
+    }
+
+    // How long a request or an ACK waits for the upstream reply before it is sent again.
+    pub(super) fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // An upstream not acknowledging tsize can't have its files checked ahead, so they are cut short once
+    // the content received exceeds the limit.
+    pub(super) fn with_max_file_size(mut self, max_file_size: Option<usize>) -> Self {
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    fn bind(&self) -> io::Result<UdpSocket> {
+        let unspecified = match self.server {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0))?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        Ok(socket)
+    }
+}
+
+impl Debug for UpstreamRoot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl Display for UpstreamRoot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<Upstream {}>", self.server)
+    }
+}
+
+impl Root for UpstreamRoot {
+    type OpenedFile = UpstreamFile;
+
+    // The upstream is requested by `poll_open`, the file is ready once it answers.
+    fn open(&self, path: &str) -> io::Result<Self::OpenedFile> {
+        Ok(self.open_first(vec![path.to_string()]))
+    }
+}
+
+impl UpstreamRoot {
+    // Requests the paths one after another on the blocking pool until the upstream has one of them, so
+    // the handler of the peer goes on meanwhile. The file is ready once `poll_open` finishes.
+    pub(super) fn open_first(&self, paths: Vec<String>) -> UpstreamFile {
+        let display = format!("{} from {}", paths.join(", "), self.server);
+        let upstream_root = self.clone();
+        let opening = tokio::task::spawn_blocking(move || {
+            let mut last_error = io::Error::from(io::ErrorKind::NotFound);
+            for path in paths {
+                match upstream_root.request(&path) {
+                    Ok(transfer) => return Ok((path, transfer)),
+                    Err(error) if error.kind() == io::ErrorKind::NotFound => last_error = error,
+                    Err(error) => return Err(error),
+                }
+            }
+            Err(last_error)
+        });
+        UpstreamFile {
+            upstream_root: self.clone(),
+            path: String::new(),
+            display,
+            opening: Some(opening),
+            transfer: None,
+            position: 0,
+        }
+    }
+
+    // Blocks until the upstream answers the request or the attempts run out.
+    fn request(&self, path: &str) -> io::Result<Transfer> {
+        let socket = self.bind()?;
+        let mut request = [0u8; 1024];
+        let request_size = write_request(&mut request, path)?;
+        let mut datagram = vec![0u8; u16::MAX as usize];
+        for _ in 0..ATTEMPTS {
+            socket.send_to(&request[..request_size], self.server)?;
+            // The upstream replies from a port of its own, which the transfer sticks to.
+            let Some((size, transfer_address)) = receive(&socket, &mut datagram, self.server.ip())?
+            else {
+                continue;
+            };
+            socket.connect(transfer_address)?;
+            let mut transfer = Transfer {
+                socket,
+                display: format!("{path} from {}", self.server),
+                block_size: DEFAULT_BLOCK_SIZE,
+                size: None,
+                max_size: self.max_file_size,
+                content: Vec::new(),
+                start: 0,
+                last_block: 0,
+                finished: false,
+                refused: false,
+            };
+            transfer.accept_first(&datagram[..size])?;
+            return Ok(transfer);
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "Upstream {} didn't answer {ATTEMPTS} requests for {path}",
+                self.server
+            ),
+        ))
+    }
+}
+
+fn write_request(buffer: &mut [u8], path: &str) -> io::Result<usize> {
+    let mut cursor = WriteCursor::new(buffer);
+    let mut write = || {
+        cursor.put_ushort(RRQ)?;
+        cursor.put_string(path)?;
+        cursor.put_string("octet")?;
+        cursor.put_string("tsize")?;
+        cursor.put_string("0")?;
+        cursor.put_string("blksize")?;
+        cursor.put_string(&REQUESTED_BLOCK_SIZE.to_string())
+    };
+    write().map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error.to_string()))
+}
+
+// None on a timeout. Datagrams from other hosts are dropped.
+fn receive(
+    socket: &UdpSocket,
+    datagram: &mut [u8],
+    server: IpAddr,
+) -> io::Result<Option<(usize, SocketAddr)>> {
+    loop {
+        match socket.recv_from(datagram) {
+            Ok((size, sender)) if sender.ip() == server => return Ok(Some((size, sender))),
+            Ok(_) => continue,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(None);
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+// The error replied by the upstream, as the error its kind stands for here.
+fn upstream_error(datagram: &[u8]) -> io::Error {
+    let mut cursor = ReadCursor::new(datagram);
+    let code = cursor.extract_ushort().unwrap_or_default();
+    let message = cursor.extract_string().unwrap_or_default();
+    let kind = match code {
+        FILE_NOT_FOUND => io::ErrorKind::NotFound,
+        ACCESS_VIOLATION => io::ErrorKind::PermissionDenied,
+        DISK_FULL => io::ErrorKind::StorageFull,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("Upstream error {code}: {message}"))
+}
+
+// A transfer from the upstream, received as the file is read. The blocks received lately are kept, so the
+// ones sent already can be served again to a client repeating an older ACK.
+struct Transfer {
+    socket: UdpSocket,
+    display: String,
+    block_size: usize,
+    size: Option<usize>,
+    max_size: Option<usize>,
+    // The content received from the offset `start` on, the earlier part is dropped once read past.
+    content: Vec<u8>,
+    start: usize,
+    last_block: u16,
+    finished: bool,
+    // The upstream ended the transfer with an error, so it isn't told the transfer is abandoned.
+    refused: bool,
+}
+
+impl Transfer {
+    fn accept_first(&mut self, datagram: &[u8]) -> io::Result<()> {
+        let mut cursor = ReadCursor::new(datagram);
+        match cursor.extract_ushort()? {
+            OACK => {
+                while let (Ok(option), Ok(value)) =
+                    (cursor.extract_string(), cursor.extract_string())
+                {
+                    match option.to_lowercase().as_str() {
+                        "tsize" => self.size = value.parse().ok(),
+                        "blksize" => {
+                            self.block_size = value
+                                .parse()
+                                .ok()
+                                .filter(|&size| size > 0)
+                                .unwrap_or(DEFAULT_BLOCK_SIZE);
+                        }
+                        _ => {}
+                    }
+                }
+                self.acknowledge()
+            }
+            // The options are ignored by the upstream.
+            DATA => self.accept_block(datagram),
+            ERROR => Err(self.refuse(&datagram[2..])),
+            opcode => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected upstream reply {opcode}"),
+            )),
+        }
+    }
+
+    fn refuse(&mut self, datagram: &[u8]) -> io::Error {
+        self.refused = true;
+        upstream_error(datagram)
+    }
+
+    // The offset the content received so far ends at.
+    fn received(&self) -> usize {
+        self.start + self.content.len()
+    }
+
+    // Takes the next block in order, repeated ones are answered with the last ACK again.
+    fn accept_block(&mut self, datagram: &[u8]) -> io::Result<()> {
+        let Some(data) = datagram.get(4..) else {
+            return Err(io::ErrorKind::InvalidData.into());
+        };
+        let block = u16::from_be_bytes([datagram[2], datagram[3]]);
+        if block == self.last_block.wrapping_add(1) {
+            self.content.extend_from_slice(data);
+            self.last_block = block;
+            self.finished = data.len() < self.block_size;
+        }
+        self.acknowledge()
+    }
+
+    fn acknowledge(&self) -> io::Result<()> {
+        let [high, low] = self.last_block.to_be_bytes();
+        self.socket.send(&[0x00, ACK as u8, high, low])?;
+        Ok(())
+    }
+
+    fn fetch_next(&mut self) -> io::Result<()> {
+        let expected = self.last_block.wrapping_add(1);
+        let mut datagram = vec![0u8; self.block_size + 4];
+        for _ in 0..ATTEMPTS {
+            let size = match self.socket.recv(&mut datagram) {
+                Ok(size) => size,
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    self.acknowledge()?;
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+            let mut cursor = ReadCursor::new(&datagram[..size]);
+            match cursor.extract_ushort()? {
+                DATA => {
+                    self.accept_block(&datagram[..size])?;
+                    if self.last_block == expected {
+                        return Ok(());
+                    }
+                }
+                ERROR => return Err(self.refuse(&datagram[2..size])),
+                // The ACK of the options was lost.
+                OACK => self.acknowledge()?,
+                _ => {}
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("Upstream sent no block {expected} of {}", self.display),
+        ))
+    }
+
+    // Fetches the blocks until the content reaches `end` or the file ends.
+    fn fill(&mut self, end: usize) -> io::Result<()> {
+        while self.received() < end && !self.finished {
+            if self.refused {
+                return Err(io::Error::other(format!(
+                    "Upstream refused the rest of {}",
+                    self.display
+                )));
+            }
+            self.fetch_next()?;
+            if let Some(max_size) = self.max_size
+                && self.received() > max_size
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::FileTooLarge,
+                    format!("{} exceeds {max_size}", self.display),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Drops the content more than the blocks retained behind `offset`. It is dropped in chunks as large as
+    // the blocks retained, rather than block by block.
+    fn release(&mut self, offset: usize) {
+        let retained = RETAINED_BLOCKS * self.block_size;
+        let kept_from = offset.saturating_sub(retained);
+        if kept_from >= self.start + retained {
+            self.content.drain(..kept_from - self.start);
+            self.start = kept_from;
+        }
+    }
+}
+
+impl Drop for Transfer {
+    // The upstream is told the transfer is abandoned rather than left retransmitting.
+    fn drop(&mut self) {
+        if !self.finished && !self.refused {
+            let mut datagram = [0u8; 64];
+            let mut cursor = WriteCursor::new(&mut datagram);
+            _ = cursor.put_ushort(ERROR);
+            _ = cursor.put_ushort(0);
+            if let Ok(size) = cursor.put_string("Transfer cancelled") {
+                _ = self.socket.send(&datagram[..size]);
+            }
+        }
+    }
+}
+
+type Opening = JoinHandle<io::Result<(String, Transfer)>>;
+
+// A file streamed from the upstream as it is read. Only the blocks received lately are kept: a client
+// repeating an ACK from further back fails the transfer, and rewinding past them requests the file again.
+pub(super) struct UpstreamFile {
+    upstream_root: UpstreamRoot,
+    // The path the upstream has, once opened.
+    path: String,
+    display: String,
+    // The requests still being answered.
+    opening: Option<Opening>,
+    transfer: Option<Transfer>,
+    position: usize,
+}
+
+impl UpstreamFile {
+    fn transfer(&mut self) -> io::Result<&mut Transfer> {
+        match self.transfer.as_mut() {
+            Some(transfer) => Ok(transfer),
+            None => Err(io::Error::other(format!("{} is not opened", self.display))),
+        }
+    }
+}
+
+impl Debug for UpstreamFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let received = self.transfer.as_ref().map_or(0, Transfer::received);
+        write!(
+            f,
+            "UpstreamFile: {} ({received} bytes received)",
+            self.display
+        )
+    }
+}
+
+impl Display for UpstreamFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<UpstreamFile {}>", self.display)
+    }
+}
+
+impl OpenedFile for UpstreamFile {
+    fn read_to(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let read_bytes = self.read_at(self.position, buffer)?;
+        self.position += read_bytes;
+        // The sender keeps the blocks of its window itself, only older ones are read again.
+        let position = self.position;
+        self.transfer()?.release(position);
+        Ok(read_bytes)
+    }
+
+    // Only known if the upstream reported it, or once the whole file is received.
+    fn get_size(&mut self) -> io::Result<usize> {
+        let transfer = self.transfer()?;
+        match transfer.size {
+            Some(size) => Ok(size),
+            None if transfer.finished => Ok(transfer.received()),
+            None => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    fn rewind(&mut self) -> io::Result<()> {
+        self.position = 0;
+        if self.transfer()?.start > 0 {
+            // The current transfer is abandoned before the file is requested again.
+            self.transfer = None;
+            self.transfer = Some(self.upstream_root.request(&self.path)?);
+        }
+        Ok(())
+    }
+
+    fn poll_open(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(opening) = self.opening.as_mut() {
+            let opened = ready!(Pin::new(opening).poll(cx));
+            self.opening = None;
+            let (path, transfer) = opened.map_err(io::Error::other)??;
+            self.display = transfer.display.clone();
+            self.path = path;
+            self.transfer = Some(transfer);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn read_at(&mut self, offset: usize, buffer: &mut [u8]) -> io::Result<usize> {
+        let transfer = self.transfer()?;
+        if offset < transfer.start {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Offset {offset} of {} is dropped already", transfer.display),
+            ));
+        }
+        transfer.fill(offset + buffer.len())?;
+        let remaining = transfer
+            .content
+            .get(offset - transfer.start..)
+            .unwrap_or_default();
+        let read_bytes = remaining.len().min(buffer.len());
+        buffer[..read_bytes].copy_from_slice(&remaining[..read_bytes]);
+        Ok(read_bytes)
+    }
+}
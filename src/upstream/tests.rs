@@ -0,0 +1,245 @@
+use super::*;
+use crate::tests_common::make_payload;
+use std::future::poll_fn;
+use std::thread;
+
+fn upstream_root(server: SocketAddr) -> UpstreamRoot {
+    UpstreamRoot::new(server).with_timeout(Duration::from_millis(100))
+}
+
+async fn open_first(server: SocketAddr, paths: &[&str]) -> io::Result<UpstreamFile> {
+    let paths = paths.iter().map(|path| path.to_string()).collect();
+    let mut file = upstream_root(server).open_first(paths);
+    poll_fn(|cx| file.poll_open(cx)).await?;
+    Ok(file)
+}
+
+fn read_all(file: &mut UpstreamFile) -> Vec<u8> {
+    let mut content = Vec::new();
+    let mut buffer = [0u8; 300];
+    loop {
+        match file.read_to(&mut buffer).unwrap() {
+            0 => return content,
+            read_bytes => content.extend_from_slice(&buffer[..read_bytes]),
+        }
+    }
+}
+
+fn data(block: u16, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0x00, DATA as u8];
+    datagram.extend_from_slice(&block.to_be_bytes());
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+fn expect_ack(socket: &UdpSocket, block: u16) {
+    let mut datagram = [0u8; 16];
+    let size = socket.recv(&mut datagram).unwrap();
+    let [high, low] = block.to_be_bytes();
+    assert_eq!(datagram[..size], [0x00, ACK as u8, high, low]);
+}
+
+// Takes a request and serves the content lockstep from a transfer port of its own, with the block size
+// acknowledged if `oack` is set. The first transmission of block 2 is lost.
+fn serve_request(listen_socket: &UdpSocket, content: &[u8], oack: bool) -> String {
+    let mut request = [0u8; 1024];
+    let (size, client) = listen_socket.recv_from(&mut request).unwrap();
+    let mut cursor = ReadCursor::new(&request[2..size]);
+    let path = cursor.extract_string().unwrap();
+    let socket = UdpSocket::bind("127.0.0.30:0").unwrap();
+    socket.connect(client).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let block_size = if oack {
+        let mut datagram = vec![0x00, OACK as u8];
+        for field in ["tsize", &content.len().to_string(), "blksize", "100"] {
+            datagram.extend_from_slice(field.as_bytes());
+            datagram.push(0);
+        }
+        socket.send(&datagram).unwrap();
+        expect_ack(&socket, 0);
+        100
+    } else {
+        DEFAULT_BLOCK_SIZE
+    };
+    let blocks: Vec<&[u8]> = content
+        .chunks(block_size)
+        .chain(content.len().is_multiple_of(block_size).then_some(&[][..]))
+        .collect();
+    for (index, block) in blocks.into_iter().enumerate() {
+        let block_index = index as u16 + 1;
+        if block_index != 2 {
+            socket.send(&data(block_index, block)).unwrap();
+        }
+        if block_index == 2 {
+            // The client repeats its ACK of block 1 on the timeout.
+            expect_ack(&socket, 1);
+            socket.send(&data(block_index, block)).unwrap();
+        }
+        expect_ack(&socket, block_index);
+    }
+    path
+}
+
+// Serves the given number of requests one after another, the paths requested are returned.
+fn fake_upstream(
+    content: Vec<u8>,
+    oack: bool,
+    requests: usize,
+) -> (SocketAddr, thread::JoinHandle<Vec<String>>) {
+    let listen_socket = UdpSocket::bind("127.0.0.30:0").unwrap();
+    let address = listen_socket.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        (0..requests)
+            .map(|_| serve_request(&listen_socket, &content, oack))
+            .collect()
+    });
+    (address, server)
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn relay_with_options() {
+    let content = make_payload(1000);
+    let (address, server) = fake_upstream(content.clone(), true, 1);
+    let mut file = open_first(address, &["boot/vmlinuz"]).await.unwrap();
+    assert_eq!(file.get_size().unwrap(), 1000);
+    assert_eq!(read_all(&mut file), content);
+    assert_eq!(server.join().unwrap(), ["boot/vmlinuz"]);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn relay_options_ignored() {
+    let content = make_payload(512 * 3);
+    let (address, server) = fake_upstream(content.clone(), false, 1);
+    let mut file = open_first(address, &["pxelinux.0"]).await.unwrap();
+    assert!(file.get_size().is_err());
+    assert_eq!(read_all(&mut file), content);
+    assert_eq!(file.get_size().unwrap(), content.len());
+    server.join().unwrap();
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn relayed_size_limited_without_tsize() {
+    let content = make_payload(512 * 3);
+    let (address, server) = fake_upstream(content, false, 1);
+    let mut file = upstream_root(address)
+        .with_max_file_size(Some(1000))
+        .open_first(vec![String::from("file.bin")]);
+    poll_fn(|cx| file.poll_open(cx)).await.unwrap();
+    assert_eq!(
+        file.get_size().unwrap_err().kind(),
+        io::ErrorKind::Unsupported
+    );
+    let mut buffer = [0u8; 300];
+    let error = loop {
+        match file.read_to(&mut buffer) {
+            Ok(read_bytes) => assert!(read_bytes > 0, "Read to the end past the limit"),
+            Err(error) => break error,
+        }
+    };
+    assert_eq!(error.kind(), io::ErrorKind::FileTooLarge);
+    // The upstream is told the transfer is abandoned rather than acknowledged the last block.
+    drop(file);
+    assert!(server.join().is_err());
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn relayed_blocks_read_again() {
+    let content = make_payload(1000);
+    let (address, server) = fake_upstream(content.clone(), true, 1);
+    let mut file = open_first(address, &["file.bin"]).await.unwrap();
+    let mut buffer = [0u8; 300];
+    file.read_to(&mut buffer).unwrap();
+    assert_eq!(file.read_at(100, &mut buffer).unwrap(), 300);
+    assert_eq!(buffer[..], content[100..400]);
+    file.rewind().unwrap();
+    assert_eq!(read_all(&mut file), content);
+    server.join().unwrap();
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn relayed_blocks_dropped_behind() {
+    let retained = RETAINED_BLOCKS * 100;
+    let content = make_payload(retained * 2 + 1000);
+    let (address, server) = fake_upstream(content.clone(), true, 2);
+    let mut file = open_first(address, &["file.bin"]).await.unwrap();
+    assert_eq!(read_all(&mut file), content);
+    let mut buffer = [0u8; 300];
+    let error = file.read_at(0, &mut buffer).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    let offset = content.len() - retained;
+    assert_eq!(file.read_at(offset, &mut buffer).unwrap(), 300);
+    assert_eq!(buffer[..], content[offset..offset + 300]);
+    // The start is gone, so the file is requested again.
+    file.rewind().unwrap();
+    assert_eq!(read_all(&mut file), content);
+    assert_eq!(server.join().unwrap(), ["file.bin", "file.bin"]);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn missing_paths_skipped() {
+    let content = make_payload(1000);
+    let listen_socket = UdpSocket::bind("127.0.0.30:0").unwrap();
+    let address = listen_socket.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let mut request = [0u8; 1024];
+        let (size, client) = listen_socket.recv_from(&mut request).unwrap();
+        let missing = ReadCursor::new(&request[2..size]).extract_string().unwrap();
+        let mut datagram = vec![0x00, ERROR as u8];
+        datagram.extend_from_slice(&FILE_NOT_FOUND.to_be_bytes());
+        datagram.extend_from_slice(b"Not found\x00");
+        listen_socket.send_to(&datagram, client).unwrap();
+        [missing, serve_request(&listen_socket, &content, true)]
+    });
+    let mut file = open_first(address, &["pxelinux.cfg/01-aa", "pxelinux.cfg/default"])
+        .await
+        .unwrap();
+    assert_eq!(read_all(&mut file), make_payload(1000));
+    assert_eq!(
+        server.join().unwrap(),
+        ["pxelinux.cfg/01-aa", "pxelinux.cfg/default"]
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn upstream_errors_translated() {
+    for (code, kind) in [
+        (FILE_NOT_FOUND, io::ErrorKind::NotFound),
+        (ACCESS_VIOLATION, io::ErrorKind::PermissionDenied),
+        (0, io::ErrorKind::Other),
+    ] {
+        let listen_socket = UdpSocket::bind("127.0.0.30:0").unwrap();
+        let address = listen_socket.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut request = [0u8; 1024];
+            let (_size, client) = listen_socket.recv_from(&mut request).unwrap();
+            let mut datagram = vec![0x00, ERROR as u8];
+            datagram.extend_from_slice(&code.to_be_bytes());
+            datagram.extend_from_slice(b"Refused\x00");
+            listen_socket.send_to(&datagram, client).unwrap();
+            // The upstream ended the transfer itself, it isn't told the transfer is cancelled.
+            listen_socket
+                .set_read_timeout(Some(Duration::from_millis(200)))
+                .unwrap();
+            assert!(listen_socket.recv(&mut request).is_err());
+        });
+        let error = open_first(address, &["file.bin"]).await.unwrap_err();
+        assert_eq!(error.kind(), kind);
+        assert!(error.to_string().contains("Refused"), "{error}");
+        server.join().unwrap();
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn silent_upstream_times_out() {
+    let silent_socket = UdpSocket::bind("127.0.0.30:0").unwrap();
+    let address = silent_socket.local_addr().unwrap();
+    let error = open_first(address, &["file.bin"]).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    // Every attempt is a request of its own.
+    silent_socket.set_nonblocking(true).unwrap();
+    let mut request = [0u8; 1024];
+    let requests = std::iter::from_fn(|| silent_socket.recv(&mut request).ok()).count();
+    assert_eq!(requests, ATTEMPTS);
+}
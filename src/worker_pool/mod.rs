@@ -0,0 +1,125 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::sync::{Arc, mpsc};
+use std::thread::{Builder, JoinHandle};
+use tokio::runtime;
+use tokio::sync::mpsc::{UnboundedSender, unbounded_channel};
+use tokio::task::LocalSet;
+
+#[cfg(test)]
+mod tests;
+
+// Runs inside the local task set of the worker, so it may spawn local tasks.
+type Job = Box<dyn FnOnce() + Send>;
+
+struct Worker {
+    jobs: Option<UnboundedSender<Job>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn spawn(name: String) -> io::Result<Self> {
+        let (jobs, mut receiver) = unbounded_channel::<Job>();
+        let thread = Builder::new().name(name).spawn(move || {
+            let runtime = runtime::Builder::new_current_thread()
+                .enable_time()
+                .enable_io()
+                .build()
+                .unwrap();
+            let local_task_set = LocalSet::new();
+            local_task_set.block_on(&runtime, async {
+                while let Some(job) = receiver.recv().await {
+                    job();
+                }
+            });
+            // The tasks still running are let finish once the pool is dropped.
+            runtime.block_on(local_task_set);
+        })?;
+        Ok(Self {
+            jobs: Some(jobs),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        drop(self.jobs.take());
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            eprintln!("Pool worker thread panicked");
+        }
+    }
+}
+
+// A few threads, each with a runtime of its own, running the tasks of many peers. Sessions are spawned
+// locally, so a task stays on the worker it was given to, which is picked in turn.
+pub(super) struct WorkerPool {
+    workers: Vec<Worker>,
+    next_worker: usize,
+}
+
+impl WorkerPool {
+    pub(super) fn new(size: usize) -> io::Result<Self> {
+        let workers = (0..size)
+            .map(|index| Worker::spawn(format!("Pool worker {index}")))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self {
+            workers,
+            next_worker: 0,
+        })
+    }
+
+    pub(super) fn spawn<T, F, Fut>(&mut self, task: F) -> PooledTask<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = T> + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let running = Arc::new(());
+        let running_on_worker = running.clone();
+        let worker = &self.workers[self.next_worker % self.workers.len()];
+        self.next_worker = self.next_worker.wrapping_add(1);
+        if let Some(jobs) = &worker.jobs {
+            // A panicking task drops the sender, which the joiner sees as no result.
+            _ = jobs.send(Box::new(move || {
+                tokio::task::spawn_local(async move {
+                    _ = sender.send(task().await);
+                    drop(running_on_worker);
+                });
+            }));
+        }
+        PooledTask { receiver, running }
+    }
+}
+
+impl Display for WorkerPool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<WorkerPool of {}>", self.workers.len())
+    }
+}
+
+impl Debug for WorkerPool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<WorkerPool of {}>", self.workers.len())
+    }
+}
+
+pub(super) struct PooledTask<T> {
+    receiver: mpsc::Receiver<T>,
+    // Shared with the worker until the task ends, however it ends.
+    running: Arc<()>,
+}
+
+impl<T> PooledTask<T> {
+    pub(super) fn is_finished(&self) -> bool {
+        Arc::strong_count(&self.running) == 1
+    }
+
+    // Blocks the calling thread until the task ends. None if it panicked or never ran.
+    pub(super) fn join(self) -> Option<T> {
+        self.receiver.recv().ok()
+    }
+}
@@ -0,0 +1,47 @@
+use super::*;
+use std::collections::HashSet;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn tasks_share_workers() {
+    let mut pool = WorkerPool::new(2).unwrap();
+    let tasks: Vec<_> = (0..10)
+        .map(|_| {
+            pool.spawn(|| async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                thread::current().name().unwrap().to_string()
+            })
+        })
+        .collect();
+    let threads: HashSet<String> = tasks.into_iter().map(|task| task.join().unwrap()).collect();
+    assert_eq!(threads.len(), 2);
+}
+
+#[test]
+fn local_tasks_spawned() {
+    let mut pool = WorkerPool::new(1).unwrap();
+    let task = pool.spawn(|| async { tokio::task::spawn_local(async { 7 }).await.unwrap() });
+    assert_eq!(task.join(), Some(7));
+}
+
+#[test]
+fn finished_task_told() {
+    let mut pool = WorkerPool::new(1).unwrap();
+    let task = pool.spawn(|| async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    });
+    assert!(!task.is_finished());
+    thread::sleep(Duration::from_millis(300));
+    assert!(task.is_finished());
+    assert_eq!(task.join(), Some(()));
+}
+
+#[test]
+fn panicked_task_yields_nothing() {
+    let mut pool = WorkerPool::new(1).unwrap();
+    let panicked = pool.spawn::<(), _, _>(|| async { panic!("Task panic") });
+    assert_eq!(panicked.join(), None);
+    let task = pool.spawn(|| async { "survived" });
+    assert_eq!(task.join(), Some("survived"));
+}
@@ -6,6 +6,9 @@ QCOW_DISK_PATH="${1:?Must be a desirable QCOW2 test disk path}"
 DATA_PATTERN="${2:?Must be a data pattern to fill files inside the disk}"
 
 rm -f "${QCOW_DISK_PATH}"
+COMPRESSED_FILE="$(mktemp)"
+trap 'rm -f "${COMPRESSED_FILE}"' EXIT
+yes "${DATA_PATTERN}" | tr -d '\n' | head -c 65537 | gzip -n > "${COMPRESSED_FILE}"
 guestfish <<EOF
   disk-create ${QCOW_DISK_PATH} qcow2 1073741824 preallocation:off
   add ${QCOW_DISK_PATH}
@@ -20,4 +23,8 @@ guestfish <<EOF
   mount /dev/sda1 /boot
   fill-pattern '${DATA_PATTERN}' 4194304 /boot/aligned.file
   fill-pattern '${DATA_PATTERN}' 4194319 /boot/nonaligned.file
+  upload ${COMPRESSED_FILE} /boot/compressed.file.gz
+  touch /boot/empty.file
+  fill-pattern '${DATA_PATTERN}' 4096 /unreadable.file
+  chmod 0 /unreadable.file
 EOF
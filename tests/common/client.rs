@@ -1,3 +1,4 @@
+// Compiled into the unit tests too, where the items only the integration tests use are dead code.
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{ErrorKind, Write};
@@ -9,12 +10,15 @@ use tokio::net::UdpSocket;
 const _BUFFER_SIZE: usize = 1536;
 const _U16_SIZE: usize = size_of::<u16>();
 const _RRQ: u16 = 0x01;
+const _WRQ: u16 = 0x02;
 const _DATA: u16 = 0x03;
 const _ACK: u16 = 0x04;
 const _ERR: u16 = 0x05;
 const _OACK: u16 = 0x06;
 
 const _WINDOW_SIZE: &str = "windowsize";
+const _DEFAULT_TIMEOUT: usize = 5;
+const _DEFAULT_BLOCK_SIZE: usize = 512;
 
 #[derive(Debug)]
 struct _SendError<T> {
@@ -78,7 +82,7 @@ pub(crate) struct TFTPClient {
 }
 
 impl TFTPClient {
-    pub(super) fn new(local_socket: UdpSocket, remote_addr: SocketAddr) -> Self {
+    pub(crate) fn new(local_socket: UdpSocket, remote_addr: SocketAddr) -> Self {
         Self {
             local_socket,
             remote_addr,
@@ -87,6 +91,7 @@ impl TFTPClient {
         }
     }
 
+    #[allow(dead_code)]
     pub(crate) async fn send_plain_read_request(
         mut self,
         file_name: &str,
@@ -105,6 +110,7 @@ impl TFTPClient {
         })
     }
 
+    #[allow(dead_code)]
     pub(crate) async fn send_optioned_read_request(
         mut self,
         file_name: &str,
@@ -129,9 +135,28 @@ impl TFTPClient {
         })
     }
 
+    // For servers already sending data, e.g. a send session driven directly by a unit test.
+    #[allow(dead_code)]
+    pub(crate) fn expect_data(self) -> SentPlainReadRequest {
+        SentPlainReadRequest {
+            file_name: String::new(),
+            local_socket: self.local_socket,
+            remote_addr: self.remote_addr,
+            read_buffer: self.read_buffer,
+            write_buffer: self.write_buffer,
+            sent_bytes: 0,
+        }
+    }
+
+    #[allow(dead_code)]
     fn make_read_request(&mut self, file_name: &str) -> (WriteCursor<'_>, usize) {
+        self.make_request(_RRQ, file_name)
+    }
+
+    #[allow(dead_code)]
+    fn make_request(&mut self, opcode: u16, file_name: &str) -> (WriteCursor<'_>, usize) {
         let mut write_cursor = WriteCursor::new(&mut self.write_buffer);
-        _ = write_cursor.put_ushort(_RRQ).unwrap();
+        _ = write_cursor.put_ushort(opcode).unwrap();
         _ = write_cursor.put_string(file_name).unwrap();
         let size = write_cursor.put_string("octet").unwrap();
         (write_cursor, size)
@@ -214,6 +239,7 @@ impl fmt::Display for DatagramStream {
     }
 }
 
+#[allow(dead_code)]
 pub(crate) struct SentReadRequestWithOpts {
     file_name: String,
     options: HashMap<String, String>,
@@ -230,20 +256,33 @@ impl fmt::Debug for SentReadRequestWithOpts {
     }
 }
 
+#[allow(dead_code)]
 impl SentReadRequestWithOpts {
+    // For servers expected to ignore the options and reply with data straight away.
+    pub(crate) fn expect_plain(self) -> SentPlainReadRequest {
+        SentPlainReadRequest {
+            file_name: self.file_name,
+            local_socket: self.local_socket,
+            remote_addr: self.remote_addr,
+            read_buffer: self.read_buffer,
+            write_buffer: self.write_buffer,
+            sent_bytes: self.sent_bytes,
+        }
+    }
+
     pub(crate) async fn read_oack(
         mut self,
         read_timeout: usize,
-    ) -> Result<OACK, TFTPClientError<Self>> {
+    ) -> Result<Oack, TFTPClientError<Self>> {
         let duration = time::Duration::from_secs(read_timeout as u64);
         let read_future = self.local_socket.recv_from(&mut self.read_buffer);
         match tokio::time::timeout(duration, read_future).await {
             Ok(Ok((read_bytes, remote_address)))
                 if remote_address.ip() == self.remote_addr.ip() =>
             {
-                let mut read_cursor = ReadCursor::new(&mut self.read_buffer[..read_bytes]);
+                let mut read_cursor = ReadCursor::new(&self.read_buffer[..read_bytes]);
                 match read_cursor.extract_ushort() {
-                    Ok(code) if code == _OACK => Ok(OACK {
+                    Ok(code) if code == _OACK => Ok(Oack {
                         datagram_stream: DatagramStream::new(self.local_socket, remote_address),
                         read_buffer: self.read_buffer,
                         write_buffer: self.write_buffer,
@@ -310,7 +349,7 @@ impl SentPlainReadRequest {
             Ok(Ok((read_bytes, remote_address)))
                 if remote_address.ip() == self.remote_addr.ip() =>
             {
-                let mut read_cursor = ReadCursor::new(&mut self.read_buffer[..read_bytes]);
+                let mut read_cursor = ReadCursor::new(&self.read_buffer[..read_bytes]);
                 match read_cursor.extract_ushort() {
                     Ok(code) if code == _DATA => Ok(Block {
                         datagram_stream: DatagramStream::new(self.local_socket, remote_address),
@@ -348,20 +387,22 @@ impl SentPlainReadRequest {
     }
 }
 
-pub(crate) struct OACK {
+#[allow(dead_code)]
+pub(crate) struct Oack {
     pub(crate) datagram_stream: DatagramStream,
     read_buffer: [u8; _BUFFER_SIZE],
     write_buffer: [u8; _BUFFER_SIZE],
     read_bytes: usize,
 }
 
-impl fmt::Debug for OACK {
+impl fmt::Debug for Oack {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "OACK")
     }
 }
 
-impl OACK {
+#[allow(dead_code)]
+impl Oack {
     pub(crate) fn fields(&self) -> HashMap<String, String> {
         let mut fields: HashMap<String, String> = HashMap::new();
         let mut cursor = ReadCursor::new(&self.read_buffer[2..self.read_bytes]);
@@ -377,7 +418,7 @@ impl OACK {
         self.datagram_stream
             .send(&self.write_buffer[..buffer_size])
             .await
-            .or_else(|error| Err(TFTPClientError::IO(error)))?;
+            .map_err(TFTPClientError::IO)?;
         Ok(SentACK {
             datagram_stream: self.datagram_stream,
             read_buffer: self.read_buffer,
@@ -385,6 +426,27 @@ impl OACK {
             buffer_size,
         })
     }
+
+    pub(crate) async fn send_error(
+        mut self,
+        code: u16,
+        message: &str,
+    ) -> Result<SentError, TFTPClientError<Self>> {
+        let mut write_cursor = WriteCursor::new(&mut self.write_buffer);
+        _ = write_cursor.put_ushort(_ERR).unwrap();
+        _ = write_cursor.put_ushort(code).unwrap();
+        let buffer_size = write_cursor.put_string(message).unwrap();
+        self.datagram_stream
+            .send(&self.write_buffer[..buffer_size])
+            .await
+            .map_err(TFTPClientError::IO)?;
+        Ok(SentError {
+            datagram_stream: self.datagram_stream,
+            read_buffer: self.read_buffer,
+            write_buffer: self.write_buffer,
+            write_bytes: buffer_size,
+        })
+    }
 }
 
 pub(crate) struct Block {
@@ -404,7 +466,11 @@ impl Block {
     pub(crate) fn data(&self) -> &[u8] {
         &self.read_buffer[_U16_SIZE * 2..self.read_bytes]
     }
-    async fn acknowledge(mut self) -> Result<SentACK, TFTPClientError<Self>> {
+    #[allow(dead_code)]
+    pub(crate) fn datagram(&self) -> &[u8] {
+        &self.read_buffer[..self.read_bytes]
+    }
+    pub(crate) async fn acknowledge(mut self) -> Result<SentACK, TFTPClientError<Self>> {
         let mut write_cursor = WriteCursor::new(&mut self.write_buffer);
         _ = write_cursor.put_ushort(_ACK).unwrap();
         let block_num = u16::from_be_bytes([self.read_buffer[2], self.read_buffer[3]]);
@@ -421,6 +487,7 @@ impl Block {
         })
     }
 
+    #[allow(dead_code)]
     pub(crate) async fn send_error(
         mut self,
         code: u16,
@@ -451,7 +518,7 @@ impl Block {
             .recv(&mut self.read_buffer, read_timeout, 4);
         match tokio::time::timeout(duration, read_future).await {
             Ok(Ok(read_bytes)) => {
-                let mut read_cursor = ReadCursor::new(&mut self.read_buffer[..read_bytes]);
+                let mut read_cursor = ReadCursor::new(&self.read_buffer[..read_bytes]);
                 match read_cursor.extract_ushort() {
                     Ok(code) if code == _DATA => Ok(Block {
                         datagram_stream: self.datagram_stream,
@@ -506,7 +573,7 @@ impl SentACK {
             .recv(&mut self.read_buffer, read_timeout, 4);
         match tokio::time::timeout(duration, read_future).await {
             Ok(Ok(read_bytes)) => {
-                let mut read_cursor = ReadCursor::new(&mut self.read_buffer[..read_bytes]);
+                let mut read_cursor = ReadCursor::new(&self.read_buffer[..read_bytes]);
                 match read_cursor.extract_ushort() {
                     Ok(code) if code == _DATA => Ok(Block {
                         datagram_stream: self.datagram_stream,
@@ -552,6 +619,7 @@ impl fmt::Debug for SentError {
 }
 
 impl SentError {
+    #[allow(dead_code)]
     pub(crate) async fn read_some(&mut self, read_timeout: usize) -> io::Result<&[u8]> {
         let recv_bytes = self
             .datagram_stream
@@ -567,119 +635,156 @@ fn _write_file(path: &PathBuf, data: &[u8]) {
     file.write_all(data).unwrap();
 }
 
-pub(crate) async fn download(client: TFTPClient, file: &str) -> Result<Vec<u8>, DownloadError> {
-    let default_timeout: usize = 5;
-    let default_block_size: usize = 512;
-    let mut read_data: Vec<u8> = Vec::new();
-    let sent_request = client
-        .send_plain_read_request(file)
-        .await
-        .map_err(|error| DownloadError::from(error))?;
-    let mut block: Option<Block> = Some(
-        sent_request
-            .read_next(default_timeout)
-            .await
-            .map_err(|error| DownloadError::from(error))?,
-    );
-    while let Some(_block) = block.take() {
-        let recv_block_len = _block.data().len();
-        read_data.extend(_block.data());
-        let sent_ack = _block
-            .acknowledge()
-            .await
-            .map_err(|error| DownloadError::from(error))?;
-        if recv_block_len == default_block_size {
-            block = Some(
-                sent_ack
-                    .read_next(default_timeout)
-                    .await
-                    .map_err(|error| DownloadError::from(error))?,
-            );
-        }
-    }
-    Ok(read_data)
+#[allow(dead_code)]
+pub(crate) async fn download(client: TFTPClient, file: &str) -> Result<Vec<u8>, TransferError> {
+    let sent_request = client.send_plain_read_request(file).await?;
+    let first_block = sent_request.read_next(_DEFAULT_TIMEOUT).await?;
+    receive_blocks(first_block, _DEFAULT_BLOCK_SIZE, 1).await
 }
 
+#[allow(dead_code)]
 pub(crate) async fn download_window(
     client: TFTPClient,
     file: &str,
     window_size: u16,
-) -> Result<Vec<u8>, DownloadError> {
-    let default_timeout: usize = 5;
-    let default_block_size: usize = 512;
-    let mut read_data: Vec<u8> = Vec::new();
+) -> Result<Vec<u8>, TransferError> {
     let options = HashMap::from([(_WINDOW_SIZE.to_string(), window_size.to_string())]);
-    let sent_request = client
-        .send_optioned_read_request(file, &options)
-        .await
-        .map_err(|error| DownloadError::from(error))?;
-    let oack = sent_request
-        .read_oack(default_timeout)
-        .await
-        .map_err(|error| DownloadError::from(error))?;
+    let sent_request = client.send_optioned_read_request(file, &options).await?;
+    let oack = sent_request.read_oack(_DEFAULT_TIMEOUT).await?;
     if let Some(window_size_received) = oack.fields().get(_WINDOW_SIZE) {
         if let Ok(window_size_received) = window_size_received.parse::<u16>() {
             if window_size_received != window_size {
-                return Err(DownloadError(format!(
+                return Err(TransferError(format!(
                     "Window size mismatch: Received {}, expected {}",
                     window_size_received, window_size
                 )));
             }
         } else {
-            return Err(DownloadError(format!(
+            return Err(TransferError(format!(
                 "Window size not recognized: {}",
                 window_size_received
             )));
         }
     } else {
-        return Err(DownloadError("Window size not set".into()));
-    }
-    let mut sent_ack: Option<SentACK> = Some(oack.acknowledge().await?);
-    let mut last_block: Option<Block> = None;
-    let mut done = false;
-    while !done {
-        for _ in 0..(window_size) {
-            let received_block = {
-                if let Some(last_block) = last_block.take() {
-                    last_block.read_next(default_timeout).await?
-                } else {
-                    sent_ack.take().unwrap().read_next(default_timeout).await?
-                }
-            };
-            let recv_block_len = received_block.data().len();
-            read_data.extend(received_block.data());
-            last_block = Some(received_block);
-            done = recv_block_len < default_block_size;
+        return Err(TransferError("Window size not set".into()));
+    }
+    let first_block = oack
+        .acknowledge()
+        .await?
+        .read_next(_DEFAULT_TIMEOUT)
+        .await?;
+    receive_blocks(first_block, _DEFAULT_BLOCK_SIZE, window_size).await
+}
+
+// Reads the rest of the transfer, acknowledging every `window_size` blocks and the short last one.
+pub(crate) async fn receive_blocks(
+    first_block: Block,
+    block_size: usize,
+    window_size: u16,
+) -> Result<Vec<u8>, TransferError> {
+    let mut read_data: Vec<u8> = Vec::new();
+    let mut received_block = first_block;
+    let mut received_in_window: u16 = 1;
+    loop {
+        read_data.extend(received_block.data());
+        let done = received_block.data().len() < block_size;
+        if done || received_in_window == window_size {
+            let sent_ack = received_block.acknowledge().await?;
             if done {
-                break;
+                return Ok(read_data);
             }
+            received_block = sent_ack.read_next(_DEFAULT_TIMEOUT).await?;
+            received_in_window = 1;
+        } else {
+            received_block = received_block.read_next(_DEFAULT_TIMEOUT).await?;
+            received_in_window += 1;
+        }
+    }
+}
+
+// Writes the file with a WRQ, then sends it in DATA blocks of the default size, each awaiting its ACK.
+#[allow(dead_code)]
+pub(crate) async fn upload(
+    mut client: TFTPClient,
+    file: &str,
+    content: &[u8],
+) -> Result<(), TransferError> {
+    let (_write_cursor, buffer_size) = client.make_request(_WRQ, file);
+    client
+        .local_socket
+        .send_to(&client.write_buffer[..buffer_size], client.remote_addr)
+        .await?;
+    let duration = time::Duration::from_secs(_DEFAULT_TIMEOUT as u64);
+    let read_future = client.local_socket.recv_from(&mut client.read_buffer);
+    // The server acknowledges the request from the port of the transfer, which the blocks are sent to.
+    let (read_bytes, transfer_address) = tokio::time::timeout(duration, read_future)
+        .await
+        .map_err(|_timeout_error| TransferError("No reply to the write request".into()))??;
+    check_ack(&client.read_buffer[..read_bytes], 0)?;
+    let datagram_stream = DatagramStream::new(client.local_socket, transfer_address);
+    let last_block = content
+        .len()
+        .is_multiple_of(_DEFAULT_BLOCK_SIZE)
+        .then_some(&[][..]);
+    for (index, block) in content
+        .chunks(_DEFAULT_BLOCK_SIZE)
+        .chain(last_block)
+        .enumerate()
+    {
+        let block_index = (index as u16).wrapping_add(1);
+        let mut write_cursor = WriteCursor::new(&mut client.write_buffer);
+        _ = write_cursor.put_ushort(_DATA).unwrap();
+        let header_size = write_cursor.put_ushort(block_index).unwrap();
+        let buffer_size = header_size + block.len();
+        client.write_buffer[header_size..buffer_size].copy_from_slice(block);
+        datagram_stream
+            .send(&client.write_buffer[..buffer_size])
+            .await?;
+        let read_bytes = datagram_stream
+            .recv(&mut client.read_buffer, _DEFAULT_TIMEOUT, 4)
+            .await?;
+        check_ack(&client.read_buffer[..read_bytes], block_index)?;
+    }
+    Ok(())
+}
+
+#[allow(dead_code)]
+fn check_ack(datagram: &[u8], block_index: u16) -> Result<(), TransferError> {
+    let mut read_cursor = ReadCursor::new(datagram);
+    match read_cursor.extract_ushort() {
+        Ok(_ACK) if read_cursor.extract_ushort().ok() == Some(block_index) => Ok(()),
+        Ok(_ERR) => {
+            let error_code = read_cursor.extract_ushort().unwrap_or_default();
+            let message = read_cursor.extract_string().unwrap_or_default();
+            Err(TFTPClientError::<()>::ClientError(error_code, message).into())
         }
-        sent_ack = Some(last_block.take().unwrap().acknowledge().await?);
+        _ => Err(TransferError(format!(
+            "Expected ACK {block_index}, received {datagram:?}"
+        ))),
     }
-    Ok(read_data)
 }
 
 #[derive(Debug)]
-pub(crate) struct DownloadError(String);
+pub(crate) struct TransferError(String);
 
-impl<T: fmt::Debug> From<TFTPClientError<T>> for DownloadError {
+impl<T: fmt::Debug> From<TFTPClientError<T>> for TransferError {
     fn from(value: TFTPClientError<T>) -> Self {
         match value {
-            TFTPClientError::Timeout(msg) => DownloadError(format!("{:?}", msg)),
-            error => DownloadError(error.to_string()),
+            TFTPClientError::Timeout(msg) => TransferError(format!("{:?}", msg)),
+            error => TransferError(error.to_string()),
         }
     }
 }
 
-impl fmt::Display for DownloadError {
+impl fmt::Display for TransferError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.0.clone())
     }
 }
 
-impl From<io::Error> for DownloadError {
+impl From<io::Error> for TransferError {
     fn from(value: io::Error) -> Self {
-        DownloadError(value.to_string())
+        TransferError(value.to_string())
     }
 }
 
@@ -756,6 +861,7 @@ impl<'a> WriteCursor<'a> {
         Ok(self.offset)
     }
 
+    #[allow(dead_code)]
     fn put_string(&mut self, string: &str) -> Result<usize, BufferError> {
         let string_size = string.len();
         let end_index = self.offset + string_size + 1;
@@ -765,7 +871,7 @@ impl<'a> WriteCursor<'a> {
             )));
         }
         self.buffer[self.offset..end_index - 1].copy_from_slice(string.as_bytes());
-        self.buffer[end_index] = 0x0;
+        self.buffer[end_index - 1] = 0x0;
         self.offset = end_index;
         Ok(self.offset)
     }
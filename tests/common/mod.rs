@@ -1,40 +1,19 @@
 use crate::common::client::TFTPClient;
-use std::any::type_name;
-use std::fs::{File, create_dir};
+use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::mpsc;
+use std::time::Instant;
 use std::{env, fs, io, net, thread, time};
 use tokio::net::UdpSocket;
 
 pub(crate) mod client;
+mod support;
 
-const DATA_PATTERN: &str = "ARBITRARY DATA";
-
-pub(super) fn make_payload(size: usize) -> Vec<u8> {
-    let pattern = DATA_PATTERN.as_bytes();
-    pattern.iter().copied().cycle().take(size).collect()
-}
-
-fn ensure_prerequisite_disk() -> (PathBuf, File) {
-    let test_data_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
-    let test_disk = test_data_dir.join("test_disk.qcow2");
-    let file = File::open(&test_data_dir).unwrap();
-    file.lock().unwrap();
-    if !test_disk.exists() {
-        let script = test_data_dir.join("build_test_qcow_disk.sh");
-        let status = Command::new(&script)
-            .arg(&test_disk)
-            .arg(DATA_PATTERN)
-            .status()
-            .expect(format!("{:?} failed", script).as_str());
-        if !status.success() {
-            panic!("{script:?} failed");
-        }
-    }
-    (test_disk, file)
-}
+use support::ensure_prerequisite_disk;
+pub(super) use support::{make_payload, mk_tmp};
 
 pub(super) fn get_free_port() -> u16 {
     let opened_socket = net::TcpListener::bind(("127.0.1.1", 0)).unwrap();
@@ -187,40 +166,93 @@ fn _get_fd_symlink_names(pid: u32) -> io::Result<Vec<String>> {
     Ok(result)
 }
 
-fn get_fn_name<T>(_: T) -> &'static str {
-    type_name::<T>()
+pub(super) async fn start_rtftp(temp_dir: PathBuf) -> RunningServer {
+    start_rtftp_with_args(temp_dir, &[]).await
+}
+
+pub(super) async fn start_rtftp_with_args(temp_dir: PathBuf, extra_args: &[&str]) -> RunningServer {
+    spawn_rtftp(temp_dir, extra_args, Stdio::inherit()).await
 }
 
-pub fn mk_tmp<T>(test_func: T) -> PathBuf {
-    let test_dir_name = get_fn_name(test_func).replace("::", "_");
-    let pid = std::process::id();
-    let test_tmp_dir = env::temp_dir().join(format!("rtftp_{pid}_{test_dir_name}"));
-    create_dir(&test_tmp_dir).unwrap();
-    test_tmp_dir
+// Server log lines, stamped when read. Lines are echoed so they still show up in the test output.
+pub(super) async fn start_rtftp_with_log(
+    temp_dir: PathBuf,
+    extra_args: &[&str],
+) -> (RunningServer, mpsc::Receiver<(Instant, String)>) {
+    let mut running_server = spawn_rtftp(temp_dir, extra_args, Stdio::piped()).await;
+    let stderr = running_server.process.stderr.take().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            eprintln!("{line}");
+            if sender.send((Instant::now(), line)).is_err() {
+                break;
+            }
+        }
+    });
+    (running_server, receiver)
 }
 
-pub(super) async fn start_rtftp(temp_dir: PathBuf) -> RunningServer {
+async fn spawn_rtftp(temp_dir: PathBuf, extra_args: &[&str], stderr: Stdio) -> RunningServer {
     let port = get_free_port();
     let ip = "127.0.0.10";
     let bin = env!("CARGO_BIN_EXE_rtftp");
-    let process = Command::new(bin)
+    let mut command = Command::new(bin);
+    command
         .arg("--listen-ip")
         .arg(ip)
         .arg("--listen-port")
         .arg(port.to_string())
         .arg("--root-dir")
+        .arg(temp_dir);
+    if !extra_args.contains(&"--idle-timeout") {
+        command.arg("--idle-timeout").arg("30");
+    }
+    let process = command.args(extra_args).stderr(stderr).spawn().unwrap();
+    let listen_socket: SocketAddr = format!("{}:{}", ip, port).parse().unwrap();
+    while !is_udp_port_open(listen_socket) {
+        tokio::time::sleep(time::Duration::from_millis(50)).await;
+    }
+    RunningServer {
+        process,
+        listen_socket,
+    }
+}
+
+pub(super) fn run_rtftp_to_completion(temp_dir: PathBuf, extra_args: &[&str]) -> Output {
+    let bin = env!("CARGO_BIN_EXE_rtftp");
+    Command::new(bin)
+        .arg("--listen-ip")
+        .arg("127.0.0.10")
+        .arg("--root-dir")
+        .arg(temp_dir)
+        .arg("--idle-timeout")
+        .arg("30")
+        .args(extra_args)
+        .output()
+        .unwrap()
+}
+
+// Listens on [::] for both IPv4 and IPv6 clients, the listen socket is the IPv6 loopback.
+pub(super) async fn start_rtftp_dual_stack(temp_dir: PathBuf) -> RunningServer {
+    let port = get_free_port();
+    let bin = env!("CARGO_BIN_EXE_rtftp");
+    let process = Command::new(bin)
+        .arg("--dual-stack")
+        .arg("--listen-port")
+        .arg(port.to_string())
+        .arg("--root-dir")
         .arg(temp_dir)
         .arg("--idle-timeout")
         .arg("30")
         .spawn()
         .unwrap();
-    let listen_socket: SocketAddr = format!("{}:{}", ip, port).parse().unwrap();
-    while !is_udp_port_open(listen_socket) {
+    while !is_udp6_wildcard_open(port) {
         tokio::time::sleep(time::Duration::from_millis(50)).await;
     }
     RunningServer {
         process,
-        listen_socket,
+        listen_socket: SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port),
     }
 }
 
@@ -236,6 +268,24 @@ impl RunningServer {
             self.listen_socket,
         )
     }
+
+    pub(crate) async fn open_client_via(&self, source_ip: &str, listen_ip: &str) -> TFTPClient {
+        let listen_socket = SocketAddr::new(listen_ip.parse().unwrap(), self.listen_socket.port());
+        while !is_udp_port_open(listen_socket) {
+            tokio::time::sleep(time::Duration::from_millis(50)).await;
+        }
+        TFTPClient::new(
+            UdpSocket::bind((source_ip, 0)).await.unwrap(),
+            listen_socket,
+        )
+    }
+
+    // Threads of the server process, as procfs lists them.
+    pub(crate) fn thread_count(&self) -> usize {
+        fs::read_dir(format!("/proc/{}/task", self.process.id()))
+            .unwrap()
+            .count()
+    }
 }
 
 impl Drop for RunningServer {
@@ -246,6 +296,18 @@ impl Drop for RunningServer {
     }
 }
 
+fn is_udp6_wildcard_open(port: u16) -> bool {
+    let Ok(file) = File::open("/proc/net/udp6") else {
+        return false;
+    };
+    let wildcard = format!("{}:{port:04X}", "0".repeat(32));
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .skip(1)
+        .any(|line| line.split_whitespace().nth(1) == Some(wildcard.as_str()))
+}
+
 fn is_udp_port_open(addr: SocketAddr) -> bool {
     let port = addr.port();
     let ip = match addr.ip() {
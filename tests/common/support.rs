@@ -0,0 +1,57 @@
+// Helpers shared by the integration tests and the unit tests of the crate.
+use std::any::type_name;
+use std::env;
+use std::fs::{self, File, create_dir};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const DATA_PATTERN: &str = "ARBITRARY DATA";
+
+pub(crate) fn make_payload(size: usize) -> Vec<u8> {
+    let pattern = DATA_PATTERN.as_bytes();
+    pattern.iter().copied().cycle().take(size).collect()
+}
+
+pub(crate) fn ensure_prerequisite_disk() -> (PathBuf, File) {
+    let test_data_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let test_disk = test_data_dir.join("test_disk.qcow2");
+    let file = File::open(&test_data_dir).unwrap();
+    file.lock().unwrap();
+    let script = test_data_dir.join("build_test_qcow_disk.sh");
+    let version_stamp = test_data_dir.join("test_disk.qcow2.version");
+    let version = disk_version(&script);
+    let built_version = fs::read_to_string(&version_stamp).unwrap_or_default();
+    if !test_disk.exists() || built_version != version {
+        let status = Command::new(&script)
+            .arg(&test_disk)
+            .arg(DATA_PATTERN)
+            .status()
+            .unwrap_or_else(|_| panic!("{script:?} failed"));
+        if !status.success() {
+            panic!("{script:?} failed");
+        }
+        fs::write(&version_stamp, version).unwrap();
+    }
+    (test_disk, file)
+}
+
+// A disk built by another revision of the script, or with another pattern, is stale.
+fn disk_version(script: &Path) -> String {
+    let mut hasher = DefaultHasher::new();
+    fs::read(script).unwrap().hash(&mut hasher);
+    DATA_PATTERN.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+fn get_fn_name<T>(_: T) -> &'static str {
+    type_name::<T>()
+}
+
+pub(crate) fn mk_tmp<T>(test_func: T) -> PathBuf {
+    let test_dir_name = get_fn_name(test_func).replace("::", "_");
+    let pid = std::process::id();
+    let test_tmp_dir = env::temp_dir().join(format!("rtftp_{pid}_{test_dir_name}"));
+    create_dir(&test_tmp_dir).unwrap();
+    test_tmp_dir
+}
@@ -1,15 +1,28 @@
-use crate::common::{make_payload, mk_tmp, run_nbd_server, start_rtftp};
+use crate::common::{
+    RunningServer, get_free_port, make_payload, mk_tmp, run_nbd_server, run_rtftp_to_completion,
+    start_rtftp, start_rtftp_dual_stack, start_rtftp_with_args, start_rtftp_with_log,
+};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::ffi::CStr;
 use std::fs::{File, Permissions, set_permissions};
-use std::io::{ErrorKind, Write};
-use std::os::unix::fs::PermissionsExt;
+use std::io::{ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::{PermissionsExt, symlink};
+use std::os::unix::net::UnixStream;
 use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::{Instant, UNIX_EPOCH};
 use std::{fs, time};
 use tokio::net::UdpSocket;
 
-use crate::common::client::{TFTPClientError, download, download_window};
+use crate::common::client::{
+    Block, TFTPClient, TFTPClientError, download, download_window, receive_blocks, upload,
+};
 
 mod common;
 
@@ -43,6 +56,98 @@ async fn send_wrong_request_type() {
     );
 }
 
+// There is no write path: a WRQ is refused, and DATA sent anyway is never buffered, whatever its size.
+#[tokio::test(flavor = "current_thread")]
+async fn write_request_and_oversized_data_refused() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(write_request_and_oversized_data_refused);
+    let running_server = start_rtftp(server_dir).await;
+    let write_request = b"\x00\x02upload.bin\x00octet\x00blksize\x00512\x00";
+    let oversized_data = [b"\x00\x03\x00\x01".as_slice(), &[0xAB; 512 + 100]].concat();
+    let local_socket = UdpSocket::bind((source_ip, 0)).await.unwrap();
+    // Illegal TFTP operation, then unknown transfer ID.
+    for (packet, code, message) in [
+        (write_request.as_slice(), 0x04, "Only RRQ is supported"),
+        (&oversized_data, 0x05, "Unknown transfer ID"),
+    ] {
+        local_socket
+            .send_to(packet, running_server.listen_socket)
+            .await
+            .unwrap();
+        let mut buffer = [0u8; _BUFFER_SIZE];
+        let bytes_read = local_socket.recv(&mut buffer).await.unwrap();
+        assert_eq!(buffer[..4], [0x00, 0x05, 0x00, code]);
+        let error_message = CStr::from_bytes_with_nul(&buffer[4..bytes_read]).unwrap();
+        assert_eq!(error_message.to_str().unwrap(), message);
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn upload_refused() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(upload_refused);
+    let running_server = start_rtftp(server_dir.clone()).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let error = upload(client, "upload.bin", &make_payload(1000))
+        .await
+        .unwrap_err();
+    assert!(
+        error.to_string().contains("[4] Only RRQ is supported"),
+        "Unexpected error {error}"
+    );
+    assert!(!server_dir.join(source_ip).join("upload.bin").exists());
+}
+
+// A client acknowledging to the listen port instead of the session port learns the transfer ID is wrong,
+// while the session goes on.
+#[tokio::test(flavor = "current_thread")]
+async fn ack_to_listen_port_refused() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(ack_to_listen_port_refused);
+    let data = make_payload(2048);
+    _write_file(&server_dir.join("default").join("file.bin"), &data);
+    let running_server = start_rtftp(server_dir).await;
+    let local_socket = UdpSocket::bind((source_ip, 0)).await.unwrap();
+    local_socket
+        .send_to(
+            b"\x00\x01file.bin\x00octet\x00",
+            running_server.listen_socket,
+        )
+        .await
+        .unwrap();
+    let mut buffer = [0u8; _BUFFER_SIZE];
+    let (bytes_read, session_socket) = local_socket.recv_from(&mut buffer).await.unwrap();
+    assert_eq!(buffer[..4], [0x00, 0x03, 0x00, 0x01]);
+    assert_eq!(bytes_read, 4 + 512);
+    assert_ne!(session_socket, running_server.listen_socket);
+    local_socket
+        .send_to(b"\x00\x04\x00\x01", running_server.listen_socket)
+        .await
+        .unwrap();
+    let (bytes_read, error_source) = loop {
+        let (bytes_read, source) = local_socket.recv_from(&mut buffer).await.unwrap();
+        // The session may send the first block again meanwhile.
+        if source == running_server.listen_socket {
+            break (bytes_read, source);
+        }
+    };
+    assert_eq!(error_source, running_server.listen_socket);
+    assert_eq!(buffer[..4], [0x00, 0x05, 0x00, 0x05]);
+    let error_message = CStr::from_bytes_with_nul(&buffer[4..bytes_read]).unwrap();
+    assert_eq!(error_message.to_str().unwrap(), "Unknown transfer ID");
+    local_socket
+        .send_to(b"\x00\x04\x00\x01", session_socket)
+        .await
+        .unwrap();
+    let bytes_read = loop {
+        let (bytes_read, source) = local_socket.recv_from(&mut buffer).await.unwrap();
+        if source == session_socket && buffer[..4] == [0x00, 0x03, 0x00, 0x02] {
+            break bytes_read;
+        }
+    };
+    assert_eq!(&buffer[4..bytes_read], &data[512..1024]);
+}
+
 #[tokio::test(flavor = "current_thread")]
 async fn send_wrong_content_type() {
     let source_ip = "127.0.0.11";
@@ -61,10 +166,129 @@ async fn send_wrong_content_type() {
         error_message
             .to_str()
             .unwrap()
-            .contains("Only octet mode is supported")
+            .contains("Unknown transfer mode")
     );
 }
 
+#[tokio::test(flavor = "current_thread")]
+async fn send_mail_mode() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(send_mail_mode);
+    let running_server = start_rtftp(server_dir).await;
+    let mail_packet = b"\x00\x01user\x00mail\x00";
+    let local_socket = UdpSocket::bind((source_ip, 0)).await.unwrap();
+    local_socket
+        .send_to(mail_packet, running_server.listen_socket)
+        .await
+        .unwrap();
+    let mut buffer = [0u8; _BUFFER_SIZE];
+    let bytes_read = local_socket.recv(&mut buffer).await.unwrap();
+    // Illegal TFTP operation.
+    assert_eq!(buffer[..4], [0x00, 0x05, 0x00, 0x04]);
+    let error_message = CStr::from_bytes_with_nul(&buffer[4..bytes_read]).unwrap();
+    assert_eq!(
+        error_message.to_str().unwrap(),
+        "mail mode is obsolete and unsupported"
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn retransmitted_request_served_once() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(retransmitted_request_served_once);
+    let file_name = "file.txt";
+    let data = make_payload(100);
+    _write_file(&server_dir.join(source_ip).join(file_name), &data);
+    let running_server = start_rtftp(server_dir).await;
+    let request = [b"\x00\x01", file_name.as_bytes(), b"\x00octet\x00"].concat();
+    let local_socket = UdpSocket::bind((source_ip, 0)).await.unwrap();
+    for _ in 0..2 {
+        local_socket
+            .send_to(&request, running_server.listen_socket)
+            .await
+            .unwrap();
+    }
+    let mut buffer = [0u8; _BUFFER_SIZE];
+    let mut senders = Vec::new();
+    // A second session would send its first block from another port, acknowledged or not.
+    while let Ok(received) = tokio::time::timeout(
+        time::Duration::from_millis(1500),
+        local_socket.recv_from(&mut buffer),
+    )
+    .await
+    {
+        let (bytes_read, sender) = received.unwrap();
+        assert_eq!(buffer[..4], [0x00, 0x03, 0x00, 0x01]);
+        assert_eq!(buffer[4..bytes_read], data);
+        local_socket
+            .send_to(&[0x00, 0x04, 0x00, 0x01], sender)
+            .await
+            .unwrap();
+        senders.push(sender);
+    }
+    assert_eq!(senders.len(), 1, "Served by {senders:?}");
+}
+
+// Downloads a file without following the reply port, returning the data and the ports it came from.
+async fn download_recording_senders(
+    local_socket: UdpSocket,
+    server: SocketAddr,
+    file_name: &str,
+) -> (Vec<u8>, Vec<SocketAddr>) {
+    let request = [b"\x00\x01", file_name.as_bytes(), b"\x00octet\x00"].concat();
+    local_socket.send_to(&request, server).await.unwrap();
+    let mut buffer = [0u8; _BUFFER_SIZE];
+    let mut data = Vec::new();
+    let mut senders = Vec::new();
+    loop {
+        let (bytes_read, sender) = tokio::time::timeout(
+            time::Duration::from_secs(5),
+            local_socket.recv_from(&mut buffer),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(buffer[..2], [0x00, 0x03]);
+        data.extend_from_slice(&buffer[4..bytes_read]);
+        senders.push(sender);
+        let ack = [&[0x00, 0x04], &buffer[2..4]].concat();
+        local_socket.send_to(&ack, sender).await.unwrap();
+        if bytes_read - 4 < 512 {
+            return (data, senders);
+        }
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn announce_port_replies_from_listen_port() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(announce_port_replies_from_listen_port);
+    let first_data = make_payload(512 * 3 + 100);
+    let second_data = make_payload(512 * 2);
+    _write_file(&server_dir.join(source_ip).join("first.bin"), &first_data);
+    _write_file(&server_dir.join(source_ip).join("second.bin"), &second_data);
+    let running_server = start_rtftp_with_args(server_dir, &["--announce-port"]).await;
+    let server = running_server.listen_socket;
+    // Concurrent sessions of a peer share the listen socket and are told apart by the peer port.
+    let ((first, first_senders), (second, second_senders)) = tokio::join!(
+        download_recording_senders(
+            UdpSocket::bind((source_ip, 0)).await.unwrap(),
+            server,
+            "first.bin"
+        ),
+        download_recording_senders(
+            UdpSocket::bind((source_ip, 0)).await.unwrap(),
+            server,
+            "second.bin"
+        ),
+    );
+    assert_eq!(first, first_data);
+    assert_eq!(second, second_data);
+    for sender in first_senders.into_iter().chain(second_senders) {
+        assert_eq!(sender, server);
+    }
+}
+
 #[tokio::test(flavor = "current_thread")]
 async fn download_local_aligned_file() {
     let source_ip = "127.0.0.11";
@@ -83,6 +307,50 @@ async fn download_local_aligned_file() {
     );
 }
 
+#[tokio::test(flavor = "current_thread")]
+async fn download_via_multiple_listen_addresses() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(download_via_multiple_listen_addresses);
+    let data = make_payload(4096 + 256);
+    let file_name = "file.txt";
+    let file = server_dir.join(source_ip).join(file_name);
+    _write_file(&file, &data);
+    let running_server = start_rtftp_with_args(server_dir, &["--listen-ip", "127.0.0.12"]).await;
+    for listen_ip in ["127.0.0.10", "127.0.0.12", "127.0.0.10"] {
+        let client = running_server.open_client_via(source_ip, listen_ip).await;
+        let read_result = download(client, file_name).await;
+        assert!(
+            matches!(&read_result, Ok(recv_data) if data == *recv_data),
+            "Unexpected error via {listen_ip}: {read_result:?}"
+        );
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn download_via_dual_stack_socket() {
+    let server_dir = mk_tmp(download_via_dual_stack_socket);
+    let file_name = "file.txt";
+    // IPv4 clients get the files of their plain addresses rather than of the mapped ones.
+    let v4_data = make_payload(4096 + 256);
+    _write_file(&server_dir.join("127.0.0.1").join(file_name), &v4_data);
+    let v6_data = make_payload(2048 + 100);
+    _write_file(&server_dir.join("::1").join(file_name), &v6_data);
+    let running_server = start_rtftp_dual_stack(server_dir).await;
+    let port = running_server.listen_socket.port();
+    for (source_ip, data) in [("127.0.0.1", &v4_data), ("::1", &v6_data)] {
+        let listen_socket = SocketAddr::new(source_ip.parse().unwrap(), port);
+        let client = TFTPClient::new(
+            UdpSocket::bind((source_ip, 0)).await.unwrap(),
+            listen_socket,
+        );
+        let read_result = download(client, file_name).await;
+        assert!(
+            matches!(&read_result, Ok(recv_data) if *data == *recv_data),
+            "Unexpected error from {source_ip}: {read_result:?}"
+        );
+    }
+}
+
 #[tokio::test(flavor = "current_thread")]
 async fn download_local_non_aligned_file() {
     let source_ip = "127.0.0.11";
@@ -150,37 +418,1097 @@ async fn attempt_download_file_default() {
 }
 
 #[tokio::test(flavor = "current_thread")]
-async fn attempt_download_file_peer_takes_precendence() {
-    let arbitrary_source_ip = "127.0.0.11";
-    let server_dir = mk_tmp(attempt_download_file_peer_takes_precendence);
-    let file_name = "file.txt";
-    let default_data = make_payload(512);
-    let default_file = server_dir.join("default").join(file_name);
-    _write_file(&default_file, &default_data);
-    let peer_data = make_payload(768);
-    let peer_file = server_dir.join(arbitrary_source_ip).join(file_name);
-    _write_file(&peer_file, &peer_data);
-    let running_server = start_rtftp(server_dir).await;
-    let client = running_server.open_paired_client(arbitrary_source_ip).await;
-    let read_data = download(client, &file_name).await.unwrap();
-    assert_eq!(read_data, peer_data);
+async fn attempt_download_file_peer_takes_precendence() {
+    let arbitrary_source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(attempt_download_file_peer_takes_precendence);
+    let file_name = "file.txt";
+    let default_data = make_payload(512);
+    let default_file = server_dir.join("default").join(file_name);
+    _write_file(&default_file, &default_data);
+    let peer_data = make_payload(768);
+    let peer_file = server_dir.join(arbitrary_source_ip).join(file_name);
+    _write_file(&peer_file, &peer_data);
+    let running_server = start_rtftp(server_dir).await;
+    let client = running_server.open_paired_client(arbitrary_source_ip).await;
+    let read_data = download(client, &file_name).await.unwrap();
+    assert_eq!(read_data, peer_data);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn attempt_download_default_root_disabled() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(attempt_download_default_root_disabled);
+    let file_name = "file.txt";
+    _write_file(
+        &server_dir.join("default").join(file_name),
+        &make_payload(512),
+    );
+    let running_server = start_rtftp_with_args(server_dir, &["--no-default-root"]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let sent_request = client.send_plain_read_request(file_name).await.unwrap();
+    let result = sent_request.read_next(5).await;
+    assert!(
+        matches!(&result, Err(TFTPClientError::ClientError(0x01, msg)) if msg == "File not found"),
+        "Unexpected result {result:?}"
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn download_renamed_default_root() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(download_renamed_default_root);
+    let file_name = "file.txt";
+    let fallback_data = make_payload(768);
+    _write_file(&server_dir.join("fallback").join(file_name), &fallback_data);
+    _write_file(
+        &server_dir.join("default").join(file_name),
+        &make_payload(512),
+    );
+    let running_server =
+        start_rtftp_with_args(server_dir, &["--default-root-name", "fallback"]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let read_data = download(client, file_name).await.unwrap();
+    assert_eq!(read_data, fallback_data);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn download_empty_file() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(download_empty_file);
+    _write_file(&server_dir.join(source_ip).join("empty.file"), b"");
+    let running_server = start_rtftp(server_dir).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let sent_request = client.send_plain_read_request("empty.file").await.unwrap();
+    let block = sent_request.read_next(5).await.unwrap();
+    assert_eq!(block.datagram(), b"\x00\x03\x00\x01");
+    let sent_ack = block.acknowledge().await.unwrap();
+    // Nothing follows the final ACK.
+    let result = sent_ack.read_next(2).await;
+    assert!(
+        matches!(&result, Err(TFTPClientError::IO(error)) if error.kind() == ErrorKind::TimedOut),
+        "Unexpected result {result:?}"
+    );
+    let client = running_server.open_paired_client(source_ip).await;
+    let read_data = download_window(client, "empty.file", 4).await.unwrap();
+    assert!(read_data.is_empty());
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn download_growing_file() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(download_growing_file);
+    let log_data = make_payload(1600);
+    let log_path = server_dir.join(source_ip).join("boot.log");
+    _write_file(&log_path, &log_data[..1000]);
+    let running_server = start_rtftp_with_args(server_dir, &["--allow-growing"]).await;
+    // Appended while the server waits for the second block to fill up.
+    let appender = {
+        let appended = log_data[1000..].to_vec();
+        std::thread::spawn(move || {
+            std::thread::sleep(time::Duration::from_millis(300));
+            let mut file = fs::OpenOptions::new().append(true).open(log_path).unwrap();
+            file.write_all(&appended).unwrap();
+        })
+    };
+    let client = running_server.open_paired_client(source_ip).await;
+    let read_data = download(client, "boot.log").await.unwrap();
+    appender.join().unwrap();
+    assert_eq!(read_data, log_data);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn download_transparent_gzip() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(download_transparent_gzip);
+    let kernel_data = make_payload(4096 + 256);
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&kernel_data).unwrap();
+    _write_file(
+        &server_dir.join(source_ip).join("kernel.gz"),
+        &encoder.finish().unwrap(),
+    );
+    let initrd_data = make_payload(8192);
+    _write_file(&server_dir.join(source_ip).join("initrd"), &initrd_data);
+    let running_server = start_rtftp_with_args(server_dir, &["--transparent-gzip"]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let read_data = download(client, "kernel").await.unwrap();
+    assert_eq!(read_data, kernel_data);
+    let client = running_server.open_paired_client(source_ip).await;
+    let compressed_data = download(client, "initrd.gz").await.unwrap();
+    let mut decompressed_data = Vec::new();
+    GzDecoder::new(compressed_data.as_slice())
+        .read_to_end(&mut decompressed_data)
+        .unwrap();
+    assert_eq!(decompressed_data, initrd_data);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn download_file_size_limit() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(download_file_size_limit);
+    let data = make_payload(4096);
+    _write_file(&server_dir.join(source_ip).join("file.bin"), &data);
+    let running_server =
+        start_rtftp_with_args(server_dir.clone(), &["--max-file-size", "4096"]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let read_data = download(client, "file.bin").await.unwrap();
+    assert_eq!(read_data, data);
+    drop(running_server);
+    let running_server = start_rtftp_with_args(server_dir, &["--max-file-size", "4095"]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let sent_request = client.send_plain_read_request("file.bin").await.unwrap();
+    let result = sent_request.read_next(5).await;
+    assert!(
+        matches!(&result, Err(TFTPClientError::ClientError(0x00, msg)) if msg == "File exceeds the maximum served size"),
+        "Unexpected result {result:?}"
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn no_oack_ignores_options() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(no_oack_ignores_options);
+    let data = make_payload(4096);
+    _write_file(&server_dir.join(source_ip).join("file.bin"), &data);
+    let running_server = start_rtftp_with_args(server_dir, &["--no-oack"]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let send_options = HashMap::from([
+        ("blksize".to_string(), "1024".to_string()),
+        ("tsize".to_string(), "0".to_string()),
+    ]);
+    let sent_request = client
+        .send_optioned_read_request("file.bin", &send_options)
+        .await
+        .unwrap();
+    let first_block = sent_request.expect_plain().read_next(5).await.unwrap();
+    assert_eq!(&first_block.datagram()[..4], b"\x00\x03\x00\x01");
+    assert_eq!(first_block.data(), &data[..512]);
+    first_block
+        .send_error(0x0, "Early termination")
+        .await
+        .unwrap();
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn socket_buffers_sized() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(socket_buffers_sized);
+    let data = make_payload(100_000);
+    _write_file(&server_dir.join(source_ip).join("file.bin"), &data);
+    let (running_server, log) = start_rtftp_with_log(
+        server_dir,
+        &["--socket-rcvbuf", "1048576", "--socket-sndbuf", "1048576"],
+    )
+    .await;
+    // Either granted in full or capped by the kernel, never refused.
+    for buffer in ["receive", "send"] {
+        _wait_for_line(
+            &log,
+            &format!("Listen socket on 127.0.0.10: The {buffer} buffer is"),
+            time::Duration::from_secs(5),
+        );
+    }
+    let client = running_server.open_paired_client(source_ip).await;
+    let read_data = download_window(client, "file.bin", 16).await.unwrap();
+    assert_eq!(read_data, data);
+}
+
+// Splits key=value fields by spaces outside of quoted values, unescaping the quoted ones.
+fn _parse_fields(line: &str) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    let mut chars = line.chars().peekable();
+    while chars.peek().is_some() {
+        let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+            chars.next();
+        } else {
+            value = chars.by_ref().take_while(|c| *c != ' ').collect();
+        }
+        fields.insert(key, value);
+    }
+    fields
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn transfer_start_summarized() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(transfer_start_summarized);
+    let data = make_payload(4096);
+    _write_file(&server_dir.join(source_ip).join("file.bin"), &data);
+    let (running_server, log) = start_rtftp_with_log(server_dir, &[]).await;
+    let socket = UdpSocket::bind((source_ip, 0)).await.unwrap();
+    let port = socket.local_addr().unwrap().port();
+    let client = TFTPClient::new(socket, running_server.listen_socket);
+    let options = HashMap::from([
+        ("blksize".to_string(), "1024".to_string()),
+        ("windowsize".to_string(), "4".to_string()),
+        ("timeout".to_string(), "3".to_string()),
+        ("tsize".to_string(), "0".to_string()),
+    ]);
+    let sent_request = client
+        .send_optioned_read_request("file.bin", &options)
+        .await
+        .unwrap();
+    let oack = sent_request.read_oack(5).await.unwrap();
+    let first_block = oack
+        .acknowledge()
+        .await
+        .unwrap()
+        .read_next(5)
+        .await
+        .unwrap();
+    assert_eq!(receive_blocks(first_block, 1024, 4).await.unwrap(), data);
+    let summary = loop {
+        match log.recv_timeout(time::Duration::from_secs(5)) {
+            Ok((_, line)) if line.contains("Transfer started ") => break line,
+            Ok(_) => continue,
+            Err(error) => panic!("No transfer summary in the server log: {error}"),
+        }
+    };
+    let fields = _parse_fields(summary.split_once("Transfer started ").unwrap().1);
+    assert_eq!(fields["peer"], format!("{source_ip}:{port}"));
+    assert_eq!(fields["file"], "file.bin");
+    assert!(fields["root"].starts_with("<Local: \""), "{summary}");
+    assert_eq!(fields["blksize"], "1024");
+    assert_eq!(fields["windowsize"], "4");
+    assert_eq!(fields["timeout"], "3");
+    assert_eq!(fields["tsize"], "4096");
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn client_declines_options() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(client_declines_options);
+    _write_file(
+        &server_dir.join(source_ip).join("file.bin"),
+        &make_payload(4096),
+    );
+    let (running_server, log) = start_rtftp_with_log(server_dir, &[]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let send_options = HashMap::from([("blksize".to_string(), "1024".to_string())]);
+    let sent_request = client
+        .send_optioned_read_request("file.bin", &send_options)
+        .await
+        .unwrap();
+    let oack = sent_request.read_oack(5).await.unwrap();
+    let mut sent_error = oack.send_error(0x08, "Options refused").await.unwrap();
+    _wait_for_line(
+        &log,
+        "Client declined the options: Options refused",
+        time::Duration::from_secs(5),
+    );
+    let reply = sent_error.read_some(2).await;
+    assert!(
+        matches!(&reply, Err(error) if error.kind() == ErrorKind::TimedOut),
+        "Unexpected reply {reply:?}"
+    );
+}
+
+// The stats socket is bound after the listen socket the server start waits for.
+fn _connect_stats(stats_socket: &PathBuf) -> UnixStream {
+    for _ in 0..50 {
+        match UnixStream::connect(stats_socket) {
+            Ok(stream) => return stream,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    ErrorKind::NotFound | ErrorKind::ConnectionRefused
+                ) =>
+            {
+                std::thread::sleep(time::Duration::from_millis(100));
+            }
+            Err(error) => panic!("Failed to connect {stats_socket:?}: {error}"),
+        }
+    }
+    panic!("{stats_socket:?} is not bound")
+}
+
+fn _read_stats(stats_socket: &PathBuf) -> serde_json::Value {
+    let mut stream = _connect_stats(stats_socket);
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    serde_json::from_str(&response).unwrap()
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn transfer_stats() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(transfer_stats);
+    let stats_socket = server_dir.join("stats.sock");
+    let sizes = [512, 4096, 100_000];
+    for size in sizes {
+        _write_file(
+            &server_dir.join(source_ip).join(format!("{size}.bin")),
+            &make_payload(size),
+        );
+    }
+    let running_server = start_rtftp_with_args(
+        server_dir.clone(),
+        &["--stats-socket", stats_socket.to_str().unwrap()],
+    )
+    .await;
+    for size in sizes {
+        let client = running_server.open_paired_client(source_ip).await;
+        let read_data = download(client, &format!("{size}.bin")).await.unwrap();
+        assert_eq!(read_data.len(), size);
+    }
+    // Sessions report after the final acknowledgement, so the stats may lag behind the client.
+    let mut stats = _read_stats(&stats_socket);
+    for _ in 0..50 {
+        if stats["transfers"]["completed"] == json!(sizes.len()) {
+            break;
+        }
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        stats = _read_stats(&stats_socket);
+    }
+    assert_eq!(stats["transfers"]["completed"], json!(sizes.len()));
+    assert_eq!(stats["transfers"]["failed"], json!(0));
+    let transfer_bytes = &stats["transfer_bytes"];
+    assert_eq!(transfer_bytes["count"], json!(sizes.len()));
+    assert_eq!(transfer_bytes["min"], json!(512));
+    assert!(transfer_bytes["max"].as_u64().unwrap() >= 100_000);
+    let transfer_duration = &stats["transfer_duration_us"];
+    assert_eq!(transfer_duration["count"], json!(sizes.len()));
+    assert!(transfer_duration["p50"].as_u64().unwrap() > 0);
+    assert!(transfer_duration["p99"].as_u64() >= transfer_duration["p50"].as_u64());
+    assert_eq!(stats["appliances"]["running"], json!(0));
+    assert_eq!(stats["appliances"]["queued"], json!(0));
+}
+
+fn _query_stats(stats_socket: &PathBuf, request: serde_json::Value) -> serde_json::Value {
+    let mut stream = _connect_stats(stats_socket);
+    stream.write_all(format!("{request}\n").as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    serde_json::from_str(&response).unwrap()
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn list_dir_queried_on_stats_socket() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(list_dir_queried_on_stats_socket);
+    let stats_socket = server_dir.join("stats.sock");
+    _write_file(&server_dir.join(source_ip).join("file.bin"), b"content");
+    // Refused before any appliance is launched, so the handler runs without a disk.
+    let config = server_dir.join(format!("{source_ip}.nbd"));
+    _write_file(
+        &config,
+        br#"{"url": "ftp://127.0.0.2/disk", "mounts": [], "tftp_root": "/"}"#,
+    );
+    let running_server = start_rtftp_with_args(
+        server_dir.clone(),
+        &["--stats-socket", stats_socket.to_str().unwrap()],
+    )
+    .await;
+    let list_dir = json!({"list_dir": {"config": config, "path": "grub"}});
+    let reply = _query_stats(&stats_socket, list_dir.clone());
+    assert_eq!(reply["error"], json!("No handler of the config is running"));
+    let client = running_server.open_paired_client(source_ip).await;
+    assert_eq!(download(client, "file.bin").await.unwrap(), b"content");
+    let reply = _query_stats(&stats_socket, list_dir);
+    assert_eq!(reply["path"], json!("grub"));
+    assert!(
+        reply["error"].as_str().unwrap().contains("is connected"),
+        "{reply}"
+    );
+    let reply = _query_stats(&stats_socket, json!({"list_dir": {}}));
+    assert!(
+        reply["error"].as_str().unwrap().contains("config"),
+        "{reply}"
+    );
+    assert_eq!(
+        _query_stats(&stats_socket, json!("snapshot"))["handlers"],
+        json!(1)
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn instance_name_in_stats() {
+    let server_dir = mk_tmp(instance_name_in_stats);
+    let stats_socket = server_dir.join("stats.sock");
+    let _running_server = start_rtftp_with_args(
+        server_dir.clone(),
+        &[
+            "--stats-socket",
+            stats_socket.to_str().unwrap(),
+            "--instance-name",
+            "pxe-rack-7",
+        ],
+    )
+    .await;
+    let stats = _read_stats(&stats_socket);
+    assert_eq!(stats["instance"], json!("pxe-rack-7"));
+}
+
+fn _scrape_metrics(metrics_addr: &str) -> String {
+    let mut stream = std::net::TcpStream::connect(metrics_addr).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: rtftp\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn metrics_endpoint_scraped() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(metrics_endpoint_scraped);
+    _write_file(
+        &server_dir.join(source_ip).join("file.bin"),
+        &make_payload(4096),
+    );
+    let metrics_addr = format!("127.0.0.10:{}", get_free_port());
+    let running_server =
+        start_rtftp_with_args(server_dir, &["--metrics-addr", metrics_addr.as_str()]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    download(client, "file.bin").await.unwrap();
+    let client = running_server.open_paired_client(source_ip).await;
+    assert!(download(client, "missing.bin").await.is_err());
+    // Sessions report after the final acknowledgement and errors are counted once sent, so the metrics
+    // may lag behind the client.
+    let mut response = _scrape_metrics(&metrics_addr);
+    for _ in 0..50 {
+        if response.contains("rtftp_transfers_total{outcome=\"completed\"} 1\n")
+            && response.contains("rtftp_errors_total{code=\"1\"} 1\n")
+        {
+            break;
+        }
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        response = _scrape_metrics(&metrics_addr);
+    }
+    let (head, metrics) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK"), "{head}");
+    assert!(
+        head.contains("Content-Type: text/plain; version=0.0.4"),
+        "{head}"
+    );
+    for expected in [
+        "# TYPE rtftp_transfers_total counter",
+        "rtftp_transfers_total{outcome=\"completed\"} 1",
+        "rtftp_transfers_total{outcome=\"failed\"} 0",
+        "rtftp_bytes_sent_total 4096",
+        "# TYPE rtftp_active_sessions gauge",
+        "rtftp_active_sessions 0",
+        "rtftp_errors_total{code=\"1\"} 1",
+        "rtftp_handlers 1",
+    ] {
+        assert!(
+            metrics.lines().any(|line| line == expected),
+            "No {expected:?} in {metrics}"
+        );
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn handler_lifecycle_stats() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(handler_lifecycle_stats);
+    let stats_socket = server_dir.join("stats.sock");
+    let data = make_payload(1024);
+    _write_file(&server_dir.join(source_ip).join("file.bin"), &data);
+    let running_server = start_rtftp_with_args(
+        server_dir.clone(),
+        &[
+            "--stats-socket",
+            stats_socket.to_str().unwrap(),
+            "--idle-timeout",
+            "1",
+            "--turn-duration-ms",
+            "100",
+        ],
+    )
+    .await;
+    let client = running_server.open_paired_client(source_ip).await;
+    assert_eq!(download(client, "file.bin").await.unwrap(), data);
+    let lifecycle = &_read_stats(&stats_socket)["handler_lifecycles"][source_ip];
+    assert_eq!(lifecycle["created"], json!(1));
+    assert_eq!(lifecycle["destroyed"], json!(0));
+    assert!(lifecycle["last_created"].as_u64().unwrap() > 0);
+    let mut stats = _read_stats(&stats_socket);
+    for _ in 0..50 {
+        if stats["handler_lifecycles"][source_ip]["destroyed"] == json!(1) {
+            break;
+        }
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        stats = _read_stats(&stats_socket);
+    }
+    let lifecycle = &stats["handler_lifecycles"][source_ip];
+    assert_eq!(lifecycle["destroyed"], json!(1));
+    assert!(lifecycle["last_destroyed"].as_u64() >= lifecycle["last_created"].as_u64());
+    assert_eq!(stats["handler_exits"]["idle timeout"], json!(1));
+    // A new request after the idle-out creates the handler anew.
+    let client = running_server.open_paired_client(source_ip).await;
+    assert_eq!(download(client, "file.bin").await.unwrap(), data);
+    let lifecycle = &_read_stats(&stats_socket)["handler_lifecycles"][source_ip];
+    assert_eq!(lifecycle["created"], json!(2));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn max_peer_ips_refuses_new_peers() {
+    let server_dir = mk_tmp(max_peer_ips_refuses_new_peers);
+    let data = make_payload(1024);
+    _write_file(&server_dir.join("default").join("file.bin"), &data);
+    let running_server = start_rtftp_with_args(
+        server_dir.clone(),
+        &["--max-peer-ips", "2", "--idle-timeout", "0"],
+    )
+    .await;
+    for source_ip in ["127.0.0.11", "127.0.0.12"] {
+        let client = running_server.open_paired_client(source_ip).await;
+        assert_eq!(download(client, "file.bin").await.unwrap(), data);
+    }
+    let client = running_server.open_paired_client("127.0.0.13").await;
+    let result = download(client, "file.bin").await;
+    assert!(
+        matches!(&result, Err(message) if message.to_string().contains("Too many peers")),
+        "{result:?}"
+    );
+    // Peers already served keep being served.
+    let client = running_server.open_paired_client("127.0.0.11").await;
+    assert_eq!(download(client, "file.bin").await.unwrap(), data);
+}
+
+// Every peer keeps its handler after a download, so the threads are counted with all of them up.
+async fn _threads_serving_peers(server_dir: PathBuf, peers: usize, extra_args: &[&str]) -> usize {
+    let mut args = vec!["--idle-timeout", "0"];
+    args.extend_from_slice(extra_args);
+    let running_server = start_rtftp_with_args(server_dir, &args).await;
+    for peer in 0..peers {
+        let source_ip = format!("127.1.{}.{}", peer / 250, peer % 250 + 1);
+        let client = running_server.open_paired_client(&source_ip).await;
+        download(client, "file.bin").await.unwrap();
+    }
+    running_server.thread_count()
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn shared_workers_bound_threads() {
+    let peers = 1000;
+    let server_dir = mk_tmp(shared_workers_bound_threads);
+    _write_file(&server_dir.join("default").join("file.bin"), b"boot");
+    let per_peer_threads = _threads_serving_peers(server_dir.clone(), peers, &[]).await;
+    let shared_threads =
+        _threads_serving_peers(server_dir, peers, &["--shared-workers", "4"]).await;
+    eprintln!("Threads for {peers} peers: {per_peer_threads} per peer, {shared_threads} shared");
+    assert!(per_peer_threads > peers, "{per_peer_threads}");
+    assert!(shared_threads < 50, "{shared_threads}");
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn max_peer_ips_evicts_idle_peer() {
+    let server_dir = mk_tmp(max_peer_ips_evicts_idle_peer);
+    let stats_socket = server_dir.join("stats.sock");
+    let data = make_payload(1024);
+    _write_file(&server_dir.join("default").join("file.bin"), &data);
+    let running_server = start_rtftp_with_args(
+        server_dir.clone(),
+        &[
+            "--max-peer-ips",
+            "2",
+            "--evict-idle-peers",
+            "--idle-timeout",
+            "0",
+            "--stats-socket",
+            stats_socket.to_str().unwrap(),
+        ],
+    )
+    .await;
+    for source_ip in ["127.0.0.11", "127.0.0.12", "127.0.0.13"] {
+        let client = running_server.open_paired_client(source_ip).await;
+        assert_eq!(download(client, "file.bin").await.unwrap(), data);
+    }
+    // The evicted handler is shut down without holding the server up, its exit is recorded later.
+    let mut stats = _read_stats(&stats_socket);
+    for _ in 0..50 {
+        if stats["handler_lifecycles"]["127.0.0.11"]["destroyed"] == json!(1) {
+            break;
+        }
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        stats = _read_stats(&stats_socket);
+    }
+    let lifecycles = &stats["handler_lifecycles"];
+    assert_eq!(lifecycles["127.0.0.11"]["destroyed"], json!(1));
+    assert_eq!(lifecycles["127.0.0.12"]["destroyed"], json!(0));
+    assert_eq!(lifecycles["127.0.0.13"]["created"], json!(1));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn eviction_leaves_server_responsive() {
+    let server_dir = mk_tmp(eviction_leaves_server_responsive);
+    let data = make_payload(4096);
+    _write_file(&server_dir.join("default").join("file.bin"), &data);
+    let running_server = start_rtftp_with_args(
+        server_dir.clone(),
+        &["--max-peer-ips", "1", "--evict-idle-peers"],
+    )
+    .await;
+    // The transfer is left unacknowledged, so the evicted handler waits for it to time out.
+    let stalled_client = running_server.open_paired_client("127.0.0.11").await;
+    let sent_request = stalled_client
+        .send_plain_read_request("file.bin")
+        .await
+        .unwrap();
+    let _stalled_block = sent_request.read_next(1).await.unwrap();
+    let started = Instant::now();
+    let client = running_server.open_paired_client("127.0.0.12").await;
+    assert_eq!(download(client, "file.bin").await.unwrap(), data);
+    let elapsed = started.elapsed();
+    assert!(elapsed < time::Duration::from_secs(1), "{elapsed:?}");
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn config_moved_away_releases_handler() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(config_moved_away_releases_handler);
+    let stats_socket = server_dir.join("stats.sock");
+    let config_file = server_dir.join(format!("{source_ip}.nbd"));
+    _write_file(&config_file, b"{}");
+    let data = make_payload(1024);
+    _write_file(&server_dir.join(source_ip).join("file.bin"), &data);
+    let running_server = start_rtftp_with_args(
+        server_dir.clone(),
+        &["--stats-socket", stats_socket.to_str().unwrap()],
+    )
+    .await;
+    let client = running_server.open_paired_client(source_ip).await;
+    assert_eq!(download(client, "file.bin").await.unwrap(), data);
+    fs::rename(&config_file, server_dir.join("replaced.json")).unwrap();
+    let mut stats = _read_stats(&stats_socket);
+    for _ in 0..50 {
+        if stats["handler_exits"]["shutdown requested"] == json!(1) {
+            break;
+        }
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        stats = _read_stats(&stats_socket);
+    }
+    assert_eq!(stats["handler_exits"]["shutdown requested"], json!(1));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn config_change_reloads_running_handler() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(config_change_reloads_running_handler);
+    let stats_socket = server_dir.join("stats.sock");
+    let data = make_payload(1024);
+    _write_file(&server_dir.join(source_ip).join("file.bin"), &data);
+    let (running_server, log) = start_rtftp_with_log(
+        server_dir.clone(),
+        &["--stats-socket", stats_socket.to_str().unwrap()],
+    )
+    .await;
+    let client = running_server.open_paired_client(source_ip).await;
+    assert_eq!(download(client, "file.bin").await.unwrap(), data);
+    _write_file(&server_dir.join(format!("{source_ip}.nbd")), b"{}");
+    _wait_for_line(
+        &log,
+        &format!("{source_ip}: Reloading roots"),
+        time::Duration::from_secs(5),
+    );
+    let client = running_server.open_paired_client(source_ip).await;
+    assert_eq!(download(client, "file.bin").await.unwrap(), data);
+    let stats = _read_stats(&stats_socket);
+    assert_eq!(stats["handler_lifecycles"][source_ip]["created"], json!(1));
+    assert_eq!(
+        stats["handler_lifecycles"][source_ip]["destroyed"],
+        json!(0)
+    );
+}
+
+fn _wait_for_line(
+    log: &mpsc::Receiver<(Instant, String)>,
+    pattern: &str,
+    timeout: time::Duration,
+) -> Instant {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match log.recv_timeout(remaining) {
+            Ok((logged_at, line)) if line.contains(pattern) => return logged_at,
+            Ok(_) => continue,
+            Err(error) => panic!("No {pattern:?} in the server log: {error}"),
+        }
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn repeated_log_lines_collapsed() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(repeated_log_lines_collapsed);
+    _write_file(
+        &server_dir.join(source_ip).join("file.bin"),
+        &make_payload(4096),
+    );
+    let (running_server, log) = start_rtftp_with_log(
+        server_dir,
+        &["--quiet-after", "1", "--quiet-window-ms", "1000"],
+    )
+    .await;
+    // The client never acknowledges, so every request after the first one is a repeat of a running session.
+    let socket = UdpSocket::bind((source_ip, 0)).await.unwrap();
+    let port = socket.local_addr().unwrap().port();
+    let request = b"\x00\x01file.bin\x00octet\x00";
+    for _ in 0..5 {
+        socket
+            .send_to(request, running_server.listen_socket)
+            .await
+            .unwrap();
+        tokio::time::sleep(time::Duration::from_millis(50)).await;
+    }
+    // The repeats are summarized once the window ends, with nothing logged after them.
+    let repeated = format!("{source_ip}: Ignore repeated request from port {port}");
+    let summary = format!("{repeated} (x3)");
+    let mut written = 0;
+    loop {
+        match log.recv_timeout(time::Duration::from_secs(5)) {
+            Ok((_, line)) if line.ends_with(&summary) => break,
+            Ok((_, line)) if line.ends_with(&repeated) => written += 1,
+            Ok(_) => continue,
+            Err(error) => panic!("No {summary:?} in the server log: {error}"),
+        }
+    }
+    assert_eq!(written, 1);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn peer_config_not_served() {
+    let source_ip = "127.0.0.14";
+    let server_dir = mk_tmp(peer_config_not_served);
+    let peer_dir = server_dir.join(source_ip);
+    // Never connected, the URL and the TLS settings are what must not leak.
+    _write_file(
+        &peer_dir.join("disk.nbd"),
+        br#"{"url": "nbds://10.0.0.1:10809/secret", "mounts": [], "tftp_root": "/"}"#,
+    );
+    _write_file(&peer_dir.join("file.bin"), b"content");
+    let running_server = start_rtftp(server_dir).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let sent_request = client.send_plain_read_request("disk.nbd").await.unwrap();
+    let result = sent_request.read_next(5).await;
+    assert!(
+        matches!(&result, Err(TFTPClientError::ClientError(0x02, _))),
+        "Unexpected result {result:?}"
+    );
+    let client = running_server.open_paired_client(source_ip).await;
+    let read_data = download_window(client, "file.bin", 1).await.unwrap();
+    assert_eq!(read_data, b"content");
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn dangling_symlink_reported() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(dangling_symlink_reported);
+    let peer_dir = server_dir.join(source_ip);
+    fs::create_dir(&peer_dir).unwrap();
+    symlink(peer_dir.join("missing.bin"), peer_dir.join("dangling.bin")).unwrap();
+    let (running_server, log) = start_rtftp_with_log(server_dir.clone(), &[]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let sent_request = client
+        .send_plain_read_request("dangling.bin")
+        .await
+        .unwrap();
+    let result = sent_request.read_next(5).await;
+    assert!(
+        matches!(&result, Err(TFTPClientError::ClientError(0x01, msg)) if msg == "File not found"),
+        "Unexpected result {result:?}"
+    );
+    let warning = format!(
+        "{} is a dangling symlink to {}",
+        peer_dir.join("dangling.bin").display(),
+        peer_dir.join("missing.bin").display()
+    );
+    loop {
+        match log.recv_timeout(time::Duration::from_secs(5)) {
+            Ok((_, line)) if line.ends_with(&warning) => break,
+            Ok(_) => continue,
+            Err(error) => panic!("No {warning:?} in the server log: {error}"),
+        }
+    }
+    drop(running_server);
+    let running_server = start_rtftp_with_args(server_dir, &["--deny-dangling-symlinks"]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let sent_request = client
+        .send_plain_read_request("dangling.bin")
+        .await
+        .unwrap();
+    let result = sent_request.read_next(5).await;
+    assert!(
+        matches!(&result, Err(TFTPClientError::ClientError(0x02, _))),
+        "Unexpected result {result:?}"
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn finished_handler_reaped_within_turn() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(finished_handler_reaped_within_turn);
+    let data = make_payload(1024);
+    _write_file(&server_dir.join(source_ip).join("file.bin"), &data);
+    let (running_server, log) = start_rtftp_with_log(
+        server_dir,
+        &["--idle-timeout", "1", "--turn-duration-ms", "100"],
+    )
+    .await;
+    let client = running_server.open_paired_client(source_ip).await;
+    assert_eq!(download(client, "file.bin").await.unwrap(), data);
+    let handler_finished = _wait_for_line(
+        &log,
+        &format!("{source_ip}: Handler inactive"),
+        time::Duration::from_secs(10),
+    );
+    let handler_reaped = _wait_for_line(
+        &log,
+        &format!("Handler for {source_ip} exited"),
+        time::Duration::from_secs(10),
+    );
+    let reap_lag = handler_reaped - handler_finished;
+    assert!(
+        reap_lag < time::Duration::from_millis(500),
+        "Handler reaped after {reap_lag:?}"
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn access_violation() {
+    let server_dir = mk_tmp(access_violation);
+    let arbitrary_source_ip = "127.0.0.11";
+    let arbitrary_file_name = "arbitrary.file";
+    let running_server = start_rtftp(server_dir.clone()).await;
+    let client = running_server.open_paired_client(arbitrary_source_ip).await;
+    set_permissions(&server_dir, Permissions::from_mode(0o055)).unwrap();
+    let sent_request = client
+        .send_plain_read_request(arbitrary_file_name)
+        .await
+        .unwrap();
+    let result = sent_request.read_next(5).await;
+    assert!(
+        matches!(&result, Err(TFTPClientError::ClientError(0x02, msg)) if msg == "Access violation"),
+        "Unexpected result {result:?}"
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn subnet_roots() {
+    let server_dir = mk_tmp(subnet_roots);
+    for (directory, content) in [
+        ("lab", "lab"),
+        ("rack", "rack"),
+        ("127.0.1.11", "peer"),
+        ("default", "default"),
+    ] {
+        _write_file(
+            &server_dir.join(directory).join("file.txt"),
+            content.as_bytes(),
+        );
+    }
+    let running_server = start_rtftp_with_args(
+        server_dir,
+        &[
+            "--subnet-root",
+            "127.0.0.0/24=lab",
+            "--subnet-root",
+            "127.0.0.8/29=rack",
+        ],
+    )
+    .await;
+    for (source_ip, expected) in [
+        ("127.0.0.11", "rack"),
+        ("127.0.0.20", "lab"),
+        ("127.0.1.11", "peer"),
+        ("127.0.1.12", "default"),
+    ] {
+        let client = running_server.open_paired_client(source_ip).await;
+        let read_data = download(client, "file.txt").await.unwrap();
+        assert_eq!(read_data, expected.as_bytes(), "{source_ip}");
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn root_layers() {
+    let server_dir = mk_tmp(root_layers);
+    let source_ip = "127.0.0.11";
+    for (directory, file_name) in [
+        ("site", "both.txt"),
+        ("common", "both.txt"),
+        ("common", "common.txt"),
+        (source_ip, "common.txt"),
+        (source_ip, "peer.txt"),
+    ] {
+        _write_file(
+            &server_dir.join(directory).join(file_name),
+            directory.as_bytes(),
+        );
+    }
+    let running_server = start_rtftp_with_args(
+        server_dir,
+        &["--root-layer", "site", "--root-layer", "common"],
+    )
+    .await;
+    for (file_name, expected) in [
+        ("both.txt", "site"),
+        ("common.txt", "common"),
+        ("peer.txt", source_ip),
+    ] {
+        let client = running_server.open_paired_client(source_ip).await;
+        let read_data = download(client, file_name).await.unwrap();
+        assert_eq!(read_data, expected.as_bytes(), "{file_name}");
+    }
+}
+
+#[test]
+fn missing_root_dir() {
+    let root_dir = mk_tmp(missing_root_dir).join("missing");
+    let output = run_rtftp_to_completion(root_dir.clone(), &[]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Unusable TFTP root directory") && stderr.contains("No such file"),
+        "{stderr}"
+    );
+    assert!(!root_dir.exists());
+}
+
+#[test]
+fn root_dir_is_a_file() {
+    let root_file = mk_tmp(root_dir_is_a_file).join("file");
+    fs::write(&root_file, b"Not a directory").unwrap();
+    let output = run_rtftp_to_completion(root_file, &["--create-root"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Unusable TFTP root directory"), "{stderr}");
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn empty_root_file_not_found() {
+    let server_dir = mk_tmp(empty_root_file_not_found);
+    let (running_server, log) = start_rtftp_with_log(server_dir, &[]).await;
+    let client = running_server.open_paired_client("127.0.0.73").await;
+    let sent_request = client.send_plain_read_request("file.bin").await.unwrap();
+    let result = sent_request.read_next(5).await;
+    assert!(
+        matches!(&result, Err(TFTPClientError::ClientError(0x01, msg)) if msg == "File not found"),
+        "Unexpected result {result:?}"
+    );
+    drop(running_server);
+    let lines: Vec<String> = log.into_iter().map(|(_stamp, line)| line).collect();
+    let reply = "Sent TFTP ERROR: [0x01] File not found";
+    assert!(lines.iter().any(|line| line.ends_with(reply)), "{lines:#?}");
+    for line in lines.iter().filter(|line| !line.ends_with(reply)) {
+        let line = line.to_lowercase();
+        assert!(
+            !line.contains("panic") && !line.contains("error"),
+            "Stray error: {line}"
+        );
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn create_missing_root_dir() {
+    let root_dir = mk_tmp(create_missing_root_dir).join("created").join("root");
+    let running_server = start_rtftp_with_args(root_dir.clone(), &["--create-root"]).await;
+    assert!(root_dir.is_dir());
+    let client = running_server.open_paired_client("127.0.0.11").await;
+    let result = download(client, "missing.txt").await;
+    assert!(
+        matches!(&result, Err(message) if message.to_string().contains("File not found")),
+        "Unexpected result {result:?}"
+    );
+}
+
+#[test]
+fn invalid_root_layer() {
+    let server_dir = mk_tmp(invalid_root_layer);
+    let output = run_rtftp_to_completion(server_dir, &["--root-layer", "../etc"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid root layer"));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn exec_hook_generates_file() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(exec_hook_generates_file);
+    let script = server_dir.join("generate.sh");
+    fs::write(&script, "#!/bin/sh\necho \"config of $1 for $2\"\n").unwrap();
+    set_permissions(&script, Permissions::from_mode(0o755)).unwrap();
+    _write_file(&server_dir.join(source_ip).join("plain.txt"), b"plain");
+    let running_server = start_rtftp_with_args(
+        server_dir,
+        &["--exec", &format!("*.ign={}", script.display())],
+    )
+    .await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let generated = download(client, "hosts/web.ign").await.unwrap();
+    assert_eq!(generated, b"config of 127.0.0.11 for hosts/web.ign\n");
+    let client = running_server.open_paired_client(source_ip).await;
+    assert_eq!(download(client, "plain.txt").await.unwrap(), b"plain");
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn slow_exec_hook_leaves_peer_served() {
+    let source_ip = "127.0.0.15";
+    let server_dir = mk_tmp(slow_exec_hook_leaves_peer_served);
+    let script = server_dir.join("generate.sh");
+    fs::write(&script, "#!/bin/sh\nsleep 2\necho slow\n").unwrap();
+    set_permissions(&script, Permissions::from_mode(0o755)).unwrap();
+    _write_file(&server_dir.join(source_ip).join("plain.txt"), b"plain");
+    let running_server = start_rtftp_with_args(
+        server_dir,
+        &["--exec", &format!("*.ign={}", script.display())],
+    )
+    .await;
+    let slow_client = running_server.open_paired_client(source_ip).await;
+    let plain_client = running_server.open_paired_client(source_ip).await;
+    let started = Instant::now();
+    let plain = async {
+        let content = download(plain_client, "plain.txt").await.unwrap();
+        (content, started.elapsed())
+    };
+    let (generated, (plain, plain_elapsed)) = tokio::join!(download(slow_client, "web.ign"), plain);
+    assert_eq!(generated.unwrap(), b"slow\n");
+    assert_eq!(plain, b"plain");
+    assert!(
+        plain_elapsed < time::Duration::from_secs(1),
+        "{plain_elapsed:?}"
+    );
+}
+
+#[test]
+fn invalid_exec_hook() {
+    let server_dir = mk_tmp(invalid_exec_hook);
+    let output = run_rtftp_to_completion(server_dir, &["--exec", "/bin/echo"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid exec hook"));
+}
+
+#[test]
+fn invalid_subnet_root() {
+    let server_dir = mk_tmp(invalid_subnet_root);
+    let output = run_rtftp_to_completion(server_dir, &["--subnet-root", "127.0.0.0/24=../etc"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid subnet root"));
 }
 
 #[tokio::test(flavor = "current_thread")]
-async fn access_violation() {
-    let server_dir = mk_tmp(access_violation);
-    let arbitrary_source_ip = "127.0.0.11";
-    let arbitrary_file_name = "arbitrary.file";
-    let running_server = start_rtftp(server_dir.clone()).await;
-    let client = running_server.open_paired_client(arbitrary_source_ip).await;
-    set_permissions(&server_dir, Permissions::from_mode(0o055)).unwrap();
-    let sent_request = client
-        .send_plain_read_request(arbitrary_file_name)
-        .await
-        .unwrap();
+async fn attempt_download_directory() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(attempt_download_directory);
+    let dir_name = "pxelinux.cfg";
+    fs::create_dir_all(server_dir.join(source_ip).join(dir_name)).unwrap();
+    let running_server = start_rtftp(server_dir).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let sent_request = client.send_plain_read_request(dir_name).await.unwrap();
     let result = sent_request.read_next(5).await;
     assert!(
-        matches!(&result, Err(TFTPClientError::ClientError(0x02, msg)) if msg == "Access violation"),
+        matches!(&result, Err(TFTPClientError::ClientError(0x01, msg)) if msg == "Requested path is a directory"),
         "Unexpected result {result:?}"
     );
 }
@@ -238,6 +1566,33 @@ async fn change_block_size_local() {
         .unwrap();
 }
 
+#[tokio::test(flavor = "current_thread")]
+async fn block_size_capped_by_mtu() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(block_size_capped_by_mtu);
+    let data = make_payload(4096);
+    let file_name = "file.txt";
+    _write_file(&server_dir.join(source_ip).join(file_name), &data);
+    let running_server =
+        start_rtftp_with_args(server_dir.clone(), &["--max-blksize-mtu", "1500"]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let send_options = HashMap::from([("blksize".to_string(), "65464".to_string())]);
+    let sent_request = client
+        .send_optioned_read_request(file_name, &send_options)
+        .await
+        .unwrap();
+    let oack = sent_request.read_oack(5).await.unwrap();
+    // 1500 less the IPv4, UDP and TFTP DATA headers.
+    assert_eq!(oack.fields()["blksize"], "1468");
+    let sent_ack = oack.acknowledge().await.unwrap();
+    let first_block = sent_ack.read_next(5).await.unwrap();
+    assert_eq!(first_block.data(), &data[..1468]);
+    first_block
+        .send_error(0x0, "Early termination")
+        .await
+        .unwrap();
+}
+
 #[tokio::test(flavor = "current_thread")]
 async fn request_file_size_local() {
     let source_ip = "127.0.0.11";
@@ -266,6 +1621,139 @@ async fn request_file_size_local() {
         .unwrap();
 }
 
+#[tokio::test(flavor = "current_thread")]
+async fn nonzero_tsize_on_read_answered() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(nonzero_tsize_on_read_answered);
+    let payload_size: usize = 4096;
+    let file_name = "file.txt";
+    _write_file(
+        &server_dir.join(source_ip).join(file_name),
+        &make_payload(payload_size),
+    );
+    let running_server = start_rtftp(server_dir.clone()).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let send_options = HashMap::from([("tsize".to_string(), "12345".to_string())]);
+    let sent_request = client
+        .send_optioned_read_request(file_name, &send_options)
+        .await
+        .unwrap();
+    let oack = sent_request.read_oack(5).await.unwrap();
+    let received_options = oack.fields();
+    assert_eq!(
+        received_options.get("tsize").unwrap(),
+        &payload_size.to_string()
+    );
+    let sent_ack = oack.acknowledge().await.unwrap();
+    let first_block = sent_ack.read_next(5).await.unwrap();
+    first_block
+        .send_error(0x0, "Early termination")
+        .await
+        .unwrap();
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn request_file_mtime_local() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(request_file_mtime_local);
+    let data = make_payload(4096);
+    let file_name = "file.txt";
+    let file = server_dir.join(source_ip).join(file_name);
+    _write_file(&file, &data);
+    let expected_mtime = fs::metadata(&file)
+        .unwrap()
+        .modified()
+        .unwrap()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let running_server = start_rtftp(server_dir.clone()).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let send_options = HashMap::from([("mtime".to_string(), "0".to_string())]);
+    let sent_request = client
+        .send_optioned_read_request(file_name, &send_options)
+        .await
+        .unwrap();
+    let oack = sent_request.read_oack(5).await.unwrap();
+    let received_options = oack.fields();
+    let raw_mtime = received_options.get("mtime").unwrap();
+    assert_eq!(raw_mtime.parse::<u64>().unwrap(), expected_mtime);
+    let sent_ack = oack.acknowledge().await.unwrap();
+    let first_block = sent_ack.read_next(5).await.unwrap();
+    first_block
+        .send_error(0x0, "Early termination")
+        .await
+        .unwrap();
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn request_file_hash_local() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(request_file_hash_local);
+    let data = make_payload(100_000);
+    let file_name = "file.txt";
+    let file = server_dir.join(source_ip).join(file_name);
+    _write_file(&file, &data);
+    let running_server = start_rtftp(server_dir.clone()).await;
+    for (algorithm, expected_digest) in [
+        ("crc32", format!("{:08x}", crc32fast::hash(&data))),
+        (
+            "sha256",
+            Sha256::digest(&data)
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect(),
+        ),
+    ] {
+        let client = running_server.open_paired_client(source_ip).await;
+        let send_options = HashMap::from([("hash".to_string(), algorithm.to_string())]);
+        let sent_request = client
+            .send_optioned_read_request(file_name, &send_options)
+            .await
+            .unwrap();
+        let oack = sent_request.read_oack(5).await.unwrap();
+        let received_options = oack.fields();
+        assert_eq!(received_options.get("hash"), Some(&expected_digest));
+        let sent_ack = oack.acknowledge().await.unwrap();
+        let first_block = sent_ack.read_next(5).await.unwrap();
+        assert_eq!(first_block.data(), &data[..512]);
+        first_block
+            .send_error(0x0, "Early termination")
+            .await
+            .unwrap();
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn send_timeout_reports_block() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(send_timeout_reports_block);
+    let data = make_payload(4096);
+    let file_name = "file.txt";
+    _write_file(&server_dir.join(source_ip).join(file_name), &data);
+    let running_server = start_rtftp(server_dir.clone()).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let send_options = HashMap::from([("timeout".to_string(), "1".to_string())]);
+    let sent_request = client
+        .send_optioned_read_request(file_name, &send_options)
+        .await
+        .unwrap();
+    let oack = sent_request.read_oack(5).await.unwrap();
+    let sent_ack = oack.acknowledge().await.unwrap();
+    let mut block = sent_ack.read_next(5).await.unwrap();
+    // Never acknowledge, just collect retransmissions until the server gives up.
+    let result = loop {
+        match block.read_next(5).await {
+            Ok(retransmitted) => block = retransmitted,
+            result => break result,
+        }
+    };
+    assert!(
+        matches!(&result, Err(TFTPClientError::ClientError(0x00, msg)) if msg == "Send timeout occurred at block 1 after 5 attempts"),
+        "Unexpected result {result:?}"
+    );
+}
+
 #[tokio::test(flavor = "current_thread")]
 async fn change_timeout() {
     let source_ip = "127.0.0.11";
@@ -352,55 +1840,366 @@ async fn change_timeout() {
         "4: Timestamp mismatch. Received: {}, Expected: {}",
         retry_buffers[3].1, 4
     );
-    assert_eq!(
-        retry_buffers[4].0, b"\x00\x05\x00\x00Send timeout occurred\x00",
-        "5: Received: {:?}, Expected: {:?}",
-        retry_buffers[4].0, b"\x00\x05\x00\x00Send timeout occurred\x00"
+    assert_eq!(
+        retry_buffers[4].0, b"\x00\x05\x00\x00Send timeout occurred\x00",
+        "5: Received: {:?}, Expected: {:?}",
+        retry_buffers[4].0, b"\x00\x05\x00\x00Send timeout occurred\x00"
+    );
+    assert_eq!(
+        retry_buffers[4].1, 5,
+        "5: Timestamp mismatch. Received: {}, Expected: {}",
+        retry_buffers[4].1, 5
+    );
+    assert_eq!(
+        retry_buffers[5].0, b"",
+        "5: Received: {:?}, Expected: {:?}",
+        retry_buffers[5].0, b""
+    );
+    assert_eq!(
+        retry_buffers[5].1, 0,
+        "5: Timestamp mismatch. Received: {}, Expected: {}",
+        retry_buffers[5].1, 0
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_download_nbd_file_aligned() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(test_download_nbd_file_aligned);
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let config = json!({
+        "url": nbd_process.get_url(),
+        "mounts": [
+            {
+                "partition": 2,
+                "mountpoint": "/",
+            },
+                {
+                "partition": 1,
+                "mountpoint": "/boot",
+            }
+        ],
+        "tftp_root": "/boot",
+    });
+    let nbd_share_config_file = server_dir.join(format!("{}.nbd", source_ip));
+    _write_file(&nbd_share_config_file, config.to_string().as_bytes());
+    let running_server = start_rtftp(server_dir.clone()).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let existing_file = "aligned.file";
+    let read_data = download(client, existing_file).await.unwrap();
+    let data = make_payload(4194304);
+    assert_eq!(read_data, data);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_download_nbd_empty_file() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(test_download_nbd_empty_file);
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let config = json!({
+        "url": nbd_process.get_url(),
+        "mounts": [
+            {
+                "partition": 2,
+                "mountpoint": "/",
+            },
+                {
+                "partition": 1,
+                "mountpoint": "/boot",
+            }
+        ],
+        "tftp_root": "/boot",
+    });
+    let nbd_share_config_file = server_dir.join(format!("{}.nbd", source_ip));
+    _write_file(&nbd_share_config_file, config.to_string().as_bytes());
+    let running_server = start_rtftp(server_dir.clone()).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let sent_request = client.send_plain_read_request("empty.file").await.unwrap();
+    let block = sent_request.read_next(30).await.unwrap();
+    assert_eq!(block.datagram(), b"\x00\x03\x00\x01");
+    let sent_ack = block.acknowledge().await.unwrap();
+    let result = sent_ack.read_next(2).await;
+    assert!(
+        matches!(&result, Err(TFTPClientError::IO(error)) if error.kind() == ErrorKind::TimedOut),
+        "Unexpected result {result:?}"
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_download_nbd_file_size_limit() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(test_download_nbd_file_size_limit);
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let config = json!({
+        "url": nbd_process.get_url(),
+        "mounts": [
+            {
+                "partition": 1,
+                "mountpoint": "/",
+            }
+        ],
+        "tftp_root": "/",
+    });
+    let nbd_share_config_file = server_dir.join(format!("{}.nbd", source_ip));
+    _write_file(&nbd_share_config_file, config.to_string().as_bytes());
+    let file_size = 4194304;
+    let running_server = start_rtftp_with_args(
+        server_dir.clone(),
+        &["--max-file-size", &file_size.to_string()],
+    )
+    .await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let read_data = download(client, "aligned.file").await.unwrap();
+    assert_eq!(read_data, make_payload(file_size));
+    drop(running_server);
+    let running_server = start_rtftp_with_args(
+        server_dir.clone(),
+        &["--max-file-size", &(file_size - 1).to_string()],
+    )
+    .await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let sent_request = client
+        .send_plain_read_request("aligned.file")
+        .await
+        .unwrap();
+    let result = sent_request.read_next(5).await;
+    assert!(
+        matches!(&result, Err(TFTPClientError::ClientError(0x00, msg)) if msg == "File exceeds the maximum served size"),
+        "Unexpected result {result:?}"
+    );
+}
+
+// Returns the downloaded data along with the first block arrival and the transfer completion times.
+async fn _download_timed(
+    client: TFTPClient,
+    file: &str,
+) -> (Vec<u8>, time::Instant, time::Instant) {
+    let mut read_data: Vec<u8> = Vec::new();
+    let sent_request = client.send_plain_read_request(file).await.unwrap();
+    let mut block = sent_request.read_next(5).await.unwrap();
+    let started = time::Instant::now();
+    loop {
+        read_data.extend(block.data());
+        let is_last = block.data().len() < 512;
+        let sent_ack = block.acknowledge().await.unwrap();
+        if is_last {
+            break;
+        }
+        block = sent_ack.read_next(5).await.unwrap();
+    }
+    (read_data, started, time::Instant::now())
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_concurrent_nbd_downloads_interleave() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(test_concurrent_nbd_downloads_interleave);
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let config = json!({
+        "url": nbd_process.get_url(),
+        "mounts": [
+            {
+                "partition": 1,
+                "mountpoint": "/",
+            }
+        ],
+        "tftp_root": "/",
+    });
+    let nbd_share_config_file = server_dir.join(format!("{}.nbd", source_ip));
+    _write_file(&nbd_share_config_file, config.to_string().as_bytes());
+    let running_server = start_rtftp(server_dir.clone()).await;
+    let first_client = running_server.open_paired_client(source_ip).await;
+    let second_client = running_server.open_paired_client(source_ip).await;
+    let (
+        (first_data, first_started, first_finished),
+        (second_data, second_started, second_finished),
+    ) = tokio::join!(
+        _download_timed(first_client, "aligned.file"),
+        _download_timed(second_client, "nonaligned.file"),
+    );
+    assert_eq!(first_data, make_payload(4194304));
+    assert_eq!(second_data, make_payload(4194319));
+    // Neither transfer waits for the other one to complete.
+    assert!(first_started < second_finished && second_started < first_finished);
+}
+
+async fn _first_block_latency(client: TFTPClient, file: &str) -> time::Duration {
+    let started = time::Instant::now();
+    let sent_request = client.send_plain_read_request(file).await.unwrap();
+    let first_block = sent_request.read_next(10).await.unwrap();
+    let latency = started.elapsed();
+    first_block
+        .send_error(0x0, "Early termination")
+        .await
+        .unwrap();
+    latency
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_nbd_prewarm() {
+    let test_dir = mk_tmp(test_nbd_prewarm);
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let mut latencies = Vec::new();
+    for (source_ip, prewarm) in [("127.0.0.11", false), ("127.0.0.12", true)] {
+        let server_dir = test_dir.join(source_ip);
+        fs::create_dir(&server_dir).unwrap();
+        let config = json!({
+            "url": nbd_process.get_url(),
+            "mounts": [
+                {
+                    "partition": 1,
+                    "mountpoint": "/",
+                }
+            ],
+            "tftp_root": "/",
+            "prewarm": prewarm,
+        });
+        _write_file(
+            &server_dir.join(format!("{source_ip}.nbd")),
+            config.to_string().as_bytes(),
+        );
+        let (running_server, log) = start_rtftp_with_log(server_dir, &[]).await;
+        if prewarm {
+            _wait_for_line(&log, "Connected config", time::Duration::from_secs(30));
+        }
+        let client = running_server.open_paired_client(source_ip).await;
+        latencies.push(_first_block_latency(client, "aligned.file").await);
+    }
+    let (cold, warm) = (latencies[0], latencies[1]);
+    assert!(warm < cold, "Prewarmed {warm:?}, cold {cold:?}");
+    assert!(
+        warm < time::Duration::from_millis(500),
+        "Prewarmed {warm:?}"
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_nbd_disk_cache() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(test_nbd_disk_cache);
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let config = json!({
+        "url": nbd_process.get_url(),
+        "mounts": [
+            {
+                "partition": 1,
+                "mountpoint": "/",
+            }
+        ],
+        "tftp_root": "/",
+    });
+    _write_file(
+        &server_dir.join(format!("{source_ip}.nbd")),
+        config.to_string().as_bytes(),
     );
-    assert_eq!(
-        retry_buffers[4].1, 5,
-        "5: Timestamp mismatch. Received: {}, Expected: {}",
-        retry_buffers[4].1, 5
+    let (running_server, log) = start_rtftp_with_log(
+        server_dir,
+        &["--idle-timeout", "1", "--disk-cache-ttl", "60"],
+    )
+    .await;
+    let client = running_server.open_paired_client(source_ip).await;
+    _first_block_latency(client, "aligned.file").await;
+    _wait_for_line(&log, "Connected config", time::Duration::from_secs(30));
+    _wait_for_line(&log, "Caching", time::Duration::from_secs(10));
+    let client = running_server.open_paired_client(source_ip).await;
+    _first_block_latency(client, "aligned.file").await;
+    _wait_for_line(&log, "Reclaimed cached disk", time::Duration::from_secs(10));
+    let relaunched = log
+        .try_iter()
+        .any(|(_logged_at, line)| line.contains("Connected config"));
+    assert!(!relaunched, "The disk is connected anew");
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_nbd_config_added_to_running_handler() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(test_nbd_config_added_to_running_handler);
+    let nbd_process = run_nbd_server("127.0.0.2");
+    let (running_server, log) = start_rtftp_with_log(server_dir.clone(), &[]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let read_result = download(client, "aligned.file").await;
+    assert!(
+        matches!(&read_result, Err(message) if message.to_string().contains("File not found")),
+        "Unexpected result {read_result:?}"
     );
-    assert_eq!(
-        retry_buffers[5].0, b"",
-        "5: Received: {:?}, Expected: {:?}",
-        retry_buffers[5].0, b""
+    let config = json!({
+        "url": nbd_process.get_url(),
+        "mounts": [
+            {
+                "partition": 1,
+                "mountpoint": "/",
+            }
+        ],
+        "tftp_root": "/",
+    });
+    _write_file(
+        &server_dir.join(format!("{source_ip}.nbd")),
+        config.to_string().as_bytes(),
     );
-    assert_eq!(
-        retry_buffers[5].1, 0,
-        "5: Timestamp mismatch. Received: {}, Expected: {}",
-        retry_buffers[5].1, 0
+    _wait_for_line(
+        &log,
+        &format!("{source_ip}: Reloading roots"),
+        time::Duration::from_secs(5),
     );
+    let client = running_server.open_paired_client(source_ip).await;
+    let read_data = download(client, "aligned.file").await.unwrap();
+    assert_eq!(read_data, make_payload(4194304));
+    let recreated = log
+        .try_iter()
+        .any(|(_logged_at, line)| line.contains("explicitly open a new handle"));
+    assert!(!recreated, "The handler is replaced");
 }
 
 #[tokio::test(flavor = "current_thread")]
-async fn test_download_nbd_file_aligned() {
+async fn test_nbd_configs_in_peer_directory() {
     let source_ip = "127.0.0.11";
-    let server_dir = mk_tmp(test_download_nbd_file_aligned);
+    let server_dir = mk_tmp(test_nbd_configs_in_peer_directory);
     let nbd_process = run_nbd_server("127.0.0.2");
-    let config = json!({
+    let root_partition_config = json!({
         "url": nbd_process.get_url(),
         "mounts": [
             {
                 "partition": 2,
                 "mountpoint": "/",
-            },
-                {
+            }
+        ],
+        "tftp_root": "/",
+    });
+    let boot_partition_config = json!({
+        "url": nbd_process.get_url(),
+        "mounts": [
+            {
                 "partition": 1,
-                "mountpoint": "/boot",
+                "mountpoint": "/",
             }
         ],
-        "tftp_root": "/boot",
+        "tftp_root": "/",
     });
-    let nbd_share_config_file = server_dir.join(format!("{}.nbd", source_ip));
-    _write_file(&nbd_share_config_file, config.to_string().as_bytes());
+    let peer_directory = server_dir.join(source_ip);
+    _write_file(
+        &peer_directory.join("1-root.nbd"),
+        root_partition_config.to_string().as_bytes(),
+    );
+    _write_file(
+        &peer_directory.join("2-boot.json"),
+        boot_partition_config.to_string().as_bytes(),
+    );
     let running_server = start_rtftp(server_dir.clone()).await;
+    // Only the boot partition has this file.
     let client = running_server.open_paired_client(source_ip).await;
-    let existing_file = "aligned.file";
-    let read_data = download(client, existing_file).await.unwrap();
-    let data = make_payload(4194304);
-    assert_eq!(read_data, data);
+    let read_data = download(client, "aligned.file").await.unwrap();
+    assert_eq!(read_data, make_payload(4194304));
+    // Only the root partition has this file.
+    let client = running_server.open_paired_client(source_ip).await;
+    let sent_request = client
+        .send_plain_read_request("unreadable.file")
+        .await
+        .unwrap();
+    let result = sent_request.read_next(5).await;
+    assert!(
+        matches!(&result, Err(TFTPClientError::ClientError(0x02, msg)) if msg == "Access violation"),
+        "Unexpected result {result:?}"
+    );
 }
 
 #[tokio::test(flavor = "current_thread")]
@@ -692,3 +2491,457 @@ async fn file_window_partial_ack() {
     let forth_block_num = u16::from_be_bytes(buffer[2..4].try_into().unwrap());
     assert_eq!(forth_block_num, 4);
 }
+
+#[tokio::test(flavor = "current_thread")]
+async fn download_allowed_pattern() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(download_allowed_pattern);
+    let data = make_payload(1024);
+    let file_name = "boot.cfg";
+    _write_file(&server_dir.join(source_ip).join(file_name), &data);
+    let running_server =
+        start_rtftp_with_args(server_dir, &["--allow", "*.cfg", "--deny", ".*"]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let read_data = download(client, file_name).await.unwrap();
+    assert_eq!(read_data, data);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn attempt_download_denied_pattern() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(attempt_download_denied_pattern);
+    let file_name = ".secret.cfg";
+    _write_file(
+        &server_dir.join(source_ip).join(file_name),
+        &make_payload(1024),
+    );
+    let running_server =
+        start_rtftp_with_args(server_dir, &["--allow", "*.cfg", "--deny", ".*"]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let sent_request = client.send_plain_read_request(file_name).await.unwrap();
+    let result = sent_request.read_next(5).await;
+    assert!(
+        matches!(&result, Err(TFTPClientError::ClientError(0x02, msg)) if msg == "Access violation"),
+        "Unexpected result {result:?}"
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn window_size_clamped() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(window_size_clamped);
+    let file_name = "file.txt";
+    _write_file(
+        &server_dir.join(source_ip).join(file_name),
+        &make_payload(4096),
+    );
+    let running_server = start_rtftp_with_args(server_dir, &["--max-window-size", "4"]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let send_options = HashMap::from([("windowsize".to_string(), 16.to_string())]);
+    let sent_request = client
+        .send_optioned_read_request(file_name, &send_options)
+        .await
+        .unwrap();
+    let oack = sent_request.read_oack(5).await.unwrap();
+    let received_options = oack.fields();
+    assert_eq!(received_options.get("windowsize").unwrap(), "4");
+    let sent_ack = oack.acknowledge().await.unwrap();
+    let first_block = sent_ack.read_next(5).await.unwrap();
+    first_block
+        .send_error(0x0, "Early termination")
+        .await
+        .unwrap();
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn window_exceeds_session_buffer() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(window_exceeds_session_buffer);
+    let file_name = "file.txt";
+    _write_file(
+        &server_dir.join(source_ip).join(file_name),
+        &make_payload(4096),
+    );
+    let running_server = start_rtftp_with_args(server_dir, &["--max-session-buffer", "4096"]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let send_options = HashMap::from([
+        ("windowsize".to_string(), 4.to_string()),
+        ("blksize".to_string(), 1024.to_string()),
+    ]);
+    let sent_request = client
+        .send_optioned_read_request(file_name, &send_options)
+        .await
+        .unwrap();
+    let result = sent_request.read_oack(5).await;
+    assert!(
+        matches!(&result, Err(TFTPClientError::ClientError(0x08, _))),
+        "Unexpected result {result:?}"
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn oack_skipped_by_client() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(oack_skipped_by_client);
+    let data = make_payload(4096);
+    let file_name = "file.txt";
+    _write_file(&server_dir.join(source_ip).join(file_name), &data);
+    let running_server = start_rtftp(server_dir).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let send_options = HashMap::from([("blksize".to_string(), 1024.to_string())]);
+    let sent_request = client
+        .send_optioned_read_request(file_name, &send_options)
+        .await
+        .unwrap();
+    let oack = sent_request.read_oack(5).await.unwrap();
+    let datagram_stream = oack.datagram_stream;
+    let first_block_acknowledge = b"\x00\x04\x00\x01";
+    datagram_stream.send(first_block_acknowledge).await.unwrap();
+    let mut buffer = [0u8; _BUFFER_SIZE];
+    let read_bytes = datagram_stream.recv(&mut buffer, 5, 4).await.unwrap();
+    assert_eq!(buffer[..4], *b"\x00\x03\x00\x01");
+    assert_eq!(buffer[4..read_bytes], data[..512]);
+    datagram_stream
+        .send(b"\x00\x05\x00\x00Early termination\x00")
+        .await
+        .unwrap();
+}
+
+#[test]
+fn validate_configs() {
+    let server_dir = mk_tmp(validate_configs);
+    let valid_config = json!({
+        "url": "nbd://127.0.0.2:1000/disk",
+        "mounts": [
+            {
+                "partition": 1,
+                "mountpoint": "/",
+            }
+        ],
+        "tftp_root": "/",
+    });
+    _write_file(
+        &server_dir.join("127.0.0.11.nbd"),
+        valid_config.to_string().as_bytes(),
+    );
+    _write_file(&server_dir.join("127.0.0.12.nbd"), b"{\"url\": ");
+    let output = run_rtftp_to_completion(server_dir, &["--validate-configs"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+    assert!(stderr.contains("Invalid config"), "{stderr}");
+    assert!(stderr.contains("127.0.0.12.nbd"), "{stderr}");
+    assert!(!stderr.contains("127.0.0.11.nbd"), "{stderr}");
+    assert!(stderr.contains("Checked 2 configs, 1 invalid"), "{stderr}");
+}
+
+async fn collect_datagrams(first_block: Block) -> Vec<Vec<u8>> {
+    let mut datagrams = Vec::new();
+    let mut block = first_block;
+    loop {
+        datagrams.push(block.datagram().to_vec());
+        let is_last = block.data().len() < 512;
+        let sent_ack = block.acknowledge().await.unwrap();
+        if is_last {
+            break;
+        }
+        block = sent_ack.read_next(5).await.unwrap();
+    }
+    datagrams
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn window_size_one_matches_plain_transfer() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(window_size_one_matches_plain_transfer);
+    let data = make_payload(512 * 3 + 100);
+    let file_name = "file.txt";
+    let file = server_dir.join(source_ip).join(file_name);
+    _write_file(&file, &data);
+    let running_server = start_rtftp(server_dir).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let sent_request = client.send_plain_read_request(file_name).await.unwrap();
+    let plain_datagrams = collect_datagrams(sent_request.read_next(5).await.unwrap()).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let send_options = HashMap::from([("windowsize".to_string(), "1".to_string())]);
+    let sent_request = client
+        .send_optioned_read_request(file_name, &send_options)
+        .await
+        .unwrap();
+    let oack = sent_request.read_oack(5).await.unwrap();
+    assert_eq!(oack.fields(), send_options);
+    let sent_ack = oack.acknowledge().await.unwrap();
+    let windowed_datagrams = collect_datagrams(sent_ack.read_next(5).await.unwrap()).await;
+    assert_eq!(plain_datagrams.len(), 4);
+    assert_eq!(windowed_datagrams, plain_datagrams);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn fallback_file() {
+    let server_dir = mk_tmp(fallback_file);
+    let config_dir = server_dir.join("default").join("pxelinux.cfg");
+    _write_file(&config_dir.join("default"), b"generic");
+    _write_file(&config_dir.join("01-aa-bb-cc-dd-ee-01"), b"specific");
+    let running_server = start_rtftp_with_args(
+        server_dir,
+        &[
+            "--fallback-file",
+            "{dir}/missing",
+            "--fallback-file",
+            "{dir}/default",
+        ],
+    )
+    .await;
+    for (file_name, expected) in [
+        ("pxelinux.cfg/01-aa-bb-cc-dd-ee-01", "specific"),
+        ("pxelinux.cfg/01-aa-bb-cc-dd-ee-02", "generic"),
+    ] {
+        let client = running_server.open_paired_client("127.0.0.11").await;
+        let read_data = download(client, file_name).await.unwrap();
+        assert_eq!(read_data, expected.as_bytes(), "{file_name}");
+    }
+    let client = running_server.open_paired_client("127.0.0.11").await;
+    assert!(
+        download(client, "elsewhere/01-aa-bb-cc-dd-ee-02")
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn serve_after_chroot() {
+    if unsafe { libc::geteuid() } != 0 {
+        eprintln!("Chroot requires root, skipping");
+        return;
+    }
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(serve_after_chroot);
+    let data = make_payload(4096);
+    _write_file(&server_dir.join(source_ip).join("file.bin"), &data);
+    _write_file(&server_dir.join("default").join("common.bin"), b"common");
+    let running_server = start_rtftp_with_args(server_dir, &["--chroot"]).await;
+    let client = running_server.open_paired_client(source_ip).await;
+    assert_eq!(download(client, "file.bin").await.unwrap(), data);
+    let client = running_server.open_paired_client(source_ip).await;
+    assert_eq!(download(client, "common.bin").await.unwrap(), b"common");
+    let client = running_server.open_paired_client(source_ip).await;
+    assert!(download(client, "../../etc/passwd").await.is_err());
+}
+
+async fn raw_oack(running_server: &RunningServer, source_ip: &str) -> Vec<u8> {
+    let socket = UdpSocket::bind((source_ip, 0)).await.unwrap();
+    let request =
+        b"\x00\x01file.bin\x00octet\x00timeout\x003\x00blksize\x001428\x00tsize\x000\x00windowsize\x0016\x00";
+    socket
+        .send_to(request, running_server.listen_socket)
+        .await
+        .unwrap();
+    let mut datagram = [0u8; 1024];
+    let (size, _) = tokio::time::timeout(
+        time::Duration::from_secs(5),
+        socket.recv_from(&mut datagram),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    datagram[..size].to_vec()
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn oack_wire_format() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(oack_wire_format);
+    _write_file(
+        &server_dir.join(source_ip).join("file.bin"),
+        &make_payload(1048576),
+    );
+    let expected =
+        b"\x00\x06timeout\x003\x00blksize\x001428\x00tsize\x001048576\x00windowsize\x0016\x00";
+    let running_server = start_rtftp(server_dir.clone()).await;
+    assert_eq!(raw_oack(&running_server, source_ip).await, expected);
+    drop(running_server);
+    let running_server = start_rtftp_with_args(server_dir, &["--oack-no-final-null"]).await;
+    let oack = raw_oack(&running_server, source_ip).await;
+    assert_eq!(oack, expected[..expected.len() - 1]);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn relay_to_upstream() {
+    let source_ip = "127.0.0.11";
+    let test_dir = mk_tmp(relay_to_upstream);
+    let relayed_data = make_payload(5000);
+    _write_file(
+        &test_dir
+            .join("upstream")
+            .join("default")
+            .join("relayed.bin"),
+        &relayed_data,
+    );
+    let local_data = make_payload(700);
+    _write_file(
+        &test_dir.join("relay").join(source_ip).join("local.bin"),
+        &local_data,
+    );
+    let upstream_server = start_rtftp(test_dir.join("upstream")).await;
+    let upstream = upstream_server.listen_socket.to_string();
+    let relay_server =
+        start_rtftp_with_args(test_dir.join("relay"), &["--upstream", &upstream]).await;
+    let client = relay_server.open_paired_client(source_ip).await;
+    assert_eq!(download(client, "relayed.bin").await.unwrap(), relayed_data);
+    let client = relay_server.open_paired_client(source_ip).await;
+    let read_data = download_window(client, "relayed.bin", 4).await.unwrap();
+    assert_eq!(read_data, relayed_data);
+    let client = relay_server.open_paired_client(source_ip).await;
+    assert_eq!(download(client, "local.bin").await.unwrap(), local_data);
+    let client = relay_server.open_paired_client(source_ip).await;
+    let sent_request = client.send_plain_read_request("missing.bin").await.unwrap();
+    let result = sent_request.read_next(5).await;
+    assert!(
+        matches!(&result, Err(TFTPClientError::ClientError(0x01, msg)) if msg == "File not found"),
+        "Unexpected result {result:?}"
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn relay_limits_size_without_tsize() {
+    let source_ip = "127.0.0.11";
+    let test_dir = mk_tmp(relay_limits_size_without_tsize);
+    let small_data = make_payload(3000);
+    let upstream_dir = test_dir.join("upstream").join("default");
+    _write_file(&upstream_dir.join("small.bin"), &small_data);
+    _write_file(&upstream_dir.join("large.bin"), &make_payload(5000));
+    fs::create_dir_all(test_dir.join("relay").join(source_ip)).unwrap();
+    // The upstream acknowledges no options, so the size of its files is unknown ahead.
+    let upstream_server = start_rtftp_with_args(test_dir.join("upstream"), &["--no-oack"]).await;
+    let upstream = upstream_server.listen_socket.to_string();
+    let relay_server = start_rtftp_with_args(
+        test_dir.join("relay"),
+        &["--upstream", &upstream, "--max-file-size", "4000"],
+    )
+    .await;
+    let client = relay_server.open_paired_client(source_ip).await;
+    assert_eq!(download(client, "small.bin").await.unwrap(), small_data);
+    let client = relay_server.open_paired_client(source_ip).await;
+    // Cut short once more than the limit arrives from the upstream.
+    let error = download(client, "large.bin").await.unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("[0] File exceeds the maximum served size"),
+        "Unexpected error {error}"
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn fallback_files_relayed_after_local_ones() {
+    let source_ip = "127.0.0.11";
+    let test_dir = mk_tmp(fallback_files_relayed_after_local_ones);
+    let upstream_default = make_payload(900);
+    _write_file(
+        &test_dir
+            .join("upstream")
+            .join("default")
+            .join("pxelinux.cfg")
+            .join("default"),
+        &upstream_default,
+    );
+    let peer_dir = test_dir.join("relay").join(source_ip);
+    fs::create_dir_all(&peer_dir).unwrap();
+    let upstream_server = start_rtftp(test_dir.join("upstream")).await;
+    let upstream = upstream_server.listen_socket.to_string();
+    let relay_server = start_rtftp_with_args(
+        test_dir.join("relay"),
+        &["--upstream", &upstream, "--fallback-file", "{dir}/default"],
+    )
+    .await;
+    // Missing everywhere under its own name, the fallback name is asked of the upstream too.
+    let client = relay_server.open_paired_client(source_ip).await;
+    let read_data = download(client, "pxelinux.cfg/01-aa-bb").await.unwrap();
+    assert_eq!(read_data, upstream_default);
+    // A local fallback is served ahead of anything the upstream has.
+    let local_default = make_payload(300);
+    _write_file(
+        &peer_dir.join("pxelinux.cfg").join("default"),
+        &local_default,
+    );
+    let client = relay_server.open_paired_client(source_ip).await;
+    let read_data = download(client, "pxelinux.cfg/01-aa-bb").await.unwrap();
+    assert_eq!(read_data, local_default);
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn silent_upstream_times_out() {
+    let source_ip = "127.0.0.11";
+    let server_dir = mk_tmp(silent_upstream_times_out);
+    let silent_socket = UdpSocket::bind("127.0.0.30:0").await.unwrap();
+    let upstream = silent_socket.local_addr().unwrap().to_string();
+    let running_server = start_rtftp_with_args(
+        server_dir,
+        &["--upstream", &upstream, "--upstream-timeout-ms", "100"],
+    )
+    .await;
+    let client = running_server.open_paired_client(source_ip).await;
+    let sent_request = client.send_plain_read_request("file.bin").await.unwrap();
+    let result = sent_request.read_next(5).await;
+    assert!(
+        matches!(&result, Err(TFTPClientError::ClientError(0x00, msg)) if msg == "Timed out accessing the file"),
+        "Unexpected result {result:?}"
+    );
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn transfer_lines_carry_request_id() {
+    let server_dir = mk_tmp(transfer_lines_carry_request_id);
+    let data = make_payload(512 * 50);
+    for source_ip in ["127.0.0.11", "127.0.0.12"] {
+        _write_file(&server_dir.join(source_ip).join("file.bin"), &data);
+    }
+    let (running_server, log) = start_rtftp_with_log(server_dir, &[]).await;
+    let first_client = running_server.open_paired_client("127.0.0.11").await;
+    let second_client = running_server.open_paired_client("127.0.0.12").await;
+    let (first, second) = tokio::join!(
+        download(first_client, "file.bin"),
+        download(second_client, "file.bin")
+    );
+    assert_eq!(first.unwrap(), data);
+    assert_eq!(second.unwrap(), data);
+    // The session lines go `<#ID LOCAL <=> PEER>: ...`.
+    let mut sessions: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    let mut finished = 0;
+    while finished < 2 {
+        let (_, line) = log
+            .recv_timeout(time::Duration::from_secs(5))
+            .expect("The transfers didn't finish in the log");
+        let Some(session_line) = line.strip_prefix("<#") else {
+            continue;
+        };
+        let (prefix, message) = session_line.split_once(">: ").unwrap();
+        let (id, addresses) = prefix.split_once(' ').unwrap();
+        let (_local, peer) = addresses.split_once(" <=> ").unwrap();
+        if message.starts_with("Sent ") {
+            finished += 1;
+        }
+        sessions
+            .entry(peer.to_string())
+            .or_default()
+            .push((id.to_string(), message.to_string()));
+    }
+    assert_eq!(sessions.len(), 2, "{sessions:?}");
+    let mut ids = Vec::new();
+    for (peer, lines) in &sessions {
+        let id = &lines[0].0;
+        assert!(
+            lines.iter().all(|(line_id, _)| line_id == id),
+            "{peer}: {lines:?}"
+        );
+        for expected in ["Opening file.bin", "Transfer started", "Sent "] {
+            assert!(
+                lines
+                    .iter()
+                    .any(|(_, message)| message.starts_with(expected)),
+                "{peer}: No {expected:?} in {lines:?}"
+            );
+        }
+        ids.push(id.clone());
+    }
+    assert_ne!(ids[0], ids[1]);
+}